@@ -47,6 +47,13 @@ struct Diff {
     /// optionally specify a chunk size
     #[argh(option)]
     scan_chunk_size: Option<usize>,
+    /// how many more bytes a match must get right to switch alignments
+    #[argh(option, default = "bidiff::DEFAULT_MATCH_THRESHOLD")]
+    match_threshold: u8,
+    /// rough memory for sorting each partition, in bytes, overrides the
+    /// number of partitions
+    #[argh(option)]
+    max_memory: Option<usize>,
 }
 
 /// Apply a patch file generated by this tool
@@ -84,8 +91,9 @@ struct Cycle {
 }
 
 /// Compression method used
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum Method {
+    #[default]
     Stored,
     Deflate,
     Brotli,
@@ -93,12 +101,6 @@ pub enum Method {
     Zstd,
 }
 
-impl Default for Method {
-    fn default() -> Self {
-        Self::Stored
-    }
-}
-
 impl Method {
     fn compress<W: Write + Seek, R: Read>(
         self,
@@ -298,16 +300,28 @@ fn do_diff(
         method,
         sort_partitions,
         scan_chunk_size,
+        match_threshold,
+        max_memory,
     }: &Diff,
 ) -> Result<()> {
     println!("Using method {:?}", method);
+    for path in [older, newer] {
+        let compressor = bidiff::squashfs_compressor(path).context("read squashfs compressor")?;
+        println!("{} is compressed with {:?}", path.display(), compressor);
+    }
     let start = Instant::now();
 
     let older_contents = fs::read(older).context("read old file")?;
     let newer_contents = fs::read(newer).context("read new file")?;
 
     let (mut patch_r, mut patch_w) = pipe::pipe();
-    let diff_params = DiffParams::new(*sort_partitions, *scan_chunk_size).unwrap();
+    let diff_params = DiffParams::builder()
+        .sort_partitions(*sort_partitions)
+        .scan_chunk_size(*scan_chunk_size)
+        .match_threshold(*match_threshold)
+        .max_memory(*max_memory)
+        .build()
+        .map_err(|e| anyhow::anyhow!(e))?;
     let older = older.clone();
     let newer = newer.clone();
     std::thread::spawn(move || {
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use argh::FromArgs;
+use bidiff::DiffParams;
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+/// Create and apply binary patches
+#[derive(FromArgs, PartialEq, Debug)]
+struct Cli {
+    #[argh(subcommand)]
+    cmd: Command,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum Command {
+    Diff(Diff),
+    Patch(Patch),
+}
+
+/// Write a patch that turns the older file into the newer one
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "diff")]
+struct Diff {
+    #[argh(positional)]
+    older: PathBuf,
+    #[argh(positional)]
+    newer: PathBuf,
+    #[argh(positional)]
+    patch: PathBuf,
+    /// number of partitions to sort the older file in, more is faster but
+    /// makes slightly larger patches
+    #[argh(option, default = "1")]
+    sort_partitions: usize,
+    /// size of the chunks to scan the newer file in, smaller is faster but
+    /// makes slightly larger patches
+    #[argh(option)]
+    scan_chunk_size: Option<usize>,
+}
+
+/// Apply a patch to the older file, writing the newer one
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "patch")]
+struct Patch {
+    #[argh(positional)]
+    older: PathBuf,
+    #[argh(positional)]
+    patch: PathBuf,
+    #[argh(positional)]
+    newer: PathBuf,
+}
+
+fn main() -> ExitCode {
+    env_logger::builder().init();
+
+    let Cli { cmd } = argh::from_env();
+    let res = match cmd {
+        Command::Diff(args) => do_diff(&args),
+        Command::Patch(args) => do_patch(&args),
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // the whole chain of causes on one line, without a backtrace
+            eprintln!("bidiff: {:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn do_diff(
+    Diff {
+        older,
+        newer,
+        patch,
+        sort_partitions,
+        scan_chunk_size,
+    }: &Diff,
+) -> Result<()> {
+    let params = DiffParams::builder()
+        .sort_partitions(*sort_partitions)
+        .scan_chunk_size(*scan_chunk_size)
+        .build()
+        .map_err(|e| anyhow::anyhow!(e))
+        .context("invalid diff parameters")?;
+
+    let older_contents = fs::read(older).with_context(|| format!("read older file {:?}", older))?;
+    let newer_contents = fs::read(newer).with_context(|| format!("read newer file {:?}", newer))?;
+
+    let mut patch_w = BufWriter::new(
+        File::create(patch).with_context(|| format!("create patch file {:?}", patch))?,
+    );
+    bidiff::simple_diff_with_params(&older_contents, &newer_contents, &mut patch_w, &params)
+        .and_then(|_| patch_w.flush())
+        .with_context(|| format!("write patch file {:?}", patch))?;
+    Ok(())
+}
+
+fn do_patch(
+    Patch {
+        older,
+        patch,
+        newer,
+    }: &Patch,
+) -> Result<()> {
+    let older_contents = fs::read(older).with_context(|| format!("read older file {:?}", older))?;
+    let mut patch_r =
+        BufReader::new(File::open(patch).with_context(|| format!("open patch file {:?}", patch))?);
+    let mut newer_w = BufWriter::new(
+        File::create(newer).with_context(|| format!("create newer file {:?}", newer))?,
+    );
+
+    bidiff::dec::apply(&older_contents, &mut patch_r, &mut newer_w)
+        .and_then(|_| newer_w.flush())
+        .with_context(|| format!("apply patch {:?}", patch))?;
+    Ok(())
+}
@@ -0,0 +1,36 @@
+//! Inputs shared by the benchmarks. This is a module rather than
+//! `benches/common.rs`, which cargo would build as a benchmark of its own.
+
+#![allow(dead_code)]
+
+/// Deterministic pseudo-random bytes, so runs stay comparable
+pub struct XorShift(pub u64);
+
+impl Default for XorShift {
+    fn default() -> Self {
+        Self(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl XorShift {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+
+    /// A copy of `buf` with `buf.len() / edits` bytes overwritten at random
+    pub fn edited(&mut self, buf: &[u8], edits: usize) -> Vec<u8> {
+        let mut edited = buf.to_vec();
+        for _ in 0..buf.len() / edits {
+            let i = self.next() as usize % buf.len();
+            edited[i] = self.next() as u8;
+        }
+        edited
+    }
+}
@@ -0,0 +1,42 @@
+//! Diffing a 1 MiB "newer" file with 1% of edits into a `Vec`, with and
+//! without [DiffParams::with_reserve_hint]. The hint is the size of the
+//! patch, as when it's known from an earlier diff of similar files.
+//!
+//! Run with `cargo bench -p bidiff --bench reserve`. Throughput is in bytes of
+//! "newer" per second.
+
+use bidiff::{simple_diff_with_params, DiffParams};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+mod common;
+use common::XorShift;
+
+const LEN: usize = 1024 * 1024;
+
+fn reserve(c: &mut Criterion) {
+    let mut rng = XorShift::default();
+    let older = rng.bytes(LEN);
+    let newer = rng.edited(&older, 100);
+
+    let unhinted = DiffParams::default();
+    let mut patch = Vec::new();
+    simple_diff_with_params(&older, &newer, &mut patch, &unhinted).unwrap();
+    let hinted = DiffParams::default().with_reserve_hint(Some(patch.len()));
+
+    let mut group = c.benchmark_group("reserve");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(LEN as u64));
+    for (name, params) in [("no hint", &unhinted), ("hint", &hinted)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut patch = Vec::new();
+                simple_diff_with_params(&older, &newer, &mut patch, params).unwrap();
+                patch.len()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, reserve);
+criterion_main!(benches);
@@ -0,0 +1,67 @@
+//! Scan throughput of [Differ::scan] over 1 MiB "newer" files that are more
+//! or less similar to "older", sorted in 1 and [available_parallelism]
+//! partitions. The suffix array is built once, outside of what's measured.
+//!
+//! Run with `cargo bench -p bidiff --bench scan`. Throughput is in bytes of
+//! "newer" per second, in binary units like the `info!` logs of the crate.
+
+use bidiff::{available_parallelism, DiffError, DiffParams, Differ};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+mod common;
+use common::XorShift;
+
+const LEN: usize = 1024 * 1024;
+
+/// "older" and the "newer" files to scan against it
+fn inputs() -> (Vec<u8>, Vec<(&'static str, Vec<u8>)>) {
+    let mut rng = XorShift::default();
+    let older = rng.bytes(LEN);
+    let edited = rng.edited(&older, 100);
+
+    // identical files take a shortcut rather than being scanned, one changed
+    // byte doesn't
+    let mut one_byte = older.clone();
+    one_byte[LEN / 2] ^= 0xFF;
+
+    let newer = vec![
+        ("1 byte changed", one_byte),
+        ("1% edits", edited),
+        ("random", rng.bytes(LEN)),
+    ];
+    (older, newer)
+}
+
+fn scan(c: &mut Criterion) {
+    let (older, newer) = inputs();
+
+    let mut group = c.benchmark_group("scan");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(LEN as u64));
+
+    let mut partition_counts = vec![1, available_parallelism()];
+    partition_counts.dedup();
+    for partitions in partition_counts {
+        let params = DiffParams::new(partitions, None).unwrap();
+        let differ = Differ::prepare(&older, &params);
+        for (name, newer) in &newer {
+            let id = BenchmarkId::new(*name, format!("{} partitions", partitions));
+            group.bench_with_input(id, newer, |b, newer| {
+                b.iter(|| {
+                    let mut matches = 0;
+                    differ
+                        .scan(newer, |_| {
+                            matches += 1;
+                            Ok::<_, DiffError>(())
+                        })
+                        .unwrap();
+                    matches
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, scan);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+//! Diffing and encoding a 4 MiB "newer" file with 1% of edits in 64 KiB
+//! chunks, into a segmented patch whose segments are encoded on the threads
+//! that scanned them ([segmented_diff_with_params]), and into a regular patch
+//! whose chunks are scanned in parallel but drained into a single encoder
+//! ([simple_diff_with_params]). Sorting the suffix array is part of both.
+//!
+//! Run with `cargo bench -p bidiff --bench segmented`. Throughput is in bytes
+//! of "newer" per second.
+
+use bidiff::{segmented_diff_with_params, simple_diff_with_params, DiffParams};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+mod common;
+use common::XorShift;
+
+const LEN: usize = 4 * 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn segmented(c: &mut Criterion) {
+    let mut rng = XorShift::default();
+    let older = rng.bytes(LEN);
+    let newer = rng.edited(&older, 100);
+    let params = DiffParams::new(1, Some(CHUNK_SIZE)).unwrap();
+
+    let mut group = c.benchmark_group("encode");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.bench_function("segmented", |b| {
+        b.iter(|| {
+            let mut patch = Vec::new();
+            segmented_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+            patch.len()
+        })
+    });
+    group.bench_function("serial drain", |b| {
+        b.iter(|| {
+            let mut patch = Vec::new();
+            simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+            patch.len()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, segmented);
+criterion_main!(benches);
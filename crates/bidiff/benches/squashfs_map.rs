@@ -0,0 +1,92 @@
+//! Listing the blocks of an old squashfs image into a [SquashfsFragmentMap],
+//! for a synthetic list of a million blocks, and diffing a synthetic 4 MiB
+//! image of 64-byte blocks against another where 1% of them changed, which
+//! looks each block up in that map. No real image is needed for either.
+//!
+//! Run with `cargo bench -p bidiff --features squashfs-rust --bench
+//! squashfs_map`, and again with `--no-default-features` to compare against
+//! building the map and looking blocks up without the `parallel` feature.
+
+use bidiff::{diff_squashfs_in_memory, FileOffset, Hash, SquashfsFragmentMap};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::path::Path;
+
+mod common;
+use common::XorShift;
+
+const MAP_BLOCKS: usize = 1024 * 1024;
+const IMAGE_BLOCKS: usize = 64 * 1024;
+const BLOCK_SIZE: usize = 64;
+const SUPERBLOCK_LEN: usize = 96;
+
+/// A squashfs superblock followed by `blocks`, then "tables" up to its end,
+/// with the hash, offset and size of each block
+fn image(blocks: &[Vec<u8>]) -> (Vec<u8>, Vec<(Hash, FileOffset, u32)>) {
+    let mut image = vec![0; SUPERBLOCK_LEN];
+    image[0..4].copy_from_slice(b"hsqs");
+    image[20..22].copy_from_slice(&1u16.to_le_bytes());
+    image[28..30].copy_from_slice(&4u16.to_le_bytes());
+    let mut listed = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        let hash = hmac_sha256::Hash::hash(block);
+        listed.push((hash, image.len() as FileOffset, block.len() as u32));
+        image.extend_from_slice(block);
+    }
+    let inode_table_start = image.len() as u64;
+    image[64..72].copy_from_slice(&inode_table_start.to_le_bytes());
+    image.extend_from_slice(&[0; 4096]);
+    (image, listed)
+}
+
+fn squashfs_map(c: &mut Criterion) {
+    let mut rng = XorShift::default();
+    let path = Path::new("old.squashfs");
+
+    let mut group = c.benchmark_group("squashfs");
+    group.sample_size(10);
+
+    let blocks: Vec<_> = (0..MAP_BLOCKS)
+        .map(|i| {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(&rng.bytes(32));
+            (hash, (i * 4096) as FileOffset, 4096)
+        })
+        .collect();
+    group.throughput(Throughput::Elements(MAP_BLOCKS as u64));
+    group.bench_function("from_blocks", |b| {
+        b.iter_batched(
+            || blocks.clone(),
+            |blocks| SquashfsFragmentMap::from_blocks(path, MAP_BLOCKS * 4096, blocks),
+            BatchSize::LargeInput,
+        )
+    });
+
+    let old_blocks: Vec<_> = (0..IMAGE_BLOCKS).map(|_| rng.bytes(BLOCK_SIZE)).collect();
+    let mut new_blocks = old_blocks.clone();
+    for _ in 0..IMAGE_BLOCKS / 100 {
+        let i = rng.next() as usize % IMAGE_BLOCKS;
+        new_blocks[i] = rng.bytes(BLOCK_SIZE);
+    }
+    let (old, old_listed) = image(&old_blocks);
+    let (new, new_listed) = image(&new_blocks);
+    group.throughput(Throughput::Elements(IMAGE_BLOCKS as u64));
+    group.bench_function("in_memory", |b| {
+        b.iter(|| {
+            let mut patch = Vec::new();
+            diff_squashfs_in_memory(
+                &old,
+                old_listed.clone(),
+                &new,
+                new_listed.clone(),
+                &mut patch,
+                &Default::default(),
+            )
+            .unwrap();
+            patch.len()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, squashfs_map);
+criterion_main!(benches);
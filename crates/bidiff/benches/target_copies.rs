@@ -0,0 +1,59 @@
+//! Patches with and without target copies ([DiffParams::with_target_copies])
+//! of a "newer" file made of a 1 MiB "older" with 1% of edits, followed by
+//! 1 MiB of new data: a handful of 4 KiB records, repeated in random order
+//! with a byte changed each time, which "older" has nothing of.
+//!
+//! Run with `cargo bench -p bidiff --features zstd --bench target_copies`.
+//! What's measured is how large the zstd-compressed patches are, which is
+//! printed before both are timed: uncompressed, a patch is about as large
+//! as "newer" either way. Throughput is in bytes of "newer" per second.
+
+use bidiff::{simple_diff_with_params, DiffParams, PatchCompression};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+mod common;
+use common::XorShift;
+
+const LEN: usize = 1024 * 1024;
+const RECORD_LEN: usize = 4096;
+const RECORDS: usize = 16;
+
+fn target_copies(c: &mut Criterion) {
+    let mut rng = XorShift::default();
+    let older = rng.bytes(LEN);
+    let mut newer = rng.edited(&older, 100);
+    let records: Vec<_> = (0..RECORDS).map(|_| rng.bytes(RECORD_LEN)).collect();
+    for _ in 0..LEN / RECORD_LEN {
+        let mut record = records[rng.next() as usize % RECORDS].clone();
+        record[rng.next() as usize % RECORD_LEN] = rng.next() as u8;
+        newer.extend_from_slice(&record);
+    }
+
+    let plain = DiffParams::default().with_compression(PatchCompression::Zstd(3));
+    let targets = DiffParams::default()
+        .with_compression(PatchCompression::Zstd(3))
+        .with_target_copies(true);
+    let patch = |params: &DiffParams| {
+        let mut patch = Vec::new();
+        simple_diff_with_params(&older, &newer, &mut patch, params).unwrap();
+        patch
+    };
+    let (plain_len, targets_len) = (patch(&plain).len(), patch(&targets).len());
+    println!(
+        "newer: {} bytes, patch: {} bytes, with target copies: {} bytes ({:.1}%)",
+        newer.len(),
+        plain_len,
+        targets_len,
+        100.0 * targets_len as f64 / plain_len as f64
+    );
+
+    let mut group = c.benchmark_group("target_copies");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(newer.len() as u64));
+    group.bench_function("off", |b| b.iter(|| patch(&plain).len()));
+    group.bench_function("on", |b| b.iter(|| patch(&targets).len()));
+    group.finish();
+}
+
+criterion_group!(benches, target_copies);
+criterion_main!(benches);
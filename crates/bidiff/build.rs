@@ -1,16 +1,17 @@
-use cc;
-use pkg_config;
-
-// Example custom build script.
+// Builds the C shim to libsquashfs, for the `squashfs` feature. With
+// `squashfs-rust`, blocks are listed in Rust and the shim isn't needed.
 fn main() {
-    // Tell Cargo that if the given file changes, to rerun this build script.
-    println!("cargo::rerun-if-changed=shim.c");
-    let glib = pkg_config::probe_library("glib-2.0").unwrap();
-    let squashfs_ng = pkg_config::probe_library("libsquashfs1").unwrap();
+    #[cfg(all(feature = "squashfs", not(feature = "squashfs-rust")))]
+    {
+        // Tell Cargo that if the given file changes, to rerun this build script.
+        println!("cargo::rerun-if-changed=shim.c");
+        let glib = pkg_config::probe_library("glib-2.0").unwrap();
+        let squashfs_ng = pkg_config::probe_library("libsquashfs1").unwrap();
 
-    cc::Build::new()
-        .includes(glib.include_paths)
-        .includes(squashfs_ng.include_paths)
-        .file("shim.c")
-        .compile("shim");
+        cc::Build::new()
+            .includes(glib.include_paths)
+            .includes(squashfs_ng.include_paths)
+            .file("shim.c")
+            .compile("shim");
+    }
 }
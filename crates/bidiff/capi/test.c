@@ -0,0 +1,90 @@
+/*
+ * Exercises the C interface. From the workspace root:
+ *
+ *   cargo rustc -p bidiff --release --features capi --crate-type cdylib
+ *   cc -I crates/bidiff/include crates/bidiff/capi/test.c \
+ *      -L target/release -lbidiff -o capi-test
+ *   LD_LIBRARY_PATH=target/release ./capi-test
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "bidiff.h"
+
+#define CHECK(cond)                                                  \
+    do {                                                             \
+        if (!(cond)) {                                               \
+            fprintf(stderr, "%s:%d: failed: %s\n", __FILE__, __LINE__, \
+                    #cond);                                          \
+            exit(1);                                                 \
+        }                                                            \
+    } while (0)
+
+struct sink {
+    uint8_t *data;
+    size_t len;
+};
+
+static int collect(void *ctx, const uint8_t *data, size_t len) {
+    struct sink *sink = ctx;
+    sink->data = realloc(sink->data, sink->len + len);
+    if (!sink->data) {
+        return 1;
+    }
+    memcpy(sink->data + sink->len, data, len);
+    sink->len += len;
+    return 0;
+}
+
+static int refuse(void *ctx, const uint8_t *data, size_t len) {
+    (void)ctx;
+    (void)data;
+    (void)len;
+    return 1;
+}
+
+int main(void) {
+    uint8_t older[4096], newer[4096];
+    for (size_t i = 0; i < sizeof(older); i++) {
+        older[i] = (uint8_t)(i * 7 % 251);
+    }
+    memcpy(newer, older, sizeof(newer));
+    memcpy(newer + 1000, "hello from C", 12);
+
+    /* query the size, then diff for real */
+    size_t patch_len = 0;
+    CHECK(bidiff_diff(older, sizeof(older), newer, sizeof(newer), NULL, 0,
+                      &patch_len) == BIDIFF_ERR_BUFFER_TOO_SMALL);
+    uint8_t *patch = malloc(patch_len);
+    CHECK(bidiff_diff(older, sizeof(older), newer, sizeof(newer), patch,
+                      patch_len, &patch_len) == BIDIFF_OK);
+
+    /* the callback variant produces the same patch */
+    struct sink sink = {NULL, 0};
+    CHECK(bidiff_diff_cb(older, sizeof(older), newer, sizeof(newer), collect,
+                         &sink) == BIDIFF_OK);
+    CHECK(sink.len == patch_len && memcmp(sink.data, patch, patch_len) == 0);
+    CHECK(bidiff_diff_cb(older, sizeof(older), newer, sizeof(newer), refuse,
+                         NULL) == BIDIFF_ERR_CALLBACK);
+
+    uint8_t applied[4096];
+    size_t applied_len = 0;
+    CHECK(bidiff_patch(older, sizeof(older), patch, patch_len, applied,
+                       sizeof(applied), &applied_len) == BIDIFF_OK);
+    CHECK(applied_len == sizeof(newer));
+    CHECK(memcmp(applied, newer, sizeof(newer)) == 0);
+
+    /* error codes */
+    CHECK(bidiff_diff(NULL, 1, newer, sizeof(newer), NULL, 0, &patch_len) ==
+          BIDIFF_ERR_NULL);
+    patch[0] ^= 0xff;
+    CHECK(bidiff_patch(older, sizeof(older), patch, patch_len, applied,
+                       sizeof(applied), &applied_len) == BIDIFF_ERR_DECODE);
+
+    free(patch);
+    free(sink.data);
+    printf("ok\n");
+    return 0;
+}
@@ -0,0 +1,47 @@
+/*
+ * C interface to bidiff, built with the `capi` feature.
+ *
+ * Every function returns BIDIFF_OK on success, or a negative BIDIFF_ERR_*
+ * code. Functions that write into a caller-provided buffer always store the
+ * required size in *out_len, so passing out = NULL, out_cap = 0 first tells
+ * the caller how much to allocate.
+ */
+
+#ifndef BIDIFF_H
+#define BIDIFF_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define BIDIFF_OK 0
+#define BIDIFF_ERR_NULL (-1)
+#define BIDIFF_ERR_BUFFER_TOO_SMALL (-2)
+#define BIDIFF_ERR_IO (-3)
+#define BIDIFF_ERR_DECODE (-4)
+#define BIDIFF_ERR_CALLBACK (-5)
+#define BIDIFF_ERR_PANIC (-6)
+
+/* Receives patch bytes. Returning non-zero aborts the diff. */
+typedef int (*bidiff_write_fn)(void *ctx, const uint8_t *data, size_t len);
+
+int bidiff_diff(const uint8_t *older, size_t older_len,
+                const uint8_t *newer, size_t newer_len,
+                uint8_t *out, size_t out_cap, size_t *out_len);
+
+int bidiff_diff_cb(const uint8_t *older, size_t older_len,
+                   const uint8_t *newer, size_t newer_len,
+                   bidiff_write_fn write, void *ctx);
+
+int bidiff_patch(const uint8_t *older, size_t older_len,
+                 const uint8_t *patch, size_t patch_len,
+                 uint8_t *out, size_t out_cap, size_t *out_len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BIDIFF_H */
@@ -407,18 +407,8 @@ int shim_get_blocks(const char *path, struct block_with_hash **blocks,
   fstat(fd, &sb);
   const char *file_map = mmap(NULL, sb.st_size, PROT_READ, MAP_PRIVATE, fd, 0);
 
-  for (unsigned long i = 0; i < blocks_and_fragments->len - 1; i++) {
-    struct block *c = &g_array_index(blocks_and_fragments, struct block, i);
-    struct block *n = &g_array_index(blocks_and_fragments, struct block, i + 1);
-    uint64_t end = c->offset + c->size;
-    if (end < n->offset) {
-      printf("gap between blocks %lx %x and %lx %x\n", c->offset, c->size,
-             n->offset, n->size);
-    } else if (end > n->offset) {
-      printf("overlap between blocks %lx %x and %lx %x\n", c->offset, c->size,
-             n->offset, n->size);
-    }
-  }
+  // gaps and overlaps between blocks are reported by the Rust side, which
+  // logs them rather than printing to stdout, where patches may go
 
   // calculate hash of each chunk
   GChecksum *checksum = g_checksum_new(G_CHECKSUM_SHA256);
@@ -446,6 +436,8 @@ int shim_get_blocks(const char *path, struct block_with_hash **blocks,
   return 0;
 }
 
+void shim_free_blocks(struct block_with_hash *blocks) { g_free(blocks); }
+
 uint64_t shim_get_inode_table_idx(const char *path) {
   sqfs_state_t state = {0};
 
@@ -456,3 +448,22 @@ uint64_t shim_get_inode_table_idx(const char *path) {
 
   return state.super.inode_table_start;
 }
+
+uint16_t shim_get_compressor(const char *path) {
+  sqfs_file_t *file = sqfs_open_file(path, SQFS_FILE_OPEN_READ_ONLY);
+  if (file == NULL) {
+    perror(path);
+    return 0;
+  }
+
+  // only the super block is read: unlike `open_sfqs`, this shouldn't fail
+  // when libsquashfs lacks the compressor
+  sqfs_super_t super;
+  int ret = sqfs_super_read(&super, file);
+  sqfs_destroy(file);
+  if (ret) {
+    sqfs_perror(path, "reading super block", ret);
+    return 0;
+  }
+  return super.compression_id;
+}
@@ -0,0 +1,104 @@
+//! Diffing from async code running on tokio, see [diff_async] and
+//! [diff_async_channel].
+//!
+//! These only move [diff] to tokio's blocking thread pool, with
+//! [spawn_blocking](tokio::task::spawn_blocking), so that its CPU-heavy
+//! sorting and scanning don't stall other tasks. The diff itself is the same
+//! synchronous one.
+
+use super::{diff, DiffError, DiffParams, DiffStats, Match, MATCH_CHANNEL_CAPACITY};
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::mpsc;
+
+/// Diff two files on tokio's blocking thread pool, returning all of the
+/// matches once it's done
+///
+/// Dropping the returned future stops the scan at the next match. Sorting
+/// runs to completion regardless, as with [DiffParams::with_cancel], which
+/// works here too.
+///
+/// Panics, when awaited, if the diff panicked.
+pub async fn diff_async<O, N>(
+    obuf: O,
+    nbuf: N,
+    params: DiffParams,
+) -> Result<(Vec<Match>, DiffStats), DiffError>
+where
+    O: AsRef<[u8]> + Send + 'static,
+    N: AsRef<[u8]> + Send + 'static,
+{
+    let dropped = Arc::new(AtomicBool::new(false));
+    let _guard = SetOnDrop(dropped.clone());
+
+    let task = tokio::task::spawn_blocking(move || {
+        let mut matches = Vec::new();
+        let stats = diff(obuf.as_ref(), nbuf.as_ref(), &params, |m| {
+            if dropped.load(Ordering::Relaxed) {
+                return Err(DiffError::Cancelled);
+            }
+            matches.push(m);
+            Ok(())
+        })?;
+        Ok((matches, stats))
+    });
+    match task.await {
+        Ok(res) => res,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        // the runtime is shutting down
+        Err(_) => Err(DiffError::Cancelled),
+    }
+}
+
+/// Diff two files on tokio's blocking thread pool, sending matches over a
+/// bounded channel as they're found, like [diff_channel](crate::diff_channel)
+///
+/// The scan waits whenever the receiver falls behind, and stops as soon as
+/// the receiver is dropped. An error, if any, is the last thing sent: if the
+/// diff panics, that's the [JoinError](tokio::task::JoinError) of its task,
+/// as an [io::ErrorKind::Other] error.
+///
+/// Panics when not called from within a tokio runtime.
+pub fn diff_async_channel<O, N>(
+    obuf: O,
+    nbuf: N,
+    params: DiffParams,
+) -> mpsc::Receiver<Result<Match, io::Error>>
+where
+    O: AsRef<[u8]> + Send + 'static,
+    N: AsRef<[u8]> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(MATCH_CHANNEL_CAPACITY);
+    let join_tx = tx.clone();
+    let task = tokio::task::spawn_blocking(move || {
+        let res = diff(obuf.as_ref(), nbuf.as_ref(), &params, |m| {
+            tx.blocking_send(Ok(m))
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver dropped"))
+        });
+        if let Err(e) = res {
+            // fails if the receiver was dropped, which is fine
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    // the receiver only sees the end of the channel once this is sent too
+    tokio::spawn(async move {
+        if let Err(e) = task.await {
+            let _ = join_tx.send(Err(io::Error::other(e))).await;
+        }
+    });
+    rx
+}
+
+/// Sets its flag when dropped, which tells the scan the future is gone
+struct SetOnDrop(Arc<AtomicBool>);
+
+impl Drop for SetOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,171 @@
+//! Patches compressed with a compressor picked automatically, see
+//! [auto_diff].
+//!
+//! A compressed patch starts with the usual header, with one of the
+//! compression flags set, then the sizes of the uncompressed and compressed
+//! patch as little-endian u64s, followed by the compressed bytes of a
+//! complete (uncompressed) patch. Uncompressed patches are written as-is.
+
+use super::{
+    enc::{self, FLAG_XZ, FLAG_ZSTD},
+    PatchCompression,
+};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::{
+    cmp::min,
+    io::{self, Write},
+};
+
+/// Size of the beginning of the patch that's compressed to estimate how well
+/// each compressor does
+const SAMPLE_SIZE: usize = 1024 * 1024;
+
+/// Compressors [auto_diff] can pick from, depending on the enabled features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    /// Requires the `zstd` feature
+    Zstd,
+    /// Requires the `xz` feature
+    Xz,
+}
+
+impl Compression {
+    /// Compressors that were compiled in
+    pub fn available() -> Vec<Compression> {
+        let mut available = Vec::new();
+        if cfg!(feature = "zstd") {
+            available.push(Compression::Zstd);
+        }
+        if cfg!(feature = "xz") {
+            available.push(Compression::Xz);
+        }
+        available
+    }
+
+    /// The header flag recording this compressor
+    pub fn flag(self) -> u32 {
+        match self {
+            Compression::None => 0,
+            Compression::Zstd => FLAG_ZSTD,
+            Compression::Xz => FLAG_XZ,
+        }
+    }
+
+    /// Compress `data` with settings that favor `fast` over size or the
+    /// other way around
+    fn compress(self, data: &[u8], fast: bool) -> io::Result<Vec<u8>> {
+        let level = match self {
+            Compression::None => PatchCompression::None,
+            Compression::Zstd => PatchCompression::Zstd(if fast { 1 } else { 19 }),
+            Compression::Xz => PatchCompression::Xz(if fast { 1 } else { 6 }),
+        };
+        level.compress(data)
+    }
+}
+
+impl PatchCompression {
+    /// The compressor, without its level
+    pub(crate) fn compressor(self) -> Compression {
+        match self {
+            PatchCompression::None => Compression::None,
+            PatchCompression::Zstd(_) => Compression::Zstd,
+            PatchCompression::Xz(_) => Compression::Xz,
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "zstd", feature = "xz")), allow(unused_variables))]
+    pub(crate) fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            PatchCompression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            PatchCompression::Zstd(level) => zstd::stream::encode_all(data, level),
+            #[cfg(feature = "xz")]
+            PatchCompression::Xz(preset) => {
+                let mut e = xz2::write::XzEncoder::new(Vec::new(), preset.min(9));
+                e.write_all(data)?;
+                e.finish()
+            }
+            #[allow(unreachable_patterns)]
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{:?} compression was not compiled in", self.compressor()),
+            )),
+        }
+    }
+}
+
+/// What [auto_diff] did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub compression: Compression,
+    /// Size of the uncompressed patch
+    pub raw_size: u64,
+    /// Size of the patch once compressed, excluding the compressed patch
+    /// header. This is the same as `raw_size` when it isn't compressed.
+    pub compressed_size: u64,
+}
+
+impl CompressionStats {
+    /// Compressed size over raw size, lower is better
+    pub fn ratio(&self) -> f64 {
+        if self.raw_size == 0 {
+            return 1.0;
+        }
+        self.compressed_size as f64 / self.raw_size as f64
+    }
+}
+
+/// Diff two files, and compress the patch with whichever of the compiled-in
+/// compressors does best on it (or not at all, if none of them help).
+///
+/// To decide, the beginning of the patch is compressed with the fast settings
+/// of each compressor, so this does more work than [simple_diff](crate::simple_diff)
+/// followed by compression. The chosen compressor and the sizes before and
+/// after compression are recorded in the header, and returned.
+pub fn auto_diff(older: &[u8], newer: &[u8], out: &mut dyn Write) -> io::Result<CompressionStats> {
+    let mut patch = Vec::new();
+    super::simple_diff(older, newer, &mut patch)?;
+
+    let sample = &patch[..min(patch.len(), SAMPLE_SIZE)];
+    let mut best = (Compression::None, sample.len());
+    for compression in Compression::available() {
+        let size = compression.compress(sample, true)?.len();
+        if size < best.1 {
+            best = (compression, size);
+        }
+    }
+
+    let compression = best.0;
+    let raw_size = patch.len() as u64;
+    if compression == Compression::None {
+        out.write_all(&patch)?;
+        return Ok(CompressionStats {
+            compression,
+            raw_size,
+            compressed_size: raw_size,
+        });
+    }
+
+    let compressed = compression.compress(&patch, false)?;
+    write_compressed(out, compression, raw_size, &compressed)?;
+    Ok(CompressionStats {
+        compression,
+        raw_size,
+        compressed_size: compressed.len() as u64,
+    })
+}
+
+/// Write `compressed`, a patch of `raw_size` bytes compressed with
+/// `compression`, after the compressed patch header
+pub(crate) fn write_compressed(
+    mut out: &mut dyn Write,
+    compression: Compression,
+    raw_size: u64,
+    compressed: &[u8],
+) -> io::Result<()> {
+    enc::write_header(&mut out, compression.flag())?;
+    out.write_u64::<LittleEndian>(raw_size)?;
+    out.write_u64::<LittleEndian>(compressed.len() as u64)?;
+    out.write_all(compressed)
+}
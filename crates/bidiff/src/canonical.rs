@@ -0,0 +1,140 @@
+//! Normalizing matches into a canonical form, see [canonicalize].
+
+use super::Match;
+
+/// Label of "newer" bytes that are stored as-is
+const LITERAL: i64 = i64::MIN;
+
+/// Normalize `matches` (covering all of `nbuf`, in order) into a canonical
+/// form that only depends on which alignments between `obuf` and `nbuf` they
+/// use, not on where exactly each match starts and stops.
+///
+/// Every byte of `nbuf` is labeled with the alignment (the distance between
+/// its position in `obuf` and in `nbuf`) it's taken from, or as literal, and
+/// then:
+///
+/// - the edges of each aligned run are trimmed down to bytes that match
+///   exactly,
+/// - runs are extended over neighbouring literal bytes that match exactly,
+/// - literals between two runs of the same alignment are absorbed into them,
+/// - bytes that match exactly under two neighbouring alignments go to the
+///   earlier one.
+///
+/// Matches are then rebuilt from the labels. This makes patches reproducible
+/// across changes to the match-finding heuristics, as long as they find the
+/// same alignments. The canonical patch can be slightly larger or smaller
+/// than the original one.
+pub fn canonicalize<I>(obuf: &[u8], nbuf: &[u8], matches: I) -> Vec<Match>
+where
+    I: IntoIterator<Item = Match>,
+{
+    let mut labels = vec![LITERAL; nbuf.len()];
+    for m in matches {
+        let diag = m.add_old_start as i64 - m.add_new_start as i64;
+        labels[m.add_new_start..m.copy_start()].fill(diag);
+    }
+
+    let exact = |i: usize, diag: i64| {
+        let j = i as i64 + diag;
+        diag != LITERAL && j >= 0 && (j as usize) < obuf.len() && obuf[j as usize] == nbuf[i]
+    };
+    let in_old = |i: usize, diag: i64| {
+        let j = i as i64 + diag;
+        j >= 0 && (j as usize) < obuf.len()
+    };
+
+    // trim the edges of aligned runs down to exact matches
+    let mut start = 0;
+    while start < labels.len() {
+        let diag = labels[start];
+        let mut end = start;
+        while end < labels.len() && labels[end] == diag {
+            end += 1;
+        }
+        if diag != LITERAL {
+            let mut lo = start;
+            while lo < end && !exact(lo, diag) {
+                labels[lo] = LITERAL;
+                lo += 1;
+            }
+            let mut hi = end;
+            while hi > lo && !exact(hi - 1, diag) {
+                labels[hi - 1] = LITERAL;
+                hi -= 1;
+            }
+        }
+        start = end;
+    }
+
+    // extend runs over literals that match exactly, forwards then backwards
+    for i in 1..labels.len() {
+        if labels[i] == LITERAL && exact(i, labels[i - 1]) {
+            labels[i] = labels[i - 1];
+        }
+    }
+    for i in (0..labels.len().saturating_sub(1)).rev() {
+        if labels[i] == LITERAL && exact(i, labels[i + 1]) {
+            labels[i] = labels[i + 1];
+        }
+    }
+
+    // absorb literals between two runs of the same alignment
+    let mut i = 0;
+    while i < labels.len() {
+        if labels[i] != LITERAL {
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end < labels.len() && labels[end] == LITERAL {
+            end += 1;
+        }
+        if i > 0 && end < labels.len() {
+            let diag = labels[i - 1];
+            if labels[end] == diag && (i..end).all(|k| in_old(k, diag)) {
+                labels[i..end].fill(diag);
+            }
+        }
+        i = end;
+    }
+
+    // break ties between neighbouring alignments in favor of the earlier one
+    for i in 1..labels.len() {
+        let (prev, cur) = (labels[i - 1], labels[i]);
+        if prev != LITERAL && cur != LITERAL && prev != cur && exact(i, prev) {
+            labels[i] = prev;
+        }
+    }
+
+    // rebuild matches: each aligned run, followed by the literals after it
+    let mut matches = Vec::new();
+    let mut i = 0;
+    while i < labels.len() {
+        let diag = labels[i];
+        let add_new_start = i;
+        let mut add_end = i;
+        if diag != LITERAL {
+            while add_end < labels.len() && labels[add_end] == diag {
+                add_end += 1;
+            }
+        }
+        let mut copy_end = add_end;
+        while copy_end < labels.len() && labels[copy_end] == LITERAL {
+            copy_end += 1;
+        }
+
+        // literals only start a match at the very beginning of "newer"
+        let add_old_start = match diag {
+            LITERAL => 0,
+            diag => (add_new_start as i64 + diag) as usize,
+        };
+        matches.push(Match {
+            add_old_start,
+            add_new_start,
+            add_length: add_end - add_new_start,
+            copy_end,
+        });
+        i = copy_end;
+    }
+    matches
+}
@@ -0,0 +1,196 @@
+//! C-ABI functions to diff and patch buffers from other languages.
+//!
+//! Build a shared library with:
+//!
+//! ```text
+//! cargo rustc -p bidiff --release --features capi --crate-type cdylib
+//! ```
+//!
+//! The matching declarations are in `include/bidiff.h`, and `capi/test.c`
+//! exercises them.
+//!
+//! Every function returns `BIDIFF_OK` (zero) on success, and one of the
+//! negative `BIDIFF_ERR_*` codes otherwise. Functions that write into a
+//! caller-provided buffer always store the number of bytes they need in
+//! `*out_len`, even when they fail with `BIDIFF_ERR_BUFFER_TOO_SMALL`, so
+//! callers can pass a null `out` first to find out how much to allocate.
+//! Panics never cross the FFI boundary, they're reported as
+//! `BIDIFF_ERR_PANIC`.
+
+use std::{
+    ffi::{c_int, c_void},
+    io::{self, Cursor, Read, Write},
+    panic::{catch_unwind, AssertUnwindSafe},
+    slice,
+};
+
+pub const BIDIFF_OK: c_int = 0;
+/// A required pointer argument was null
+pub const BIDIFF_ERR_NULL: c_int = -1;
+/// `out_cap` is smaller than `*out_len`, nothing was written to `out`
+pub const BIDIFF_ERR_BUFFER_TOO_SMALL: c_int = -2;
+/// Writing the patch failed
+pub const BIDIFF_ERR_IO: c_int = -3;
+/// The patch is invalid, or doesn't apply to "older"
+pub const BIDIFF_ERR_DECODE: c_int = -4;
+/// The caller's callback returned non-zero
+pub const BIDIFF_ERR_CALLBACK: c_int = -5;
+/// An internal error occured
+pub const BIDIFF_ERR_PANIC: c_int = -6;
+
+/// Receives patch bytes. Returning non-zero aborts the diff.
+pub type BidiffWriteFn =
+    unsafe extern "C" fn(ctx: *mut c_void, data: *const u8, len: usize) -> c_int;
+
+/// Diff `older` against `newer`, writing the patch into `out`
+///
+/// # Safety
+///
+/// `older` and `newer` must be valid for reads of `older_len` and `newer_len`
+/// bytes (they may be null when their length is zero), `out` must be valid
+/// for writes of `out_cap` bytes (or null), and `out_len` must be valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn bidiff_diff(
+    older: *const u8,
+    older_len: usize,
+    newer: *const u8,
+    newer_len: usize,
+    out: *mut u8,
+    out_cap: usize,
+    out_len: *mut usize,
+) -> c_int {
+    let (older, newer) = match (input(older, older_len), input(newer, newer_len)) {
+        (Some(older), Some(newer)) => (older, newer),
+        _ => return BIDIFF_ERR_NULL,
+    };
+    guard(|| {
+        let mut patch = Vec::new();
+        if crate::simple_diff(older, newer, &mut patch).is_err() {
+            return BIDIFF_ERR_IO;
+        }
+        copy_out(&patch, out, out_cap, out_len)
+    })
+}
+
+/// Diff `older` against `newer`, passing the patch to `write` as it's
+/// produced, along with `ctx`
+///
+/// # Safety
+///
+/// `older` and `newer` must be valid for reads of `older_len` and `newer_len`
+/// bytes (they may be null when their length is zero).
+#[no_mangle]
+pub unsafe extern "C" fn bidiff_diff_cb(
+    older: *const u8,
+    older_len: usize,
+    newer: *const u8,
+    newer_len: usize,
+    write: Option<BidiffWriteFn>,
+    ctx: *mut c_void,
+) -> c_int {
+    let (older, newer, write) = match (input(older, older_len), input(newer, newer_len), write) {
+        (Some(older), Some(newer), Some(write)) => (older, newer, write),
+        _ => return BIDIFF_ERR_NULL,
+    };
+    guard(|| {
+        let mut w = CallbackWriter {
+            write,
+            ctx,
+            failed: false,
+        };
+        match crate::simple_diff(older, newer, &mut w) {
+            Ok(_) => BIDIFF_OK,
+            Err(_) if w.failed => BIDIFF_ERR_CALLBACK,
+            Err(_) => BIDIFF_ERR_IO,
+        }
+    })
+}
+
+/// Apply `patch` to `older`, writing the result into `out`
+///
+/// # Safety
+///
+/// `older` and `patch` must be valid for reads of `older_len` and `patch_len`
+/// bytes (they may be null when their length is zero), `out` must be valid
+/// for writes of `out_cap` bytes (or null), and `out_len` must be valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn bidiff_patch(
+    older: *const u8,
+    older_len: usize,
+    patch: *const u8,
+    patch_len: usize,
+    out: *mut u8,
+    out_cap: usize,
+    out_len: *mut usize,
+) -> c_int {
+    let (older, patch) = match (input(older, older_len), input(patch, patch_len)) {
+        (Some(older), Some(patch)) => (older, patch),
+        _ => return BIDIFF_ERR_NULL,
+    };
+    guard(|| {
+        let mut newer = Vec::new();
+        let applied = bipatch::Reader::new(patch, Cursor::new(older))
+            .map_err(|_| ())
+            .and_then(|mut r| r.read_to_end(&mut newer).map_err(|_| ()));
+        if applied.is_err() {
+            return BIDIFF_ERR_DECODE;
+        }
+        copy_out(&newer, out, out_cap, out_len)
+    })
+}
+
+unsafe fn input<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if ptr.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(ptr, len))
+    }
+}
+
+unsafe fn copy_out(data: &[u8], out: *mut u8, out_cap: usize, out_len: *mut usize) -> c_int {
+    if out_len.is_null() {
+        return BIDIFF_ERR_NULL;
+    }
+    *out_len = data.len();
+    if data.is_empty() {
+        return BIDIFF_OK;
+    }
+    if out_cap < data.len() {
+        return BIDIFF_ERR_BUFFER_TOO_SMALL;
+    }
+    if out.is_null() {
+        return BIDIFF_ERR_NULL;
+    }
+    out.copy_from_nonoverlapping(data.as_ptr(), data.len());
+    BIDIFF_OK
+}
+
+fn guard(f: impl FnOnce() -> c_int) -> c_int {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(BIDIFF_ERR_PANIC)
+}
+
+struct CallbackWriter {
+    write: BidiffWriteFn,
+    ctx: *mut c_void,
+    failed: bool,
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // `buf` is valid for `buf.len()` bytes for the duration of
+        // the call, and `ctx` is passed back as the caller gave it
+        if unsafe { (self.write)(self.ctx, buf.as_ptr(), buf.len()) } != 0 {
+            self.failed = true;
+            return Err(io::Error::other("callback failed"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,854 @@
+//! Applying patches written by [enc::Writer](crate::enc::Writer), see
+//! [apply].
+//!
+//! This handles the plain control stream, with or without a manifest,
+//! signature, raw delta, target copies or checksums, and compressed patches
+//! when the matching feature is enabled. Segmented patches need the
+//! `bipatch` crate. Patches that arrive in pieces, or whose application
+//! must be resumable, can be applied with an [Applier], and the controls of
+//! a patch held in memory iterated over with a [ControlIter].
+
+use super::{
+    enc::{
+        END_OF_CONTROLS, FLAG_CHECKSUM, FLAG_MANIFEST, FLAG_RAW_DELTA, FLAG_SIGNED,
+        FLAG_SOURCE_CHECKSUM, FLAG_TARGET_COPY, FLAG_XZ, FLAG_ZSTD, MAGIC, VERSION,
+    },
+    Control, Hash,
+};
+use byteorder::{LittleEndian, ReadBytesExt};
+use integer_encoding::VarIntReader;
+use std::{
+    convert::TryFrom,
+    error::Error,
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+};
+
+/// Version of patches written before header flags were introduced, which
+/// are read as having no flags
+pub const LEGACY_VERSION: u32 = 0x1000;
+
+const SUPPORTED_FLAGS: u32 =
+    FLAG_MANIFEST | FLAG_RAW_DELTA | FLAG_TARGET_COPY | FLAG_CHECKSUM | FLAG_SOURCE_CHECKSUM;
+/// Flags of patches whose controls are followed by a trailer
+const TRAILER_FLAGS: u32 = FLAG_MANIFEST | FLAG_CHECKSUM;
+/// Most bytes of a target copy [apply] buffers at once
+const TARGET_PIECE_LEN: usize = 64 * 1024;
+
+/// The file [apply] reconstructed doesn't match the checksum of the patch.
+/// [apply] fails with an [io::Error] of kind [ErrorKind::InvalidData]
+/// wrapping this.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    pub expected: Hash,
+    pub actual: Hash,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "checksum mismatch: expected ")?;
+        for b in &self.expected {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ", got ")?;
+        for b in &self.actual {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+/// The old file given to [apply] isn't the one the patch was made from, see
+/// [WriterOptions::source_checksum](crate::enc::WriterOptions::source_checksum).
+/// [apply] fails with an [io::Error] of kind [ErrorKind::InvalidData]
+/// wrapping this, before writing anything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrongBaseImage {
+    /// SHA-256 of the old file the patch was made from
+    pub expected: Hash,
+    /// SHA-256 of the old file it was given
+    pub actual: Hash,
+}
+
+impl fmt::Display for WrongBaseImage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "patch was made from another old file: expected ")?;
+        for b in &self.expected {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, ", got ")?;
+        for b in &self.actual {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for WrongBaseImage {}
+
+/// A control of the patch reads out of bounds of the old file or of the
+/// output. [apply] fails with an [io::Error] of kind
+/// [ErrorKind::InvalidData] wrapping this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedPatch {
+    /// The patch adds from the old file up to this offset, or seeks to it,
+    /// but it's outside of the old file
+    OutOfBounds(i64),
+    /// A target copy reads from this offset of the output, which isn't
+    /// reconstructed yet
+    TargetOutOfBounds(usize),
+}
+
+impl fmt::Display for MalformedPatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MalformedPatch::OutOfBounds(offset) => write!(
+                f,
+                "patch reads out of bounds of the old file, at {}",
+                offset
+            ),
+            MalformedPatch::TargetOutOfBounds(offset) => write!(
+                f,
+                "target copy past the reconstructed output, at {}",
+                offset
+            ),
+        }
+    }
+}
+
+impl Error for MalformedPatch {}
+
+impl From<MalformedPatch> for io::Error {
+    fn from(e: MalformedPatch) -> Self {
+        io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+/// Reads the controls of a patch, one at a time
+pub struct Reader<R>
+where
+    R: Read,
+{
+    r: R,
+    flags: u32,
+    add: Vec<u8>,
+    copy: Vec<u8>,
+    done: bool,
+    source_checksum: Option<Hash>,
+}
+
+impl<R> Reader<R>
+where
+    R: Read,
+{
+    /// Read the header of the patch, skipping its signature if it has one
+    ///
+    /// Fails with [ErrorKind::InvalidData] when the patch doesn't start with
+    /// [MAGIC], or has a version other than [VERSION] and [LEGACY_VERSION].
+    pub fn new(mut r: R) -> Result<Self, io::Error> {
+        let mut flags = read_header(&mut r)?;
+        if flags & FLAG_SIGNED != 0 {
+            let len = r.read_u32::<LittleEndian>()?;
+            read_exactly(&mut r, len as u64, &mut Vec::new())?;
+            flags = read_header(&mut r)?;
+        }
+        if flags & !SUPPORTED_FLAGS != 0 {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported patch flags: `{:X}`", flags & !SUPPORTED_FLAGS),
+            ));
+        }
+        let source_checksum = if flags & FLAG_SOURCE_CHECKSUM != 0 {
+            let mut checksum = Hash::default();
+            r.read_exact(&mut checksum)?;
+            Some(checksum)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            r,
+            flags,
+            add: Vec::new(),
+            copy: Vec::new(),
+            done: false,
+            source_checksum,
+        })
+    }
+
+    /// The header flags of the patch (of the signed patch, for signed ones)
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// The checksum of the "older" file the patch was made from, for patches
+    /// that have one
+    pub fn source_checksum(&self) -> Option<Hash> {
+        self.source_checksum
+    }
+
+    /// Read the next control, or `None` once there are no more. Whatever
+    /// follows the controls (like a manifest) is left unread.
+    pub fn next_control(&mut self) -> Result<Option<Control<'_>>, io::Error> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let add_len = match read_varint_or_end(&mut self.r)? {
+            Some(END_OF_CONTROLS) if self.flags & TRAILER_FLAGS != 0 => None,
+            add_len => add_len,
+        };
+        let add_len = match add_len {
+            Some(add_len) => add_len,
+            None => {
+                self.done = true;
+                return Ok(None);
+            }
+        };
+        read_exactly(&mut self.r, add_len, &mut self.add)?;
+
+        let copy_len = self.r.read_varint()?;
+        read_exactly(&mut self.r, copy_len, &mut self.copy)?;
+
+        let mut target = 0..0;
+        if self.flags & FLAG_TARGET_COPY != 0 {
+            let target_len: usize = self.r.read_varint()?;
+            if target_len > 0 {
+                let start: usize = self.r.read_varint()?;
+                let end = start.checked_add(target_len).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "target copy length overflows")
+                })?;
+                target = start..end;
+            }
+        }
+
+        let seek = self.r.read_varint()?;
+
+        Ok(Some(Control {
+            add: &self.add,
+            copy: &self.copy,
+            seek,
+            target,
+            source_hash: None,
+        }))
+    }
+
+    /// Read the checksum of the "newer" file, once [Reader::next_control]
+    /// returned `None`, skipping the manifest if there's one. Returns `None`
+    /// for patches without a checksum.
+    pub fn checksum(&mut self) -> Result<Option<Hash>, io::Error> {
+        if self.flags & FLAG_CHECKSUM == 0 {
+            return Ok(None);
+        }
+        if self.flags & FLAG_MANIFEST != 0 {
+            let len: u64 = self.r.read_varint()?;
+            for _ in 0..len {
+                let _offset: u64 = self.r.read_varint()?;
+                let _length: u64 = self.r.read_varint()?;
+            }
+        }
+        let mut checksum = Hash::default();
+        self.r.read_exact(&mut checksum)?;
+        Ok(Some(checksum))
+    }
+}
+
+/// Read a varint, or `None` when `r` ends before its first byte: a varint
+/// cut short after that is an [ErrorKind::UnexpectedEof] error
+fn read_varint_or_end<R: Read>(r: &mut R) -> Result<Option<u64>, io::Error> {
+    let mut first = [0u8];
+    loop {
+        match r.read(&mut first) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    (&first[..]).chain(r).read_varint().map(Some)
+}
+
+/// Undo the compression of a patch written by [auto_diff](crate::auto_diff)
+/// or with [DiffParams::with_zstd_level](crate::DiffParams), returning a
+/// patch [Reader::new] can read. Uncompressed patches are returned as-is.
+pub fn decompress<'a, R>(mut patch: R) -> Result<Box<dyn Read + 'a>, io::Error>
+where
+    R: Read + 'a,
+{
+    let flags = read_header(&mut patch)?;
+    let compression = flags & (FLAG_ZSTD | FLAG_XZ);
+    if compression == 0 {
+        // put the header back, `Reader::new` wants it
+        let mut header = Vec::with_capacity(12);
+        header.extend_from_slice(&MAGIC.to_le_bytes());
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&flags.to_le_bytes());
+        return Ok(Box::new(io::Cursor::new(header).chain(patch)));
+    }
+
+    let _raw_size = patch.read_u64::<LittleEndian>()?;
+    let compressed_size = patch.read_u64::<LittleEndian>()?;
+    decoder(compression, patch.take(compressed_size))
+}
+
+#[cfg_attr(not(any(feature = "zstd", feature = "xz")), allow(unused_variables))]
+fn decoder<'a, R>(compression: u32, compressed: R) -> Result<Box<dyn Read + 'a>, io::Error>
+where
+    R: Read + 'a,
+{
+    match compression {
+        #[cfg(feature = "zstd")]
+        FLAG_ZSTD => Ok(Box::new(zstd::stream::read::Decoder::new(compressed)?)),
+        #[cfg(feature = "xz")]
+        FLAG_XZ => Ok(Box::new(xz2::read::XzDecoder::new(compressed))),
+        _ => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("unsupported patch compression: `{:X}`", compression),
+        )),
+    }
+}
+
+/// Apply `patch` to `old`, writing the new file to `out`, decompressing it
+/// first if needed (see [decompress]), and return the length of the new
+/// file: all the bytes written to `out`, by adds, copies and target copies
+///
+/// Fails with [ErrorKind::UnexpectedEof] when the patch is truncated in the
+/// middle of a control, and [ErrorKind::InvalidData] when its controls read
+/// out of bounds of `old`, or of what was reconstructed so far (see
+/// [MalformedPatch]), or when what was reconstructed doesn't match the
+/// checksum of the patch (see [ChecksumMismatch]). What was written to `out`
+/// is then corrupt. Patches with a checksum of their old file are checked
+/// against `old` first, and fail the same way without writing anything
+/// when it's another one (see [WrongBaseImage]).
+///
+/// Output is written to `out` control by control, so apart from `old`, only
+/// the current control is held in memory, unless the patch has target
+/// copies: those can copy from anywhere in the output, which is then kept
+/// around as it's written.
+///
+/// A short patch can still describe a huge output, with long target copies
+/// that overlap themselves: callers applying untrusted patches should bound
+/// how much they let `out` take.
+pub fn apply(old: &[u8], patch: &mut dyn Read, out: &mut dyn Write) -> Result<u64, io::Error> {
+    let mut r = Reader::new(decompress(patch)?)?;
+    if let Some(expected) = r.source_checksum() {
+        let actual = hmac_sha256::Hash::hash(old);
+        if actual != expected {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                WrongBaseImage { expected, actual },
+            ));
+        }
+    }
+    let raw = r.flags() & FLAG_RAW_DELTA != 0;
+    // target copies read from the output, so it's kept around for them.
+    // Otherwise, each control is written out as soon as it's read
+    let mut history = if r.flags() & FLAG_TARGET_COPY != 0 {
+        Some(Vec::new())
+    } else {
+        None
+    };
+
+    let mut hasher = hmac_sha256::Hash::new();
+    let mut written = 0_u64;
+    let mut emit = |bytes: &[u8], history: &mut Option<Vec<u8>>| {
+        hasher.update(bytes);
+        written += bytes.len() as u64;
+        if let Some(history) = history.as_mut() {
+            history.extend_from_slice(bytes);
+        }
+        out.write_all(bytes)
+    };
+    // the cursor in "old" is kept signed, like seeks: it's checked after
+    // each one, so it's always within `0..=old.len()` when an add reads
+    // from it
+    let mut old_pos: i64 = 0;
+    let mut buf = Vec::new();
+    while let Some(c) = r.next_control()? {
+        let old_add = old_region(old, old_pos, c.add.len())?;
+        if raw {
+            emit(c.add, &mut history)?;
+        } else {
+            buf.clear();
+            buf.extend(c.add.iter().zip(old_add).map(|(d, o)| d.wrapping_add(*o)));
+            emit(&buf, &mut history)?;
+        }
+        emit(c.copy, &mut history)?;
+
+        // the reader only reads target copies with `FLAG_TARGET_COPY`, so
+        // there's a history whenever there's a copy
+        if history.is_some() {
+            // copies may overlap the bytes they produce, so they're made in
+            // pieces of what was reconstructed before each one
+            let (mut start, mut len) = (c.target.start, c.target.len());
+            while len > 0 {
+                let reconstructed = history.as_deref().unwrap_or_default();
+                let available = reconstructed
+                    .len()
+                    .checked_sub(start)
+                    .filter(|available| *available > 0)
+                    .ok_or(MalformedPatch::TargetOutOfBounds(start))?;
+                let n = len.min(available).min(TARGET_PIECE_LEN);
+                buf.clear();
+                buf.extend_from_slice(&reconstructed[start..start + n]);
+                emit(&buf, &mut history)?;
+                start += n;
+                len -= n;
+            }
+        }
+
+        old_pos = seek(old, old_pos, c.add.len(), c.seek)?;
+    }
+
+    if let Some(expected) = r.checksum()? {
+        let actual = hasher.finalize();
+        if actual != expected {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                ChecksumMismatch { expected, actual },
+            ));
+        }
+    }
+    Ok(written)
+}
+
+/// The `len` bytes of `old` an add reads from at `old_pos`
+fn old_region(old: &[u8], old_pos: i64, len: usize) -> Result<&[u8], MalformedPatch> {
+    usize::try_from(old_pos)
+        .ok()
+        .and_then(|pos| old.get(pos..))
+        .and_then(|rest| rest.get(..len))
+        .ok_or_else(|| {
+            let len = i64::try_from(len).unwrap_or(i64::MAX);
+            MalformedPatch::OutOfBounds(old_pos.saturating_add(len))
+        })
+}
+
+/// The cursor in `old` after an add of `add_len` bytes at `old_pos`
+/// (checked by [old_region]) then a seek
+fn seek(old: &[u8], old_pos: i64, add_len: usize, seek: i64) -> Result<i64, MalformedPatch> {
+    // the add region was in bounds, so this doesn't overflow, but the seek
+    // can go anywhere
+    let old_pos = (old_pos + add_len as i64).saturating_add(seek);
+    if old_pos < 0 || old_pos as u64 > old.len() as u64 {
+        return Err(MalformedPatch::OutOfBounds(old_pos));
+    }
+    Ok(old_pos)
+}
+
+/// How far an [Applier] got through a patch, which is also where it can
+/// pick up from, see [Applier::resume]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ApplyProgress {
+    /// How many bytes of the patch were applied. This is always the end of
+    /// the header or of a control, so feeding the patch can restart from
+    /// there.
+    pub patch_offset: u64,
+    /// The cursor in the old file
+    pub old_pos: u64,
+    /// How many bytes were written to the output
+    pub new_pos: u64,
+    /// The header flags of the patch, once its header was read
+    pub flags: Option<u32>,
+    /// Whether the whole patch was applied, trailer included
+    pub done: bool,
+}
+
+/// Applies a patch as it comes, one chunk at a time, for patches that are
+/// received or read piecemeal, and applications that must survive being
+/// interrupted
+///
+/// Each call to [Applier::step] applies whatever controls are complete so
+/// far and keeps the rest of the chunk around until the next one, so
+/// controls (and the header) can be split at any byte. Once the patch
+/// ended, [Applier::finish] tells whether it was all there.
+///
+/// Output only ever stops at the end of a control, so the [ApplyProgress]
+/// returned by each step is a checkpoint:
+/// [resuming](Applier::resume) from it, with the output truncated to its
+/// `new_pos` and the patch fed from its `patch_offset`, finishes the job.
+///
+/// Checks are the same as [apply], but compressed patches (see
+/// [decompress]) and patches with target copies aren't supported: target
+/// copies read from the output, which isn't kept.
+pub struct Applier<'a> {
+    old: &'a [u8],
+    /// Bytes of the patch received but not applied yet, starting at
+    /// `progress.patch_offset`
+    pending: Vec<u8>,
+    progress: ApplyProgress,
+    /// Hashes the output for the checksum of the patch, `None` when resumed
+    /// since what was written before isn't known
+    hasher: Option<hmac_sha256::Hash>,
+    buf: Vec<u8>,
+}
+
+impl<'a> Applier<'a> {
+    /// Start applying a patch to `old`
+    pub fn new(old: &'a [u8]) -> Self {
+        Self {
+            old,
+            pending: Vec::new(),
+            progress: ApplyProgress::default(),
+            hasher: Some(hmac_sha256::Hash::new()),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Pick up applying a patch to `old` where `progress` left off. The
+    /// output must have been truncated to `progress.new_pos`, and the
+    /// patch is then fed from `progress.patch_offset`.
+    ///
+    /// The checksum of the new file can't be checked anymore, since the
+    /// output written before isn't hashed.
+    pub fn resume(old: &'a [u8], progress: ApplyProgress) -> Self {
+        Self {
+            hasher: None,
+            progress,
+            ..Self::new(old)
+        }
+    }
+
+    /// How far the patch was applied
+    pub fn progress(&self) -> ApplyProgress {
+        self.progress
+    }
+
+    /// Apply as much of the patch as possible with `patch_chunk`, the bytes
+    /// that follow those given so far, writing the output to `out`
+    ///
+    /// Fails like [apply], except for truncated patches: those are only
+    /// noticed by [Applier::finish]. Bytes after the end of the patch are
+    /// ignored.
+    ///
+    /// When writing to `out` fails, it may hold part of the failed control
+    /// past [ApplyProgress::new_pos]. Truncate it there before stepping
+    /// again (with an empty chunk to retry) or resuming.
+    pub fn step(&mut self, patch_chunk: &[u8], out: &mut dyn Write) -> io::Result<ApplyProgress> {
+        if self.progress.done {
+            return Ok(self.progress);
+        }
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(patch_chunk);
+
+        let mut consumed = 0;
+        let res = self.consume(&pending, &mut consumed, out);
+        pending.drain(..consumed);
+        self.pending = pending;
+        res.map(|()| self.progress)
+    }
+
+    /// Tell the applier that the patch ended
+    ///
+    /// Fails with [ErrorKind::UnexpectedEof] when it was truncated.
+    pub fn finish(&mut self) -> io::Result<ApplyProgress> {
+        if !self.progress.done {
+            match self.progress.flags {
+                Some(flags) if flags & TRAILER_FLAGS == 0 && self.pending.is_empty() => {
+                    self.progress.done = true;
+                }
+                _ => return Err(ErrorKind::UnexpectedEof.into()),
+            }
+        }
+        Ok(self.progress)
+    }
+
+    /// Apply the header, controls and trailer that are complete in
+    /// `pending`, counting the bytes they took in `consumed`
+    fn consume(
+        &mut self,
+        pending: &[u8],
+        consumed: &mut usize,
+        out: &mut dyn Write,
+    ) -> io::Result<()> {
+        while !self.progress.done {
+            let mut r = &pending[*consumed..];
+            if r.is_empty() {
+                break;
+            }
+            let res = match self.progress.flags {
+                None => self.header(&mut r),
+                Some(flags) => self.control(flags, &mut r, out),
+            };
+            match res {
+                // the rest is still to come
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                res => res?,
+            }
+            let len = pending.len() - *consumed - r.len();
+            *consumed += len;
+            self.progress.patch_offset += len as u64;
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, r: &mut &[u8]) -> io::Result<()> {
+        let flags = read_slice_header(r, self.old)?;
+        if flags & FLAG_TARGET_COPY != 0 {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "target copies can't be applied incrementally",
+            ));
+        }
+        self.progress.flags = Some(flags);
+        Ok(())
+    }
+
+    /// Apply the next control, or the trailer, once all of it is in `r`
+    fn control(&mut self, flags: u32, r: &mut &[u8], out: &mut dyn Write) -> io::Result<()> {
+        let (add, copy, seek_by) = match read_slice_control(r, flags)? {
+            Some(c) => (c.add, c.copy, c.seek),
+            None => return self.trailer(flags, r),
+        };
+
+        let old_pos = i64::try_from(self.progress.old_pos).unwrap_or(i64::MAX);
+        let old_add = old_region(self.old, old_pos, add.len())?;
+        let new_old_pos = seek(self.old, old_pos, add.len(), seek_by)?;
+
+        let add = if flags & FLAG_RAW_DELTA != 0 {
+            add
+        } else {
+            self.buf.clear();
+            self.buf
+                .extend(add.iter().zip(old_add).map(|(d, o)| d.wrapping_add(*o)));
+            &self.buf
+        };
+        out.write_all(add)?;
+        out.write_all(copy)?;
+        // progress only counts whole controls, even when writing the copy
+        // of this one fails after its add went out
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(add);
+            hasher.update(copy);
+        }
+        self.progress.new_pos += (add.len() + copy.len()) as u64;
+        self.progress.old_pos = new_old_pos as u64;
+        Ok(())
+    }
+
+    fn trailer(&mut self, flags: u32, r: &mut &[u8]) -> io::Result<()> {
+        if flags & FLAG_MANIFEST != 0 {
+            let len: u64 = r.read_varint()?;
+            for _ in 0..len {
+                let _offset: u64 = r.read_varint()?;
+                let _length: u64 = r.read_varint()?;
+            }
+        }
+        if flags & FLAG_CHECKSUM != 0 {
+            let mut expected = Hash::default();
+            r.read_exact(&mut expected)?;
+            if let Some(hasher) = self.hasher.take() {
+                let actual = hasher.finalize();
+                if actual != expected {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        ChecksumMismatch { expected, actual },
+                    ));
+                }
+            }
+        }
+        self.progress.done = true;
+        Ok(())
+    }
+}
+
+/// Iterates over the controls of a patch held in memory, without copying
+/// them
+///
+/// Controls are checked like [apply] checks them, against `old` and against
+/// the length of what the controls before them produce, but nothing is
+/// reconstructed: adds are still deltas against `old` (unless the patch is
+/// a raw delta, see [ControlIter::flags]), starting where the adds and seeks
+/// of the controls before left the cursor in it, from 0. Whatever follows
+/// the controls (like a manifest or checksum) is left alone.
+///
+/// The header is read by the first call to [Iterator::next]. Patches that
+/// are compressed (see [decompress]), truncated or malformed yield an
+/// error, and then nothing.
+pub struct ControlIter<'a> {
+    old: &'a [u8],
+    /// What's left of the patch
+    patch: &'a [u8],
+    flags: Option<u32>,
+    old_pos: i64,
+    new_pos: u64,
+    done: bool,
+}
+
+impl<'a> ControlIter<'a> {
+    /// Iterate over the controls of `patch`, made from `old`
+    pub fn new(old: &'a [u8], patch: &'a [u8]) -> Self {
+        Self {
+            old,
+            patch,
+            flags: None,
+            old_pos: 0,
+            new_pos: 0,
+            done: false,
+        }
+    }
+
+    /// The header flags of the patch (of the signed patch, for signed ones),
+    /// once the first control was read
+    pub fn flags(&self) -> Option<u32> {
+        self.flags
+    }
+
+    fn next_control(&mut self) -> io::Result<Option<Control<'a>>> {
+        let flags = match self.flags {
+            Some(flags) => flags,
+            None => {
+                let flags = read_slice_header(&mut self.patch, self.old)?;
+                self.flags = Some(flags);
+                flags
+            }
+        };
+        let c = match read_slice_control(&mut self.patch, flags)? {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        old_region(self.old, self.old_pos, c.add.len())?;
+        self.old_pos = seek(self.old, self.old_pos, c.add.len(), c.seek)?;
+        // target copies read from what was produced before them
+        let produced = self.new_pos + (c.add.len() + c.copy.len()) as u64;
+        if !c.target.is_empty() && c.target.start as u64 >= produced {
+            return Err(MalformedPatch::TargetOutOfBounds(c.target.start).into());
+        }
+        self.new_pos = produced + c.target.len() as u64;
+        Ok(Some(c))
+    }
+}
+
+impl<'a> Iterator for ControlIter<'a> {
+    type Item = io::Result<Control<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.next_control().transpose();
+        if !matches!(res, Some(Ok(_))) {
+            self.done = true;
+        }
+        res
+    }
+}
+
+/// Read the header of a patch held in memory, skipping its signature if it
+/// has one, and check it was made from `old` if it says which one that was,
+/// like [Reader::new] and [apply] do
+fn read_slice_header(r: &mut &[u8], old: &[u8]) -> io::Result<u32> {
+    let mut flags = read_header(r)?;
+    if flags & FLAG_SIGNED != 0 {
+        let len = r.read_u32::<LittleEndian>()?;
+        take(r, len as u64)?;
+        flags = read_header(r)?;
+    }
+    if flags & (FLAG_ZSTD | FLAG_XZ) != 0 {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "compressed patches need to be decompressed first",
+        ));
+    }
+    if flags & !SUPPORTED_FLAGS != 0 {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("unsupported patch flags: `{:X}`", flags & !SUPPORTED_FLAGS),
+        ));
+    }
+    if flags & FLAG_SOURCE_CHECKSUM != 0 {
+        let mut expected = Hash::default();
+        r.read_exact(&mut expected)?;
+        let actual = hmac_sha256::Hash::hash(old);
+        if actual != expected {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                WrongBaseImage { expected, actual },
+            ));
+        }
+    }
+    Ok(flags)
+}
+
+/// Read the next control of a patch held in memory, like
+/// [Reader::next_control] does, or `None` once there are no more
+fn read_slice_control<'p>(r: &mut &'p [u8], flags: u32) -> io::Result<Option<Control<'p>>> {
+    if r.is_empty() {
+        return Ok(None);
+    }
+    let add_len = r.read_varint::<u64>()?;
+    if add_len == END_OF_CONTROLS && flags & TRAILER_FLAGS != 0 {
+        return Ok(None);
+    }
+    let add = take(r, add_len)?;
+    let copy_len = r.read_varint()?;
+    let copy = take(r, copy_len)?;
+
+    let mut target = 0..0;
+    if flags & FLAG_TARGET_COPY != 0 {
+        let target_len: usize = r.read_varint()?;
+        if target_len > 0 {
+            let start: usize = r.read_varint()?;
+            let end = start.checked_add(target_len).ok_or_else(|| {
+                io::Error::new(ErrorKind::InvalidData, "target copy length overflows")
+            })?;
+            target = start..end;
+        }
+    }
+
+    let seek = r.read_varint()?;
+    Ok(Some(Control {
+        add,
+        copy,
+        seek,
+        target,
+        source_hash: None,
+    }))
+}
+
+/// The next `len` bytes of `r`, failing with [ErrorKind::UnexpectedEof] if
+/// they aren't all there
+fn take<'p>(r: &mut &'p [u8], len: u64) -> io::Result<&'p [u8]> {
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= r.len())
+        .ok_or(ErrorKind::UnexpectedEof)?;
+    let (bytes, rest) = r.split_at(len);
+    *r = rest;
+    Ok(bytes)
+}
+
+pub(crate) fn read_header<R: Read>(r: &mut R) -> Result<u32, io::Error> {
+    let magic = r.read_u32::<LittleEndian>()?;
+    if magic != MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("wrong magic: expected `{:X}`, got `{:X}`", MAGIC, magic),
+        ));
+    }
+    match r.read_u32::<LittleEndian>()? {
+        VERSION => r.read_u32::<LittleEndian>(),
+        LEGACY_VERSION => Ok(0),
+        version => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "wrong version: expected `{:X}`, got `{:X}`",
+                VERSION, version
+            ),
+        )),
+    }
+}
+
+/// Read exactly `len` bytes into `buf`, without trusting `len` for how much
+/// to allocate
+fn read_exactly<R: Read>(r: &mut R, len: u64, buf: &mut Vec<u8>) -> Result<(), io::Error> {
+    buf.clear();
+    r.take(len).read_to_end(buf)?;
+    if (buf.len() as u64) < len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
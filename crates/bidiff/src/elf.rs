@@ -0,0 +1,117 @@
+//! Diffing ELF files section by section, see [diff_elf].
+
+use super::{diff_range, enc, DiffParams, Match, Translator};
+use object::{Object, ObjectSection, SectionKind};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io::{self, Write},
+    ops::Range,
+};
+
+/// What a region of an ELF file is, used to pair regions of both files
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum RegionKey {
+    /// The `n`th section with a given name and kind
+    Section(String, SectionKind, usize),
+    /// Whatever sits between the previous section and this one (headers,
+    /// padding...)
+    Before(Box<RegionKey>),
+    /// Whatever follows the last section, usually the section header table
+    Trailer,
+}
+
+/// Split `data` into regions that cover it entirely, in file order
+fn regions(data: &[u8]) -> io::Result<Vec<(RegionKey, Range<usize>)>> {
+    let file = object::File::parse(data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    if file.format() != object::BinaryFormat::Elf {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an ELF file",
+        ));
+    }
+
+    let mut sections = Vec::new();
+    let mut seen = HashMap::new();
+    for section in file.sections() {
+        let (offset, size) = match section.file_range() {
+            Some(range) if range.1 > 0 => range,
+            _ => continue,
+        };
+        let name = section.name().unwrap_or_default().to_string();
+        // section headers aren't checked against the file, that's done when
+        // clamping below, as long as they fit in a usize
+        let range = offset
+            .checked_add(size)
+            .and_then(|end| Some(usize::try_from(offset).ok()?..usize::try_from(end).ok()?))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("section {:?} is out of range", name),
+                )
+            })?;
+        let n = seen.entry((name.clone(), section.kind())).or_insert(0);
+        sections.push((RegionKey::Section(name, section.kind(), *n), range));
+        *n += 1;
+    }
+    sections.sort_by_key(|(_, range)| range.start);
+
+    let mut regions = Vec::new();
+    let mut pos = 0;
+    for (key, range) in sections {
+        // sections shouldn't overlap, but don't trust that
+        let range = range.start.max(pos)..range.end.min(data.len());
+        if range.is_empty() {
+            continue;
+        }
+        if range.start > pos {
+            regions.push((RegionKey::Before(Box::new(key.clone())), pos..range.start));
+        }
+        pos = range.end;
+        regions.push((key, range));
+    }
+    if pos < data.len() {
+        regions.push((RegionKey::Trailer, pos..data.len()));
+    }
+    Ok(regions)
+}
+
+/// Diff two ELF files, writing the patch to `out`
+///
+/// Sections are paired by name and kind, and each section of `new` is only
+/// diffed against its counterpart in `old`, so that layout changes don't
+/// throw off matching. Whatever is between sections is paired the same way,
+/// by the section that follows it. Sections of `new` that aren't in `old` are
+/// stored as-is.
+pub fn diff_elf(
+    old: &[u8],
+    new: &[u8],
+    params: &DiffParams,
+    out: &mut dyn Write,
+) -> Result<(), io::Error> {
+    let old_regions: HashMap<_, _> = regions(old)?.into_iter().collect();
+
+    let mut w = enc::Writer::new(out)?;
+    let mut translator = Translator::new(old, new, |control| w.write(control));
+
+    let res = (|| {
+        for (key, new_range) in regions(new)? {
+            match old_regions.get(&key) {
+                Some(old_range) => {
+                    diff_range(old, old_range.clone(), new, new_range, params, |m| {
+                        translator.translate(m)
+                    })?;
+                }
+                None => translator.translate(Match {
+                    add_old_start: 0,
+                    add_new_start: new_range.start,
+                    add_length: 0,
+                    copy_end: new_range.end,
+                })?,
+            }
+        }
+        Ok(())
+    })();
+    translator.close_after(res)
+}
@@ -1,16 +1,99 @@
-use super::Control;
+use super::{Control, DeltaMode, Hash};
 use byteorder::{LittleEndian, WriteBytesExt};
 use integer_encoding::VarIntWriter;
-use std::io::{self, Write};
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    io::{self, Write},
+};
 
 pub const MAGIC: u32 = 0xB1DF;
-pub const VERSION: u32 = 0x1000;
+pub const VERSION: u32 = 0x1001;
+
+/// The patch is made of independently-applicable segments, see [write_segmented]
+pub const FLAG_SEGMENTED: u32 = 1 << 0;
+/// The controls are followed by a manifest, see [WriterOptions::manifest]
+pub const FLAG_MANIFEST: u32 = 1 << 1;
+/// Add regions were translated with [DeltaMode::Raw]
+pub const FLAG_RAW_DELTA: u32 = 1 << 2;
+/// The rest of the patch is a zstd-compressed patch, see [crate::auto]
+pub const FLAG_ZSTD: u32 = 1 << 3;
+/// The rest of the patch is an xz-compressed patch, see [crate::auto]
+pub const FLAG_XZ: u32 = 1 << 4;
+/// The header is followed by a signature, then the signed patch, see
+/// [WriterOptions::signature]
+pub const FLAG_SIGNED: u32 = 1 << 5;
+/// Controls are followed by a copy from the "newer" file, see
+/// [WriterOptions::target_copies]
+pub const FLAG_TARGET_COPY: u32 = 1 << 6;
+/// The controls are followed by a SHA-256 of the "newer" file, see
+/// [WriterOptions::checksum]
+pub const FLAG_CHECKSUM: u32 = 1 << 7;
+/// The patch is a bundle of a forward and a reverse patch, see
+/// [crate::reversible]
+pub const FLAG_REVERSIBLE: u32 = 1 << 8;
+/// The header is followed by a SHA-256 of the "older" file, see
+/// [WriterOptions::source_checksum]
+pub const FLAG_SOURCE_CHECKSUM: u32 = 1 << 9;
+
+/// Marks the end of the controls, when they're followed by a trailer
+pub const END_OF_CONTROLS: u64 = u64::MAX;
 
 pub struct Writer<W>
 where
     W: Write,
 {
     w: W,
+    manifest: Option<Manifest>,
+    target_copies: bool,
+    checksum: Option<Hash>,
+}
+
+/// Options for [Writer::with_options]
+#[derive(Debug, Clone, Default)]
+pub struct WriterOptions {
+    /// Keep track of the `(offset, length)` regions of the "older" file the
+    /// controls add from, and write them as a trailing section when calling
+    /// [Writer::finish].
+    ///
+    /// This lets storage layers know which parts of the "older" file a patch
+    /// references without applying it.
+    pub manifest: bool,
+    /// How add regions were translated, this must match the [DeltaMode] the
+    /// [Translator](crate::Translator) was created with.
+    pub delta_mode: DeltaMode,
+    /// A detached signature to store in the header. The patch then starts
+    /// with a header flagged [FLAG_SIGNED], the length of the signature as a
+    /// little-endian u32 and the signature itself, followed by the patch that
+    /// would have been written without a signature.
+    ///
+    /// That unsigned patch is what the signature should be computed over
+    /// (`bipatch::signed_bytes` returns it), so signing takes two passes:
+    /// one without a signature to sign the result, and one with it.
+    pub signature: Option<Vec<u8>>,
+    /// Write the [Control::target] of each control, which the
+    /// [Translator](crate::Translator) only fills in when created
+    /// [with target copies](crate::Translator::with_target_copies).
+    ///
+    /// Each control's literal bytes are then followed by the length of the
+    /// target copy, and if it isn't empty, its offset in the "newer" file,
+    /// both as varints.
+    pub target_copies: bool,
+    /// The SHA-256 of the "newer" file, written as a trailing section when
+    /// calling [Writer::finish], after the manifest if there's one. Appliers
+    /// check the file they reconstruct against it.
+    pub checksum: Option<Hash>,
+    /// The SHA-256 of the "older" file, written right after the header.
+    /// Appliers check the file they're given against it before applying
+    /// anything, and refuse patches made for another one.
+    pub source_checksum: Option<Hash>,
+}
+
+/// Distinct regions of the "older" file used by the controls written so far
+#[derive(Default)]
+struct Manifest {
+    old_pos: i64,
+    regions: BTreeSet<(u64, u64)>,
 }
 
 impl<W> Writer<W>
@@ -18,13 +101,93 @@ where
     W: Write,
 {
     pub fn new(mut w: W) -> Result<Self, io::Error> {
-        w.write_u32::<LittleEndian>(MAGIC)?;
-        w.write_u32::<LittleEndian>(VERSION)?;
+        write_header(&mut w, 0)?;
+
+        Ok(Self {
+            w,
+            manifest: None,
+            target_copies: false,
+            checksum: None,
+        })
+    }
+
+    /// Create a writer with a manifest, see [WriterOptions::manifest]
+    pub fn with_manifest(w: W) -> Result<Self, io::Error> {
+        Self::with_options(
+            w,
+            &WriterOptions {
+                manifest: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn with_options(mut w: W, options: &WriterOptions) -> Result<Self, io::Error> {
+        let mut flags = 0;
+        if options.manifest {
+            flags |= FLAG_MANIFEST;
+        }
+        if options.delta_mode == DeltaMode::Raw {
+            flags |= FLAG_RAW_DELTA;
+        }
+        if options.target_copies {
+            flags |= FLAG_TARGET_COPY;
+        }
+        if options.checksum.is_some() {
+            flags |= FLAG_CHECKSUM;
+        }
+        if options.source_checksum.is_some() {
+            flags |= FLAG_SOURCE_CHECKSUM;
+        }
+        if let Some(signature) = options.signature.as_ref() {
+            let len = u32::try_from(signature.len()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "signatures can't be larger than 4 GiB",
+                )
+            })?;
+            write_header(&mut w, FLAG_SIGNED)?;
+            w.write_u32::<LittleEndian>(len)?;
+            w.write_all(signature)?;
+        }
+        write_header(&mut w, flags)?;
+        if let Some(source_checksum) = options.source_checksum.as_ref() {
+            w.write_all(source_checksum)?;
+        }
 
-        Ok(Self { w })
+        Ok(Self {
+            w,
+            manifest: if options.manifest {
+                Some(Default::default())
+            } else {
+                None
+            },
+            target_copies: options.target_copies,
+            checksum: options.checksum,
+        })
+    }
+
+    /// Create a writer for the body of a single segment of a segmented
+    /// patch. No header is written.
+    pub fn segment(w: W) -> Self {
+        Self {
+            w,
+            manifest: None,
+            target_copies: false,
+            checksum: None,
+        }
     }
 
     pub fn write(&mut self, c: &Control) -> Result<(), io::Error> {
+        if let Some(manifest) = self.manifest.as_mut() {
+            if !c.add.is_empty() {
+                manifest
+                    .regions
+                    .insert((manifest.old_pos as u64, c.add.len() as u64));
+            }
+            manifest.old_pos += c.add.len() as i64 + c.seek;
+        }
+
         let w = &mut self.w;
 
         w.write_varint(c.add.len())?;
@@ -33,16 +196,82 @@ where
         w.write_varint(c.copy.len())?;
         w.write_all(c.copy)?;
 
+        if self.target_copies {
+            w.write_varint(c.target.len())?;
+            if !c.target.is_empty() {
+                w.write_varint(c.target.start)?;
+            }
+        } else if !c.target.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "target copies need WriterOptions::target_copies",
+            ));
+        }
+
         w.write_varint(c.seek)?;
 
         Ok(())
     }
 
+    /// Write controls encoded by a [segment](Writer::segment) writer, which
+    /// don't make it to the manifest
+    pub(crate) fn write_encoded(&mut self, controls: &[u8]) -> Result<(), io::Error> {
+        self.w.write_all(controls)
+    }
+
     pub fn flush(&mut self) -> Result<(), io::Error> {
         self.w.flush()
     }
 
+    /// Write the trailing sections, if any, and return the inner writer.
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        let w = &mut self.w;
+        if self.manifest.is_some() || self.checksum.is_some() {
+            w.write_varint(END_OF_CONTROLS)?;
+        }
+        if let Some(manifest) = self.manifest.take() {
+            w.write_varint(manifest.regions.len())?;
+            for (offset, length) in manifest.regions {
+                w.write_varint(offset)?;
+                w.write_varint(length)?;
+            }
+        }
+        if let Some(checksum) = self.checksum.as_ref() {
+            w.write_all(checksum)?;
+        }
+        Ok(self.w)
+    }
+
+    /// Return the inner writer, without writing trailing sections.
     pub fn into_inner(self) -> W {
         self.w
     }
 }
+
+pub(crate) fn write_header<W: Write>(w: &mut W, flags: u32) -> Result<(), io::Error> {
+    w.write_u32::<LittleEndian>(MAGIC)?;
+    w.write_u32::<LittleEndian>(VERSION)?;
+    w.write_u32::<LittleEndian>(flags)?;
+    Ok(())
+}
+
+/// Write a segmented patch, made of segment bodies written by
+/// [Writer::segment].
+///
+/// The header is followed by the number of segments, the length of each
+/// segment, and then the segments themselves. The "older" file position is
+/// reset to 0 at the start of each segment, so they can be applied (and
+/// compressed) independently of each other.
+pub fn write_segmented<W: Write>(mut w: W, segments: &[Vec<u8>]) -> Result<(), io::Error> {
+    write_header(&mut w, FLAG_SEGMENTED)?;
+
+    w.write_varint(segments.len())?;
+    for segment in segments {
+        w.write_varint(segment.len())?;
+    }
+    for segment in segments {
+        w.write_all(segment)?;
+    }
+
+    Ok(())
+}
@@ -1,7 +1,8 @@
+use std::cmp::min;
+
 /// Generate a "newer" input from an "older" input and a set of instructions
 pub fn apply_instructions(older: &[u8], instructions: &[u8]) -> Vec<u8> {
-    use std::cmp::min;
-    let mut newer: Vec<_> = older.iter().map(|x| *x).collect();
+    let mut newer: Vec<_> = older.to_vec();
 
     for couple in instructions.chunks(2) {
         if couple.len() != 2 {
@@ -12,10 +13,7 @@ pub fn apply_instructions(older: &[u8], instructions: &[u8]) -> Vec<u8> {
         if i < 128 {
             let pos = (i as usize) % newer.len();
             let len = j as usize;
-            let data: Vec<u8> = (&newer[pos..min(pos + len, newer.len())])
-                .iter()
-                .map(|x| *x)
-                .collect();
+            let data: Vec<u8> = newer[pos..min(pos + len, newer.len())].to_vec();
             for c in data {
                 newer.push(c);
             }
@@ -31,3 +29,69 @@ pub fn apply_instructions(older: &[u8], instructions: &[u8]) -> Vec<u8> {
     }
     newer
 }
+
+/// Generate a set of instructions that `apply_instructions` turns from
+/// "older" into "newer"
+///
+/// `apply_instructions` can only swap adjacent bytes and append to its input,
+/// so "newer" must be reachable that way: its first `older.len()` bytes must
+/// be a permutation of "older" (where only the first 256 positions can be
+/// swapped), and anything past that is appended.
+///
+/// # Panics
+///
+/// Panics if "newer" cannot be expressed as such a transformation.
+pub fn make_instructions(older: &[u8], newer: &[u8]) -> Vec<u8> {
+    assert!(
+        newer.len() >= older.len(),
+        "newer must be at least as long as older"
+    );
+
+    let mut instructions = Vec::new();
+    let mut current = older.to_vec();
+    let (prefix, mut tail) = newer.split_at(older.len());
+
+    // bubble the wanted byte into place, one adjacent swap at a time
+    for (target, &wanted) in prefix.iter().enumerate() {
+        let found = (target..current.len())
+            .find(|&k| current[k] == wanted)
+            .expect("prefix of newer must be a permutation of older");
+        for k in (target..found).rev() {
+            assert!(k < 256, "only the first 256 bytes can be swapped");
+            instructions.extend_from_slice(&[150, k as u8]);
+            current.swap(k, k + 1);
+        }
+    }
+
+    while !tail.is_empty() {
+        // copies can only start in the first 128 bytes, and are at most 255 long
+        let best_copy = (0..min(128, current.len()))
+            .map(|pos| {
+                let len = current[pos..]
+                    .iter()
+                    .zip(tail)
+                    .take(255)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                (pos, len)
+            })
+            .max_by_key(|&(_, len)| len);
+
+        let len = match best_copy {
+            Some((pos, len)) if len > 2 => {
+                instructions.extend_from_slice(&[pos as u8, len as u8]);
+                len
+            }
+            _ => {
+                // runs are at most 21 long
+                let len = tail.iter().take(21).take_while(|&&b| b == tail[0]).count();
+                instructions.extend_from_slice(&[128 + len as u8, tail[0]]);
+                len
+            }
+        };
+        current.extend_from_slice(&tail[..len]);
+        tail = &tail[len..];
+    }
+
+    instructions
+}
@@ -1,26 +1,102 @@
+#![cfg_attr(not(test), deny(clippy::print_stdout, clippy::print_stderr))]
+
 use log::*;
+#[cfg(all(feature = "enc", feature = "parallel"))]
 use rayon::prelude::*;
-use sacabase::StringIndex;
-use sacapart::PartitionedSuffixArray;
+use sacabase::common_prefix_len;
+pub use sacabase::{LongestCommonSubstring, StringIndex};
+pub use sacapart::PartitionedSuffixArray;
 use std::collections::HashMap;
-use std::ffi::c_char;
-use std::ffi::c_int;
-use std::ffi::CString;
-use std::path::Path;
+use std::convert::TryFrom;
+#[cfg(feature = "enc")]
+use std::io::Write;
 use std::{
     cmp::min,
     error::Error,
-    io::{self, Write},
-    time::Instant,
+    io::{self, Read},
+    ops::{Deref, Range},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
+/// Log at info level that the step described by the format arguments took
+/// `duration` for `bytes` bytes, appending the time and speed to the message
+///
+/// With the `log-kv` feature, the record also carries them as `bytes`,
+/// `duration_ms` and `throughput_bps` fields.
+macro_rules! info_took {
+    ($bytes:expr, $duration:expr, $($arg:tt)+) => {{
+        let (bytes, duration): (u64, Duration) = ($bytes, $duration);
+        #[cfg(feature = "log-kv")]
+        info!(
+            bytes = bytes,
+            duration_ms = duration.as_millis() as u64,
+            throughput_bps = throughput(bytes, duration);
+            "{} took {}", format_args!($($arg)+), DurationSpeed(bytes, duration)
+        );
+        #[cfg(not(feature = "log-kv"))]
+        info!("{} took {}", format_args!($($arg)+), DurationSpeed(bytes, duration));
+    }};
+}
+
 #[cfg(feature = "enc")]
 pub mod enc;
 
+#[cfg(feature = "enc")]
+pub mod dec;
+
+#[cfg(feature = "enc")]
+pub mod auto;
+#[cfg(feature = "enc")]
+pub use auto::auto_diff;
+#[cfg(feature = "enc")]
+pub mod reversible;
+#[cfg(feature = "enc")]
+pub use reversible::simple_diff_reversible;
+
 #[cfg(any(test, feature = "instructions"))]
 pub mod instructions;
 
+mod canonical;
+pub use canonical::canonicalize;
+
+mod target;
+
+#[cfg(feature = "capi")]
+pub mod capi;
+
+pub mod telemetry;
+
+#[cfg(feature = "elf")]
+pub mod elf;
+#[cfg(feature = "elf")]
+pub use elf::diff_elf;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+#[cfg(feature = "mmap")]
+pub use mmap::simple_diff_files;
+#[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+pub mod squashfs;
+#[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+pub use squashfs::{
+    diff_squashfs, diff_squashfs_in_memory, diff_squashfs_in_memory_with_regions,
+    diff_squashfs_with_map, diff_squashfs_with_regions, squashfs_compressor, FileOffset,
+    SquashfsCompressor, SquashfsError, SquashfsFragmentMap, SquashfsRegion,
+};
+#[cfg(feature = "match-list")]
+pub mod match_list;
+#[cfg(feature = "match-list")]
+pub use match_list::{read_match_list, write_match_list};
+#[cfg(feature = "tokio")]
+pub mod async_diff;
+#[cfg(feature = "tokio")]
+pub use async_diff::{diff_async, diff_async_channel};
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Match {
     pub add_old_start: usize,
     pub add_new_start: usize,
@@ -33,15 +109,159 @@ impl Match {
     pub fn copy_start(&self) -> usize {
         self.add_new_start + self.add_length
     }
+
+    /// Number of bytes of "newer" stored as-is, after the add region
+    ///
+    /// Fails with [io::ErrorKind::InvalidData] when the copy region ends
+    /// before it starts, as in a malformed match list.
+    #[inline(always)]
+    pub fn copy_length(&self) -> io::Result<usize> {
+        self.copy_end
+            .checked_sub(self.copy_start())
+            .ok_or_else(|| self.backwards())
+    }
+
+    /// Number of bytes of "newer" this match covers, added and copied
+    ///
+    /// Fails like [Match::copy_length].
+    #[inline(always)]
+    pub fn total_new_bytes(&self) -> io::Result<usize> {
+        self.copy_end
+            .checked_sub(self.add_new_start)
+            .ok_or_else(|| self.backwards())
+    }
+
+    fn backwards(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "match copies backwards, from {} to {}",
+                self.copy_start(),
+                self.copy_end
+            ),
+        )
+    }
+
+    /// Whether nothing of "newer" is taken from "older", only stored as-is
+    #[inline(always)]
+    pub fn is_pure_copy(&self) -> bool {
+        self.add_length == 0
+    }
+
+    /// Whether all of it is taken from "older", with nothing stored as-is
+    ///
+    /// A match that covers nothing is both a pure add and a pure copy.
+    #[inline(always)]
+    pub fn is_pure_add(&self) -> bool {
+        self.copy_end == self.copy_start()
+    }
 }
 
+/// One instruction of a patch: add `add` to the bytes of "older" at the
+/// current position, append `copy`, then seek
+///
+/// New fields may be added, so controls are built with [Control::new]
+/// rather than with a struct literal, and setting the other fields on the
+/// result.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct Control<'a> {
     pub add: &'a [u8],
     pub copy: &'a [u8],
+    /// How far to move the position in "older" once `add` has been applied,
+    /// relative to the end of the add region. The position starts at 0,
+    /// and each add region starts where the previous seek landed.
+    ///
+    /// The [Translator] only seeks between the ends of matches and the start
+    /// of the next ones, so the position stays within `0..=older.len()`.
+    /// Patches can hold any value though: appliers ([dec::apply] and
+    /// bipatch) fail as soon as a seek lands the position out of those
+    /// bounds, rather than wrapping it around or reading from there.
+    pub seek: i64,
+    /// Bytes of the "newer" file that were already reconstructed, to append
+    /// after `copy`, see [Translator::with_target_copies]. Always empty
+    /// otherwise.
+    pub target: Range<usize>,
+    /// SHA-256 of the "older" bytes the add region is relative to, see
+    /// [Translator::with_source_hashes]
+    pub source_hash: Option<Hash>,
+}
+
+impl<'a> Control<'a> {
+    /// A control adding `add`, copying `copy` and seeking by `seek`, with no
+    /// target copy nor source hash
+    pub fn new(add: &'a [u8], copy: &'a [u8], seek: i64) -> Self {
+        Self {
+            add,
+            copy,
+            seek,
+            target: 0..0,
+            source_hash: None,
+        }
+    }
+
+    /// Copy the control out of the buffers it borrows from
+    pub fn to_owned(&self) -> ControlOwned {
+        ControlOwned {
+            add: self.add.to_vec(),
+            copy: self.copy.to_vec(),
+            seek: self.seek,
+            target: self.target.clone(),
+            source_hash: self.source_hash,
+        }
+    }
+}
+
+/// A [Control] that owns its data, for keeping it around or serializing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ControlOwned {
+    pub add: Vec<u8>,
+    pub copy: Vec<u8>,
     pub seek: i64,
+    pub target: Range<usize>,
+    pub source_hash: Option<Hash>,
 }
 
+impl ControlOwned {
+    /// Borrow the control, to pass it to [enc::Writer::write] for example
+    pub fn as_control(&self) -> Control<'_> {
+        Control {
+            add: &self.add,
+            copy: &self.copy,
+            seek: self.seek,
+            target: self.target.clone(),
+            source_hash: self.source_hash,
+        }
+    }
+}
+
+/// How the bytes of add regions are stored in [Control::add]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeltaMode {
+    /// The wrapping difference between the "newer" and the "older" bytes,
+    /// which is what bsdiff does.
+    #[default]
+    WrappingSub,
+    /// The "newer" bytes, as-is. This compresses worse, but is simpler for
+    /// some consumers.
+    Raw,
+}
+
+/// Turns [Match]es into [Control]s, passed to `on_control`
+///
+/// Each control is only sent once the next match is known, so the last one
+/// is sent by [Translator::close], which must be called once all matches have
+/// been translated. Dropping a translator never calls `on_control`: whatever
+/// it captures may be in any state by then. Debug builds panic when a
+/// translator with a pending control is dropped without being closed, unless
+/// `on_control` failed or it was [abandoned](Translator::abandon).
+///
+/// Matches must tile "newer": the first one starts at 0, each one starts
+/// where the previous one (or [Translator::copy_target]) ended, and the last
+/// one ends at the end of "newer". The copy regions of the controls are then
+/// strictly forward-advancing and never overlap, which appliers can rely on.
+/// This is checked as controls are sent, in debug builds.
 pub struct Translator<'a, F, E>
 where
     F: FnMut(&Control) -> Result<(), E>,
@@ -53,6 +273,15 @@ where
     buf: Vec<u8>,
     on_control: F,
     closed: bool,
+    failed: bool,
+    rewind: bool,
+    delta_mode: DeltaMode,
+    source_hashes: bool,
+    target_index: Option<target::TargetIndex>,
+    pieces: Vec<(Range<usize>, Range<usize>)>,
+    /// Copy of earlier "newer" bytes that follows the pending control, see
+    /// [Translator::copy_target]
+    pending_target: Option<Range<usize>>,
 }
 
 impl<'a, F, E> Translator<'a, F, E>
@@ -61,35 +290,164 @@ where
     E: Error,
 {
     pub fn new(obuf: &'a [u8], nbuf: &'a [u8], on_control: F) -> Self {
+        Self::with_delta_mode(obuf, nbuf, DeltaMode::default(), on_control)
+    }
+
+    pub fn with_delta_mode(
+        obuf: &'a [u8],
+        nbuf: &'a [u8],
+        delta_mode: DeltaMode,
+        on_control: F,
+    ) -> Self {
+        Self::from_parts(
+            obuf,
+            nbuf,
+            delta_mode,
+            Vec::with_capacity(16 * 1024),
+            on_control,
+        )
+    }
+
+    /// Translate into `buf` instead of allocating a buffer, so that it can be
+    /// reused from one diff to the next, see [Translator::into_buffer]
+    ///
+    /// `buf` is cleared, but keeps its capacity. It holds the add region of
+    /// the pending control, so it grows to the size of the largest one.
+    pub fn with_buffer(obuf: &'a [u8], nbuf: &'a [u8], mut buf: Vec<u8>, on_control: F) -> Self {
+        buf.clear();
+        Self::from_parts(obuf, nbuf, DeltaMode::default(), buf, on_control)
+    }
+
+    fn from_parts(
+        obuf: &'a [u8],
+        nbuf: &'a [u8],
+        delta_mode: DeltaMode,
+        buf: Vec<u8>,
+        on_control: F,
+    ) -> Self {
         Self {
             obuf,
             nbuf,
-            buf: Vec::with_capacity(16 * 1024),
+            buf,
             prev_match: None,
             on_control,
             closed: false,
+            failed: false,
+            rewind: false,
+            delta_mode,
+            source_hashes: false,
+            target_index: None,
+            pieces: Vec::new(),
+            pending_target: None,
         }
     }
 
+    /// Annotate each control with a hash of the "older" bytes its add region
+    /// is relative to, in [Control::source_hash].
+    ///
+    /// Identical regions get identical hashes across diffs, which lets
+    /// content-addressed pipelines recognize them. This hashes everything
+    /// that's added, so it's opt-in.
+    pub fn with_source_hashes(mut self) -> Self {
+        self.source_hashes = true;
+        self
+    }
+
+    /// Look for repeats of the literal bytes of each control earlier in the
+    /// "newer" file, and replace them with copies of the bytes that applying
+    /// the patch already reconstructed, in [Control::target].
+    ///
+    /// A control is split in several when its literal bytes have repeats,
+    /// none of which read from the "older" file. This shrinks patches of
+    /// files with repetitive new content, but needs a writer created with
+    /// [WriterOptions::target_copies](enc::WriterOptions::target_copies),
+    /// and keeps the whole "newer" file in memory when applying.
+    pub fn with_target_copies(mut self) -> Self {
+        self.target_index = Some(target::TargetIndex::new());
+        self
+    }
+
     fn send_control(&mut self, m: Option<&Match>) -> Result<(), E> {
         if let Some(pm) = self.prev_match.take() {
-            if let Some(m) = m {
-                assert_eq!(m.add_new_start, pm.copy_end);
-            }
-            (self.on_control)(&Control {
-                add: &self.buf[..pm.add_length],
-                copy: &self.nbuf[pm.copy_start()..pm.copy_end],
-                seek: if let Some(m) = m {
-                    m.add_old_start as i64 - (pm.add_old_start + pm.add_length) as i64
+            let pending_target = self.pending_target.take().unwrap_or(0..0);
+            debug_assert!(
+                pm.copy_start() <= pm.copy_end,
+                "match copies backwards, from {} to {}",
+                pm.copy_start(),
+                pm.copy_end
+            );
+            match m {
+                Some(m) => assert_eq!(m.add_new_start, pm.copy_end + pending_target.len()),
+                None => debug_assert_eq!(
+                    pm.copy_end + pending_target.len(),
+                    self.nbuf.len(),
+                    "matches stop before the end of newer"
+                ),
+            }
+            let seek = if let Some(m) = m {
+                m.add_old_start as i64 - (pm.add_old_start + pm.add_length) as i64
+            } else if self.rewind {
+                -((pm.add_old_start + pm.add_length) as i64)
+            } else {
+                0
+            };
+
+            let literal = pm.copy_start()..pm.copy_end;
+            self.pieces.clear();
+            match self.target_index.as_mut() {
+                Some(index) => index.split(self.nbuf, literal, &mut self.pieces),
+                None => self.pieces.push((literal, 0..0)),
+            }
+
+            // the last piece never has a target copy of its own, so it can
+            // take the pending one
+            let last = self.pieces.len() - 1;
+            self.pieces[last].1 = pending_target;
+
+            // only the first piece adds, and only the last one seeks
+            for (i, (copy, target)) in self.pieces.iter().enumerate() {
+                let add = if i == 0 {
+                    &self.obuf[pm.add_old_start..pm.add_old_start + pm.add_length]
                 } else {
-                    0
-                },
-            })?;
+                    &[]
+                };
+                let res = (self.on_control)(&Control {
+                    add: if i == 0 {
+                        &self.buf[..pm.add_length]
+                    } else {
+                        &[]
+                    },
+                    copy: &self.nbuf[copy.clone()],
+                    seek: if i == last { seek } else { 0 },
+                    target: target.clone(),
+                    source_hash: self.source_hashes.then(|| hmac_sha256::Hash::hash(add)),
+                });
+                self.failed |= res.is_err();
+                res?;
+            }
         }
         Ok(())
     }
 
     pub fn translate(&mut self, m: Match) -> Result<(), E> {
+        debug_assert!(
+            self.prev_match.is_some() || m.add_new_start == 0,
+            "first match starts at {}, not at the start of newer",
+            m.add_new_start
+        );
+        if self.prev_match.is_none() && m.add_old_start != 0 {
+            // patches start reading "older" from the beginning, so the first
+            // match needs a seek of its own
+            let res = (self.on_control)(&Control {
+                add: &[],
+                copy: &[],
+                seek: m.add_old_start as i64,
+                target: 0..0,
+                source_hash: self.source_hashes.then(|| hmac_sha256::Hash::hash(&[])),
+            });
+            self.failed |= res.is_err();
+            res?;
+        }
         self.send_control(Some(&m))?;
 
         self.buf.clear();
@@ -101,26 +459,123 @@ where
         // within the closure while `self.buf` is being mutated.
         let nbuf = &self.nbuf;
         let obuf = &self.obuf;
-        self.buf.extend(
-            (0..m.add_length)
-                .map(|i| nbuf[m.add_new_start + i].wrapping_sub(obuf[m.add_old_start + i])),
-        );
+        match self.delta_mode {
+            DeltaMode::WrappingSub => self.buf.extend(
+                (0..m.add_length)
+                    .map(|i| nbuf[m.add_new_start + i].wrapping_sub(obuf[m.add_old_start + i])),
+            ),
+            DeltaMode::Raw => self
+                .buf
+                .extend_from_slice(&nbuf[m.add_new_start..m.copy_start()]),
+        }
 
         self.prev_match = Some(m);
         Ok(())
     }
 
-    pub fn close(mut self) -> Result<(), E> {
-        self.do_close()
+    /// Translate a copy of `src`, bytes of "newer" that applying the patch
+    /// already reconstructed, into the next `src.len()` bytes of "newer"
+    ///
+    /// This is for callers that know where "newer" repeats itself, unlike
+    /// [Translator::with_target_copies] which searches for repeats. At least
+    /// one match must have been translated before, and the next one must
+    /// start right after the copy. Like with
+    /// [Translator::with_target_copies], the writer needs
+    /// [WriterOptions::target_copies](enc::WriterOptions::target_copies).
+    ///
+    /// Fails with [DiffError::TargetCopy] when `src` wasn't reconstructed
+    /// yet, or its copy would run past the end of "newer".
+    pub fn copy_target(&mut self, src: Range<usize>) -> Result<(), E>
+    where
+        E: From<DiffError>,
+    {
+        let reconstructed = self.prev_match.as_ref().map_or(0, |pm| {
+            pm.copy_end + self.pending_target.as_ref().map_or(0, |p| p.len())
+        });
+        if self.prev_match.is_none()
+            || src.start > src.end
+            || src.end > reconstructed
+            || reconstructed + src.len() > self.nbuf.len()
+        {
+            self.failed = true;
+            return Err(DiffError::TargetCopy(src).into());
+        }
+        let pm = self.prev_match.as_ref().expect("checked above");
+        if let Some(pending) = self.pending_target.as_ref() {
+            // the pending control has a copy already, so it's sent without
+            // seeking, and this copy goes to an empty control
+            let new_pos = pm.copy_end + pending.len();
+            let next = Match {
+                add_old_start: pm.add_old_start + pm.add_length,
+                add_new_start: new_pos,
+                add_length: 0,
+                copy_end: new_pos,
+            };
+            self.send_control(Some(&next))?;
+            self.buf.clear();
+            self.prev_match = Some(next);
+        }
+        self.pending_target = Some(src);
+        Ok(())
     }
 
-    fn do_close(&mut self) -> Result<(), E> {
-        if !self.closed {
-            self.send_control(None)?;
-            self.closed = true;
+    /// [Translator::translate] each of `matches`, in order, stopping at the
+    /// first error
+    pub fn translate_all<I>(&mut self, matches: I) -> Result<(), E>
+    where
+        I: IntoIterator<Item = Match>,
+    {
+        for m in matches {
+            self.translate(m)?;
         }
         Ok(())
     }
+
+    /// Send the last control
+    pub fn close(mut self) -> Result<(), E> {
+        self.closed = true;
+        self.send_control(None)
+    }
+
+    /// [Close](Translator::close) the translator, and give back its buffer
+    /// for another one to use, see [Translator::with_buffer]
+    pub fn into_buffer(mut self) -> Result<Vec<u8>, E> {
+        self.closed = true;
+        self.send_control(None)?;
+        Ok(std::mem::take(&mut self.buf))
+    }
+
+    /// Send the last control, seeking back to the start of "older", so that
+    /// the controls of another translator can follow it
+    pub fn close_and_rewind(mut self) -> Result<(), E> {
+        self.rewind = true;
+        self.close()
+    }
+
+    /// Drop the translator without sending its last control, because
+    /// whatever was feeding it matches failed
+    pub fn abandon(mut self) {
+        self.failed = true;
+    }
+
+    /// [Close](Translator::close) the translator if `res`, the outcome of
+    /// feeding it matches, is `Ok`, and [abandon](Translator::abandon) it
+    /// otherwise
+    pub fn close_after<T, E2>(self, res: Result<T, E2>) -> Result<T, E2>
+    where
+        E2: From<E>,
+    {
+        match res {
+            Ok(v) => {
+                self.close()?;
+                Ok(v)
+            }
+            Err(e) => {
+                self.abandon();
+                Err(e)
+            }
+        }
+    }
 }
 
 impl<'a, F, E> Drop for Translator<'a, F, E>
@@ -129,13 +584,17 @@ where
     E: Error,
 {
     fn drop(&mut self) {
-        // dropping a Translator ignores errors on purpose,
-        // just like File does
-        self.do_close().unwrap_or(());
+        debug_assert!(
+            self.closed || self.failed || self.prev_match.is_none() || std::thread::panicking(),
+            "Translator dropped without calling close(), its last control was lost"
+        );
     }
 }
 
-struct BsdiffIterator<'a> {
+/// Finds matches of `nbuf` in `obuf` with `sa`, the index of `obuf`, which
+/// is either borrowed or, for [matches], owned. The index may outlive both
+/// buffers.
+struct BsdiffIterator<'a, S = &'a (dyn StringIndex<'a> + 'a)> {
     scan: usize,
     pos: usize,
     length: usize,
@@ -145,11 +604,32 @@ struct BsdiffIterator<'a> {
 
     obuf: &'a [u8],
     nbuf: &'a [u8],
-    sa: &'a dyn StringIndex<'a>,
+    sa: S,
+    tie_policy: TiePolicy,
+    match_threshold: usize,
+    cancel: Option<Arc<AtomicBool>>,
+    /// Size of the partitions of the suffix array to check matches against,
+    /// see [BsdiffIterator::verifying]
+    #[cfg(all(feature = "verify-sa", debug_assertions))]
+    verify_partition_size: Option<usize>,
 }
 
 impl<'a> BsdiffIterator<'a> {
-    pub fn new(obuf: &'a [u8], nbuf: &'a [u8], sa: &'a dyn StringIndex<'a>) -> Self {
+    pub fn new(
+        obuf: &'a [u8],
+        nbuf: &'a [u8],
+        sa: &'a dyn StringIndex<'a>,
+        params: &DiffParams,
+    ) -> Self {
+        Self::with_index(obuf, nbuf, sa, params)
+    }
+}
+
+impl<'a, 'i, S> BsdiffIterator<'a, S>
+where
+    S: Deref<Target = dyn StringIndex<'i> + 'i>,
+{
+    fn with_index(obuf: &'a [u8], nbuf: &'a [u8], sa: S, params: &DiffParams) -> Self {
         Self {
             scan: 0,
             pos: 0,
@@ -160,11 +640,37 @@ impl<'a> BsdiffIterator<'a> {
             obuf,
             nbuf,
             sa,
+            tie_policy: params.tie_policy,
+            match_threshold: params.match_threshold as usize,
+            cancel: params.cancel.clone(),
+            #[cfg(all(feature = "verify-sa", debug_assertions))]
+            verify_partition_size: None,
         }
     }
+
+    /// Check some of the matches found against a brute-force search (see
+    /// [verify_match]), for a suffix array sorted in partitions of
+    /// `partition_size` bytes
+    #[cfg(all(feature = "verify-sa", debug_assertions))]
+    fn verifying(mut self, partition_size: usize) -> Self {
+        self.verify_partition_size = Some(partition_size.max(1));
+        self
+    }
+
+    /// Scan as if the bytes before `nbuf` were taken from "older" up to
+    /// `old_pos`, so that the first match continues that alignment
+    fn resuming_at(mut self, old_pos: usize) -> Self {
+        debug_assert!(old_pos <= self.obuf.len(), "resuming past the end of older");
+        self.lastpos = old_pos;
+        self.lastoffset = old_pos as isize;
+        self
+    }
 }
 
-impl<'a> Iterator for BsdiffIterator<'a> {
+impl<'a, 'i, S> Iterator for BsdiffIterator<'a, S>
+where
+    S: Deref<Target = dyn StringIndex<'i> + 'i>,
+{
     type Item = Match;
     fn next(&mut self) -> Option<Self::Item> {
         let obuflen = self.obuf.len();
@@ -176,9 +682,33 @@ impl<'a> Iterator for BsdiffIterator<'a> {
 
             let mut scsc = self.scan;
             'inner: while self.scan < nbuflen {
+                // the caller tells a cancelled scan from a finished one
+                if is_set(&self.cancel) {
+                    return None;
+                }
+
                 let res = self.sa.longest_substring_match(&self.nbuf[self.scan..]);
-                self.pos = res.start;
-                self.length = res.len;
+                #[cfg(all(feature = "verify-sa", debug_assertions))]
+                if let Some(partition_size) = self.verify_partition_size {
+                    if self.scan.is_multiple_of(VERIFY_SA_INTERVAL) {
+                        let needle = &self.nbuf[self.scan..];
+                        verify_match(self.obuf, partition_size, needle, res.start, res.len);
+                    }
+                }
+                // custom indexes may point anywhere when they find nothing,
+                // and matches past the end of "older" are no matches at all.
+                // Everything below relies on `pos <= obuflen`.
+                if res
+                    .start
+                    .checked_add(res.len)
+                    .is_some_and(|end| end <= obuflen)
+                {
+                    self.pos = res.start;
+                    self.length = res.len;
+                } else {
+                    self.pos = min(res.start, obuflen);
+                    self.length = 0;
+                }
 
                 {
                     while scsc < self.scan + self.length {
@@ -190,7 +720,7 @@ impl<'a> Iterator for BsdiffIterator<'a> {
                     }
                 }
 
-                let significantly_better = self.length > oldscore + 8;
+                let significantly_better = self.length > oldscore + self.match_threshold;
                 let same_length = self.length == oldscore && self.length != 0;
 
                 if same_length || significantly_better {
@@ -208,7 +738,13 @@ impl<'a> Iterator for BsdiffIterator<'a> {
             } // 'inner
 
             let done_scanning = self.scan == nbuflen;
-            if self.length != oldscore || done_scanning {
+            let take_tie = self.tie_policy == TiePolicy::Match
+                && self.length != 0
+                && self.pos as isize - self.scan as isize != self.lastoffset;
+            if self.length != oldscore || done_scanning || take_tie {
+                debug_assert!(self.lastscan <= self.scan && self.scan <= nbuflen);
+                debug_assert!(self.lastpos <= obuflen && self.pos <= obuflen);
+
                 // length forward from lastscan
                 let mut lenf = {
                     let (mut s, mut sf, mut lenf) = (0_isize, 0_isize, 0_isize);
@@ -249,6 +785,8 @@ impl<'a> Iterator for BsdiffIterator<'a> {
                     }
                     lenb as usize
                 };
+                // so that `scan - lenb` and `pos - lenb` can't underflow
+                debug_assert!(lenb <= self.scan - self.lastscan && lenb <= self.pos);
 
                 let lastscan_was_better = self.lastscan + lenf > self.scan - lenb;
                 if lastscan_was_better {
@@ -305,12 +843,110 @@ impl<'a> Iterator for BsdiffIterator<'a> {
     }
 }
 
+/// What the scanner does when a match is exactly as good as carrying on
+/// with the current alignment between the "older" and "newer" files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TiePolicy {
+    /// Keep extending the current alignment, which means fewer (but longer)
+    /// controls. This is what bsdiff does.
+    #[default]
+    Extend,
+    /// Switch to the new match, which helps when it keeps matching past the
+    /// point where the current alignment stops, at the cost of extra controls
+    /// when it doesn't.
+    Match,
+}
+
+/// How [simple_diff_with_params] compresses patches, see
+/// [DiffParams::with_compression]
+///
+/// zstd compresses and decompresses much faster, xz usually compresses
+/// patches further but takes several times as long and needs more memory to
+/// do it. zstd suits patches
+/// that are made and applied right away, xz those that are archived, or sent
+/// to many devices over slow links.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PatchCompression {
+    /// Patches are written as-is
+    #[default]
+    None,
+    /// zstd at this level, clamped to zstd's range. Requires the `zstd`
+    /// feature.
+    Zstd(i32),
+    /// xz at this preset, from 0 to 9 (higher ones are clamped to 9).
+    /// Requires the `xz` feature.
+    Xz(u32),
+}
+
+impl PatchCompression {
+    /// Whether the feature this compressor needs was compiled in
+    pub fn is_compiled_in(self) -> bool {
+        match self {
+            PatchCompression::None => true,
+            PatchCompression::Zstd(_) => cfg!(feature = "zstd"),
+            PatchCompression::Xz(_) => cfg!(feature = "xz"),
+        }
+    }
+}
+
+/// Progress of a diff, reported to [DiffParams::with_progress]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// The suffix array of "older" was built (or skipped), scanning starts
+    SuffixArrayBuilt,
+    /// `scanned` bytes of "newer" out of `total` were scanned. `scanned`
+    /// only ever grows, and ends at `total`.
+    Scanned { scanned: usize, total: usize },
+}
+
+/// Bytes the sequential scan goes through between [ProgressEvent::Scanned]
+/// events
+const PROGRESS_INTERVAL: usize = 1024 * 1024;
+
 /// Parameters used when creating diffs
 pub struct DiffParams {
     sort_partitions: usize,
     scan_chunk_size: Option<usize>,
+    max_sort_bytes: Option<usize>,
+    max_memory: Option<usize>,
+    tie_policy: TiePolicy,
+    match_threshold: u8,
+    reserve_hint: Option<usize>,
+    check_coverage: bool,
+    auto_parallel_threshold: usize,
+    target_copies: bool,
+    checksum: bool,
+    source_checksum: bool,
+    progress: Option<Arc<dyn Fn(ProgressEvent) + Send + Sync>>,
+    cancel: Option<Arc<AtomicBool>>,
+    known_identical_prefix: Option<usize>,
+    compression: PatchCompression,
+    merge_threshold: Option<usize>,
+    max_match_length: Option<usize>,
+    chunk_overlap: usize,
 }
 
+/// Default for [DiffParams::with_match_threshold]
+pub const DEFAULT_MATCH_THRESHOLD: u8 = 8;
+/// Largest value accepted by [DiffParams::with_match_threshold]
+pub const MAX_MATCH_THRESHOLD: u8 = 64;
+
+/// Default for [DiffParams::with_auto_parallel_threshold]
+pub const DEFAULT_AUTO_PARALLEL_THRESHOLD: usize = 4 * 1024 * 1024;
+
+/// Size of the chunks "newer" files over the
+/// [auto parallel threshold](DiffParams::with_auto_parallel_threshold) are
+/// scanned in
+pub const AUTO_SCAN_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Most sort partitions [DiffParams::auto] uses, however many threads are
+/// available
+pub const MAX_AUTO_SORT_PARTITIONS: usize = 8;
+
+/// Fewest bytes of "older" a sort partition covers when
+/// [DiffParams::with_max_memory] picks the number of partitions
+pub const MIN_SORT_PARTITION_SIZE: usize = 64 * 1024;
+
 impl DiffParams {
     /// Construct new diff params and check validity
     ///
@@ -320,361 +956,6452 @@ impl DiffParams {
     ///   Increase this number increases parallelism but produces slightly worse
     ///   patches. Needs to be at least 1.
     /// - `scan_chunk_size`: Size of chunks to use for scanning. When `None`, treat
-    ///   the input as a single chunk. Smaller chunks increase parallelism but
-    ///   produce slightly worse patches. When `Some`, it needs to be at least 1.
+    ///   the input as a single chunk, unless it's over the
+    ///   [auto parallel threshold](DiffParams::with_auto_parallel_threshold).
+    ///   Smaller chunks increase parallelism but produce slightly worse
+    ///   patches. When `Some`, it needs to be at least 1.
+    ///
+    /// The two are independent: each chunk of "newer" is looked up in the
+    /// whole index of "older", whichever partitions it's split in, so there
+    /// can be more chunks than partitions or the other way around.
     pub fn new(
         sort_partitions: usize,
         scan_chunk_size: Option<usize>,
     ) -> Result<Self, Box<dyn Error + Send + Sync + 'static>> {
-        if sort_partitions < 1 {
-            return Err("number of sort partitions cannot be less than 1".into());
-        }
-        if scan_chunk_size.filter(|s| *s < 1).is_some() {
-            return Err("scan chunk size cannot be less than 1".into());
-        }
-
-        Ok(Self {
-            sort_partitions,
-            scan_chunk_size,
-        })
+        Self::builder()
+            .sort_partitions(sort_partitions)
+            .scan_chunk_size(scan_chunk_size)
+            .build()
     }
-}
 
-impl Default for DiffParams {
-    fn default() -> Self {
-        Self {
-            sort_partitions: 1,
-            scan_chunk_size: None,
+    /// Start building diff params from the defaults, see [DiffParamsBuilder]
+    pub fn builder() -> DiffParamsBuilder {
+        DiffParamsBuilder {
+            params: Self::default(),
         }
     }
-}
-
-/// Diff two files
-pub fn diff<F, E>(obuf: &[u8], nbuf: &[u8], params: &DiffParams, mut on_match: F) -> Result<(), E>
-where
-    F: FnMut(Match) -> Result<(), E>,
-{
-    info!("building suffix array...");
-    let before_suffix = Instant::now();
-    let sa = PartitionedSuffixArray::new(obuf, params.sort_partitions, divsufsort::sort);
-    info!(
-        "sorting took {}",
-        DurationSpeed(obuf.len() as u64, before_suffix.elapsed())
-    );
-
-    let before_scan = Instant::now();
-    if let Some(chunk_size) = params.scan_chunk_size {
-        // +1 to make sure we don't have > num_partitions
-        let num_chunks = (nbuf.len() + chunk_size - 1) / chunk_size;
-
-        info!(
-            "scanning with {}B chunks... ({} chunks total)",
-            chunk_size, num_chunks
-        );
-
-        let mut txs = Vec::with_capacity(num_chunks);
-        let mut rxs = Vec::with_capacity(num_chunks);
-        for _ in 0..num_chunks {
-            let (tx, rx) = std::sync::mpsc::channel::<Vec<Match>>();
-            txs.push(tx);
-            rxs.push(rx);
-        }
-
-        nbuf.par_chunks(chunk_size).zip(txs).for_each(|(nbuf, tx)| {
-            let iter = BsdiffIterator::new(obuf, nbuf, &sa);
-            tx.send(iter.collect()).expect("should send results");
-        });
-
-        for (i, rx) in rxs.into_iter().enumerate() {
-            let offset = i * chunk_size;
-            let v = rx.recv().expect("should receive results");
-            for mut m in v {
-                // if m.add_length == 0 && m.copy_end == m.copy_start() {
-                //     continue;
-                // }
 
-                m.add_new_start += offset;
-                m.copy_end += offset;
-                on_match(m)?;
-            }
-        }
-    } else {
-        for m in BsdiffIterator::new(obuf, nbuf, &sa) {
-            on_match(m)?
+    /// Diff params that trade some patch quality for speed, using the
+    /// available threads (see [available_parallelism])
+    ///
+    /// Older files are sorted in one partition per thread, up to
+    /// [MAX_AUTO_SORT_PARTITIONS]: each partition makes sorting faster but
+    /// lookups slower, and misses matches that straddle partitions, so
+    /// patches grow a little with each one. Newer files are scanned in
+    /// chunks when they're over the
+    /// [auto parallel threshold](DiffParams::with_auto_parallel_threshold),
+    /// like with the default params.
+    ///
+    /// Unlike with the default params, the patch depends on the number of
+    /// threads of the machine that made it.
+    pub fn auto() -> Self {
+        Self {
+            sort_partitions: available_parallelism().clamp(1, MAX_AUTO_SORT_PARTITIONS),
+            ..Default::default()
         }
     }
 
-    info!(
-        "scanning took {}",
-        DurationSpeed(obuf.len() as u64, before_scan.elapsed())
-    );
+    /// Skip suffix sorting when the "older" file is larger than
+    /// `max_sort_bytes`, to bound the time spent diffing.
+    ///
+    /// Instead, the common prefix and suffix of both files are taken from the
+    /// "older" file, and everything in between is stored as-is. This is only
+    /// a good patch when changes are localized: if they're spread all over,
+    /// the patch ends up about as large as the "newer" file.
+    pub fn with_max_sort_bytes(mut self, max_sort_bytes: Option<usize>) -> Self {
+        self.max_sort_bytes = max_sort_bytes;
+        self
+    }
 
-    Ok(())
-}
+    /// Choose the number of sort partitions from the size of the "older"
+    /// file, so that sorting each partition takes about `max_memory` bytes
+    /// at most. This overrides `sort_partitions` (with a warning when it
+    /// changes it). `max_memory` can't be 0.
+    ///
+    /// This is a hint for sizing partitions, not a bound on what diffing
+    /// takes. Sorting a partition takes about 4 bytes per byte of "older"
+    /// it covers, and partitions are sorted in parallel. The complete suffix
+    /// array still takes 4 bytes per byte of "older", however many
+    /// partitions it has, on top of both files and the buffers of the scan
+    /// and of the patch. Partitions also never go under
+    /// [MIN_SORT_PARTITION_SIZE] bytes of "older", since each one makes
+    /// every lookup slower.
+    pub fn with_max_memory(
+        mut self,
+        max_memory: Option<usize>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync + 'static>> {
+        self.max_memory = max_memory;
+        self.validate()?;
+        Ok(self)
+    }
 
-use std::fmt;
+    /// Choose what to do with matches that tie with the current alignment,
+    /// see [TiePolicy]
+    pub fn with_tie_policy(mut self, tie_policy: TiePolicy) -> Self {
+        self.tie_policy = tie_policy;
+        self
+    }
 
-struct DurationSpeed(u64, std::time::Duration);
+    /// How many more bytes a match must get right than the current alignment
+    /// for the scanner to switch to it. Defaults to
+    /// [DEFAULT_MATCH_THRESHOLD], and can't be over [MAX_MATCH_THRESHOLD].
+    ///
+    /// Lower values switch alignments more eagerly, which suits data with
+    /// many short moved regions, at the cost of more controls. Higher values
+    /// stick to the current alignment longer.
+    pub fn with_match_threshold(
+        mut self,
+        match_threshold: u8,
+    ) -> Result<Self, Box<dyn Error + Send + Sync + 'static>> {
+        self.match_threshold = match_threshold;
+        self.validate()?;
+        Ok(self)
+    }
 
-impl fmt::Display for DurationSpeed {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let (size, duration) = (self.0, self.1);
-        write!(f, "{:?} ({})", duration, Speed(size, duration))
+    /// Estimated size of the encoded patch, in bytes.
+    ///
+    /// When set, [simple_diff_with_params] and [segmented_diff_with_params]
+    /// encode into buffers reserved up-front from this estimate, instead of
+    /// growing them (or writing to `out` piecemeal) as controls come in. The
+    /// patch is the same either way.
+    pub fn with_reserve_hint(mut self, reserve_hint: Option<usize>) -> Self {
+        self.reserve_hint = reserve_hint;
+        self
     }
-}
 
-struct Speed(u64, std::time::Duration);
+    /// Check that the emitted matches exactly tile the "newer" file, and fail
+    /// with a [DiffError] when they don't, see [CoverageCheck]
+    pub fn with_coverage_check(mut self, check_coverage: bool) -> Self {
+        self.check_coverage = check_coverage;
+        self
+    }
 
-impl fmt::Display for Speed {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let (size, duration) = (self.0, self.1);
-        let per_sec = size as f64 / duration.as_secs_f64();
-        write!(f, "{} / s", Size(per_sec as u64))
+    /// When no `scan_chunk_size` is given, "newer" files of at least
+    /// `auto_parallel_threshold` bytes are split into [AUTO_SCAN_CHUNK_SIZE]
+    /// chunks, however many threads are available, so that the patch doesn't
+    /// depend on the machine. Smaller ones are scanned sequentially. Defaults
+    /// to [DEFAULT_AUTO_PARALLEL_THRESHOLD], use `usize::MAX` to always scan
+    /// sequentially.
+    pub fn with_auto_parallel_threshold(mut self, auto_parallel_threshold: usize) -> Self {
+        self.auto_parallel_threshold = auto_parallel_threshold;
+        self
     }
-}
 
-struct Size(u64);
+    /// Let [simple_diff_with_params] copy repeated literal bytes from the
+    /// already reconstructed part of the "newer" file, see
+    /// [Translator::with_target_copies]. Segmented patches never do, so that
+    /// segments stay independent.
+    pub fn with_target_copies(mut self, target_copies: bool) -> Self {
+        self.target_copies = target_copies;
+        self
+    }
 
-impl fmt::Display for Size {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let x = self.0;
+    /// Let [simple_diff_with_params] end the patch with a SHA-256 of the
+    /// "newer" file, see [WriterOptions::checksum](enc::WriterOptions::checksum)
+    pub fn with_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
 
-        if x > 1024 * 1024 {
-            write!(f, "{:.2} MiB", x as f64 / (1024.0 * 1024.0))
-        } else if x > 1024 {
-            write!(f, "{:.1} KiB", x as f64 / (1024.0))
-        } else {
-            write!(f, "{} B", x)
-        }
+    /// Let [simple_diff_with_params] and [diff_squashfs] start the patch with
+    /// a SHA-256 of the "older" file, see
+    /// [WriterOptions::source_checksum](enc::WriterOptions::source_checksum),
+    /// so that it can't be applied to another one. This is off by default,
+    /// and costs 32 bytes and hashing "older" on both ends.
+    ///
+    /// Readers that predate it refuse such patches for their unknown flag.
+    pub fn with_source_checksum(mut self, source_checksum: bool) -> Self {
+        self.source_checksum = source_checksum;
+        self
     }
-}
 
-#[cfg(feature = "enc")]
-pub fn simple_diff(older: &[u8], newer: &[u8], out: &mut dyn Write) -> Result<(), io::Error> {
-    simple_diff_with_params(older, newer, out, &Default::default())
-}
+    /// Compress the patches written by [simple_diff_with_params], into the
+    /// same format as [auto_diff]'s compressed patches: the header records
+    /// the compressor. [dec::apply] decompresses them transparently,
+    /// `bipatch` needs them to go through `bipatch::decompress` first.
+    ///
+    /// The control stream is compressed as a whole, with add, copy and seek
+    /// data interleaved, rather than as three separate streams: that would
+    /// compress a bit better, but needs a different patch layout and can't
+    /// be applied as it's decompressed. When the compressor wasn't compiled
+    /// in (see [PatchCompression::is_compiled_in]),
+    /// [DiffParamsBuilder::build] fails, and so does diffing, with
+    /// [io::ErrorKind::Unsupported], before anything is diffed.
+    pub fn with_compression(mut self, compression: PatchCompression) -> Self {
+        self.compression = compression;
+        self
+    }
 
-#[cfg(feature = "enc")]
-pub fn simple_diff_with_params(
-    older: &[u8],
-    newer: &[u8],
-    out: &mut dyn Write,
-    diff_params: &DiffParams,
-) -> Result<(), io::Error> {
-    let mut w = enc::Writer::new(out)?;
+    /// Compress the patches written by [simple_diff_with_params] with zstd at
+    /// `zstd_level`, or not at all, see [DiffParams::with_compression]
+    #[cfg(feature = "zstd")]
+    pub fn with_zstd_level(self, zstd_level: Option<i32>) -> Self {
+        self.with_compression(zstd_level.map_or(PatchCompression::None, PatchCompression::Zstd))
+    }
 
-    let mut translator = Translator::new(older, newer, |control| w.write(control));
-    diff(older, newer, diff_params, |m| translator.translate(m))?;
-    translator.close()?;
+    /// Call `progress` as the diff goes, see [ProgressEvent]
+    ///
+    /// When scanning in chunks, progress is reported as the results of each
+    /// chunk are collected, in order, so it's only as fine-grained as the
+    /// chunks are. [diff_streaming] doesn't know how much there is to scan,
+    /// and only reports [ProgressEvent::SuffixArrayBuilt].
+    pub fn with_progress(
+        mut self,
+        progress: Option<Arc<dyn Fn(ProgressEvent) + Send + Sync>>,
+    ) -> Self {
+        self.progress = progress;
+        self
+    }
 
-    Ok(())
-}
+    /// Stop diffing soon after `cancel` is set, failing with
+    /// [DiffError::Cancelled], which [diff] and the other diffing functions
+    /// return through their callback's error type
+    ///
+    /// The flag is checked while scanning: suffix sorting runs to completion
+    /// regardless.
+    pub fn with_cancel(mut self, cancel: Option<Arc<AtomicBool>>) -> Self {
+        self.cancel = cancel;
+        self
+    }
 
-type Hash = [u8; 32];
+    /// Take the first `known_identical_prefix` bytes of "newer" from "older"
+    /// with a single match, and only scan what follows, when they're known to
+    /// be identical (from filesystem metadata, or because the files are
+    /// append-mostly logs)
+    ///
+    /// The prefix is checked before it's used, which is much faster than
+    /// scanning it: when it's wrong, only the part that does match is
+    /// skipped, with a warning. It's clamped to the length of both files, and
+    /// ignored when sorting is skipped (which skips the common prefix
+    /// anyway), by [diff_streaming] and by segmented diffs.
+    pub fn with_known_identical_prefix(mut self, known_identical_prefix: Option<usize>) -> Self {
+        self.known_identical_prefix = known_identical_prefix;
+        self
+    }
 
-#[repr(C)]
-struct Block {
-    offset: u64,
-    size: u32,
-    hash: Hash, //sha256
-}
+    /// Merge consecutive matches together while what they cover of "newer"
+    /// stays under `merge_threshold` bytes, so that noisy data, which the
+    /// scanner splits into many short matches, takes fewer controls
+    ///
+    /// A match is merged into the previous one by extending its add region
+    /// when it picks up in "older" right where it ends (and it has no copy
+    /// region), and by storing it as-is otherwise, so the patch rebuilds the
+    /// same "newer" either way, but may have more bytes to store. Matches are
+    /// only merged within a scan chunk.
+    pub fn with_merge_threshold(mut self, merge_threshold: Option<usize>) -> Self {
+        self.merge_threshold = merge_threshold;
+        self
+    }
 
-extern "C" {
-    fn shim_get_blocks(
-        path: *const c_char,
-        blocks: *mut *mut Block,
-        blocks_len: *mut usize,
-    ) -> c_int;
-    fn shim_get_inode_table_idx(path: *const c_char) -> u64;
-}
+    /// Split the matches the scanner finds so that none covers more than
+    /// `max_match_length` bytes of "newer", add and copy regions together,
+    /// which bounds the size of each control
+    ///
+    /// A match is split into consecutive pieces, each picking up in "older"
+    /// where the previous one ended, so the patch rebuilds the same "newer",
+    /// with one more control per piece. `max_match_length` needs to be at
+    /// least 1.
+    pub fn with_max_match_length(
+        mut self,
+        max_match_length: Option<usize>,
+    ) -> Result<Self, Box<dyn Error + Send + Sync + 'static>> {
+        self.max_match_length = max_match_length;
+        self.validate()?;
+        Ok(self)
+    }
 
-fn get_inode_table_idx(path: &Path) -> Result<usize, std::io::Error> {
-    let c_path = CString::new(path.to_str().unwrap()).unwrap();
-    let ret = unsafe { shim_get_inode_table_idx(c_path.as_ptr() as *const c_char) };
-    if ret == 0 {
-        return Err(std::io::Error::last_os_error());
+    /// Have each scan chunk but the first also scan the `chunk_overlap`
+    /// bytes before it, so that matches that straddle chunk boundaries are
+    /// found whole
+    ///
+    /// Where chunks overlap, the matches of the next chunk are kept from the
+    /// start of the one that crosses the boundary when it's longer than the
+    /// last match of the previous chunk, which the boundary cut short, and
+    /// from the boundary otherwise. Matches are trimmed to fit, so the patch
+    /// still covers each byte once. Overlapping chunks are translated and
+    /// encoded as they're reconciled, rather than on the workers that
+    /// scanned them. Overlaps past the previous chunk are scanned for
+    /// nothing. Segmented diffs, whose segments are independent, ignore it.
+    pub fn with_chunk_overlap(mut self, chunk_overlap: usize) -> Self {
+        self.chunk_overlap = chunk_overlap;
+        self
     }
-    Ok(ret as usize)
-}
 
-struct Fragments {
-    data: Vec<Block>,
-    pos: usize,
-}
+    /// `m`, split according to [DiffParams::with_max_match_length]
+    fn split_match(&self, m: Match) -> impl Iterator<Item = Match> {
+        split_match(m, self.max_match_length.unwrap_or(usize::MAX))
+    }
 
-impl Fragments {
-    fn new(path: &Path) -> Result<Self, std::io::Error> {
-        let c_path = CString::new(path.to_str().unwrap()).unwrap();
-        let mut blocks = std::ptr::null_mut();
-        let mut blocks_len = 0usize;
-        let ret = unsafe {
-            shim_get_blocks(
-                c_path.as_ptr() as *const c_char,
-                &mut blocks as *mut *mut Block,
-                &mut blocks_len as *mut usize,
-            )
+    /// The part of the known identical prefix that's actually identical
+    fn identical_prefix(&self, obuf: &[u8], nbuf: &[u8]) -> usize {
+        let known = match self.known_identical_prefix {
+            Some(known) => min(known, min(obuf.len(), nbuf.len())),
+            None => return 0,
         };
-        if ret != 0 {
-            return Err(std::io::Error::last_os_error());
+        let prefix = common_prefix_len(&obuf[..known], &nbuf[..known]);
+        if prefix < known {
+            warn!(
+                "files differ at {}, within the {} known identical prefix",
+                prefix,
+                Size(known as u64)
+            );
         }
-        let data = unsafe { Vec::from_raw_parts(blocks, blocks_len as usize, blocks_len as usize) };
-        Ok(Self { data, pos: 0 })
+        prefix
     }
+
+    fn validate(&self) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
+        if self.sort_partitions < 1 {
+            return Err("number of sort partitions cannot be less than 1".into());
+        }
+        if self.scan_chunk_size.filter(|s| *s < 1).is_some() {
+            return Err("scan chunk size cannot be less than 1".into());
+        }
+        if self.max_match_length.filter(|l| *l < 1).is_some() {
+            return Err("max match length cannot be less than 1".into());
+        }
+        if self.max_memory.filter(|m| *m < 1).is_some() {
+            return Err("max memory cannot be less than 1".into());
+        }
+        if !self.compression.is_compiled_in() {
+            return Err(format!("{:?} compression was not compiled in", self.compression).into());
+        }
+        if self.match_threshold > MAX_MATCH_THRESHOLD {
+            return Err(format!(
+                "match threshold cannot be over {}, got {}",
+                MAX_MATCH_THRESHOLD, self.match_threshold
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn cancelled(&self) -> Result<(), DiffError> {
+        if is_set(&self.cancel) {
+            return Err(DiffError::Cancelled);
+        }
+        Ok(())
+    }
+
+    fn report(&self, event: ProgressEvent) {
+        if let Some(progress) = self.progress.as_ref() {
+            progress(event);
+        }
+    }
+
+    /// Number of partitions to sort an "older" file of `obuf_len` bytes in,
+    /// see [DiffParams::with_max_memory]
+    fn sort_partitions_for(&self, obuf_len: usize) -> usize {
+        match self.max_memory {
+            Some(max_memory) => obuf_len
+                .saturating_mul(std::mem::size_of::<i32>())
+                .div_ceil(max_memory.max(1))
+                .min(obuf_len.div_ceil(MIN_SORT_PARTITION_SIZE))
+                .max(1),
+            None => self.sort_partitions,
+        }
+    }
+
+    /// Size of the chunks to scan a "newer" file of `nbuf_len` bytes in, or
+    /// `None` to scan it sequentially
+    fn chunk_size_for(&self, nbuf_len: usize) -> Option<usize> {
+        match self.scan_chunk_size {
+            Some(chunk_size) => Some(chunk_size),
+            None if nbuf_len >= self.auto_parallel_threshold => Some(AUTO_SCAN_CHUNK_SIZE),
+            None => None,
+        }
+    }
+}
+
+impl Default for DiffParams {
+    fn default() -> Self {
+        Self {
+            sort_partitions: 1,
+            scan_chunk_size: None,
+            max_sort_bytes: None,
+            max_memory: None,
+            tie_policy: TiePolicy::Extend,
+            match_threshold: DEFAULT_MATCH_THRESHOLD,
+            reserve_hint: None,
+            check_coverage: false,
+            auto_parallel_threshold: DEFAULT_AUTO_PARALLEL_THRESHOLD,
+            target_copies: false,
+            checksum: false,
+            source_checksum: false,
+            progress: None,
+            cancel: None,
+            known_identical_prefix: None,
+            compression: PatchCompression::None,
+            merge_threshold: None,
+            max_match_length: None,
+            chunk_overlap: 0,
+        }
+    }
+}
+
+/// Builds [DiffParams] one option at a time, starting from the defaults,
+/// see [DiffParams::builder]
+///
+/// Each setter does what the matching `DiffParams::with_*` method does (or
+/// sets the matching parameter of [DiffParams::new]), and invalid values are
+/// only reported by [DiffParamsBuilder::build].
+pub struct DiffParamsBuilder {
+    params: DiffParams,
+}
+
+impl DiffParamsBuilder {
+    /// Number of partitions to sort "older" in, see [DiffParams::new]
+    pub fn sort_partitions(mut self, sort_partitions: usize) -> Self {
+        self.params.sort_partitions = sort_partitions;
+        self
+    }
+
+    /// Size of the chunks to scan "newer" in, see [DiffParams::new]
+    pub fn scan_chunk_size(mut self, scan_chunk_size: Option<usize>) -> Self {
+        self.params.scan_chunk_size = scan_chunk_size;
+        self
+    }
+
+    /// See [DiffParams::with_max_sort_bytes]
+    pub fn max_sort_bytes(mut self, max_sort_bytes: Option<usize>) -> Self {
+        self.params.max_sort_bytes = max_sort_bytes;
+        self
+    }
+
+    /// See [DiffParams::with_max_memory]
+    pub fn max_memory(mut self, max_memory: Option<usize>) -> Self {
+        self.params.max_memory = max_memory;
+        self
+    }
+
+    /// See [DiffParams::with_tie_policy]
+    pub fn tie_policy(mut self, tie_policy: TiePolicy) -> Self {
+        self.params.tie_policy = tie_policy;
+        self
+    }
+
+    /// See [DiffParams::with_match_threshold]
+    pub fn match_threshold(mut self, match_threshold: u8) -> Self {
+        self.params.match_threshold = match_threshold;
+        self
+    }
+
+    /// See [DiffParams::with_reserve_hint]
+    pub fn reserve_hint(mut self, reserve_hint: Option<usize>) -> Self {
+        self.params.reserve_hint = reserve_hint;
+        self
+    }
+
+    /// See [DiffParams::with_coverage_check]
+    pub fn coverage_check(mut self, check_coverage: bool) -> Self {
+        self.params.check_coverage = check_coverage;
+        self
+    }
+
+    /// See [DiffParams::with_auto_parallel_threshold]
+    pub fn auto_parallel_threshold(mut self, auto_parallel_threshold: usize) -> Self {
+        self.params.auto_parallel_threshold = auto_parallel_threshold;
+        self
+    }
+
+    /// See [DiffParams::with_target_copies]
+    pub fn target_copies(mut self, target_copies: bool) -> Self {
+        self.params.target_copies = target_copies;
+        self
+    }
+
+    /// See [DiffParams::with_checksum]
+    pub fn checksum(mut self, checksum: bool) -> Self {
+        self.params.checksum = checksum;
+        self
+    }
+
+    /// See [DiffParams::with_source_checksum]
+    pub fn source_checksum(mut self, source_checksum: bool) -> Self {
+        self.params.source_checksum = source_checksum;
+        self
+    }
+
+    /// See [DiffParams::with_compression]
+    pub fn compression(mut self, compression: PatchCompression) -> Self {
+        self.params.compression = compression;
+        self
+    }
+
+    /// See [DiffParams::with_zstd_level]
+    #[cfg(feature = "zstd")]
+    pub fn zstd_level(mut self, zstd_level: Option<i32>) -> Self {
+        self.params = self.params.with_zstd_level(zstd_level);
+        self
+    }
+
+    /// See [DiffParams::with_progress]
+    pub fn progress(mut self, progress: Option<Arc<dyn Fn(ProgressEvent) + Send + Sync>>) -> Self {
+        self.params.progress = progress;
+        self
+    }
+
+    /// See [DiffParams::with_cancel]
+    pub fn cancel(mut self, cancel: Option<Arc<AtomicBool>>) -> Self {
+        self.params.cancel = cancel;
+        self
+    }
+
+    /// See [DiffParams::with_known_identical_prefix]
+    pub fn known_identical_prefix(mut self, known_identical_prefix: Option<usize>) -> Self {
+        self.params.known_identical_prefix = known_identical_prefix;
+        self
+    }
+
+    /// See [DiffParams::with_merge_threshold]
+    pub fn merge_threshold(mut self, merge_threshold: Option<usize>) -> Self {
+        self.params.merge_threshold = merge_threshold;
+        self
+    }
+
+    /// See [DiffParams::with_max_match_length]
+    pub fn max_match_length(mut self, max_match_length: Option<usize>) -> Self {
+        self.params.max_match_length = max_match_length;
+        self
+    }
+
+    /// See [DiffParams::with_chunk_overlap]
+    pub fn chunk_overlap(mut self, chunk_overlap: usize) -> Self {
+        self.params.chunk_overlap = chunk_overlap;
+        self
+    }
+
+    /// Check the options and return the diff params, failing on the first
+    /// invalid one, like [DiffParams::new] and
+    /// [DiffParams::with_match_threshold] do
+    pub fn build(self) -> Result<DiffParams, Box<dyn Error + Send + Sync + 'static>> {
+        self.params.validate()?;
+        Ok(self.params)
+    }
+}
+
+fn is_set(flag: &Option<Arc<AtomicBool>>) -> bool {
+    flag.as_ref()
+        .is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
+
+/// Number of threads worth using for sorting and scanning, for picking
+/// [DiffParams] automatically.
+///
+/// Falls back to 1 when it can't be determined, which happens in some
+/// sandboxes and containers, and without the `parallel` feature.
+pub fn available_parallelism() -> usize {
+    if cfg!(feature = "parallel") {
+        parallelism_or_one(std::thread::available_parallelism())
+    } else {
+        1
+    }
+}
+
+/// Most chunks [scan_chunks] keeps results of at once, per thread of the pool
+#[cfg(feature = "parallel")]
+const CHUNKS_IN_FLIGHT_PER_WORKER: usize = 2;
+
+/// Call `scan` on each of `num_chunks` chunks, and `consume` on its results,
+/// in order. `scan` returns `None` when the diff was cancelled.
+///
+/// With the `parallel` feature, chunks are scanned by jobs of rayon's pool
+/// while the current thread consumes them. A job is spawned for a chunk once
+/// it's close enough to the consumer, so only a few chunk results are held at
+/// once, however many chunks there are. Scan jobs never wait on anything, and
+/// the consumer runs pending jobs while it waits, when it's on a thread of the
+/// pool: this can be called from within a parallel iterator (say, by
+/// [diff_best_of]) without taking up the threads its jobs need.
+#[cfg(feature = "parallel")]
+fn scan_chunks<T, S, C, E>(num_chunks: usize, scan: S, mut consume: C) -> Result<(), E>
+where
+    T: Send,
+    S: Fn(usize) -> Option<T> + Sync,
+    C: FnMut(usize, T) -> Result<(), E>,
+    E: From<DiffError>,
+{
+    use std::{
+        collections::VecDeque,
+        sync::{atomic::AtomicBool, mpsc},
+    };
+
+    let window = rayon::current_num_threads() * CHUNKS_IN_FLIGHT_PER_WORKER;
+    let stopped = AtomicBool::new(false);
+    let (scan, stopped) = (&scan, &stopped);
+
+    rayon::in_place_scope(|s| {
+        /// Lets the jobs that didn't start yet go when the consumer stops,
+        /// however it does
+        struct Stop<'a>(&'a AtomicBool);
+        impl Drop for Stop<'_> {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::Relaxed);
+            }
+        }
+        let _stop = Stop(stopped);
+
+        let mut pending = VecDeque::with_capacity(window);
+        let spawn = |i: usize| {
+            let (tx, rx) = mpsc::channel();
+            // the sender is dropped if `scan` panics, so that the consumer
+            // stops waiting for it (the panic is then resumed by the scope)
+            s.spawn(move |_| {
+                if !stopped.load(Ordering::Relaxed) {
+                    // the consumer may have stopped, in which case nobody's
+                    // listening
+                    let _ = tx.send(scan(i));
+                }
+            });
+            rx
+        };
+        pending.extend((0..num_chunks.min(window)).map(spawn));
+
+        for i in 0..num_chunks {
+            let rx = pending.pop_front().expect("chunks are spawned in order");
+            let res = recv_in_pool(&rx).flatten().ok_or(DiffError::Cancelled)?;
+            if i + window < num_chunks {
+                pending.push_back(spawn(i + window));
+            }
+            consume(i, res)?;
+        }
+        Ok(())
+    })
+}
+
+/// Wait for `rx`, running pending jobs of rayon's pool meanwhile when the
+/// current thread is one of its threads. Its result is `None` when the
+/// sender was dropped.
+#[cfg(feature = "parallel")]
+fn recv_in_pool<T>(rx: &std::sync::mpsc::Receiver<T>) -> Option<T> {
+    use std::{sync::mpsc::TryRecvError, time::Duration};
+
+    loop {
+        match rx.try_recv() {
+            Ok(res) => return Some(res),
+            Err(TryRecvError::Disconnected) => return None,
+            Err(TryRecvError::Empty) => {}
+        }
+        match rayon::yield_now() {
+            Some(rayon::Yield::Executed) => {}
+            // the job is running on another thread, but more jobs may be
+            // spawned meanwhile, so check again every now and then
+            Some(rayon::Yield::Idle) => {
+                if let Ok(res) = rx.recv_timeout(Duration::from_millis(1)) {
+                    return Some(res);
+                }
+            }
+            // the pool's threads run the jobs, whatever this one does
+            None => return rx.recv().ok(),
+        }
+    }
+}
+
+/// Without the `parallel` feature, each chunk is scanned and consumed before
+/// the next one is scanned
+#[cfg(not(feature = "parallel"))]
+fn scan_chunks<T, S, C, E>(num_chunks: usize, scan: S, mut consume: C) -> Result<(), E>
+where
+    T: Send,
+    S: Fn(usize) -> Option<T> + Sync,
+    C: FnMut(usize, T) -> Result<(), E>,
+    E: From<DiffError>,
+{
+    for i in 0..num_chunks {
+        let res = scan(i).ok_or(DiffError::Cancelled)?;
+        consume(i, res)?;
+    }
+    Ok(())
+}
+
+/// The `chunk_size` chunks of `buf`, processed in parallel with the
+/// `parallel` feature, and in order without it
+#[cfg(all(feature = "enc", feature = "parallel"))]
+fn chunks(buf: &[u8], chunk_size: usize) -> rayon::slice::Chunks<'_, u8> {
+    buf.par_chunks(chunk_size)
+}
+
+#[cfg(all(feature = "enc", not(feature = "parallel")))]
+fn chunks(buf: &[u8], chunk_size: usize) -> std::slice::Chunks<'_, u8> {
+    buf.chunks(chunk_size)
+}
+
+fn parallelism_or_one(res: io::Result<std::num::NonZeroUsize>) -> usize {
+    match res {
+        Ok(n) => n.get(),
+        Err(e) => {
+            warn!("could not determine available parallelism, using 1: {}", e);
+            1
+        }
+    }
+}
+
+/// Nanoseconds per `n * log2(n)` of suffix sorting an "older" file of `n`
+/// bytes, on a single thread. Ballpark figure from sorting a few hundred MiB
+/// of sources on a laptop.
+const SORT_NANOS_PER_BYTE_LOG: f64 = 1.2;
+/// Nanoseconds per byte of scanning "newer", on a single thread
+const SCAN_NANOS_PER_BYTE: f64 = 15.0;
+/// Nanoseconds per byte of taking the common prefix and suffix, when sorting
+/// is skipped
+const TRIM_NANOS_PER_BYTE: f64 = 1.0;
+
+/// Rough estimate of the time [diff] takes on files of these sizes, for
+/// scheduling.
+///
+/// This uses a simple model (sorting in `O(n log n)`, scanning in `O(m)`,
+/// both sped up by parallelism when `params` allows it) with constants
+/// measured on a laptop, so it's only good to an order of magnitude.
+pub fn estimate_duration(obuf_len: usize, nbuf_len: usize, params: &DiffParams) -> Duration {
+    let (n, m) = (obuf_len as f64, nbuf_len as f64);
+    let threads = available_parallelism();
+
+    let nanos = if params
+        .max_sort_bytes
+        .filter(|&max| obuf_len > max)
+        .is_some()
+    {
+        (n + m) * TRIM_NANOS_PER_BYTE
+    } else if obuf_len < SMALL_INPUT_THRESHOLD {
+        n * m
+    } else {
+        let sort_threads = params.sort_partitions_for(obuf_len).min(threads) as f64;
+        let scan_threads = match params.chunk_size_for(nbuf_len) {
+            Some(chunk_size) => nbuf_len.div_ceil(chunk_size).clamp(1, threads),
+            None => 1,
+        } as f64;
+        n * n.log2() * SORT_NANOS_PER_BYTE_LOG / sort_threads
+            + m * SCAN_NANOS_PER_BYTE / scan_threads
+    };
+    Duration::from_nanos(nanos.max(1.0) as u64)
+}
+
+/// Statistics about a diff, as returned by [diff]
+#[derive(Debug, Clone, Default)]
+pub struct DiffStats {
+    /// Whether the patch is trivial: all of `nbuf` is taken from `obuf` in
+    /// order, without any literal data or seeks, and both are identical.
+    pub is_identity: bool,
+    /// Whether suffix sorting was skipped because `obuf` was larger than
+    /// [DiffParams::with_max_sort_bytes]
+    pub sort_skipped: bool,
+    /// Number of matches emitted
+    pub matches: usize,
+    /// Bytes of "newer" taken from "older" (stored as differences)
+    pub add_bytes: u64,
+    /// Bytes of "newer" stored as-is, which is what compresses the least
+    pub copy_bytes: u64,
+    /// Sum of the distances the position in "older" jumps by between
+    /// matches, including to the first one
+    pub seek_distance: u64,
+    /// Matches that take bytes of "older" up to, or across, the boundary
+    /// between two sort partitions, see [DiffParams::new]
+    ///
+    /// Each partition is searched separately, and a match found in one is
+    /// only extended into the next when that search happened to find the
+    /// occurence that runs into its end. These are the matches that
+    /// partitioning may have cut short, so this estimates how much patch
+    /// quality more partitions cost. Always 0 with a single partition, and
+    /// with an index given to [Differ::with_index].
+    pub partition_boundary_matches: usize,
+    /// Time spent in each phase of the diff
+    pub timings: DiffTimings,
+}
+
+/// Measures how long things take, with [std::time::Instant], except on
+/// `wasm32-unknown-unknown`, which has no clock: everything takes no time
+/// there
+#[derive(Clone, Copy)]
+struct Stopwatch {
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    start: std::time::Instant,
+}
+
+impl Stopwatch {
+    fn start() -> Self {
+        Self {
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            start: std::time::Instant::now(),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        return self.start.elapsed();
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        return Duration::ZERO;
+    }
+}
+
+/// Time spent sorting and scanning, as part of [DiffStats]
+///
+/// These are the durations the `info!` logs and the [telemetry] metrics
+/// report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiffTimings {
+    /// Building the index of "older". Zero when it was given by the caller
+    /// or reused, see [Differ::with_index] and [DiffContext], and when
+    /// sorting was skipped.
+    pub sort: Duration,
+    /// Scanning "newer" for matches
+    pub scan: Duration,
+}
+
+/// Accumulates [DiffStats] as matches are emitted
+struct StatsRecorder {
+    next_old: usize,
+    trivial: bool,
+    /// Size of the sort partitions of "older", when there's more than one
+    partition_size: Option<usize>,
+    stats: DiffStats,
+}
+
+impl StatsRecorder {
+    fn new() -> Self {
+        Self {
+            next_old: 0,
+            trivial: true,
+            partition_size: None,
+            stats: Default::default(),
+        }
+    }
+
+    /// Count the matches that reach the boundaries of `partition_size`
+    /// partitions, see [DiffStats::partition_boundary_matches]
+    fn with_partition_size(mut self, partition_size: Option<usize>) -> Self {
+        self.partition_size = partition_size;
+        self
+    }
+
+    fn record(&mut self, m: &Match) {
+        self.trivial &= m.add_old_start == self.next_old && m.is_pure_add();
+        if let Some(size) = self.partition_size {
+            // the next boundary after the start of the add region
+            let boundary = (m.add_old_start / size + 1) * size;
+            if m.add_length > 0 && m.add_old_start + m.add_length >= boundary {
+                self.stats.partition_boundary_matches += 1;
+            }
+        }
+        self.stats.matches += 1;
+        self.stats.add_bytes += m.add_length as u64;
+        // matches from the scan never copy backwards
+        self.stats.copy_bytes += m.copy_length().unwrap_or(0) as u64;
+        self.stats.seek_distance += m.add_old_start.abs_diff(self.next_old) as u64;
+        self.next_old = m.add_old_start + m.add_length;
+    }
+
+    fn finish(self, obuf: &[u8], nbuf: &[u8]) -> DiffStats {
+        self.finish_with(nbuf.len(), || obuf == nbuf)
+    }
+
+    /// `identical` is only called when the matches are trivial
+    fn finish_with(self, new_len: usize, identical: impl FnOnce() -> bool) -> DiffStats {
+        DiffStats {
+            is_identity: self.trivial && self.next_old == new_len && identical(),
+            ..self.stats
+        }
+    }
+}
+
+/// Errors raised while diffing, as opposed to those returned by callbacks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffError {
+    /// This part of "newer" isn't covered by any match
+    Gap(Range<usize>),
+    /// This part of "newer" is covered by more than one match
+    Overlap(Range<usize>),
+    /// Matches go past the end of "newer", which ends at the start of this
+    /// range
+    OutOfBounds(Range<usize>),
+    /// [DiffParams::with_cancel]'s flag was set
+    Cancelled,
+    /// [diff_best_of] was given no "older" file to diff against
+    NoOlder,
+    /// This range of "newer", given to [Translator::copy_target], wasn't
+    /// reconstructed yet, or copying it would run past the end of "newer"
+    TargetCopy(Range<usize>),
+}
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiffError::Gap(r) => write!(f, "newer range {:?} is not covered by any match", r),
+            DiffError::Overlap(r) => write!(f, "newer range {:?} is covered by several matches", r),
+            DiffError::OutOfBounds(r) => write!(f, "matches cover {:?}, past the end of newer", r),
+            DiffError::Cancelled => write!(f, "diff was cancelled"),
+            DiffError::NoOlder => write!(f, "no older file to diff against"),
+            DiffError::TargetCopy(r) => write!(f, "can't copy newer range {:?} yet", r),
+        }
+    }
+}
+
+impl Error for DiffError {}
+
+impl From<DiffError> for io::Error {
+    fn from(e: DiffError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Checks that matches exactly tile the "newer" file, each one starting where
+/// the previous one ended
+///
+/// [diff] does this itself when [DiffParams::with_coverage_check] is set, this
+/// is for checking custom sources of matches.
+#[derive(Debug, Default)]
+pub struct CoverageCheck {
+    next_new: usize,
+}
+
+impl CoverageCheck {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn record(&mut self, m: &Match) -> Result<(), DiffError> {
+        let start = m.add_new_start;
+        if start > self.next_new {
+            return Err(DiffError::Gap(self.next_new..start));
+        }
+        if start < self.next_new {
+            return Err(DiffError::Overlap(start..min(self.next_new, m.copy_end)));
+        }
+        self.next_new = m.copy_end.max(start);
+        Ok(())
+    }
+
+    /// Check that all of a "newer" file of `new_len` bytes was covered
+    pub fn finish(self, new_len: usize) -> Result<(), DiffError> {
+        match self.next_new.cmp(&new_len) {
+            std::cmp::Ordering::Less => Err(DiffError::Gap(self.next_new..new_len)),
+            std::cmp::Ordering::Greater => Err(DiffError::OutOfBounds(new_len..self.next_new)),
+            std::cmp::Ordering::Equal => Ok(()),
+        }
+    }
+}
+
+/// Diff two files
+///
+/// `obuf` and `nbuf` must not change until this returns. The borrow checker
+/// guarantees that for regular memory, but not for memory that's shared with
+/// other processes, like a memory-mapped file someone else may write to. In
+/// that case, copy the files into memory and use [diff_owned].
+///
+/// Fails with a [DiffError], converted into `on_match`'s error type, when
+/// [DiffParams::with_coverage_check] finds matches that don't tile "newer",
+/// or with [DiffError::Cancelled] once [DiffParams::with_cancel]'s flag is
+/// set. Errors of `on_match` are returned as-is.
+pub fn diff<F, E>(
+    obuf: &[u8],
+    nbuf: &[u8],
+    params: &DiffParams,
+    on_match: F,
+) -> Result<DiffStats, E>
+where
+    F: FnMut(Match) -> Result<(), E>,
+    E: From<DiffError>,
+{
+    Differ::prepare_for(obuf, nbuf, params).scan(nbuf, on_match)
+}
+
+/// Whether diffing `obuf` and `nbuf` doesn't need any lookup: "newer" is
+/// either empty (which takes no matches) or identical to "older" (which takes
+/// a single match adding all of it), or "older" is empty (which takes a
+/// single match copying all of "newer")
+fn is_trivial(obuf: &[u8], nbuf: &[u8]) -> bool {
+    obuf.is_empty() || nbuf.is_empty() || obuf == nbuf
+}
+
+/// Diff two files like [diff], using `sa`, an index of `obuf` built by the
+/// caller, instead of building a suffix array, see [Differ::with_index]
+pub fn diff_with_index<'a, F, E>(
+    obuf: &'a [u8],
+    nbuf: &[u8],
+    sa: &'a (dyn StringIndex<'a> + Sync + 'a),
+    params: &'a DiffParams,
+    on_match: F,
+) -> Result<DiffStats, E>
+where
+    F: FnMut(Match) -> Result<(), E>,
+    E: From<DiffError>,
+{
+    Differ::with_index(obuf, sa, params).scan(nbuf, on_match)
+}
+
+/// Diff two files, passing the resulting controls to `on_control` instead of
+/// matches
+///
+/// This wires a [Translator] to [diff], and hands `on_control` the same
+/// controls the Translator builds, ready to be written with
+/// [enc::Writer::write] or inspected. The last, pending control is flushed
+/// once diffing succeeds.
+pub fn diff_controls<F, E>(
+    obuf: &[u8],
+    nbuf: &[u8],
+    params: &DiffParams,
+    on_control: F,
+) -> Result<DiffStats, E>
+where
+    F: FnMut(&Control) -> Result<(), E>,
+    E: Error + From<DiffError>,
+{
+    let mut translator = Translator::new(obuf, nbuf, on_control);
+    let res = diff(obuf, nbuf, params, |m| translator.translate(m));
+    translator.close_after(res)
+}
+
+/// Diff two files held in owned buffers, passing the resulting controls to
+/// `on_control`
+///
+/// Taking ownership guarantees nothing else can modify the files while
+/// they're being diffed, see [diff].
+pub fn diff_owned<F, E>(
+    old: Vec<u8>,
+    new: Vec<u8>,
+    params: &DiffParams,
+    on_control: F,
+) -> Result<DiffStats, E>
+where
+    F: FnMut(&Control) -> Result<(), E>,
+    E: Error + From<DiffError>,
+{
+    diff_controls(&old[..], &new[..], params, on_control)
+}
+
+/// Size of the chunks [diff_streaming] reads "newer" in, when no
+/// `scan_chunk_size` is given
+pub const DEFAULT_STREAMING_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Diff `obuf` against a "newer" file read from `nbuf`, one chunk at a time
+///
+/// Chunks are `scan_chunk_size` bytes (or [DEFAULT_STREAMING_CHUNK_SIZE]),
+/// and each is scanned as it's read. Matches are emitted with offsets
+/// relative to the start of the whole stream, like with [diff].
+///
+/// Only `obuf`, its suffix array and a single chunk of "newer" are in memory
+/// at a time, where [diff] needs all of "newer". In exchange, chunks are
+/// scanned one after the other rather than in parallel, and matches never
+/// span chunks, which makes patches slightly worse, as with a
+/// `scan_chunk_size` on the in-memory path. Encoding the matches into a
+/// patch still needs the "newer" bytes they refer to, which is up to
+/// `on_match`.
+pub fn diff_streaming<R, F, E>(
+    obuf: &[u8],
+    mut nbuf: R,
+    params: &DiffParams,
+    mut on_match: F,
+) -> Result<DiffStats, E>
+where
+    R: Read,
+    F: FnMut(Match) -> Result<(), E>,
+    E: From<DiffError> + From<io::Error>,
+{
+    let differ = Differ::prepare(obuf, params);
+    let chunk_size = params
+        .scan_chunk_size
+        .unwrap_or(DEFAULT_STREAMING_CHUNK_SIZE);
+
+    let mut stats = StatsRecorder::new();
+    let mut coverage = params.check_coverage.then(CoverageCheck::new);
+    let mut identical = true;
+
+    let before_scan = Stopwatch::start();
+    let mut chunk = Vec::new();
+    let mut offset = 0;
+    loop {
+        chunk.clear();
+        (&mut nbuf)
+            .take(chunk_size as u64)
+            .read_to_end(&mut chunk)?;
+        if chunk.is_empty() {
+            break;
+        }
+
+        for mut m in differ.chunk_matches(&chunk) {
+            params.cancelled()?;
+            m.add_new_start += offset;
+            m.copy_end += offset;
+            stats.record(&m);
+            if let Some(coverage) = coverage.as_mut() {
+                coverage.record(&m)?;
+            }
+            on_match(m)?;
+        }
+        params.cancelled()?;
+        identical &= obuf.get(offset..offset + chunk.len()) == Some(&chunk[..]);
+        offset += chunk.len();
+    }
+
+    let scan_duration = before_scan.elapsed();
+    info_took!(offset as u64, scan_duration, "streaming scan");
+    telemetry::record_scan(offset, scan_duration);
+
+    if let Some(coverage) = coverage {
+        coverage.finish(offset)?;
+    }
+
+    Ok(DiffStats {
+        sort_skipped: matches!(differ.index, Index::Skipped),
+        timings: DiffTimings {
+            sort: differ.sort_duration,
+            scan: scan_duration,
+        },
+        ..stats.finish_with(offset, || identical && offset == obuf.len())
+    })
+}
+
+/// Number of matches [diff_channel] buffers before the scan waits for the
+/// receiver to catch up
+const MATCH_CHANNEL_CAPACITY: usize = 1024;
+
+/// Diff two files on a separate thread, sending matches over a bounded
+/// channel as they're found
+///
+/// The scan waits whenever the receiver falls behind, and stops as soon as
+/// the receiver is dropped. An error, if any, is the last thing sent: if the
+/// diff panics, that's an [io::ErrorKind::Other] error, so that a channel
+/// that just ends means all the matches were sent.
+pub fn diff_channel<O, N>(
+    obuf: O,
+    nbuf: N,
+    params: DiffParams,
+) -> std::sync::mpsc::Receiver<Result<Match, io::Error>>
+where
+    O: AsRef<[u8]> + Send + 'static,
+    N: AsRef<[u8]> + Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::sync_channel(MATCH_CHANNEL_CAPACITY);
+    std::thread::spawn(move || {
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            diff(obuf.as_ref(), nbuf.as_ref(), &params, |m| {
+                tx.send(Ok(m))
+                    .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver dropped"))
+            })
+        }))
+        .unwrap_or_else(|payload| Err(panic_error(payload)));
+        if let Err(e) = res {
+            // fails if the receiver was dropped, which is fine
+            let _ = tx.send(Err(e));
+        }
+    });
+    rx
+}
+
+/// An error carrying the message of a panic caught on a diffing thread
+fn panic_error(payload: Box<dyn std::any::Any + Send>) -> io::Error {
+    let msg = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown panic");
+    io::Error::other(format!("diff thread panicked: {}", msg))
+}
+
+/// Iterate over the matches of `nbuf` in `obuf`, in order, for callers that
+/// encode them their own way rather than through a [Translator]
+///
+/// The suffix array of `obuf` is built before this returns (honoring
+/// `sort_partitions`, `max_memory` and `max_sort_bytes`), and the iterator
+/// owns it, only
+/// borrowing from it as it goes. Scanning is sequential, `scan_chunk_size`
+/// and the auto-parallel threshold are ignored. When cancelled (see
+/// [DiffParams::with_cancel]), the iterator stops early.
+pub fn matches<'a>(
+    obuf: &'a [u8],
+    nbuf: &'a [u8],
+    params: &DiffParams,
+) -> impl Iterator<Item = Match> + 'a {
+    let index = if is_trivial(obuf, nbuf) {
+        Index::Skipped
+    } else {
+        Differ::index(obuf, params)
+    };
+    params.report(ProgressEvent::SuffixArrayBuilt);
+
+    let it: Box<dyn Iterator<Item = Match> + 'a> = match index {
+        Index::Sorted(sa) => {
+            #[cfg(all(feature = "verify-sa", debug_assertions))]
+            let partition_size = obuf.len() / sa.num_partitions() + 1;
+            let sa: Box<dyn StringIndex<'a> + 'a> = Box::new(sa);
+            let it = BsdiffIterator::with_index(obuf, nbuf, sa, params);
+            #[cfg(all(feature = "verify-sa", debug_assertions))]
+            let it = it.verifying(partition_size);
+            Box::new(it)
+        }
+        Index::Naive(sa) => {
+            let sa: Box<dyn StringIndex<'a> + 'a> = Box::new(sa);
+            Box::new(BsdiffIterator::with_index(obuf, nbuf, sa, params))
+        }
+        Index::Custom(sa) => Box::new(BsdiffIterator::new(obuf, nbuf, sa, params)),
+        Index::Skipped => Box::new(trimmed_matches(obuf, nbuf).into_iter()),
+    };
+    it
+}
+
+/// A diff that went through the sorting phase, and is ready to scan one or
+/// more "newer" files
+///
+/// This lets callers look at the memory used by the suffix array before
+/// deciding whether to go on with scanning.
+pub struct Differ<'a> {
+    obuf: &'a [u8],
+    params: &'a DiffParams,
+    index: Index<'a>,
+    /// Time spent building `index`
+    sort_duration: Duration,
+}
+
+/// Inputs smaller than this are searched by brute force rather than with a
+/// suffix array, which is faster for them and finds the best matches.
+const SMALL_INPUT_THRESHOLD: usize = 256;
+
+enum Index<'a> {
+    Sorted(PartitionedSuffixArray<'a, i32>),
+    Naive(NaiveIndex<'a>),
+    /// Given by the caller, see [Differ::with_index]
+    Custom(&'a (dyn StringIndex<'a> + Sync + 'a)),
+    /// Sorting was skipped, see `DiffParams::with_max_sort_bytes`
+    Skipped,
 }
 
-impl Iterator for Fragments {
-    type Item = (Hash, u64, u32); //Hash & offset & size
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.pos < self.data.len() {
-            let block = &self.data[self.pos];
-            self.pos += 1;
-            Some((block.hash, block.offset, block.size))
-        } else {
-            None
+impl Index<'_> {
+    /// Size of the partitions `obuf` was sorted in, when there's more than
+    /// one. This is how [PartitionedSuffixArray] splits it.
+    fn partition_size(&self, obuf: &[u8]) -> Option<usize> {
+        match self {
+            Index::Sorted(sa) if sa.num_partitions() > 1 => {
+                Some(obuf.len() / sa.num_partitions() + 1)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// With `verify-sa`, the match found at one scan position of "newer" in
+/// this many is checked against [NaiveIndex]
+#[cfg(all(feature = "verify-sa", debug_assertions))]
+const VERIFY_SA_INTERVAL: usize = 1024;
+
+/// Panic unless `obuf[start..start + len]` is a match of `needle`, at least
+/// as long as any within one partition of `partition_size` bytes: matches
+/// that span partitions may be missed, but none within one. Brute force is
+/// slow, but it doesn't share any code with the suffix arrays it checks.
+#[cfg(all(feature = "verify-sa", debug_assertions))]
+fn verify_match(obuf: &[u8], partition_size: usize, needle: &[u8], start: usize, len: usize) {
+    let found = start
+        .checked_add(len)
+        .and_then(|end| obuf.get(start..end))
+        .filter(|found| needle.starts_with(found));
+    assert!(
+        found.is_some(),
+        "index found {} bytes at {}, which don't match",
+        len,
+        start
+    );
+    for (i, partition) in obuf.chunks(partition_size).enumerate() {
+        let naive = NaiveIndex { text: partition }.longest_substring_match(needle);
+        assert!(
+            len >= naive.len,
+            "index found {} bytes at {}, but {} bytes match at {}",
+            len,
+            start,
+            naive.len,
+            i * partition_size + naive.start
+        );
+    }
+}
+
+/// Finds the longest match by comparing `needle` against every position of
+/// the text
+struct NaiveIndex<'a> {
+    text: &'a [u8],
+}
+
+impl<'a> StringIndex<'a> for NaiveIndex<'a> {
+    fn longest_substring_match(&self, needle: &[u8]) -> LongestCommonSubstring<'a> {
+        let (start, len) = (0..self.text.len())
+            .map(|start| (start, common_prefix_len(&self.text[start..], needle)))
+            .fold((0, 0), |best, m| if m.1 > best.1 { m } else { best });
+        LongestCommonSubstring {
+            text: self.text,
+            start,
+            len,
+        }
+    }
+}
+
+impl<'a> Differ<'a> {
+    /// Build the suffix array of `obuf`
+    pub fn prepare(obuf: &'a [u8], params: &'a DiffParams) -> Self {
+        let before_suffix = Stopwatch::start();
+        let index = Self::index(obuf, params);
+        let sort_duration = before_suffix.elapsed();
+        if let Index::Sorted(_) = index {
+            info_took!(obuf.len() as u64, sort_duration, "sorting");
+        }
+        telemetry::record_sort(obuf.len(), sort_duration);
+        params.report(ProgressEvent::SuffixArrayBuilt);
+
+        Self {
+            obuf,
+            params,
+            index,
+            sort_duration,
+        }
+    }
+
+    /// Build the suffix array of `obuf`, unless there's nothing to look up in
+    /// it to diff `nbuf`, see [is_trivial]. This is the front end of [diff].
+    fn prepare_for(obuf: &'a [u8], nbuf: &[u8], params: &'a DiffParams) -> Self {
+        if !is_trivial(obuf, nbuf) {
+            return Self::prepare(obuf, params);
+        }
+        params.report(ProgressEvent::SuffixArrayBuilt);
+        Self {
+            obuf,
+            params,
+            index: Index::Skipped,
+            sort_duration: Duration::ZERO,
+        }
+    }
+
+    /// Use `sa`, an index of `obuf` built by the caller, instead of building
+    /// a suffix array. This lets one index be reused across diffs, even
+    /// across [Differ]s. `max_sort_bytes` is ignored.
+    pub fn with_index(
+        obuf: &'a [u8],
+        sa: &'a (dyn StringIndex<'a> + Sync + 'a),
+        params: &'a DiffParams,
+    ) -> Self {
+        params.report(ProgressEvent::SuffixArrayBuilt);
+        Self {
+            obuf,
+            params,
+            index: Index::Custom(sa),
+            sort_duration: Duration::ZERO,
+        }
+    }
+
+    fn index(obuf: &'a [u8], params: &DiffParams) -> Index<'a> {
+        if let Some(max_sort_bytes) = params.max_sort_bytes.filter(|&max| obuf.len() > max) {
+            warn!(
+                "older is {}, over the {} sorting budget, only trimming common prefix and suffix",
+                Size(obuf.len() as u64),
+                Size(max_sort_bytes as u64)
+            );
+            return Index::Skipped;
+        }
+
+        if obuf.len() < SMALL_INPUT_THRESHOLD {
+            return Index::Naive(NaiveIndex { text: obuf });
+        }
+
+        info!("building suffix array...");
+        let partitions = params.sort_partitions_for(obuf.len());
+        if let Some(max_memory) = params.max_memory {
+            if partitions != params.sort_partitions {
+                warn!(
+                    "max memory of {} overrides {} sort partitions",
+                    Size(max_memory as u64),
+                    params.sort_partitions
+                );
+            }
+            info!(
+                "sorting in {} partitions to sort each in about {}",
+                partitions,
+                Size(max_memory as u64)
+            );
+        }
+        Index::Sorted(PartitionedSuffixArray::new(
+            obuf,
+            partitions,
+            divsufsort::sort,
+        ))
+    }
+
+    /// Number of bytes used by the suffix array, not counting `obuf` itself
+    pub fn sa_memory_bytes(&self) -> usize {
+        match self.index {
+            Index::Sorted(_) => self.obuf.len() * std::mem::size_of::<i32>(),
+            Index::Naive(_) | Index::Custom(_) | Index::Skipped => 0,
+        }
+    }
+
+    /// Matches for a single chunk of "newer", with offsets relative to it
+    fn chunk_matches<'b>(&'b self, nbuf: &'b [u8]) -> Box<dyn Iterator<Item = Match> + 'b> {
+        self.chunk_matches_from(nbuf, 0)
+    }
+
+    /// [Differ::chunk_matches], for a chunk whose first match is extended
+    /// from `old_pos` in "older", see [BsdiffIterator::resuming_at]
+    fn chunk_matches_from<'b>(
+        &'b self,
+        nbuf: &'b [u8],
+        old_pos: usize,
+    ) -> Box<dyn Iterator<Item = Match> + 'b> {
+        let matches: Box<dyn Iterator<Item = Match> + 'b> = match &self.index {
+            _ if is_trivial(self.obuf, nbuf) => {
+                Box::new(trimmed_matches(self.obuf, nbuf).into_iter())
+            }
+            Index::Sorted(sa) => {
+                let it = BsdiffIterator::new(self.obuf, nbuf, sa, self.params).resuming_at(old_pos);
+                #[cfg(all(feature = "verify-sa", debug_assertions))]
+                let it = it.verifying(self.obuf.len() / sa.num_partitions() + 1);
+                Box::new(it)
+            }
+            Index::Naive(sa) => {
+                Box::new(BsdiffIterator::new(self.obuf, nbuf, sa, self.params).resuming_at(old_pos))
+            }
+            Index::Custom(sa) => {
+                let sa: &dyn StringIndex<'a> = *sa;
+                Box::new(
+                    BsdiffIterator::with_index(self.obuf, nbuf, sa, self.params)
+                        .resuming_at(old_pos),
+                )
+            }
+            Index::Skipped => Box::new(trimmed_matches(self.obuf, nbuf).into_iter()),
+        };
+        // matches that cover nothing only take a control: what follows them
+        // seeks from the end of the previous add region either way
+        let matches = matches.filter(|m| matches!(m.total_new_bytes(), Ok(n) if n > 0));
+        let matches: Box<dyn Iterator<Item = Match> + 'b> = match self.params.merge_threshold {
+            Some(threshold) => Box::new(MergedMatches::new(matches, threshold)),
+            None => Box::new(matches),
+        };
+        match self.params.max_match_length {
+            Some(max) => Box::new(matches.flat_map(move |m| split_match(m, max))),
+            None => matches,
+        }
+    }
+
+    /// Scan `nbuf` for matches, see [diff], whose errors this shares
+    pub fn scan<F, E>(&self, nbuf: &[u8], on_match: F) -> Result<DiffStats, E>
+    where
+        F: FnMut(Match) -> Result<(), E>,
+        E: From<DiffError>,
+    {
+        self.scan_with(nbuf, |_, _| (), on_match, |()| Ok(()))
+    }
+
+    /// Size of the chunks `nbuf` is scanned in parallel in, if it is
+    fn chunk_size(&self, nbuf_len: usize) -> Option<usize> {
+        let chunk_size = self.params.chunk_size_for(nbuf_len);
+        chunk_size.filter(|_| !matches!(self.index, Index::Skipped))
+    }
+
+    /// Scan `nbuf` for matches, like [Differ::scan]. When it's scanned in
+    /// chunks, `work` is also called on the worker that scanned each chunk,
+    /// with the chunk and its matches (relative to it), and `on_chunk` with
+    /// its result, in order, after the matches of the chunk.
+    fn scan_with<T, W, F, C, E>(
+        &self,
+        nbuf: &[u8],
+        work: W,
+        mut on_match: F,
+        mut on_chunk: C,
+    ) -> Result<DiffStats, E>
+    where
+        T: Send,
+        W: Fn(&[u8], &[Match]) -> T + Sync,
+        F: FnMut(Match) -> Result<(), E>,
+        C: FnMut(T) -> Result<(), E>,
+        E: From<DiffError>,
+    {
+        let (obuf, params) = (self.obuf, self.params);
+
+        let mut stats = StatsRecorder::new().with_partition_size(self.index.partition_size(obuf));
+        let mut coverage = params.check_coverage.then(CoverageCheck::new);
+        let mut on_match = |m: Match| {
+            stats.record(&m);
+            if let Some(coverage) = coverage.as_mut() {
+                coverage.record(&m)?;
+            }
+            on_match(m)
+        };
+
+        let before_scan = Stopwatch::start();
+        let trivial = is_trivial(obuf, nbuf);
+        let sort_skipped = matches!(self.index, Index::Skipped) && !trivial;
+
+        // the known identical prefix is taken as-is, and only the rest of
+        // "newer" is scanned, resuming from the end of the prefix in "older"
+        let prefix = if trivial || sort_skipped {
+            0
+        } else {
+            params.identical_prefix(obuf, nbuf)
+        };
+        let prefix_match = Match {
+            add_old_start: 0,
+            add_new_start: 0,
+            add_length: prefix,
+            copy_end: prefix,
+        };
+        let rest = &nbuf[prefix..];
+
+        match self.chunk_size(rest.len()).filter(|_| !trivial) {
+            Some(chunk_size) => {
+                // the prefix gets a chunk of its own, so that `on_chunk`
+                // sees it too
+                if prefix > 0 {
+                    let prefix_matches = params.split_match(prefix_match).collect::<Vec<_>>();
+                    let res = work(&nbuf[..prefix], &prefix_matches);
+                    for m in prefix_matches {
+                        on_match(m)?;
+                    }
+                    on_chunk(res)?;
+                }
+
+                // every chunk is scanned against the whole index, so this
+                // is unrelated to the number of sort partitions
+                let num_chunks = rest.len().div_ceil(chunk_size);
+
+                info!(
+                    "scanning with {}B chunks... ({} chunks total)",
+                    chunk_size, num_chunks
+                );
+
+                // chunks after the first one also scan the overlap before
+                // them, in `rest`
+                let overlap = params.chunk_overlap;
+                let scanned_from = |i: usize| match i {
+                    0 => 0,
+                    _ => (i * chunk_size).saturating_sub(overlap),
+                };
+
+                let scan_chunk = |i: usize| {
+                    let nbuf = &rest[scanned_from(i)..min((i + 1) * chunk_size, rest.len())];
+                    let old_pos = if i == 0 { prefix } else { 0 };
+                    let matches: Vec<Match> = self.chunk_matches_from(nbuf, old_pos).collect();
+                    // a cancelled chunk's matches are incomplete
+                    params.cancelled().ok()?;
+                    // overlapping chunks are only final once reconciled with
+                    // the next one
+                    let res = (overlap == 0).then(|| work(nbuf, &matches));
+                    Some((matches, res))
+                };
+                // `matches` are absolute, and cover `nbuf` from `start`
+                let mut emit = |start: usize, matches: Vec<Match>, res: Option<T>| {
+                    let end = matches.last().map_or(start, |m| m.copy_end);
+                    let res = match res {
+                        Some(res) => res,
+                        None => {
+                            let relative: Vec<Match> = matches
+                                .iter()
+                                .map(|m| Match {
+                                    add_new_start: m.add_new_start - start,
+                                    copy_end: m.copy_end - start,
+                                    ..*m
+                                })
+                                .collect();
+                            work(&nbuf[start..end], &relative)
+                        }
+                    };
+                    for m in matches {
+                        on_match(m)?;
+                    }
+                    on_chunk(res)?;
+                    // chunks are consumed in order, so this only grows
+                    params.report(ProgressEvent::Scanned {
+                        scanned: end,
+                        total: nbuf.len(),
+                    });
+                    Ok::<_, E>(())
+                };
+                // with an overlap, the previous chunk, held back until the
+                // next one tells where it ends
+                let mut held: Option<(usize, Vec<Match>)> = None;
+                let consume_chunk = |i: usize, (mut v, res): (Vec<Match>, Option<T>)| {
+                    let offset = prefix + scanned_from(i);
+                    for m in &mut v {
+                        m.add_new_start += offset;
+                        m.copy_end += offset;
+                    }
+                    if overlap == 0 {
+                        return emit(offset, v, res);
+                    }
+                    match held.take() {
+                        Some((start, mut previous)) => {
+                            let cut = overlap_cut(&previous, &v, start, prefix + i * chunk_size);
+                            truncate_matches_end(&mut previous, cut);
+                            truncate_matches_start(&mut v, cut);
+                            held = Some((cut, v));
+                            emit(start, previous, None)
+                        }
+                        None => {
+                            held = Some((offset, v));
+                            Ok(())
+                        }
+                    }
+                };
+                scan_chunks(num_chunks, scan_chunk, consume_chunk)?;
+                if let Some((start, matches)) = held {
+                    emit(start, matches, None)?;
+                }
+            }
+            None => {
+                let mut reported = 0;
+                if prefix > 0 {
+                    for m in params.split_match(prefix_match) {
+                        on_match(m)?;
+                    }
+                }
+                for mut m in self.chunk_matches_from(rest, prefix) {
+                    params.cancelled()?;
+                    m.add_new_start += prefix;
+                    m.copy_end += prefix;
+                    let scanned = m.copy_end;
+                    on_match(m)?;
+                    if scanned >= reported + PROGRESS_INTERVAL {
+                        reported = scanned;
+                        params.report(ProgressEvent::Scanned {
+                            scanned,
+                            total: nbuf.len(),
+                        });
+                    }
+                }
+                params.cancelled()?;
+                if reported < nbuf.len() {
+                    params.report(ProgressEvent::Scanned {
+                        scanned: nbuf.len(),
+                        total: nbuf.len(),
+                    });
+                }
+            }
+        }
+
+        let scan_duration = before_scan.elapsed();
+        info_took!(nbuf.len() as u64, scan_duration, "scanning");
+        telemetry::record_scan(nbuf.len(), scan_duration);
+
+        if let Some(coverage) = coverage {
+            coverage.finish(nbuf.len())?;
+        }
+
+        Ok(DiffStats {
+            sort_skipped,
+            timings: DiffTimings {
+                sort: self.sort_duration,
+                scan: scan_duration,
+            },
+            ..stats.finish(obuf, nbuf)
+        })
+    }
+}
+
+/// An "older" file and its index, built once to diff it against any number
+/// of "newer" files
+///
+/// [Differ] does the same while borrowing the file, this owns it, so it can
+/// be kept around, e.g. by a server that produces patches from one base
+/// image to many targets.
+pub struct DiffContext {
+    // borrows from `old`, so it's declared (and dropped) first
+    index: Index<'static>,
+    old: Vec<u8>,
+}
+
+impl DiffContext {
+    /// Build the index of `old`. Only the `sort_partitions`, `max_memory`
+    /// and `max_sort_bytes` of `params` matter here, the rest is taken from
+    /// the params given to each diff.
+    pub fn new(old: Vec<u8>, params: &DiffParams) -> Self {
+        // Safety: the heap buffer of a `Vec` doesn't move when the `Vec`
+        // does, and `old` is never modified nor dropped before `index`
+        let obuf: &'static [u8] = unsafe { std::slice::from_raw_parts(old.as_ptr(), old.len()) };
+        let before_suffix = Stopwatch::start();
+        let index = Differ::index(obuf, params);
+        telemetry::record_sort(obuf.len(), before_suffix.elapsed());
+        params.report(ProgressEvent::SuffixArrayBuilt);
+
+        Self { index, old }
+    }
+
+    /// The "older" file
+    pub fn old(&self) -> &[u8] {
+        &self.old
+    }
+
+    /// Number of bytes used by the index, not counting the "older" file itself
+    pub fn sa_memory_bytes(&self) -> usize {
+        match self.index {
+            Index::Sorted(_) => self.old.len() * std::mem::size_of::<i32>(),
+            Index::Naive(_) | Index::Custom(_) | Index::Skipped => 0,
+        }
+    }
+
+    /// Diff the "older" file against `nbuf`, see [diff]
+    pub fn diff_against<F, E>(
+        &self,
+        nbuf: &[u8],
+        params: &DiffParams,
+        on_match: F,
+    ) -> Result<DiffStats, E>
+    where
+        F: FnMut(Match) -> Result<(), E>,
+        E: From<DiffError>,
+    {
+        self.differ(params).scan(nbuf, on_match)
+    }
+
+    fn differ<'s>(&'s self, params: &'s DiffParams) -> Differ<'s> {
+        let index = match &self.index {
+            Index::Sorted(sa) => Index::Custom(sa),
+            Index::Naive(sa) => Index::Naive(NaiveIndex { text: sa.text }),
+            Index::Custom(_) => unreachable!("contexts always build their own index"),
+            Index::Skipped => Index::Skipped,
+        };
+        Differ {
+            obuf: &self.old,
+            params,
+            index,
+            sort_duration: Duration::ZERO,
+        }
+    }
+}
+
+/// Consecutive matches of `inner` merged together while they cover at most
+/// `threshold` bytes of "newer", see [DiffParams::with_merge_threshold]
+struct MergedMatches<I> {
+    inner: I,
+    threshold: usize,
+    pending: Option<Match>,
+}
+
+impl<I: Iterator<Item = Match>> MergedMatches<I> {
+    fn new(inner: I, threshold: usize) -> Self {
+        Self {
+            inner,
+            threshold,
+            pending: None,
+        }
+    }
+}
+
+/// `a` followed by `b` as a single match, when it covers at most `threshold`
+/// bytes of "newer"
+fn merge_matches(a: &Match, b: &Match, threshold: usize) -> Option<Match> {
+    // only matches that tile "newer" can be merged
+    if b.add_new_start != a.copy_end || b.copy_end - a.add_new_start > threshold {
+        return None;
+    }
+    let add_length = if a.is_pure_add() && b.add_old_start == a.add_old_start + a.add_length {
+        // `b` carries on with the same alignment
+        a.add_length + b.add_length
+    } else {
+        // `b` is stored as-is, after `a`'s copy region
+        a.add_length
+    };
+    Some(Match {
+        add_length,
+        copy_end: b.copy_end,
+        ..*a
+    })
+}
+
+impl<I: Iterator<Item = Match>> Iterator for MergedMatches<I> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        let mut pending = self.pending.take().or_else(|| self.inner.next())?;
+        for m in self.inner.by_ref() {
+            match merge_matches(&pending, &m, self.threshold) {
+                Some(merged) => pending = merged,
+                None => {
+                    self.pending = Some(m);
+                    break;
+                }
+            }
+        }
+        Some(pending)
+    }
+}
+
+/// `m` split into consecutive matches covering at most `max` bytes of
+/// "newer" each, see [DiffParams::with_max_match_length]
+fn split_match(m: Match, max: usize) -> impl Iterator<Item = Match> {
+    let copy_start = m.copy_start();
+    (m.add_new_start..m.copy_end)
+        .step_by(max)
+        .map(move |start| {
+            let end = start.saturating_add(max).min(m.copy_end);
+            // pieces of the copy region start where the add region ended in
+            // "older", so that they don't seek
+            let add_start = start.min(copy_start);
+            Match {
+                add_old_start: m.add_old_start + (add_start - m.add_new_start),
+                add_new_start: start,
+                add_length: end.min(copy_start) - add_start,
+                copy_end: end,
+            }
+        })
+}
+
+/// Where to go from the matches of a scan chunk, which cover "newer" from
+/// `previous_start` to `boundary`, to those of the next one, which scanned
+/// the overlap before `boundary` again (see [DiffParams::with_chunk_overlap]):
+/// the start of the match of the next chunk that crosses `boundary`, when
+/// it's longer than the last match of the previous chunk, and `boundary`
+/// otherwise
+fn overlap_cut(
+    previous: &[Match],
+    next: &[Match],
+    previous_start: usize,
+    boundary: usize,
+) -> usize {
+    let crossing = next.iter().find(|m| m.copy_end > boundary);
+    match (previous.last(), crossing) {
+        (Some(last), Some(m)) if m.add_length > last.add_length => {
+            m.add_new_start.max(previous_start)
+        }
+        _ => boundary,
+    }
+}
+
+/// Trim consecutive `matches` so that they end by `end` in "newer"
+fn truncate_matches_end(matches: &mut Vec<Match>, end: usize) {
+    matches.retain(|m| m.add_new_start < end);
+    if let Some(m) = matches.last_mut() {
+        m.add_length = m.add_length.min(end - m.add_new_start);
+        m.copy_end = m.copy_end.min(end);
+    }
+}
+
+/// Trim consecutive `matches` so that they start at `start` in "newer"
+fn truncate_matches_start(matches: &mut Vec<Match>, start: usize) {
+    matches.retain(|m| m.copy_end > start);
+    if let Some(m) = matches.first_mut().filter(|m| m.add_new_start < start) {
+        // past the add region, it picks up in "older" where it ended, like
+        // pieces of split matches do
+        let cut = (start - m.add_new_start).min(m.add_length);
+        m.add_old_start += cut;
+        m.add_length -= cut;
+        m.add_new_start = start;
+    }
+}
+
+/// Matches taking the common prefix and suffix of `obuf` and `nbuf` from
+/// `obuf`, with everything in between stored as-is
+fn trimmed_matches(obuf: &[u8], nbuf: &[u8]) -> Vec<Match> {
+    if nbuf.is_empty() {
+        return Vec::new();
+    }
+
+    let prefix = obuf.iter().zip(nbuf).take_while(|(o, n)| o == n).count();
+    let suffix = obuf[prefix..]
+        .iter()
+        .rev()
+        .zip(nbuf[prefix..].iter().rev())
+        .take_while(|(o, n)| o == n)
+        .count();
+
+    let mut matches = vec![Match {
+        add_old_start: 0,
+        add_new_start: 0,
+        add_length: prefix,
+        copy_end: nbuf.len() - suffix,
+    }];
+    if suffix > 0 {
+        matches.push(Match {
+            add_old_start: obuf.len() - suffix,
+            add_new_start: nbuf.len() - suffix,
+            add_length: suffix,
+            copy_end: nbuf.len(),
+        });
+    }
+    matches
+}
+
+/// Diff `obuf[old_range]` against `nbuf[new_range]`
+///
+/// Emitted matches have offsets relative to the start of `obuf` and `nbuf`
+/// rather than to the ranges, so they can be fed to a [Translator] built over
+/// the whole buffers. This fails like [diff] does.
+pub fn diff_range<F, E>(
+    obuf: &[u8],
+    old_range: Range<usize>,
+    nbuf: &[u8],
+    new_range: Range<usize>,
+    params: &DiffParams,
+    mut on_match: F,
+) -> Result<DiffStats, E>
+where
+    F: FnMut(Match) -> Result<(), E>,
+    E: From<DiffError>,
+{
+    let (old_offset, new_offset) = (old_range.start, new_range.start);
+    diff(&obuf[old_range], &nbuf[new_range], params, |m| {
+        on_match(Match {
+            add_old_start: m.add_old_start + old_offset,
+            add_new_start: m.add_new_start + new_offset,
+            copy_end: m.copy_end + new_offset,
+            ..m
+        })
+    })
+}
+
+/// Diff two files made of fixed-size blocks, such as filesystem images
+///
+/// Blocks of `nbuf` that also appear (at any block-aligned offset) in `obuf`
+/// are taken from there as-is. Runs of blocks that don't are diffed
+/// byte-by-byte against the blocks of `obuf` at the same offsets, which
+/// works well as long as blocks are modified in place. This avoids suffix
+/// sorting all of `obuf`, which makes it much faster than [diff] for
+/// mostly-unchanged inputs, but gives worse patches when data moves around
+/// by less than a block. This fails like [diff] does.
+pub fn diff_blocks<F, E>(
+    obuf: &[u8],
+    nbuf: &[u8],
+    block_size: usize,
+    params: &DiffParams,
+    mut on_match: F,
+) -> Result<DiffStats, E>
+where
+    F: FnMut(Match) -> Result<(), E>,
+    E: From<DiffError>,
+{
+    assert!(block_size > 0, "block size cannot be 0");
+
+    let before_hash = Stopwatch::start();
+    let mut old_blocks = HashMap::new();
+    for (i, block) in obuf.chunks(block_size).enumerate() {
+        old_blocks.entry(block).or_insert(i * block_size);
+    }
+    info_took!(obuf.len() as u64, before_hash.elapsed(), "hashing blocks");
+
+    let mut stats = StatsRecorder::new();
+    let mut on_match = |m: Match| {
+        stats.record(&m);
+        on_match(m)
+    };
+
+    let mut matched_blocks = 0;
+    let mut unmatched_start = None;
+    for (i, block) in nbuf.chunks(block_size).enumerate() {
+        let new_start = i * block_size;
+        if let Some(&old_start) = old_blocks.get(block) {
+            if let Some(start) = unmatched_start.take() {
+                diff_aligned(obuf, nbuf, start..new_start, params, &mut on_match)?;
+            }
+            matched_blocks += 1;
+            on_match(Match {
+                add_old_start: old_start,
+                add_new_start: new_start,
+                add_length: block.len(),
+                copy_end: new_start + block.len(),
+            })?;
+        } else if unmatched_start.is_none() {
+            unmatched_start = Some(new_start);
+        }
+    }
+    if let Some(start) = unmatched_start {
+        diff_aligned(obuf, nbuf, start..nbuf.len(), params, &mut on_match)?;
+    }
+
+    info!(
+        "{} of {} blocks matched",
+        matched_blocks,
+        nbuf.len().div_ceil(block_size)
+    );
+
+    Ok(stats.finish(obuf, nbuf))
+}
+
+/// Diff `nbuf[new_range]` against the same range of `obuf`
+fn diff_aligned<F, E>(
+    obuf: &[u8],
+    nbuf: &[u8],
+    new_range: Range<usize>,
+    params: &DiffParams,
+    mut on_match: F,
+) -> Result<(), E>
+where
+    F: FnMut(Match) -> Result<(), E>,
+    E: From<DiffError>,
+{
+    let old_range = min(new_range.start, obuf.len())..min(new_range.end, obuf.len());
+    if old_range.is_empty() {
+        return on_match(Match {
+            add_old_start: 0,
+            add_new_start: new_range.start,
+            add_length: 0,
+            copy_end: new_range.end,
+        });
+    }
+    diff_range(obuf, old_range, nbuf, new_range, params, on_match)?;
+    Ok(())
+}
+
+/// Diff two sequences of records, such as database rows
+///
+/// Emitted matches have offsets relative to the concatenation of all records,
+/// so the [Translator] must be built over the concatenated buffers. Records of
+/// `new` that also appear in `old` are taken from there as-is. Others are
+/// diffed byte-by-byte against the record of `old` at the same index, or
+/// stored as-is if there's none. This fails like [diff] does.
+pub fn diff_records<R, F, E>(
+    old: &[R],
+    new: &[R],
+    params: &DiffParams,
+    mut on_match: F,
+) -> Result<DiffStats, E>
+where
+    R: AsRef<[u8]>,
+    F: FnMut(Match) -> Result<(), E>,
+    E: From<DiffError>,
+{
+    let mut old_starts = Vec::with_capacity(old.len());
+    let mut old_records = HashMap::new();
+    let mut old_len = 0;
+    for record in old {
+        let record = record.as_ref();
+        old_starts.push(old_len);
+        old_records.entry(record).or_insert(old_len);
+        old_len += record.len();
+    }
+
+    let mut stats = StatsRecorder::new();
+    let mut on_match = |m: Match| {
+        stats.record(&m);
+        on_match(m)
+    };
+
+    let mut new_start = 0;
+    for (i, record) in new.iter().enumerate() {
+        let record = record.as_ref();
+        let new_end = new_start + record.len();
+        if let Some(&old_start) = old_records.get(record) {
+            on_match(Match {
+                add_old_start: old_start,
+                add_new_start: new_start,
+                add_length: record.len(),
+                copy_end: new_end,
+            })?;
+        } else if let Some(old_record) = old.get(i).map(|r| r.as_ref()).filter(|r| !r.is_empty()) {
+            let old_start = old_starts[i];
+            diff(old_record, record, params, |m| {
+                on_match(Match {
+                    add_old_start: m.add_old_start + old_start,
+                    add_new_start: m.add_new_start + new_start,
+                    copy_end: m.copy_end + new_start,
+                    ..m
+                })
+            })?;
+        } else {
+            on_match(Match {
+                add_old_start: 0,
+                add_new_start: new_start,
+                add_length: 0,
+                copy_end: new_end,
+            })?;
+        }
+        new_start = new_end;
+    }
+
+    Ok(stats.finish_with(new_start, || {
+        old.len() == new.len() && old.iter().zip(new).all(|(o, n)| o.as_ref() == n.as_ref())
+    }))
+}
+
+use std::fmt;
+
+struct DurationSpeed(u64, std::time::Duration);
+
+impl fmt::Display for DurationSpeed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (size, duration) = (self.0, self.1);
+        write!(f, "{:?} ({})", duration, Speed(size, duration))
+    }
+}
+
+struct Speed(u64, std::time::Duration);
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} / s", Size(throughput(self.0, self.1)))
+    }
+}
+
+/// Bytes per second of going through `size` bytes in `duration`
+fn throughput(size: u64, duration: Duration) -> u64 {
+    (size as f64 / duration.as_secs_f64()) as u64
+}
+
+struct Size(u64);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let x = self.0;
+
+        if x > 1024 * 1024 {
+            write!(f, "{:.2} MiB", x as f64 / (1024.0 * 1024.0))
+        } else if x > 1024 {
+            write!(f, "{:.1} KiB", x as f64 / (1024.0))
+        } else {
+            write!(f, "{} B", x)
+        }
+    }
+}
+
+#[cfg(feature = "enc")]
+pub fn simple_diff(
+    older: &[u8],
+    newer: &[u8],
+    out: &mut dyn Write,
+) -> Result<DiffStats, io::Error> {
+    simple_diff_with_params(older, newer, out, &Default::default())
+}
+
+#[cfg(feature = "enc")]
+pub fn simple_diff_with_params(
+    older: &[u8],
+    newer: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<DiffStats, io::Error> {
+    telemetry::count_patch(out, |out| write_patch(older, newer, out, diff_params))
+}
+
+#[cfg(feature = "enc")]
+fn write_patch(
+    older: &[u8],
+    newer: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<DiffStats, io::Error> {
+    if !diff_params.compression.is_compiled_in() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{:?} compression was not compiled in",
+                diff_params.compression
+            ),
+        ));
+    }
+    if diff_params.compression != PatchCompression::None {
+        let mut patch = Vec::with_capacity(diff_params.reserve_hint.unwrap_or_default());
+        let stats = encode_patch(older, newer, &mut patch, diff_params)?;
+        let compressed = diff_params.compression.compress(&patch)?;
+        auto::write_compressed(
+            out,
+            diff_params.compression.compressor(),
+            patch.len() as u64,
+            &compressed,
+        )?;
+        return Ok(stats);
+    }
+
+    if let Some(hint) = diff_params.reserve_hint {
+        let mut patch = Vec::with_capacity(hint);
+        let stats = encode_patch(older, newer, &mut patch, diff_params)?;
+        out.write_all(&patch)?;
+        return Ok(stats);
+    }
+    encode_patch(older, newer, out, diff_params)
+}
+
+#[cfg(feature = "enc")]
+fn encode_patch(
+    older: &[u8],
+    newer: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<DiffStats, io::Error> {
+    let options = enc::WriterOptions {
+        target_copies: diff_params.target_copies,
+        checksum: diff_params.checksum.then(|| hmac_sha256::Hash::hash(newer)),
+        source_checksum: diff_params
+            .source_checksum
+            .then(|| hmac_sha256::Hash::hash(older)),
+        ..Default::default()
+    };
+    let mut w = enc::Writer::with_options(out, &options)?;
+
+    let differ = Differ::prepare_for(older, newer, diff_params);
+    let stats = if differ.chunk_size(newer.len()).is_some() && !diff_params.target_copies {
+        // translate and encode each chunk on the worker that scanned it,
+        // see `encode_chunk`
+        differ.scan_with(
+            newer,
+            |nbuf, matches| encode_chunk(older, nbuf, matches),
+            |_| Ok(()),
+            |chunk| w.write_encoded(&chunk?),
+        )?
+    } else {
+        let mut translator = Translator::new(older, newer, |control| w.write(control));
+        if diff_params.target_copies {
+            translator = translator.with_target_copies();
+        }
+        let res = differ.scan(newer, |m| translator.translate(m));
+        translator.close_after(res)?
+    };
+    w.finish()?;
+
+    Ok(stats)
+}
+
+/// Estimate the size of the patch [simple_diff_with_params] would write,
+/// without encoding it
+///
+/// This runs the whole diff, but only adds up the size of each control
+/// (the add and copy bytes counted in [DiffStats], plus the varints framing
+/// them), and that of the header and checksums. Nothing is written or
+/// compressed.
+///
+/// The estimate is an upper bound for the uncompressed control stream:
+/// target copies only make it smaller, and compression usually does. When
+/// "newer" is scanned in chunks, the controls are counted chunk by chunk,
+/// including the one each chunk ends with to seek back to the start of
+/// "older", like they're encoded.
+#[cfg(feature = "enc")]
+pub fn estimate_patch_size(
+    older: &[u8],
+    newer: &[u8],
+    params: &DiffParams,
+) -> Result<usize, DiffError> {
+    use integer_encoding::VarInt;
+
+    let control_framing = |c: &Control| {
+        c.add.len().required_space() + c.copy.len().required_space() + c.seek.required_space()
+    };
+    let mut framing = 0;
+    let differ = Differ::prepare_for(older, newer, params);
+    let stats = if differ.chunk_size(newer.len()).is_some() && !params.target_copies {
+        // the same controls as `encode_chunk` encodes
+        differ.scan_with(
+            newer,
+            |nbuf, matches| -> Result<usize, DiffError> {
+                let mut chunk_framing = 0;
+                let mut translator = Translator::new(older, nbuf, |c| {
+                    chunk_framing += control_framing(c);
+                    Ok(())
+                });
+                translator.translate_all(matches.iter().map(|m| Match { ..*m }))?;
+                translator.close_and_rewind()?;
+                Ok(chunk_framing)
+            },
+            |_| Ok(()),
+            |chunk_framing| {
+                framing += chunk_framing?;
+                Ok(())
+            },
+        )?
+    } else {
+        let mut translator = Translator::new(older, newer, |c| -> Result<(), DiffError> {
+            framing += control_framing(c);
+            Ok(())
+        });
+        let res = differ.scan(newer, |m| translator.translate(m));
+        translator.close_after(res)?
+    };
+
+    // magic, version and flags
+    let header = 3 * 4 + if params.source_checksum { 32 } else { 0 };
+    let checksum = if params.checksum {
+        enc::END_OF_CONTROLS.required_space() + 32
+    } else {
+        0
+    };
+    Ok(header + framing + stats.add_bytes as usize + stats.copy_bytes as usize + checksum)
+}
+
+/// Diff `newer` against each of `olders`, and return the index of the one
+/// that likely makes the smallest compressed patch, with the stats of that
+/// diff
+///
+/// This is for updates that can be applied to one of several prior versions.
+/// Uncompressed patches are all about as large as `newer`, since add bytes
+/// are stored whether they changed or not: what sets patches apart once
+/// compressed is how much of `newer` isn't taken from "older" as-is. So each
+/// candidate is diffed as a whole, and ranked by its copy bytes and changed
+/// add bytes (plus the framing of its controls), the first of the smallest
+/// winning. This is a heuristic: nothing is encoded or compressed, and how
+/// well the rest compresses isn't taken into account. Diff the winner again
+/// to write its patch.
+///
+/// With the `parallel` feature, candidates are diffed in parallel, on top of
+/// the parallelism of each diff, so this holds all their suffix arrays at
+/// once. Fails with [DiffError::NoOlder] when `olders` is empty.
+#[cfg(feature = "enc")]
+pub fn diff_best_of(
+    olders: &[&[u8]],
+    newer: &[u8],
+    params: &DiffParams,
+) -> Result<(usize, DiffStats), DiffError> {
+    use integer_encoding::VarInt;
+
+    if olders.is_empty() {
+        return Err(DiffError::NoOlder);
+    }
+
+    let candidate = |older: &[u8]| {
+        let mut cost = 0;
+        let stats = diff_controls(older, newer, params, |c| -> Result<(), DiffError> {
+            cost += c.add.iter().filter(|b| **b != 0).count()
+                + c.copy.len()
+                + c.add.len().required_space()
+                + c.copy.len().required_space()
+                + c.seek.required_space();
+            Ok(())
+        })?;
+        Ok((cost, stats))
+    };
+    let candidates = candidates(olders)
+        .map(candidate)
+        .collect::<Result<Vec<_>, DiffError>>()?;
+    for (i, (cost, _)) in candidates.iter().enumerate() {
+        debug!("candidate {} leaves {} to store", i, Size(*cost as u64));
+    }
+    let best = (0..candidates.len())
+        .min_by_key(|i| candidates[*i].0)
+        .expect("olders isn't empty");
+    let (_, stats) = candidates
+        .into_iter()
+        .nth(best)
+        .expect("best is a candidate");
+    Ok((best, stats))
+}
+
+/// The candidates of [diff_best_of], diffed in parallel with the `parallel`
+/// feature, and in order without it
+#[cfg(all(feature = "enc", feature = "parallel"))]
+fn candidates<'a>(olders: &'a [&'a [u8]]) -> rayon::iter::Copied<rayon::slice::Iter<'a, &'a [u8]>> {
+    olders.par_iter().copied()
+}
+
+#[cfg(all(feature = "enc", not(feature = "parallel")))]
+fn candidates<'a>(olders: &'a [&'a [u8]]) -> std::iter::Copied<std::slice::Iter<'a, &'a [u8]>> {
+    olders.iter().copied()
+}
+
+/// Encode the controls of a single chunk of "newer", given its matches
+/// (relative to it)
+///
+/// Translating a match needs the next one, to know how far to seek, which
+/// isn't known when the next match is in another chunk. Instead, the
+/// controls of a chunk start from the beginning of "older", like those of a
+/// whole patch, and the last one seeks back to it. This costs one more
+/// control per chunk, when the first match of the next chunk doesn't start
+/// at the beginning of "older".
+#[cfg(feature = "enc")]
+fn encode_chunk(older: &[u8], nbuf: &[u8], matches: &[Match]) -> Result<Vec<u8>, io::Error> {
+    let mut chunk = Vec::new();
+    let mut w = enc::Writer::segment(&mut chunk);
+    let mut translator = Translator::new(older, nbuf, |control| w.write(control));
+    translator.translate_all(matches.iter().map(|m| Match { ..*m }))?;
+    translator.close_and_rewind()?;
+    Ok(chunk)
+}
+
+/// Diff two files into a segmented patch (see [enc::write_segmented])
+///
+/// Each `scan_chunk_size` chunk of `newer` is scanned, translated and encoded
+/// into its own segment, all in parallel. When `scan_chunk_size` is `None`,
+/// the patch has a single segment.
+#[cfg(feature = "enc")]
+pub fn segmented_diff_with_params(
+    older: &[u8],
+    newer: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<(), io::Error> {
+    let differ = Differ::prepare(older, diff_params);
+    let chunk_size = diff_params.scan_chunk_size.unwrap_or(newer.len()).max(1);
+    let segment_capacity = diff_params
+        .reserve_hint
+        .map_or(0, |hint| hint / newer.len().div_ceil(chunk_size).max(1));
+
+    let before_encode = Stopwatch::start();
+    let segments = chunks(newer, chunk_size)
+        .map(|nbuf| {
+            let mut segment = Vec::with_capacity(segment_capacity);
+            let mut w = enc::Writer::segment(&mut segment);
+            let mut translator = Translator::new(older, nbuf, |control| w.write(control));
+            translator.translate_all(differ.chunk_matches(nbuf))?;
+            translator.close()?;
+            Ok(segment)
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    info_took!(
+        newer.len() as u64,
+        before_encode.elapsed(),
+        "scanning and encoding {} segments",
+        segments.len()
+    );
+
+    telemetry::count_patch(out, |out| enc::write_segmented(out, &segments))
+}
+
+/// A SHA-256 hash
+pub type Hash = [u8; 32];
+
+pub fn assert_cycle(older: &[u8], newer: &[u8]) {
+    assert_cycle_with_params(older, newer, &Default::default())
+}
+
+pub fn assert_cycle_with_params(older: &[u8], newer: &[u8], params: &DiffParams) {
+    if let Err(e) = verify(older, newer, params) {
+        panic!("{}", e);
+    }
+}
+
+/// Errors raised by [verify]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The byte reconstructed at `offset` isn't the one of "newer"
+    Mismatch {
+        offset: usize,
+        expected: u8,
+        actual: u8,
+    },
+    /// The reconstructed file is `actual` bytes long, rather than the
+    /// `expected` length of "newer"
+    Length { expected: usize, actual: usize },
+    /// A control read "older" at this position, which is out of its bounds
+    OutOfBounds(i64),
+    /// Diffing failed
+    Diff(DiffError),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Mismatch {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "reconstructed byte {} is {:#04x}, expected {:#04x}",
+                offset, actual, expected
+            ),
+            VerifyError::Length { expected, actual } => {
+                write!(f, "reconstructed {} bytes, expected {}", actual, expected)
+            }
+            VerifyError::OutOfBounds(pos) => {
+                write!(f, "older read at {}, out of its bounds", pos)
+            }
+            VerifyError::Diff(e) => write!(f, "diff failed: {}", e),
+        }
+    }
+}
+
+impl Error for VerifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VerifyError::Diff(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<DiffError> for VerifyError {
+    fn from(e: DiffError) -> Self {
+        VerifyError::Diff(e)
+    }
+}
+
+/// Diff `older` and `newer`, and check that replaying the resulting controls
+/// on `older` gives `newer` back, without encoding a patch
+pub fn verify(older: &[u8], newer: &[u8], params: &DiffParams) -> Result<(), VerifyError> {
+    let mut older_pos = 0_i64;
+    let mut fresh_len = 0_usize;
+    let check = |offset: usize, actual: u8| match newer.get(offset) {
+        Some(&expected) if expected != actual => Err(VerifyError::Mismatch {
+            offset,
+            expected,
+            actual,
+        }),
+        Some(_) => Ok(()),
+        None => Err(VerifyError::Length {
+            expected: newer.len(),
+            actual: offset + 1,
+        }),
+    };
+
+    let mut translator = Translator::new(older, newer, |control| -> Result<(), VerifyError> {
+        for &ab in control.add {
+            let ob = usize::try_from(older_pos)
+                .ok()
+                .and_then(|pos| older.get(pos))
+                .ok_or(VerifyError::OutOfBounds(older_pos))?;
+            check(fresh_len, ab.wrapping_add(*ob))?;
+            older_pos += 1;
+            fresh_len += 1;
+        }
+
+        for &cb in control.copy {
+            check(fresh_len, cb)?;
+            fresh_len += 1;
+        }
+
+        older_pos += control.seek;
+        Ok(())
+    });
+
+    let res = diff(older, newer, params, |m| translator.translate(m));
+    translator.close_after(res)?;
+
+    if fresh_len != newer.len() {
+        return Err(VerifyError::Length {
+            expected: newer.len(),
+            actual: fresh_len,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::instructions::{apply_instructions, make_instructions};
+    use proptest::prelude::*;
+    use std::io::{self, Cursor, Read, Write};
+
+    /// Encode the matches emitted by `f` into a patch
+    fn encode<F>(older: &[u8], newer: &[u8], f: F) -> Vec<u8>
+    where
+        F: FnOnce(&mut dyn FnMut(super::Match) -> std::io::Result<()>),
+    {
+        let mut patch = Vec::new();
+        let mut w = super::enc::Writer::new(&mut patch).unwrap();
+        let mut translator = super::Translator::new(older, newer, |c| w.write(c));
+        f(&mut |m| translator.translate(m));
+        translator.close().unwrap();
+        patch
+    }
+
+    fn apply_patch(older: &[u8], patch: &[u8]) -> Vec<u8> {
+        let mut r = bipatch::Reader::new(patch, Cursor::new(older)).unwrap();
+        let mut fresh = Vec::new();
+        r.read_to_end(&mut fresh).unwrap();
+        fresh
+    }
+
+    /// Deterministic input that repeats every 251 bytes
+    fn fixture(len: u32) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn short_patch() {
+        let older = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            1, 2, 0,
+        ];
+        let instructions = [
+            12, 16, 5, 40, 132, 1, 47, 43, 20, 86, 150, 0, 150, 0, 150, 0, 115, 31, 0, 0, 0, 0, 0,
+            0, 0, 1, 38, 188, 128, 0, 150, 0,
+        ];
+        let newer = apply_instructions(&older[..], &instructions[..]);
+
+        super::assert_cycle(&older[..], &newer[..]);
+    }
+
+    #[test]
+    fn segmented_patch() {
+        let older = fixture(4096);
+        let mut newer = older[..3000].to_vec();
+        newer.extend_from_slice(b"some brand new data");
+        newer.extend_from_slice(&older[1000..]);
+
+        for chunk_size in [None, Some(1), Some(100), Some(1024), Some(100_000)] {
+            let params = super::DiffParams::new(2, chunk_size).unwrap();
+            let mut patch = Vec::new();
+            super::segmented_diff_with_params(&older[..], &newer[..], &mut patch, &params).unwrap();
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        }
+    }
+
+    #[test]
+    fn identity_stats() {
+        let older = fixture(1024);
+        let mut newer = older.clone();
+
+        let stats = super::diff(&older[..], &newer[..], &Default::default(), |_| {
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+        assert!(stats.is_identity);
+
+        newer[512] ^= 0xFF;
+        let stats = super::diff(&older[..], &newer[..], &Default::default(), |_| {
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+        assert!(!stats.is_identity);
+    }
+
+    #[test]
+    fn huge_footer_seek() {
+        const GIB: usize = 1024 * 1024 * 1024;
+        let pattern = |seed: u32| (0..4096_u32).map(move |i| ((i * 7 + seed) % 251) as u8);
+
+        // zeroed allocations are lazy, so only the pages we touch get mapped
+        let mut older = vec![0_u8; GIB + 8192];
+        older[..64].copy_from_slice(&[1; 64]);
+        for (o, b) in older[GIB + 4096..].iter_mut().zip(pattern(0)) {
+            *o = b;
+        }
+
+        let mut newer = vec![1_u8; 64];
+        newer.extend(pattern(0).take(2048));
+        newer.extend_from_slice(b"something new");
+        newer.extend(pattern(0).skip(2048));
+        newer.extend_from_slice(&[1; 32]);
+        let (footer_start, trailer_start) = (64, newer.len() - 32);
+
+        let params = Default::default();
+        let mut patch = Vec::new();
+        let mut max_seek = 0_u64;
+        {
+            let mut w = super::enc::Writer::new(&mut patch).unwrap();
+            let mut translator = super::Translator::new(&older[..], &newer[..], |c| {
+                max_seek = max_seek.max(c.seek.unsigned_abs());
+                w.write(c)
+            });
+            let mut diff_range = |old_range, new_range| {
+                super::diff_range(&older[..], old_range, &newer[..], new_range, &params, |m| {
+                    translator.translate(m)
+                })
+                .unwrap();
+            };
+            diff_range(0..64, 0..footer_start);
+            diff_range(GIB + 4096..GIB + 8192, footer_start..trailer_start);
+            diff_range(0..64, trailer_start..newer.len());
+            translator.close().unwrap();
+        }
+
+        assert!(max_seek > GIB as u64);
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    #[test]
+    fn manifest() {
+        let older = fixture(4096);
+        let mut newer = older[2000..3000].to_vec();
+        newer.extend_from_slice(b"some brand new data");
+        newer.extend_from_slice(&older[..1000]);
+        newer.extend_from_slice(&older[2000..3000]);
+
+        let mut patch = Vec::new();
+        let mut expected = std::collections::BTreeSet::new();
+        {
+            let mut w = super::enc::Writer::with_manifest(&mut patch).unwrap();
+            let mut old_pos = 0_i64;
+            let mut translator = super::Translator::new(&older[..], &newer[..], |c| {
+                if !c.add.is_empty() {
+                    expected.insert((old_pos as u64, c.add.len() as u64));
+                }
+                old_pos += c.add.len() as i64 + c.seek;
+                w.write(c)
+            });
+            super::diff(&older[..], &newer[..], &Default::default(), |m| {
+                translator.translate(m)
+            })
+            .unwrap();
+            translator.close().unwrap();
+            w.finish().unwrap();
+        }
+
+        let mut r = bipatch::Reader::new(&patch[..], Cursor::new(&older[..])).unwrap();
+        let mut fresh = Vec::new();
+        r.read_to_end(&mut fresh).unwrap();
+        assert_eq!(fresh, newer);
+
+        let expected: Vec<_> = expected.into_iter().collect();
+        assert!(!expected.is_empty());
+        assert_eq!(r.manifest(), Some(&expected[..]));
+    }
+
+    #[test]
+    fn delta_modes() {
+        use super::{enc, DeltaMode};
+
+        let older = fixture(4096);
+        let mut newer = older[2000..3000].to_vec();
+        newer.extend_from_slice(b"some brand new data");
+        newer.extend(older[..1000].iter().map(|b| b ^ 1));
+
+        for delta_mode in [DeltaMode::WrappingSub, DeltaMode::Raw] {
+            let mut patch = Vec::new();
+            let options = enc::WriterOptions {
+                delta_mode,
+                ..Default::default()
+            };
+            let mut w = enc::Writer::with_options(&mut patch, &options).unwrap();
+            let mut translator =
+                super::Translator::with_delta_mode(&older[..], &newer[..], delta_mode, |c| {
+                    w.write(c)
+                });
+            super::diff(&older[..], &newer[..], &Default::default(), |m| {
+                translator.translate(m)
+            })
+            .unwrap();
+            translator.close().unwrap();
+
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        }
+    }
+
+    #[test]
+    fn max_sort_bytes() {
+        let older = fixture(4096);
+        let mut newer = older[..2000].to_vec();
+        newer.extend_from_slice(b"some brand new data");
+        newer.extend_from_slice(&older[2100..]);
+
+        let diff = |params: &super::DiffParams| {
+            super::diff(&older[..], &newer[..], params, |_| {
+                Ok::<_, std::io::Error>(())
+            })
+            .unwrap()
+        };
+
+        let params = super::DiffParams::default().with_max_sort_bytes(Some(older.len()));
+        assert!(!diff(&params).sort_skipped);
+
+        let params = super::DiffParams::default().with_max_sort_bytes(Some(older.len() - 1));
+        assert!(diff(&params).sort_skipped);
+        super::assert_cycle_with_params(&older[..], &newer[..], &params);
+        super::assert_cycle_with_params(&older[..], &older[..], &params);
+        super::assert_cycle_with_params(&older[..], &older[..10], &params);
+        super::assert_cycle_with_params(&older[..], &[], &params);
+    }
+
+    #[test]
+    fn diff_blocks() {
+        let block_size = 64;
+        let older = fixture(64 * 64);
+        let mut newer = older.clone();
+        // modified in place
+        newer[100] ^= 0xFF;
+        newer[1000..1010].copy_from_slice(b"0123456789");
+        // moved around
+        newer.copy_within(64 * 10..64 * 20, 64 * 30);
+        // appended
+        newer.extend_from_slice(&older[..100]);
+        newer.extend_from_slice(b"some brand new data");
+
+        for newer in [&newer[..], &older[..], &newer[..1000], &[]] {
+            let params = Default::default();
+            let patch = encode(&older[..], newer, |on_match| {
+                super::diff_blocks(&older[..], newer, block_size, &params, on_match).unwrap();
+            });
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        }
+    }
+
+    #[test]
+    fn diff_blocks_leading_seek() {
+        let block_size = 64;
+        let older = fixture(64 * 4);
+        // the first block of newer is the third one of older, so the first
+        // match doesn't start at the beginning of older
+        let newer = [&older[128..192], &older[..64]].concat();
+
+        let mut controls = Vec::new();
+        let mut translator = super::Translator::new(&older[..], &newer[..], |c| {
+            controls.push(c.to_owned());
+            Ok::<_, io::Error>(())
+        });
+        super::diff_blocks(
+            &older[..],
+            &newer[..],
+            block_size,
+            &Default::default(),
+            |m| translator.translate(m),
+        )
+        .unwrap();
+        translator.close().unwrap();
+        assert!(controls[0].add.is_empty() && controls[0].copy.is_empty());
+        assert_eq!(controls[0].seek, 128);
+
+        let patch = encode(&older[..], &newer[..], |on_match| {
+            super::diff_blocks(
+                &older[..],
+                &newer[..],
+                block_size,
+                &Default::default(),
+                on_match,
+            )
+            .unwrap();
+        });
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    #[test]
+    fn tie_policy() {
+        use super::{DiffParams, TiePolicy};
+
+        let mut state = 12345_u32;
+        let mut noise = || {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        };
+        let (a, b): (Vec<u8>, Vec<u8>) = (
+            (0..64).map(|_| noise()).collect(),
+            (0..64).map(|_| noise()).collect(),
+        );
+
+        // the same two blocks over and over, so most matches tie with the
+        // current alignment
+        let (mut older, mut newer) = (Vec::new(), Vec::new());
+        for i in 0..32 {
+            for (buf, sep) in [(&mut older, i), (&mut newer, 31 - i)] {
+                buf.extend_from_slice(&a[..]);
+                buf.push(sep);
+                buf.extend_from_slice(&b[..]);
+                buf.push(noise());
+            }
+        }
+
+        let sizes: Vec<_> = [TiePolicy::Extend, TiePolicy::Match]
+            .iter()
+            .map(|&tie_policy| {
+                let params = DiffParams::default().with_tie_policy(tie_policy);
+                super::assert_cycle_with_params(&older[..], &newer[..], &params);
+
+                let mut matches = 0;
+                let patch = encode(&older[..], &newer[..], |on_match| {
+                    super::diff(&older[..], &newer[..], &params, |m| {
+                        matches += 1;
+                        on_match(m)
+                    })
+                    .unwrap();
+                });
+                (matches, patch.len())
+            })
+            .collect();
+
+        // extending keeps a single alignment, matching jumps around
+        assert_eq!(sizes[0].0, 1);
+        assert!(sizes[1].0 > 1);
+        assert!(sizes[0].1 < sizes[1].1);
+    }
+
+    #[test]
+    fn diff_records() {
+        let older: Vec<[u8; 16]> = (0..64_u8)
+            .map(|i| {
+                let mut record = [i; 16];
+                record[..8].copy_from_slice(b"record: ");
+                record
+            })
+            .collect();
+        let mut newer = older.clone();
+        newer[3][12] = 0xFF;
+        newer.swap(10, 20);
+        newer[30] = *b"something new!!!";
+        newer.push(older[0]);
+        newer.push(*b"0123456789ABCDEF");
+
+        for newer in [&newer[..], &older[..], &newer[..10], &[]] {
+            let (old_bytes, new_bytes) = (older.concat(), newer.concat());
+            let patch = encode(&old_bytes[..], &new_bytes[..], |on_match| {
+                super::diff_records(&older[..], newer, &Default::default(), on_match).unwrap();
+            });
+            assert_eq!(apply_patch(&old_bytes[..], &patch[..]), new_bytes);
+        }
+    }
+
+    #[test]
+    fn differ() {
+        let older = fixture(4096);
+        let params = Default::default();
+        let differ = super::Differ::prepare(&older[..], &params);
+        assert!(differ.sa_memory_bytes() > 0);
+
+        for newer in [&older[1000..3000], &older[..10], &[]] {
+            let patch = encode(&older[..], newer, |on_match| {
+                differ.scan(newer, on_match).unwrap();
+            });
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        }
+    }
+
+    #[test]
+    fn source_hashes() {
+        use std::collections::HashMap;
+
+        let older = fixture(4096);
+        let mut newer_a = older[1000..2000].to_vec();
+        newer_a.extend_from_slice(b"some brand new data");
+        let mut newer_b = older[1000..2000].to_vec();
+        newer_b.extend_from_slice(b"other brand new data");
+
+        let sources = |newer: &[u8]| {
+            let mut sources = HashMap::new();
+            let mut old_pos = 0_i64;
+            let mut translator = super::Translator::new(&older[..], newer, |c| {
+                let source = (old_pos as usize, c.add.len());
+                sources.insert(source, c.source_hash.unwrap());
+                old_pos += c.add.len() as i64 + c.seek;
+                Ok::<_, std::io::Error>(())
+            })
+            .with_source_hashes();
+            super::diff(&older[..], newer, &Default::default(), |m| {
+                translator.translate(m)
+            })
+            .unwrap();
+            translator.close().unwrap();
+            sources
+        };
+        let (sources_a, sources_b) = (sources(&newer_a[..]), sources(&newer_b[..]));
+
+        let mut common = 0;
+        for ((start, len), hash) in sources_a.iter() {
+            assert_eq!(
+                *hash,
+                hmac_sha256::Hash::hash(&older[*start..*start + *len])
+            );
+            if let Some(other) = sources_b.get(&(*start, *len)) {
+                assert_eq!(hash, other);
+                common += 1;
+            }
+        }
+        assert!(common > 0);
+    }
+
+    #[test]
+    fn matches() {
+        let older = fixture(65536);
+        let mut newer = older[..30000].to_vec();
+        newer.extend_from_slice(b"inserted in the middle");
+        newer.extend_from_slice(&older[20000..]);
+
+        let key = |m: &super::Match| (m.add_old_start, m.add_new_start, m.add_length, m.copy_end);
+        for params in [
+            super::DiffParams::default(),
+            super::DiffParams::new(4, None).unwrap(),
+            super::DiffParams::default().with_max_sort_bytes(Some(1024)),
+        ] {
+            let matches: Vec<_> = super::matches(&older[..], &newer[..], &params).collect();
+            let mut expected = Vec::new();
+            super::diff(&older[..], &newer[..], &params, |m| {
+                expected.push(m);
+                Ok::<_, super::DiffError>(())
+            })
+            .unwrap();
+            assert_eq!(
+                matches.iter().map(key).collect::<Vec<_>>(),
+                expected.iter().map(key).collect::<Vec<_>>()
+            );
+
+            let patch = encode(&older[..], &newer[..], |on_match| {
+                for m in super::matches(&older[..], &newer[..], &params) {
+                    on_match(m).unwrap();
+                }
+            });
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        }
+    }
+
+    #[test]
+    fn diff_with_index() {
+        use super::{DiffParams, PartitionedSuffixArray};
+
+        let older = fixture(65536);
+        let sa = PartitionedSuffixArray::new(&older[..], 1, divsufsort::sort);
+        for (i, params) in [
+            DiffParams::default(),
+            DiffParams::new(1, Some(4096)).unwrap(),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let mut newer = older[..10000 * (i + 1)].to_vec();
+            newer.extend_from_slice(b"new bytes");
+            newer.extend_from_slice(&older[40000..]);
+
+            let patch = encode(&older[..], &newer[..], |on_match| {
+                super::diff_with_index(&older[..], &newer[..], &sa, params, on_match).unwrap();
+            });
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        }
+    }
+
+    // the index is wrong on purpose
+    #[cfg(not(feature = "verify-sa"))]
+    #[test]
+    fn out_of_bounds_index() {
+        use super::{DiffParams, LongestCommonSubstring, PartitionedSuffixArray, StringIndex};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // an index that sometimes finds matches past the end of "older",
+        // or that run past it
+        struct Bogus<'a> {
+            sa: PartitionedSuffixArray<'a, i32>,
+            calls: AtomicUsize,
+            len: usize,
+        }
+        impl<'a> StringIndex<'a> for Bogus<'a> {
+            fn longest_substring_match(&self, needle: &[u8]) -> LongestCommonSubstring<'a> {
+                let mut lcs = self.sa.longest_substring_match(needle);
+                match self.calls.fetch_add(1, Ordering::Relaxed) % 3 {
+                    0 => (lcs.start, lcs.len) = (self.len + 1000, 50),
+                    1 => (lcs.start, lcs.len) = (self.len - 2, 50),
+                    _ => {}
+                }
+                lcs
+            }
+        }
+
+        let older: Vec<u8> = (0..20000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut newer = older[7000..].to_vec();
+        newer.extend_from_slice(b"in between");
+        newer.extend_from_slice(&older[..7000]);
+        let sa = Bogus {
+            sa: PartitionedSuffixArray::new(&older[..], 1, divsufsort::sort),
+            calls: AtomicUsize::new(0),
+            len: older.len(),
+        };
+        let params = DiffParams::default().with_coverage_check(true);
+        let patch = encode(&older[..], &newer[..], |on_match| {
+            super::diff_with_index(&older[..], &newer[..], &sa, &params, on_match).unwrap();
+        });
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    #[cfg(all(feature = "verify-sa", debug_assertions))]
+    #[test]
+    fn verify_sa() {
+        use super::DiffParams;
+
+        let older: Vec<u8> = (0..20000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut newer = older[7000..].to_vec();
+        newer.extend_from_slice(b"in between");
+        newer.extend_from_slice(&older[..7000]);
+
+        for partitions in [1, 4, 64] {
+            let params = DiffParams::new(partitions, None).unwrap();
+            let patch = encode(&older[..], &newer[..], |on_match| {
+                super::diff(&older[..], &newer[..], &params, on_match).unwrap();
+            });
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        }
+
+        // matches may span partitions
+        super::verify_match(b"abcdef", 3, b"bcdx", 1, 3);
+        super::verify_match(b"abcdef", 3, b"bcdx", 1, 2);
+    }
+
+    #[cfg(all(feature = "verify-sa", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "but 3 bytes match at 3")]
+    fn verify_sa_shorter() {
+        super::verify_match(b"abcdef", 3, b"defx", 0, 0);
+    }
+
+    #[cfg(all(feature = "verify-sa", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "which don't match")]
+    fn verify_sa_wrong() {
+        super::verify_match(b"abcdef", 6, b"defx", 0, 3);
+    }
+
+    #[test]
+    fn diff_context() {
+        use super::{DiffContext, DiffError, DiffParams};
+
+        let older = fixture(65536);
+        let params = DiffParams::new(2, None).unwrap();
+        let context = DiffContext::new(older.clone(), &params);
+        assert_eq!(context.old(), &older[..]);
+        assert_eq!(context.sa_memory_bytes(), older.len() * 4);
+
+        let key = |m: &super::Match| (m.add_old_start, m.add_new_start, m.add_length, m.copy_end);
+        for i in 1..4 {
+            let mut newer = older[..10000 * i].to_vec();
+            newer.extend_from_slice(b"new bytes");
+            newer.extend_from_slice(&older[30000..]);
+
+            let mut from_context = Vec::new();
+            context
+                .diff_against(&newer[..], &params, |m| {
+                    from_context.push(key(&m));
+                    Ok::<_, DiffError>(())
+                })
+                .unwrap();
+            let mut from_scratch = Vec::new();
+            super::diff(&older[..], &newer[..], &params, |m| {
+                from_scratch.push(key(&m));
+                Ok::<_, DiffError>(())
+            })
+            .unwrap();
+            assert_eq!(from_context, from_scratch);
+        }
+
+        let small = DiffContext::new(b"tiny".to_vec(), &params);
+        assert_eq!(small.sa_memory_bytes(), 0);
+        let patch = encode(small.old(), b"tiny bit", |on_match| {
+            small.diff_against(b"tiny bit", &params, on_match).unwrap();
+        });
+        assert_eq!(apply_patch(small.old(), &patch[..]), b"tiny bit");
+    }
+
+    #[test]
+    fn diff_params_builder() {
+        use super::{DiffParams, TiePolicy, MAX_MATCH_THRESHOLD};
+
+        let params = DiffParams::builder()
+            .sort_partitions(3)
+            .scan_chunk_size(Some(4096))
+            .match_threshold(4)
+            .tie_policy(TiePolicy::Match)
+            .max_memory(Some(1 << 20))
+            .checksum(true)
+            .build()
+            .unwrap();
+        assert_eq!(params.sort_partitions, 3);
+        assert_eq!(params.scan_chunk_size, Some(4096));
+        assert_eq!(params.match_threshold, 4);
+        assert_eq!(params.tie_policy, TiePolicy::Match);
+        assert_eq!(params.max_memory, Some(1 << 20));
+        assert!(params.checksum);
+
+        let defaults = DiffParams::builder().build().unwrap();
+        assert_eq!(defaults.sort_partitions, 1);
+        assert_eq!(defaults.scan_chunk_size, None);
+
+        // same validation as `new` and `with_match_threshold`
+        assert!(DiffParams::builder().sort_partitions(0).build().is_err());
+        assert!(DiffParams::builder()
+            .scan_chunk_size(Some(0))
+            .build()
+            .is_err());
+        assert!(DiffParams::builder()
+            .match_threshold(MAX_MATCH_THRESHOLD + 1)
+            .build()
+            .is_err());
+        assert!(DiffParams::new(0, None).is_err());
+        assert!(DiffParams::default()
+            .with_match_threshold(MAX_MATCH_THRESHOLD + 1)
+            .is_err());
+
+        let older = b"builder ".repeat(100);
+        let mut newer = older.clone();
+        newer[300..310].fill(b'#');
+        let mut patch = Vec::new();
+        super::simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+        let mut fresh = Vec::new();
+        super::dec::apply(&older, &mut &patch[..], &mut fresh).unwrap();
+        assert_eq!(fresh, newer);
+    }
+
+    #[test]
+    fn auto_params() {
+        use super::{DiffParams, MAX_AUTO_SORT_PARTITIONS};
+
+        let params = DiffParams::auto();
+        let threads = super::available_parallelism();
+        assert_eq!(
+            params.sort_partitions,
+            threads.min(MAX_AUTO_SORT_PARTITIONS)
+        );
+        assert!(params.sort_partitions >= 1);
+        assert_eq!(params.scan_chunk_size, None);
+        assert_eq!(
+            params.chunk_size_for(64 << 20),
+            Some(super::AUTO_SCAN_CHUNK_SIZE)
+        );
+
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        newer[1000..1010].fill(0);
+        super::assert_cycle_with_params(&older[..], &newer[..], &params);
+    }
+
+    #[test]
+    fn max_memory() {
+        use super::{DiffParams, Differ, Index, MIN_SORT_PARTITION_SIZE};
+
+        let params = DiffParams::new(3, None).unwrap();
+        assert_eq!(params.sort_partitions_for(1 << 20), 3);
+
+        let params = params.with_max_memory(Some(1 << 20)).unwrap();
+        assert_eq!(params.sort_partitions_for(1 << 20), 4);
+        assert_eq!(params.sort_partitions_for((1 << 20) + 1), 5);
+        assert_eq!(params.sort_partitions_for(1000), 1);
+        assert_eq!(params.sort_partitions_for(0), 1);
+
+        // tiny limits don't make tiny partitions
+        assert!(DiffParams::default().with_max_memory(Some(0)).is_err());
+        assert!(DiffParams::builder().max_memory(Some(0)).build().is_err());
+        let params = DiffParams::default().with_max_memory(Some(1)).unwrap();
+        assert_eq!(params.sort_partitions_for(1000), 1);
+        assert_eq!(params.sort_partitions_for(10 * MIN_SORT_PARTITION_SIZE), 10);
+
+        let older = fixture(4 * MIN_SORT_PARTITION_SIZE as u32);
+        let mut newer = older.clone();
+        newer[1000..1010].fill(0);
+        let params = DiffParams::default()
+            .with_max_memory(Some(older.len()))
+            .unwrap();
+        let differ = Differ::prepare(&older[..], &params);
+        match &differ.index {
+            Index::Sorted(sa) => assert_eq!(sa.num_partitions(), 4),
+            _ => panic!("older should be sorted"),
+        }
+        let patch = encode(&older[..], &newer[..], |on_match| {
+            differ.scan(&newer[..], on_match).unwrap();
+        });
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    #[test]
+    fn small_inputs() {
+        use super::{BsdiffIterator, Differ, PartitionedSuffixArray};
+
+        let older = fixture(96);
+        let mut newer = older[..50].to_vec();
+        newer.extend_from_slice(b"new");
+        newer.extend_from_slice(&older[40..]);
+
+        let params = Default::default();
+        let differ = Differ::prepare(&older[..], &params);
+        assert_eq!(differ.sa_memory_bytes(), 0);
+        let naive = encode(&older[..], &newer[..], |on_match| {
+            differ.scan(&newer[..], on_match).unwrap();
+        });
+
+        let sa = PartitionedSuffixArray::new(&older[..], 1, divsufsort::sort);
+        let sorted = encode(&older[..], &newer[..], |on_match| {
+            for m in BsdiffIterator::new(&older[..], &newer[..], &sa, &params) {
+                on_match(m).unwrap();
+            }
+        });
+
+        assert_eq!(apply_patch(&older[..], &naive[..]), newer);
+        assert_eq!(apply_patch(&older[..], &sorted[..]), newer);
+        assert!(naive.len() <= sorted.len());
+    }
+
+    #[cfg(feature = "capi")]
+    #[test]
+    fn capi() {
+        use super::capi::*;
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[1000..1012].copy_from_slice(b"hello from C");
+
+        unsafe {
+            let mut patch_len = 0;
+            let res = bidiff_diff(
+                older.as_ptr(),
+                older.len(),
+                newer.as_ptr(),
+                newer.len(),
+                std::ptr::null_mut(),
+                0,
+                &mut patch_len,
+            );
+            assert_eq!(res, BIDIFF_ERR_BUFFER_TOO_SMALL);
+
+            let mut patch = vec![0u8; patch_len];
+            let res = bidiff_diff(
+                older.as_ptr(),
+                older.len(),
+                newer.as_ptr(),
+                newer.len(),
+                patch.as_mut_ptr(),
+                patch.len(),
+                &mut patch_len,
+            );
+            assert_eq!(res, BIDIFF_OK);
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+
+            let mut applied = vec![0u8; newer.len()];
+            let mut applied_len = 0;
+            let res = bidiff_patch(
+                older.as_ptr(),
+                older.len(),
+                patch.as_ptr(),
+                patch.len(),
+                applied.as_mut_ptr(),
+                applied.len(),
+                &mut applied_len,
+            );
+            assert_eq!(res, BIDIFF_OK);
+            assert_eq!(applied, newer);
+
+            let res = bidiff_patch(
+                older.as_ptr(),
+                older.len(),
+                patch.as_ptr(),
+                0,
+                applied.as_mut_ptr(),
+                applied.len(),
+                &mut applied_len,
+            );
+            assert_eq!(res, BIDIFF_ERR_DECODE);
+        }
+    }
+
+    /// Counts the reallocations made by each thread, for [reserve_hint]
+    struct CountingAlloc;
+
+    thread_local! {
+        static REALLOCS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    #[global_allocator]
+    static ALLOC: CountingAlloc = CountingAlloc;
+
+    unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(
+            &self,
+            ptr: *mut u8,
+            layout: std::alloc::Layout,
+            new_size: usize,
+        ) -> *mut u8 {
+            // the thread-local may be gone while the thread is exiting
+            let _ = REALLOCS.try_with(|r| r.set(r.get() + 1));
+            std::alloc::System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[test]
+    fn reserve_hint() {
+        struct CountingWriter {
+            buf: Vec<u8>,
+            writes: usize,
+        }
+
+        impl Write for CountingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.writes += 1;
+                self.buf.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        for i in (0..newer.len()).step_by(1000) {
+            newer[i] = newer[i].wrapping_add(1);
+        }
+
+        let diff_with = |params: &super::DiffParams| {
+            let mut w = CountingWriter {
+                buf: Vec::new(),
+                writes: 0,
+            };
+            super::simple_diff_with_params(&older[..], &newer[..], &mut w, params).unwrap();
+            w
+        };
+        let unhinted = diff_with(&Default::default());
+        let hinted =
+            diff_with(&super::DiffParams::default().with_reserve_hint(Some(newer.len() / 8)));
+
+        assert_eq!(hinted.buf, unhinted.buf);
+        assert_eq!(hinted.writes, 1);
+        assert!(unhinted.writes > 1);
+        assert_eq!(apply_patch(&older[..], &hinted.buf[..]), newer);
+
+        // the patch no longer grows in `out` a few bytes at a time, and fits
+        // in the reserved buffer
+        let reallocs_with = |params: &super::DiffParams| {
+            let mut out = Vec::new();
+            let before = REALLOCS.with(|r| r.get());
+            super::simple_diff_with_params(&older[..], &newer[..], &mut out, params).unwrap();
+            REALLOCS.with(|r| r.get()) - before
+        };
+        let unhinted = reallocs_with(&Default::default());
+        let hinted = reallocs_with(&super::DiffParams::default().with_reserve_hint(Some(4096)));
+        assert!(
+            hinted < unhinted,
+            "{} reallocations with the hint, {} without",
+            hinted,
+            unhinted
+        );
+    }
+
+    #[test]
+    fn coverage_check() {
+        use super::{CoverageCheck, DiffError, DiffParams, Match};
+
+        let older = fixture(4096);
+        let mut newer = older[..2000].to_vec();
+        newer.extend_from_slice(b"inserted");
+        newer.extend_from_slice(&older[1500..]);
+        let params = DiffParams::default().with_coverage_check(true);
+        super::diff(&older[..], &newer[..], &params, |_| Ok::<_, io::Error>(())).unwrap();
+
+        let m = |add_new_start, copy_end| Match {
+            add_old_start: 0,
+            add_new_start,
+            add_length: 0,
+            copy_end,
+        };
+
+        let mut gap = CoverageCheck::new();
+        gap.record(&m(0, 10)).unwrap();
+        assert_eq!(gap.record(&m(12, 20)), Err(DiffError::Gap(10..12)));
+
+        let mut overlap = CoverageCheck::new();
+        overlap.record(&m(0, 10)).unwrap();
+        assert_eq!(overlap.record(&m(8, 20)), Err(DiffError::Overlap(8..10)));
+
+        let mut short = CoverageCheck::new();
+        short.record(&m(0, 10)).unwrap();
+        assert_eq!(short.finish(16), Err(DiffError::Gap(10..16)));
+
+        let mut long = CoverageCheck::new();
+        long.record(&m(0, 10)).unwrap();
+        assert_eq!(long.finish(8), Err(DiffError::OutOfBounds(8..10)));
+    }
+
+    #[test]
+    fn diff_callback_error() {
+        use super::{DiffError, DiffParams};
+        use std::sync::{atomic::AtomicBool, Arc};
+
+        // errors of the callback come back as-is, and those of the diff
+        // through From
+        #[derive(Debug, PartialEq)]
+        enum Error {
+            Full,
+            Diff(DiffError),
+        }
+        impl From<DiffError> for Error {
+            fn from(e: DiffError) -> Self {
+                Error::Diff(e)
+            }
+        }
+
+        let older = fixture(4096);
+        let newer = [&older[2048..], &older[..2048]].concat();
+        let mut budget = 1;
+        let res = super::diff(&older[..], &newer[..], &DiffParams::default(), |_| {
+            if budget == 0 {
+                return Err(Error::Full);
+            }
+            budget -= 1;
+            Ok(())
+        });
+        assert_eq!(res.unwrap_err(), Error::Full);
+
+        let params = DiffParams::default().with_cancel(Some(Arc::new(AtomicBool::new(true))));
+        let res = super::diff(&older[..], &newer[..], &params, |_| Ok::<_, Error>(()));
+        assert_eq!(res.unwrap_err(), Error::Diff(DiffError::Cancelled));
+        // blocks that changed are diffed like files
+        let mut edited = older.clone();
+        edited[100] ^= 1;
+        let res = super::diff_blocks(
+            &older[..],
+            &edited[..],
+            512,
+            &params,
+            |_| Ok::<_, Error>(()),
+        );
+        assert_eq!(res.unwrap_err(), Error::Diff(DiffError::Cancelled));
+    }
+
+    #[test]
+    fn auto_diff() {
+        use super::auto::Compression;
+
+        let older = fixture(4096);
+        let mut newer = older[..1000].to_vec();
+        newer.extend(b"a very compressible insert. ".iter().cycle().take(8192));
+        newer.extend_from_slice(&older[1000..]);
+
+        let mut patch = Vec::new();
+        let stats = super::auto_diff(&older[..], &newer[..], &mut patch).unwrap();
+        let mut raw = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut raw).unwrap();
+        assert_eq!(stats.raw_size, raw.len() as u64);
+
+        let sizes = bipatch::compressed_sizes(&patch[..]).unwrap();
+        if Compression::available().is_empty() {
+            assert_eq!(stats.compression, Compression::None);
+            assert_eq!(stats.compressed_size, stats.raw_size);
+            assert_eq!(sizes, None);
+        } else {
+            assert_ne!(stats.compression, Compression::None);
+            // magic, version, flags, then both sizes
+            assert_eq!(stats.compressed_size, patch.len() as u64 - 28);
+            assert!(stats.ratio() < 1.0);
+            let sizes = sizes.unwrap();
+            assert_eq!(sizes.raw, stats.raw_size);
+            assert_eq!(sizes.compressed, stats.compressed_size);
+        }
+
+        let patch = bipatch::decompress(&patch[..]).unwrap();
+        let mut r = bipatch::Reader::new(patch, Cursor::new(&older[..])).unwrap();
+        let mut applied = Vec::new();
+        r.read_to_end(&mut applied).unwrap();
+        assert_eq!(applied, newer);
+    }
+
+    #[cfg(feature = "elf")]
+    #[test]
+    fn diff_elf() {
+        use object::{write, Architecture, BinaryFormat, Endianness, SectionKind};
+
+        let elf = |text: &[u8], data: &[u8]| {
+            let mut obj =
+                write::Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+            let text_id = obj.add_section(Vec::new(), b".text".to_vec(), SectionKind::Text);
+            obj.append_section_data(text_id, text, 16);
+            let data_id = obj.add_section(Vec::new(), b".data".to_vec(), SectionKind::Data);
+            obj.append_section_data(data_id, data, 16);
+            obj.write().unwrap()
+        };
+
+        let text = fixture(8192);
+        let data: Vec<u8> = (0..4096_u32).map(|i| (i * 13 % 241) as u8).collect();
+        let mut new_data = data.clone();
+        new_data.truncate(3000);
+        new_data.extend_from_slice(b"changed data section");
+
+        let older = elf(&text[..], &data[..]);
+        let newer = elf(&text[..], &new_data[..]);
+
+        let mut patch = Vec::new();
+        super::diff_elf(&older[..], &newer[..], &Default::default(), &mut patch).unwrap();
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        // unchanged parts are taken from older, so they're all zeroes in the
+        // patch rather than stored as-is
+        let nonzero = patch.iter().filter(|&&b| b != 0).count();
+        assert!(nonzero < 1024, "{} non-zero bytes", nonzero);
+
+        assert!(super::diff_elf(
+            &older[..],
+            b"not an elf",
+            &Default::default(),
+            &mut Vec::new()
+        )
+        .is_err());
+
+        // a section whose end overflows, which object doesn't check
+        let mut overflowing = newer.clone();
+        let u64_at = |elf: &[u8], at: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&elf[at..at + 8]);
+            u64::from_le_bytes(bytes) as usize
+        };
+        let (shoff, shnum) = (u64_at(&newer, 0x28), newer[0x3c] as usize);
+        let text = (0..shnum)
+            .map(|i| shoff + i * 64)
+            .find(|&header| newer[header + 4] == 1 && u64_at(&newer, header + 0x20) > 0)
+            .expect("a section with contents");
+        overflowing[text + 0x18..text + 0x20].copy_from_slice(&(u64::MAX - 10).to_le_bytes());
+        let e = super::diff_elf(
+            &older[..],
+            &overflowing[..],
+            &Default::default(),
+            &mut Vec::new(),
+        )
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "match-list")]
+    #[test]
+    fn match_list() {
+        use super::{read_match_list, write_match_list, Match};
+
+        let older: Vec<u8> = (0..32768_u32).map(|i| (i * 23 % 239) as u8).collect();
+        let mut newer = older[10000..].to_vec();
+        newer.extend_from_slice(b"moved around");
+        newer.extend_from_slice(&older[..10000]);
+        newer[5000..5050].fill(0x42);
+
+        let mut matches = Vec::new();
+        super::diff(&older[..], &newer[..], &Default::default(), |m| {
+            matches.push(m);
+            Ok::<_, io::Error>(())
+        })
+        .unwrap();
+        assert!(matches.len() > 2);
+
+        let mut list = Vec::new();
+        write_match_list(&matches, &mut list).unwrap();
+        let read = read_match_list(&mut &list[..]).unwrap();
+        let fields = |m: &Match| (m.add_old_start, m.add_new_start, m.add_length, m.copy_end);
+        assert_eq!(
+            read.iter().map(fields).collect::<Vec<_>>(),
+            matches.iter().map(fields).collect::<Vec<_>>()
+        );
+        assert!(list.len() < matches.len() * 16);
+
+        let e = read_match_list(&mut &list[..list.len() - 1]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        // a match starting before the beginning of "older"
+        let mut list = Vec::new();
+        write_match_list(
+            &[Match {
+                add_old_start: 0,
+                add_new_start: 0,
+                add_length: 4,
+                copy_end: 4,
+            }],
+            &mut list,
+        )
+        .unwrap();
+        list[0] = 2;
+        list.extend_from_slice(&[9, 0, 0, 0]);
+        let e = read_match_list(&mut &list[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let backwards = Match {
+            add_old_start: 0,
+            add_new_start: 10,
+            add_length: 4,
+            copy_end: 8,
+        };
+        let e = write_match_list(&[backwards], &mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn simple_diff_files() {
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("bidiff-mmap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        newer[1000..1100].fill(0xAA);
+        newer.extend_from_slice(b"appended");
+
+        let diff_files = |older: &[u8], newer: &[u8]| {
+            let (old_path, new_path) = (dir.join("old"), dir.join("new"));
+            fs::write(&old_path, older).unwrap();
+            fs::write(&new_path, newer).unwrap();
+            let mut patch = Vec::new();
+            // Safety: nothing else touches these files
+            unsafe {
+                super::simple_diff_files(&old_path, &new_path, &mut patch, &Default::default())
+            }
+            .unwrap();
+            assert_eq!(apply_patch(older, &patch[..]), newer);
+        };
+        diff_files(&older[..], &newer[..]);
+        diff_files(&[], &newer[..]);
+        diff_files(&older[..], &[]);
+        diff_files(&[], &[]);
+
+        assert!(unsafe {
+            super::simple_diff_files(
+                &dir.join("missing"),
+                &dir.join("new"),
+                &mut Vec::new(),
+                &Default::default(),
+            )
+        }
+        .is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn diff_async() {
+        use super::DiffParams;
+
+        let older = fixture(65536);
+        let mut newer = older[20000..].to_vec();
+        newer.extend_from_slice(b"async");
+        newer.extend_from_slice(&older[..20000]);
+
+        let mut expected = Vec::new();
+        super::diff(&older, &newer, &DiffParams::default(), |m| {
+            expected.push((m.add_old_start, m.add_new_start, m.add_length, m.copy_end));
+            Ok::<_, io::Error>(())
+        })
+        .unwrap();
+        let key = |m: &super::Match| (m.add_old_start, m.add_new_start, m.add_length, m.copy_end);
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (matches, stats) =
+                super::diff_async(older.clone(), newer.clone(), DiffParams::default())
+                    .await
+                    .unwrap();
+            assert_eq!(matches.iter().map(key).collect::<Vec<_>>(), expected);
+            assert_eq!(stats.matches, expected.len());
+
+            let mut rx =
+                super::diff_async_channel(older.clone(), newer.clone(), DiffParams::default());
+            let mut received = Vec::new();
+            while let Some(m) = rx.recv().await {
+                received.push(key(&m.unwrap()));
+            }
+            assert_eq!(received, expected);
+
+            // a panic ends the channel with an error rather than just ending it
+            let params = DiffParams::default().with_progress(Some(std::sync::Arc::new(|event| {
+                if let super::ProgressEvent::Scanned { .. } = event {
+                    panic!("progress panicked");
+                }
+            })));
+            let mut rx = super::diff_async_channel(older.clone(), newer.clone(), params);
+            let mut last = None;
+            while let Some(m) = rx.recv().await {
+                last = Some(m);
+            }
+            let e = last.unwrap().unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::Other);
+            assert!(e.to_string().contains("progress panicked"), "{}", e);
+        });
+    }
+
+    #[test]
+    fn diff_channel() {
+        let older = fixture(4096);
+        let mut newer = older[..3000].to_vec();
+        newer.extend_from_slice(b"sent over a channel");
+        newer.extend_from_slice(&older[2000..]);
+
+        let rx = super::diff_channel(older.clone(), newer.clone(), Default::default());
+        let patch = encode(&older[..], &newer[..], |on_match| {
+            for m in rx {
+                on_match(m.unwrap()).unwrap();
+            }
+        });
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+
+        // dropping the receiver early stops the scan without blocking
+        let rx = super::diff_channel(older.clone(), newer.clone(), Default::default());
+        rx.recv().unwrap().unwrap();
+        drop(rx);
+
+        // a panic ends the channel with an error rather than just ending it
+        let params =
+            super::DiffParams::default().with_progress(Some(std::sync::Arc::new(|event| {
+                if let super::ProgressEvent::Scanned { .. } = event {
+                    panic!("progress panicked");
+                }
+            })));
+        let rx = super::diff_channel(older.clone(), newer.clone(), params);
+        let e = rx.into_iter().find_map(Result::err).unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        assert_eq!(e.to_string(), "diff thread panicked: progress panicked");
+    }
+
+    #[test]
+    fn signature() {
+        use super::enc::{Writer, WriterOptions};
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[2000..2006].copy_from_slice(b"signed");
+
+        let write = |options: &WriterOptions| {
+            let mut patch = Vec::new();
+            let mut w = Writer::with_options(&mut patch, options).unwrap();
+            let mut translator = super::Translator::new(&older[..], &newer[..], |c| w.write(c));
+            super::diff(&older[..], &newer[..], &Default::default(), |m| {
+                translator.translate(m)
+            })
+            .unwrap();
+            translator.close().unwrap();
+            patch
+        };
+
+        let unsigned = write(&Default::default());
+        assert_eq!(bipatch::signature(&unsigned[..]).unwrap(), None);
+        assert_eq!(bipatch::signed_bytes(&unsigned[..]).unwrap(), &unsigned[..]);
+
+        let dummy = hmac_sha256::Hash::hash(bipatch::signed_bytes(&unsigned[..]).unwrap());
+        let signed = write(&WriterOptions {
+            signature: Some(dummy.to_vec()),
+            ..Default::default()
+        });
+        assert_eq!(bipatch::signature(&signed[..]).unwrap(), Some(&dummy[..]));
+        let signed_bytes = bipatch::signed_bytes(&signed[..]).unwrap();
+        assert_eq!(signed_bytes, &unsigned[..]);
+        assert!(!signed_bytes.windows(dummy.len()).any(|w| w == dummy));
+
+        assert_eq!(apply_patch(&older[..], &signed[..]), newer);
+    }
+
+    #[test]
+    fn available_parallelism() {
+        use super::{parallelism_or_one, DiffParams};
+
+        assert!(super::available_parallelism() >= 1);
+
+        let failed = parallelism_or_one(Err(io::Error::other("sandboxed")));
+        assert_eq!(failed, 1);
+        assert!(DiffParams::new(failed, Some(failed)).is_ok());
+    }
+
+    #[test]
+    fn estimate_duration() {
+        use super::{estimate_duration, DiffParams};
+
+        let params = DiffParams::default();
+        let small = estimate_duration(1 << 20, 1 << 20, &params);
+        let large = estimate_duration(1 << 30, 1 << 30, &params);
+        assert!(small > std::time::Duration::ZERO);
+        assert!(large > small * 512);
+        assert!(estimate_duration(0, 0, &params) > std::time::Duration::ZERO);
+
+        let skipped = DiffParams::default().with_max_sort_bytes(Some(1 << 20));
+        assert!(estimate_duration(1 << 30, 1 << 30, &skipped) < large);
+    }
+
+    #[test]
+    fn old_regions() {
+        use super::Match;
+        use integer_encoding::VarInt;
+
+        let older = fixture(4096);
+        let mut newer = older[1000..2000].to_vec();
+        newer.extend_from_slice(b"literal");
+        newer.extend_from_slice(&older[100..300]);
+        newer.extend_from_slice(&older[1500..2500]);
+
+        let patch = encode(&older[..], &newer[..], |on_match| {
+            on_match(Match {
+                add_old_start: 1000,
+                add_new_start: 0,
+                add_length: 1000,
+                copy_end: 1007,
+            })
+            .unwrap();
+            on_match(Match {
+                add_old_start: 100,
+                add_new_start: 1007,
+                add_length: 200,
+                copy_end: 1207,
+            })
+            .unwrap();
+            on_match(Match {
+                add_old_start: 1500,
+                add_new_start: 1207,
+                add_length: 1000,
+                copy_end: 2207,
+            })
+            .unwrap();
+        });
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        assert_eq!(
+            bipatch::old_regions(&patch[..]).unwrap(),
+            vec![(100, 300), (1000, 2500)]
+        );
+
+        let mut segmented = Vec::new();
+        let params = super::DiffParams::new(1, Some(1000)).unwrap();
+        super::segmented_diff_with_params(&older[..], &newer[..], &mut segmented, &params).unwrap();
+        let regions = bipatch::old_regions(&segmented[..]).unwrap();
+        assert!(regions
+            .iter()
+            .all(|&(start, end)| start < end && end <= 4096));
+
+        // seeks that overflow are rejected rather than wrapped around
+        let mut overflowing = patch[..12].to_vec();
+        for _ in 0..2 {
+            overflowing.extend_from_slice(&[0, 0]);
+            overflowing.extend_from_slice(&i64::MAX.encode_var_vec());
+        }
+        match bipatch::old_regions(&overflowing[..]) {
+            Err(bipatch::DecodeError::IO(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn translate_all() {
+        let older = b"the quick brown fox jumps over the lazy dog".repeat(20);
+        let mut newer = older.clone();
+        newer[100..120].fill(b'!');
+        newer.extend_from_slice(b"and then some");
+
+        let params = super::DiffParams::default();
+        let matches: Vec<_> = super::matches(&older, &newer, &params).collect();
+        let copy = |m: &super::Match| super::Match { ..*m };
+
+        let one_by_one = encode(&older, &newer, |on_match| {
+            for m in &matches {
+                on_match(copy(m)).unwrap();
+            }
+        });
+        let mut all = Vec::new();
+        let mut w = super::enc::Writer::new(&mut all).unwrap();
+        let mut translator = super::Translator::new(&older, &newer, |c| w.write(c));
+        translator.translate_all(matches.iter().map(copy)).unwrap();
+        translator.close().unwrap();
+
+        assert_eq!(all, one_by_one);
+        assert_eq!(apply_patch(&older, &all), newer);
+    }
+
+    #[test]
+    fn translator_close() {
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[100..105].copy_from_slice(b"close");
+
+        // close the translator, which releases its borrow of the writer, and
+        // only then finish the writer
+        let mut patch = Vec::new();
+        let mut w = super::enc::Writer::with_manifest(&mut patch).unwrap();
+        let mut translator = super::Translator::new(&older[..], &newer[..], |c| w.write(c));
+        super::diff(&older[..], &newer[..], &Default::default(), |m| {
+            translator.translate(m)
+        })
+        .unwrap();
+        translator.close().unwrap();
+        w.finish().unwrap();
+
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "without calling close()")]
+    fn translator_dropped_without_close() {
+        let older = [1, 2, 3, 4];
+        let mut translator =
+            super::Translator::new(&older[..], &older[..], |_| Ok::<_, io::Error>(()));
+        super::diff(&older[..], &older[..], &Default::default(), |m| {
+            translator.translate(m)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn translator_dropped_after_error() {
+        let older = fixture(4096);
+        let newer = [&older[..1000], b"something new", &older[1000..]].concat();
+
+        // writing a control fails, and `?` drops the translator right away
+        let res = (|| -> io::Result<()> {
+            let mut translator = super::Translator::new(&older[..], &newer[..], |_| {
+                Err(io::Error::other("disk full"))
+            });
+            super::diff(&older[..], &newer[..], &Default::default(), |m| {
+                translator.translate(m)
+            })?;
+            translator.close()
+        })();
+        assert_eq!(res.unwrap_err().to_string(), "disk full");
+
+        // matches stop coming after some were translated
+        let mut translator =
+            super::Translator::new(&older[..], &newer[..], |_| Ok::<_, io::Error>(()));
+        let res = super::diff(&older[..], &newer[..], &Default::default(), |m| {
+            translator.translate(m)?;
+            Err(io::Error::other("no more matches"))
+        });
+        let e = translator.close_after(res).unwrap_err();
+        assert_eq!(e.to_string(), "no more matches");
+    }
+
+    #[test]
+    fn auto_parallel_threshold() {
+        use super::{DiffParams, AUTO_SCAN_CHUNK_SIZE};
+
+        // the chunks only depend on the size of "newer", not on the machine
+        let params = DiffParams::default().with_auto_parallel_threshold(1024);
+        assert_eq!(params.chunk_size_for(1000), None);
+        assert_eq!(params.chunk_size_for(4096), Some(AUTO_SCAN_CHUNK_SIZE));
+
+        let explicit = DiffParams::new(1, Some(100))
+            .unwrap()
+            .with_auto_parallel_threshold(1024);
+        assert_eq!(explicit.chunk_size_for(1000), Some(100));
+
+        let never = DiffParams::default().with_auto_parallel_threshold(usize::MAX);
+        assert_eq!(never.chunk_size_for(1 << 30), None);
+
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        newer[30000..30005].copy_from_slice(b"chunk");
+        super::assert_cycle_with_params(&older[..], &newer[..], &params);
+    }
+
+    #[test]
+    fn diff_controls() {
+        let older: Vec<u8> = (0..4096_u32).map(|i| (i * 13 % 241) as u8).collect();
+        let mut newer = older.clone();
+        newer[2000..2010].copy_from_slice(b"controls!!");
+        newer.extend_from_slice(b"trailing literal bytes");
+
+        let mut controls = Vec::new();
+        let mut patch = Vec::new();
+        let mut w = super::enc::Writer::new(&mut patch).unwrap();
+        super::diff_controls(&older[..], &newer[..], &Default::default(), |c| {
+            controls.push(c.to_owned());
+            w.write(c)
+        })
+        .unwrap();
+        w.flush().unwrap();
+        drop(w);
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+
+        let mut owned = Vec::new();
+        super::diff_owned(older.clone(), newer.clone(), &Default::default(), |c| {
+            owned.push(c.to_owned());
+            Ok::<_, std::io::Error>(())
+        })
+        .unwrap();
+        assert_eq!(controls, owned);
+    }
+
+    #[test]
+    fn known_identical_prefix() {
+        let older: Vec<u8> = (0..16384_u32).map(|i| (i * 31 % 251) as u8).collect();
+        let mut newer = older.clone();
+        newer[12000..12016].copy_from_slice(b"changed at 12000");
+        newer.extend_from_slice(b"appended to newer");
+
+        for scan_chunk_size in [None, Some(1000)] {
+            for (known, expected) in [(10000, 10000), (13000, 12000), (1 << 20, 12000)] {
+                let params = super::DiffParams::new(1, scan_chunk_size)
+                    .unwrap()
+                    .with_known_identical_prefix(Some(known));
+
+                let mut matches = Vec::new();
+                super::diff(&older[..], &newer[..], &params, |m| {
+                    matches.push(super::Match { ..m });
+                    Ok::<_, std::io::Error>(())
+                })
+                .unwrap();
+                assert_eq!(matches[0].add_old_start, 0);
+                assert_eq!(matches[0].add_new_start, 0);
+                assert_eq!(matches[0].add_length, expected);
+                assert_eq!(matches[0].copy_end, expected);
+
+                let mut patch = Vec::new();
+                super::simple_diff_with_params(&older[..], &newer[..], &mut patch, &params)
+                    .unwrap();
+                assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+            }
+        }
+    }
+
+    #[test]
+    fn estimate_patch_size() {
+        let older: Vec<u8> = (0..8192_u32).map(|i| (i * 11 % 247) as u8).collect();
+        let mut newer = older.clone();
+        newer[3000..3020].copy_from_slice(b"twenty changed bytes");
+        newer.extend_from_slice(b"appended literal bytes");
+        newer.drain(500..600);
+
+        // chunks end with a control of their own, which is counted too
+        for (checksum, chunk_size) in [(false, None), (true, None), (false, Some(1000))] {
+            let params = super::DiffParams::new(1, chunk_size)
+                .unwrap()
+                .with_checksum(checksum);
+            let mut patch = Vec::new();
+            super::simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+            let estimate = super::estimate_patch_size(&older, &newer, &params).unwrap();
+            assert_eq!(estimate, patch.len());
+        }
+    }
+
+    #[test]
+    fn translator_buffer() {
+        let older: Vec<u8> = (0..4096_u32).map(|i| (i * 13 % 241) as u8).collect();
+        let mut newer = older.clone();
+        newer[1000..1010].copy_from_slice(b"buffered!!");
+
+        let params = super::DiffParams::default();
+        let matches: Vec<_> = super::matches(&older, &newer, &params).collect();
+        let mut buf = vec![0xFF; 10];
+        for _ in 0..2 {
+            let mut patch = Vec::new();
+            let mut w = super::enc::Writer::new(&mut patch).unwrap();
+            let mut translator =
+                super::Translator::with_buffer(&older, &newer, buf, |c| w.write(c));
+            translator
+                .translate_all(matches.iter().map(|m| super::Match { ..*m }))
+                .unwrap();
+            buf = translator.into_buffer().unwrap();
+            assert!(buf.capacity() >= 4096);
+            drop(w);
+            assert_eq!(apply_patch(&older, &patch), newer);
+        }
+    }
+
+    #[test]
+    fn match_lengths() {
+        use super::Match;
+
+        let m = Match {
+            add_old_start: 100,
+            add_new_start: 10,
+            add_length: 5,
+            copy_end: 18,
+        };
+        assert_eq!(m.copy_start(), 15);
+        assert_eq!(m.copy_length().unwrap(), 3);
+        assert_eq!(
+            m.total_new_bytes().unwrap(),
+            m.add_length + m.copy_length().unwrap()
+        );
+        assert!(!m.is_pure_copy() && !m.is_pure_add());
+
+        let copy = Match { add_length: 0, ..m };
+        assert_eq!((copy.copy_start(), copy.copy_length().unwrap()), (10, 8));
+        assert!(copy.is_pure_copy() && !copy.is_pure_add());
+
+        let add = Match { copy_end: 15, ..m };
+        assert_eq!(
+            (add.copy_length().unwrap(), add.total_new_bytes().unwrap()),
+            (0, 5)
+        );
+        assert!(add.is_pure_add() && !add.is_pure_copy());
+
+        let empty = Match {
+            add_length: 0,
+            copy_end: 10,
+            ..m
+        };
+        assert_eq!(empty.total_new_bytes().unwrap(), 0);
+        assert!(empty.is_pure_add() && empty.is_pure_copy());
+
+        // malformed matches, ending before they start
+        let backwards = Match { copy_end: 12, ..m };
+        let e = backwards.copy_length().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let backwards = Match { copy_end: 5, ..m };
+        let e = backwards.total_new_bytes().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reversible() {
+        use super::reversible::{self, Direction};
+
+        let older: Vec<u8> = (0..8192_u32).map(|i| (i * 7 % 253) as u8).collect();
+        let mut newer = older.clone();
+        newer[1000..1100].fill(0xAA);
+        newer.extend_from_slice(b"only in newer");
+
+        let mut bundle = Vec::new();
+        let stats = super::simple_diff_reversible(&older, &newer, &mut bundle, &Default::default())
+            .unwrap();
+        assert!(!stats.forward.is_identity);
+        assert!(!stats.reverse.is_identity);
+
+        let mut out = Vec::new();
+        reversible::apply(&older, &bundle, Direction::Forward, &mut out).unwrap();
+        assert_eq!(out, newer);
+        out.clear();
+        reversible::apply(&newer, &bundle, Direction::Reverse, &mut out).unwrap();
+        assert_eq!(out, older);
+
+        // each patch can be applied on its own
+        let reverse = reversible::patch(&bundle, Direction::Reverse).unwrap();
+        assert_eq!(apply_patch(&newer, reverse), older);
+
+        // a bundle isn't a patch, and truncated bundles are rejected
+        assert!(super::dec::apply(&older, &mut &bundle[..], &mut Vec::new()).is_err());
+        let truncated = &bundle[..bundle.len() - 1];
+        assert!(reversible::patch(truncated, Direction::Forward).is_err());
+        let mut patch = Vec::new();
+        super::simple_diff(&older, &newer, &mut patch).unwrap();
+        assert!(reversible::patch(&patch, Direction::Forward).is_err());
+    }
+
+    #[test]
+    fn copy_target() {
+        use super::{enc, Match, Translator};
+
+        let older = fixture(1024);
+        // newer: older[..512], then [..256] of itself, twice, then literals
+        let mut newer = older[..512].to_vec();
+        newer.extend_from_slice(&older[..256]);
+        newer.extend_from_slice(&older[..256]);
+        newer.extend_from_slice(b"the end");
+
+        let mut patch = Vec::new();
+        let options = enc::WriterOptions {
+            target_copies: true,
+            ..Default::default()
+        };
+        let mut w = enc::Writer::with_options(&mut patch, &options).unwrap();
+        let mut translator = Translator::new(&older, &newer, |c| w.write(c));
+        translator
+            .translate(Match {
+                add_old_start: 0,
+                add_new_start: 0,
+                add_length: 512,
+                copy_end: 512,
+            })
+            .unwrap();
+        translator.copy_target(0..256).unwrap();
+        translator.copy_target(512..768).unwrap();
+        translator
+            .translate(Match {
+                add_old_start: 0,
+                add_new_start: 1024,
+                add_length: 0,
+                copy_end: newer.len(),
+            })
+            .unwrap();
+        translator.close().unwrap();
+        w.finish().unwrap();
+
+        // the repeats aren't stored
+        assert!(patch.len() < 512 + 64, "{} bytes", patch.len());
+        assert_eq!(apply_patch(&older, &patch), newer);
+        let mut out = Vec::new();
+        let len = super::dec::apply(&older, &mut &patch[..], &mut out).unwrap();
+        assert_eq!(out, newer);
+        assert_eq!(len, newer.len() as u64);
+
+        // copies of what wasn't reconstructed, or past the end of newer, fail
+        let mut w = enc::Writer::with_options(Vec::new(), &options).unwrap();
+        let mut translator = Translator::new(&older, &newer, |c| w.write(c));
+        let e = translator.copy_target(0..1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        translator
+            .translate(Match {
+                add_old_start: 0,
+                add_new_start: 0,
+                add_length: 512,
+                copy_end: 1000,
+            })
+            .unwrap();
+        for src in [500..1001, 0..100] {
+            let e = translator.copy_target(src.clone()).unwrap_err();
+            let e = e.into_inner().unwrap().downcast::<super::DiffError>();
+            assert_eq!(*e.unwrap(), super::DiffError::TargetCopy(src));
+        }
+    }
+
+    #[test]
+    fn diff_owned() {
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[100..105].copy_from_slice(b"owned");
+
+        let mut patch = Vec::new();
+        let mut w = super::enc::Writer::new(&mut patch).unwrap();
+        let stats = super::diff_owned(older.clone(), newer.clone(), &Default::default(), |c| {
+            w.write(c)
+        })
+        .unwrap();
+        assert!(!stats.is_identity);
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    #[test]
+    fn canonicalize() {
+        use super::{canonicalize, DiffParams, Match, TiePolicy};
+
+        // not periodic, so that there's only one good alignment for each part
+        let older: Vec<u8> = (0..8192_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut newer = older[..3000].to_vec();
+        newer.extend_from_slice(b"some inserted bytes");
+        newer.extend_from_slice(&older[2500..6000]);
+        for i in (0..newer.len()).step_by(700) {
+            newer[i] = newer[i].wrapping_add(3);
+        }
+
+        let matches = |params: &DiffParams| {
+            let mut matches = Vec::new();
+            super::diff(&older[..], &newer[..], params, |m| {
+                matches.push(m);
+                Ok::<_, io::Error>(())
+            })
+            .unwrap();
+            matches
+        };
+        let reference = matches(&Default::default());
+
+        // same alignments, split differently and with shorter add regions
+        let perturbed: Vec<Match> = reference
+            .iter()
+            .flat_map(|m| {
+                let half = m.add_length / 2;
+                let trimmed = half.min(3);
+                vec![
+                    Match {
+                        add_old_start: m.add_old_start,
+                        add_new_start: m.add_new_start,
+                        add_length: half,
+                        copy_end: m.add_new_start + half,
+                    },
+                    Match {
+                        add_old_start: m.add_old_start + half,
+                        add_new_start: m.add_new_start + half,
+                        add_length: m.add_length - half - trimmed,
+                        copy_end: m.copy_end,
+                    },
+                ]
+            })
+            .collect();
+        let tie_match = matches(&DiffParams::default().with_tie_policy(TiePolicy::Match));
+
+        let fields = |ms: Vec<Match>| {
+            ms.into_iter()
+                .map(|m| (m.add_old_start, m.add_new_start, m.add_length, m.copy_end))
+                .collect::<Vec<_>>()
+        };
+        let canonical = fields(canonicalize(&older[..], &newer[..], reference));
+        assert_eq!(
+            fields(canonicalize(&older[..], &newer[..], perturbed)),
+            canonical
+        );
+        assert_eq!(
+            fields(canonicalize(&older[..], &newer[..], tie_match)),
+            canonical
+        );
+
+        let patch = encode(&older[..], &newer[..], |on_match| {
+            for (add_old_start, add_new_start, add_length, copy_end) in canonical {
+                on_match(Match {
+                    add_old_start,
+                    add_new_start,
+                    add_length,
+                    copy_end,
+                })
+                .unwrap();
+            }
+        });
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        use super::telemetry::*;
+        use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, SharedString, Unit};
+        use std::{
+            collections::HashMap,
+            sync::{
+                atomic::{AtomicU64, Ordering},
+                Arc, Mutex,
+            },
+        };
+
+        struct Values(Mutex<Vec<f64>>);
+
+        impl metrics::HistogramFn for Values {
+            fn record(&self, value: f64) {
+                self.0.lock().unwrap().push(value);
+            }
+        }
+
+        #[derive(Default)]
+        struct Capture {
+            counters: Mutex<HashMap<String, Arc<AtomicU64>>>,
+            histograms: Mutex<HashMap<String, Arc<Values>>>,
+        }
+
+        impl metrics::Recorder for Capture {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                let mut counters = self.counters.lock().unwrap();
+                Counter::from_arc(counters.entry(key.name().to_string()).or_default().clone())
+            }
+
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+
+            fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+                let mut histograms = self.histograms.lock().unwrap();
+                let values = histograms
+                    .entry(key.name().to_string())
+                    .or_insert_with(|| Arc::new(Values(Mutex::new(Vec::new()))));
+                Histogram::from_arc(values.clone())
+            }
+        }
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer.extend_from_slice(b"metrics");
+
+        let capture = Capture::default();
+        let mut patch = Vec::new();
+        metrics::with_local_recorder(&capture, || {
+            super::simple_diff(&older[..], &newer[..], &mut patch).unwrap();
+        });
+
+        let counter = |name: &str| capture.counters.lock().unwrap()[name].load(Ordering::Relaxed);
+        assert_eq!(counter(OLDER_BYTES), older.len() as u64);
+        assert_eq!(counter(NEWER_BYTES), newer.len() as u64);
+        assert_eq!(counter(PATCH_BYTES), patch.len() as u64);
+
+        let histograms = capture.histograms.lock().unwrap();
+        for name in &[SORT_SECONDS, SCAN_SECONDS] {
+            let values = histograms[*name].0.lock().unwrap();
+            assert_eq!(values.len(), 1);
+            assert!(values[0] >= 0.0);
+        }
+    }
+
+    #[test]
+    fn target_copies() {
+        use super::DiffParams;
+
+        let older = fixture(4096);
+        let novel: Vec<u8> = (0..512_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut newer = older[..2048].to_vec();
+        for _ in 0..4 {
+            newer.extend_from_slice(&novel);
+        }
+        newer.extend_from_slice(&older[2048..]);
+        newer.extend_from_slice(&novel);
+        // a copy that overlaps what it produces
+        newer.extend_from_slice(&b"abc".repeat(200));
+
+        let mut plain = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut plain).unwrap();
+        let mut patch = Vec::new();
+        let params = DiffParams::default().with_target_copies(true);
+        super::simple_diff_with_params(&older[..], &newer[..], &mut patch, &params).unwrap();
+
+        assert!(patch.len() + 2048 < plain.len());
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        assert_eq!(
+            bipatch::old_regions(&patch[..]).unwrap(),
+            bipatch::old_regions(&plain[..]).unwrap()
+        );
+
+        // with reads that stop in the middle of target copies
+        let mut r = bipatch::Reader::new(&patch[..], Cursor::new(&older[..])).unwrap();
+        let mut fresh = Vec::new();
+        let mut buf = [0u8; 7];
+        loop {
+            let n = r.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            fresh.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(fresh, newer);
+    }
+
+    /// Apply controls that may read out of bounds of "older", returning what
+    /// the reader made of them
+    fn apply_controls(older: &[u8], controls: &[(usize, i64)]) -> io::Result<Vec<u8>> {
+        let patch = controls_patch(controls);
+        let mut r = bipatch::Reader::new(&patch[..], Cursor::new(older)).unwrap();
+        let mut fresh = Vec::new();
+        r.read_to_end(&mut fresh)?;
+        Ok(fresh)
+    }
+
+    /// Same as [apply_controls], with [super::dec::apply]
+    fn dec_apply_controls(older: &[u8], controls: &[(usize, i64)]) -> io::Result<Vec<u8>> {
+        let patch = controls_patch(controls);
+        let mut fresh = Vec::new();
+        super::dec::apply(older, &mut &patch[..], &mut fresh)?;
+        Ok(fresh)
+    }
+
+    /// A patch of controls adding `add_len` bytes, copying 4, then seeking
+    fn controls_patch(controls: &[(usize, i64)]) -> Vec<u8> {
+        let mut patch = Vec::new();
+        let mut w = super::enc::Writer::new(&mut patch).unwrap();
+        for &(add_len, seek) in controls {
+            let add = vec![1u8; add_len];
+            w.write(&super::Control::new(&add, b"copy", seek)).unwrap();
+        }
+        patch
+    }
+
+    fn out_of_bounds_offset(e: &io::Error) -> Option<i64> {
+        match e.get_ref()?.downcast_ref::<bipatch::DecodeError>()? {
+            bipatch::DecodeError::OutOfBounds(offset) => Some(*offset),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let older = [0u8; 64];
+
+        // seeking to the very end is fine, past it isn't
+        assert!(apply_controls(&older, &[(0, 64), (0, 0)]).is_ok());
+        let e = apply_controls(&older, &[(0, 65)]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(out_of_bounds_offset(&e), Some(65));
+
+        let e = apply_controls(&older, &[(16, 0), (0, -17)]).unwrap_err();
+        assert_eq!(out_of_bounds_offset(&e), Some(-1));
+
+        // adding past the end
+        let e = apply_controls(&older, &[(0, 60), (8, 0)]).unwrap_err();
+        assert_eq!(out_of_bounds_offset(&e), Some(68));
+
+        let e = apply_controls(&older, &[(0, i64::MAX), (0, i64::MAX)]).unwrap_err();
+        assert_eq!(out_of_bounds_offset(&e), Some(i64::MAX));
+    }
+
+    fn malformed(e: &io::Error) -> Option<super::dec::MalformedPatch> {
+        e.get_ref()?
+            .downcast_ref::<super::dec::MalformedPatch>()
+            .copied()
+    }
+
+    #[test]
+    fn dec_out_of_bounds() {
+        use super::dec::{self, MalformedPatch::*};
+
+        let older = [0u8; 64];
+        let oob = |controls: &[(usize, i64)]| {
+            let e = dec_apply_controls(&older, controls).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            malformed(&e)
+        };
+
+        // seeking to the very end is fine, past it isn't
+        assert!(dec_apply_controls(&older, &[(0, 64), (0, 0)]).is_ok());
+        assert_eq!(oob(&[(0, 65)]), Some(OutOfBounds(65)));
+        assert_eq!(oob(&[(16, 0), (0, -17)]), Some(OutOfBounds(-1)));
+        // adding past the end
+        assert_eq!(oob(&[(0, 60), (8, 0)]), Some(OutOfBounds(68)));
+        // the cursor doesn't wrap around
+        assert_eq!(
+            oob(&[(0, i64::MAX), (0, i64::MAX)]),
+            Some(OutOfBounds(i64::MAX))
+        );
+        assert_eq!(oob(&[(0, i64::MIN)]), Some(OutOfBounds(i64::MIN)));
+
+        let target_patch = |target: std::ops::Range<usize>| {
+            let mut patch = Vec::new();
+            let options = super::enc::WriterOptions {
+                target_copies: true,
+                ..Default::default()
+            };
+            let mut w = super::enc::Writer::with_options(&mut patch, &options).unwrap();
+            let mut control = super::Control::new(&[], b"copy", 0);
+            control.target = target;
+            w.write(&control).unwrap();
+            w.finish().unwrap();
+            patch
+        };
+        let apply = |patch: &[u8]| {
+            let mut fresh = Vec::new();
+            dec::apply(&older, &mut &patch[..], &mut fresh).map(|_| fresh)
+        };
+        // overlapping copies are fine, copies from what's not there yet aren't
+        assert_eq!(apply(&target_patch(2..12)).unwrap(), b"copypypypypypy");
+        let e = apply(&target_patch(4..8)).unwrap_err();
+        assert_eq!(malformed(&e), Some(TargetOutOfBounds(4)));
+    }
+
+    #[test]
+    fn extreme_seeks() {
+        use super::dec::MalformedPatch::*;
+
+        let older: Vec<u8> = (0..64).collect();
+
+        // seeks are relative to the end of the add region, and can go all the
+        // way back to the start of "older"
+        let fresh = dec_apply_controls(&older, &[(8, 56), (0, -64), (2, -2)]).unwrap();
+        assert_eq!(&fresh[..9], &[1, 2, 3, 4, 5, 6, 7, 8, b'c']);
+        assert_eq!(&fresh[16..18], &[1, 2]);
+        assert_eq!(
+            fresh,
+            apply_controls(&older, &[(8, 56), (0, -64), (2, -2)]).unwrap()
+        );
+
+        let cases: &[(&[(usize, i64)], i64)] = &[
+            // out of bounds as soon as the seek lands there, even if the next
+            // one would bring the cursor back in
+            (&[(0, 100), (0, -100)], 100),
+            (&[(32, -33), (0, 33)], -1),
+            // the cursor saturates instead of wrapping around
+            (&[(1, i64::MAX)], i64::MAX),
+            (&[(0, 64), (0, i64::MAX)], i64::MAX),
+            (&[(1, i64::MIN)], i64::MIN + 1),
+            (&[(0, i64::MIN), (0, i64::MAX)], i64::MIN),
+            // adding past the end after seeking to it
+            (&[(0, 64), (1, 0)], 65),
+        ];
+        for &(controls, offset) in cases {
+            let e = dec_apply_controls(&older, controls).unwrap_err();
+            assert_eq!(malformed(&e), Some(OutOfBounds(offset)), "{:?}", controls);
+            let e = apply_controls(&older, controls).unwrap_err();
+            assert_eq!(out_of_bounds_offset(&e), Some(offset), "{:?}", controls);
+        }
+    }
+
+    #[test]
+    fn partition_boundary_matches() {
+        use super::DiffParams;
+
+        let older: Vec<u8> = (0..65536_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        // two matches, swapped around
+        let mut newer = older[40000..].to_vec();
+        newer.extend_from_slice(&older[..40000]);
+
+        let boundary_matches = |partitions| {
+            let params = DiffParams::new(partitions, None).unwrap();
+            let stats =
+                super::diff(&older[..], &newer[..], &params, |_| Ok::<_, io::Error>(())).unwrap();
+            stats.partition_boundary_matches
+        };
+        assert_eq!(boundary_matches(1), 0);
+        // with 2 partitions, only the match of `..40000` crosses the boundary
+        // at 32769, with 4, both cross one
+        assert_eq!(boundary_matches(2), 1);
+        assert_eq!(boundary_matches(4), 2);
+    }
+
+    #[test]
+    fn chunks_and_partitions() {
+        use super::DiffParams;
+
+        let older: Vec<u8> = (0..20000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut newer = older[7000..].to_vec();
+        newer.extend_from_slice(&older[..7000]);
+        newer[100..110].fill(0);
+
+        // many more chunks than partitions, and many more partitions than
+        // chunks
+        for (partitions, chunk_size) in [(1, 97), (2, 500), (16, 20000), (64, 3000)] {
+            let params = DiffParams::new(partitions, Some(chunk_size)).unwrap();
+            let mut patch = Vec::new();
+            super::simple_diff_with_params(&older[..], &newer[..], &mut patch, &params).unwrap();
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+        }
+    }
+
+    #[test]
+    fn chunk_overlap() {
+        use super::DiffParams;
+
+        let mut state = 7_u32;
+        let mut noise = |n: usize| -> Vec<u8> {
+            (0..n)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                })
+                .collect()
+        };
+        let older = noise(20000);
+        // runs of "older" from all over, between bits of noise, so that
+        // plenty of matches straddle chunk boundaries
+        let mut newer = Vec::new();
+        for i in 0..60_usize {
+            let start = i * 7919 % 19000;
+            newer.extend_from_slice(&older[start..start + 150 + i % 50]);
+            newer.extend(noise(20));
+        }
+
+        let diff = |overlap: usize| {
+            let params = DiffParams::new(1, Some(97))
+                .unwrap()
+                .with_chunk_overlap(overlap)
+                .with_coverage_check(true);
+            let mut copied = 0;
+            let patch = encode(&older[..], &newer[..], |on_match| {
+                super::diff(&older[..], &newer[..], &params, |m| {
+                    copied += m.copy_length().unwrap();
+                    on_match(m)
+                })
+                .unwrap();
+            });
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+            let mut fresh = Vec::new();
+            super::simple_diff_with_params(&older[..], &newer[..], &mut fresh, &params).unwrap();
+            assert_eq!(apply_patch(&older[..], &fresh[..]), newer);
+            copied
+        };
+        // overlaps longer than chunks only scan more for nothing
+        let without = diff(0);
+        for overlap in [1, 16, 64, 97, 500] {
+            assert!(diff(overlap) < without, "overlap of {}", overlap);
+        }
+    }
+
+    #[test]
+    fn scan_chunks() {
+        use super::DiffError;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // chunks come out in order, and only a few are scanned ahead of the
+        // consumer, however many there are
+        #[cfg(feature = "parallel")]
+        let workers = rayon::current_num_threads();
+        #[cfg(not(feature = "parallel"))]
+        let workers = 0;
+        let scanned = AtomicUsize::new(0);
+        let mut consumed = Vec::new();
+        super::scan_chunks(
+            10_000,
+            |i| {
+                scanned.fetch_add(1, Ordering::SeqCst);
+                Some(i)
+            },
+            |i, res| {
+                assert_eq!(i, res);
+                let ahead = scanned.load(Ordering::SeqCst) - consumed.len();
+                assert!(ahead <= 2 * workers + 1, "{}", ahead);
+                consumed.push(res);
+                Ok::<_, DiffError>(())
+            },
+        )
+        .unwrap();
+        assert_eq!(consumed, (0..10_000).collect::<Vec<_>>());
+
+        // stopping early, or a cancelled chunk, doesn't wait for the others
+        let res = super::scan_chunks(10_000, Some, |i, _| match i {
+            100 => Err(DiffError::Cancelled),
+            _ => Ok(()),
+        });
+        assert!(matches!(res, Err(DiffError::Cancelled)));
+        let res = super::scan_chunks(
+            10_000,
+            |i| Some(i).filter(|i| *i != 100),
+            |i, _| {
+                assert!(i < 100);
+                Ok::<_, DiffError>(())
+            },
+        );
+        assert!(matches!(res, Err(DiffError::Cancelled)));
+    }
+
+    #[test]
+    fn scan_chunks_panic() {
+        use super::DiffError;
+        use std::{panic, sync::mpsc, time::Duration};
+
+        // a panicking scan (say, from a custom index) propagates to the
+        // caller, rather than leaving the consumer waiting for its chunk
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let res = panic::catch_unwind(|| {
+                super::scan_chunks(
+                    1000,
+                    |i| {
+                        assert_ne!(i, 100, "scan panicked");
+                        Some(i)
+                    },
+                    |_, _| Ok::<_, DiffError>(()),
+                )
+            });
+            let _ = tx.send(res.is_err());
+        });
+        let panicked = rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("scan_chunks hung after a scan panicked");
+        assert!(panicked);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn scan_chunks_in_pool() {
+        use super::DiffError;
+        use rayon::prelude::*;
+        use std::{sync::mpsc, time::Duration};
+
+        // scanning from every thread of a small pool at once, as diffing
+        // several files in a parallel iterator does, still leaves threads to
+        // scan the chunks
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .build()
+                .unwrap();
+            let sums = pool.install(|| {
+                (0..8_usize)
+                    .into_par_iter()
+                    .map(|_| {
+                        let mut sum = 0;
+                        super::scan_chunks(100, Some, |_, i| {
+                            sum += i;
+                            Ok::<_, DiffError>(())
+                        })
+                        .map(|()| sum)
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            });
+            let _ = tx.send(sums.unwrap());
+        });
+        let sums = rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("scan_chunks hung in a parallel iterator");
+        assert_eq!(sums, vec![4950; 8]);
+    }
+
+    #[test]
+    fn merge_threshold() {
+        use super::{DiffError, DiffParams};
+
+        // short runs of "older" with noise in between, which the scanner
+        // splits into many short matches
+        let older: Vec<u8> = (0..20000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut newer = Vec::new();
+        for i in 0..1000_usize {
+            let start = (i * 7919) % (older.len() - 64);
+            newer.extend_from_slice(&older[start..start + 12 + i % 5]);
+            newer.extend_from_slice(&[i as u8; 3][..i % 4]);
+        }
+
+        let controls = |params: &DiffParams| {
+            let mut n = 0;
+            super::diff_controls(&older[..], &newer[..], params, |_| {
+                n += 1;
+                Ok::<_, DiffError>(())
+            })
+            .unwrap();
+            n
+        };
+        let unmerged = controls(&DiffParams::default().with_match_threshold(2).unwrap());
+        for (threshold, chunk_size) in [(0, None), (32, None), (256, None), (256, Some(1000))] {
+            let params = DiffParams::builder()
+                .match_threshold(2)
+                .merge_threshold(Some(threshold))
+                .scan_chunk_size(chunk_size)
+                .coverage_check(true)
+                .build()
+                .unwrap();
+            super::assert_cycle_with_params(&older[..], &newer[..], &params);
+            let merged = controls(&params);
+            if threshold == 0 {
+                assert_eq!(merged, unmerged);
+            } else {
+                assert!(
+                    merged < unmerged,
+                    "{} controls, {} before",
+                    merged,
+                    unmerged
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn empty_matches() {
+        use super::{DiffError, DiffParams, Match, Translator};
+
+        let older: Vec<u8> = (0..20000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut newer = older[7000..].to_vec();
+        newer.extend_from_slice(b"in between");
+        newer.extend_from_slice(&older[..7000]);
+
+        let mut runs = Vec::new();
+        for chunk_size in [None, Some(1000)] {
+            let params = DiffParams::new(1, chunk_size).unwrap();
+            let mut matches = Vec::new();
+            super::diff(&older[..], &newer[..], &params, |m| {
+                assert!(m.copy_end > m.add_new_start, "{:?}", m);
+                matches.push(m);
+                Ok::<_, DiffError>(())
+            })
+            .unwrap();
+            runs.push(matches);
+        }
+
+        // matches that cover nothing change the controls, but not what the
+        // patch rebuilds, wherever they point in "older"
+        let matches = &runs[0][..];
+        let mut with_empty = Vec::new();
+        for (i, m) in matches.iter().enumerate() {
+            with_empty.push(Match {
+                add_old_start: (i * 1237) % older.len(),
+                add_new_start: m.add_new_start,
+                add_length: 0,
+                copy_end: m.add_new_start,
+            });
+            with_empty.push(Match { ..*m });
+        }
+        let patch = |matches: &[Match]| {
+            let mut patch = Vec::new();
+            let mut w = super::enc::Writer::new(&mut patch).unwrap();
+            let mut translator = Translator::new(&older[..], &newer[..], |c| w.write(c));
+            translator
+                .translate_all(matches.iter().map(|m| Match { ..*m }))
+                .unwrap();
+            translator.close().unwrap();
+            w.flush().unwrap();
+            patch
+        };
+        assert_eq!(apply_patch(&older[..], &patch(matches)[..]), newer);
+        assert_eq!(apply_patch(&older[..], &patch(&with_empty)[..]), newer);
+    }
+
+    #[test]
+    fn diff_best_of() {
+        use super::DiffParams;
+
+        let noise = |mut seed: u32| -> Vec<u8> {
+            (0..20000)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 17;
+                    seed ^= seed << 5;
+                    seed as u8
+                })
+                .collect()
+        };
+        let newer = noise(1);
+        // all of "newer" but what's cut from them can be taken from "older"
+        let close = [&newer[..5000], &newer[7000..]].concat();
+        let mut closer = [&newer[..5000], &newer[5100..]].concat();
+        closer[10000] ^= 1;
+        let (unrelated, closer_copy) = (noise(12345), closer.clone());
+        let olders: [&[u8]; 4] = [&unrelated, &close, &closer, &closer_copy];
+
+        // the first of the smallest wins, and its stats are returned
+        let params = DiffParams::default();
+        let (best, stats) = super::diff_best_of(&olders, &newer, &params).unwrap();
+        assert_eq!(best, 2);
+        assert_eq!(stats.copy_bytes, 100);
+        assert_eq!(stats.add_bytes, newer.len() as u64 - 100);
+
+        let (best, _) = super::diff_best_of(&olders[..2], &newer, &params).unwrap();
+        assert_eq!(best, 1);
+
+        assert_eq!(
+            super::diff_best_of(&[], &newer, &params).unwrap_err(),
+            super::DiffError::NoOlder
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn diff_best_of_in_pool() {
+        use super::DiffParams;
+        use std::{sync::mpsc, time::Duration};
+
+        // candidates are diffed on every thread of the pool, and each of
+        // them scans its chunks on the same pool
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        newer[30000..30100].fill(0);
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(2)
+                .build()
+                .unwrap();
+            let params = DiffParams::builder()
+                .scan_chunk_size(Some(4096))
+                .build()
+                .unwrap();
+            let mut unrelated = older.clone();
+            unrelated.reverse();
+            let olders: [&[u8]; 3] = [&unrelated, &older, &unrelated];
+            let res = pool.install(|| super::diff_best_of(&olders, &newer, &params));
+            let _ = tx.send(res.unwrap().0);
+        });
+        let best = rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("diff_best_of hung on a 2-thread pool");
+        assert_eq!(best, 1);
+    }
+
+    #[test]
+    fn max_match_length() {
+        use super::{DiffError, DiffParams};
+
+        let older: Vec<u8> = (0..20000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut newer = older[7000..].to_vec();
+        newer.extend_from_slice(&[b'!'; 3000]);
+        newer.extend_from_slice(&older[..7000]);
+        newer[100..110].fill(0);
+
+        assert!(DiffParams::default()
+            .with_max_match_length(Some(0))
+            .is_err());
+        for max in [1, 7, 1000, 100_000] {
+            let with_max = |builder: super::DiffParamsBuilder| {
+                builder
+                    .max_match_length(Some(max))
+                    .coverage_check(true)
+                    .build()
+                    .unwrap()
+            };
+            for params in [
+                with_max(DiffParams::builder()),
+                with_max(DiffParams::builder().scan_chunk_size(Some(4000))),
+                with_max(DiffParams::builder().known_identical_prefix(Some(5000))),
+                with_max(DiffParams::builder().merge_threshold(Some(4096))),
+            ] {
+                for newer in [&newer[..], &older[..]] {
+                    super::diff(&older[..], newer, &params, |m| {
+                        assert!(m.total_new_bytes().unwrap() <= max, "{:?}", m);
+                        Ok::<_, DiffError>(())
+                    })
+                    .unwrap();
+                    super::assert_cycle_with_params(&older[..], newer, &params);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_encoding() {
+        use super::{dec, DiffParams, DiffStats};
+
+        let older: Vec<u8> = (0..65536_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        // moved blocks, so that chunks end away from the start of older
+        let mut newer = older[40000..].to_vec();
+        newer.extend_from_slice(b"in between");
+        newer.extend_from_slice(&older[..40000]);
+        newer[30000..30010].fill(0);
+
+        let diff = |params: &DiffParams| {
+            let mut patch = Vec::new();
+            let stats: DiffStats =
+                super::simple_diff_with_params(&older[..], &newer[..], &mut patch, params).unwrap();
+            (patch, stats)
+        };
+        let (serial, serial_stats) = diff(&DiffParams::default());
+        for chunk_size in [1000, 4096, 30000] {
+            let params = DiffParams::new(1, Some(chunk_size)).unwrap();
+            let (patch, stats) = diff(&params);
+            assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+            let mut fresh = Vec::new();
+            dec::apply(&older[..], &mut &patch[..], &mut fresh).unwrap();
+            assert_eq!(fresh, newer);
+            assert!(stats.matches > serial_stats.matches);
+            // seams cost a few bytes each
+            assert!(patch.len() < serial.len() + 16 * newer.len() / chunk_size + 64);
+        }
+    }
+
+    #[test]
+    fn verify() {
+        use super::{DiffParams, VerifyError};
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[100..110].copy_from_slice(b"verify me!");
+        newer.extend_from_slice(b"tail");
+        super::verify(&older[..], &newer[..], &DiffParams::default()).unwrap();
+        super::verify(&[], &newer[..], &DiffParams::default()).unwrap();
+        super::verify(&older[..], &[], &DiffParams::default()).unwrap();
+
+        let e = VerifyError::Mismatch {
+            offset: 12,
+            expected: 0xab,
+            actual: 0x01,
+        };
+        assert_eq!(
+            e.to_string(),
+            "reconstructed byte 12 is 0x01, expected 0xab"
+        );
+
+        let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let params = DiffParams::default().with_cancel(Some(cancel));
+        assert_eq!(
+            super::verify(&older[..], &newer[..], &params),
+            Err(VerifyError::Diff(super::DiffError::Cancelled))
+        );
+    }
+
+    #[test]
+    fn checksum() {
+        use super::{dec, enc, DiffParams};
+
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        newer[100..108].copy_from_slice(b"checksum");
+        newer.extend_from_slice(b"tail");
+
+        let mismatch = |e: io::Error| {
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            let inner = e.into_inner().unwrap();
+            assert!(
+                inner.is::<dec::ChecksumMismatch>()
+                    || matches!(
+                        inner.downcast_ref::<bipatch::DecodeError>(),
+                        Some(bipatch::DecodeError::ChecksumMismatch { .. })
+                    ),
+                "{:?}",
+                inner
+            );
+        };
+        let check = |patch: &[u8]| {
+            assert_eq!(apply_patch(&older[..], patch), newer);
+            let mut fresh = Vec::new();
+            dec::apply(&older[..], &mut &patch[..], &mut fresh).unwrap();
+            assert_eq!(fresh, newer);
+
+            let mut corrupt = patch.to_vec();
+            *corrupt.last_mut().unwrap() ^= 1;
+            let mut r = bipatch::Reader::new(&corrupt[..], Cursor::new(&older[..])).unwrap();
+            mismatch(r.read_to_end(&mut Vec::new()).unwrap_err());
+            mismatch(dec::apply(&older[..], &mut &corrupt[..], &mut Vec::new()).unwrap_err());
+
+            // the checksum can't be dropped
+            let truncated = &patch[..patch.len() - 33];
+            let mut r = bipatch::Reader::new(truncated, Cursor::new(&older[..])).unwrap();
+            assert!(r.read_to_end(&mut Vec::new()).is_err());
+            assert!(dec::apply(&older[..], &mut &truncated[..], &mut Vec::new()).is_err());
+        };
+
+        for params in [
+            DiffParams::default(),
+            DiffParams::new(1, Some(4096)).unwrap(),
+        ] {
+            let mut patch = Vec::new();
+            let params = params.with_checksum(true);
+            super::simple_diff_with_params(&older[..], &newer[..], &mut patch, &params).unwrap();
+            check(&patch[..]);
+        }
+
+        let options = enc::WriterOptions {
+            manifest: true,
+            checksum: Some(hmac_sha256::Hash::hash(&newer[..])),
+            ..Default::default()
+        };
+        let mut w = enc::Writer::with_options(Vec::new(), &options).unwrap();
+        let mut translator = super::Translator::new(&older[..], &newer[..], |c| w.write(c));
+        super::diff(&older[..], &newer[..], &DiffParams::default(), |m| {
+            translator.translate(m)
+        })
+        .unwrap();
+        translator.close().unwrap();
+        let patch = w.finish().unwrap();
+        check(&patch[..]);
+        let mut r = bipatch::Reader::new(&patch[..], Cursor::new(&older[..])).unwrap();
+        r.read_to_end(&mut Vec::new()).unwrap();
+        assert!(r.manifest().is_some());
+    }
+
+    #[test]
+    fn dec_apply() {
+        use super::{dec, DiffParams};
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[100..105].copy_from_slice(b"apply");
+        newer.extend_from_slice(&b"tail".repeat(20));
+
+        // the length it returns is that of what it wrote
+        let apply = |patch: &[u8]| {
+            let mut fresh = Vec::new();
+            let len = dec::apply(&older[..], &mut &patch[..], &mut fresh)?;
+            assert_eq!(len, fresh.len() as u64);
+            Ok::<_, io::Error>(fresh)
+        };
+
+        let mut patch = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut patch).unwrap();
+        assert_eq!(apply(&patch[..]).unwrap(), newer);
+
+        let mut targets = Vec::new();
+        let params = DiffParams::default().with_target_copies(true);
+        super::simple_diff_with_params(&older[..], &newer[..], &mut targets, &params).unwrap();
+        assert_eq!(apply(&targets[..]).unwrap(), newer);
+
+        // truncated in the middle of a control
+        let e = apply(&patch[..patch.len() - 3]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        // or of the add length of one, which isn't the end of the controls
+        let mut cut_varint = patch.clone();
+        cut_varint.push(0x80);
+        let e = apply(&cut_varint[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let e = apply(&patch[4..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mut seek_past_end = patch[..12].to_vec();
+        seek_past_end.extend_from_slice(&[0, 0, 0x80, 0x80, 0x01]);
+        let e = apply(&seek_past_end[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dec_applier() {
+        use super::{dec, DiffParams};
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[100..105].copy_from_slice(b"apply");
+        newer[2000..2100].reverse();
+        newer.extend_from_slice(&b"tail".repeat(20));
+
+        let mut patch = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut patch).unwrap();
+
+        // controls and the header can be split anywhere
+        for chunk_len in [1, 7, 100, patch.len()] {
+            let mut applier = dec::Applier::new(&older);
+            let mut fresh = Vec::new();
+            for chunk in patch.chunks(chunk_len) {
+                let progress = applier.step(chunk, &mut fresh).unwrap();
+                assert_eq!(progress.new_pos, fresh.len() as u64);
+                assert!(progress.patch_offset <= patch.len() as u64);
+            }
+            let progress = applier.finish().unwrap();
+            assert!(progress.done);
+            assert_eq!(progress.patch_offset, patch.len() as u64);
+            assert_eq!(fresh, newer);
+        }
+
+        // interrupted halfway, then resumed from the last checkpoint
+        let mut applier = dec::Applier::new(&older);
+        let mut fresh = Vec::new();
+        let mut checkpoint = applier.progress();
+        for chunk in patch[..patch.len() / 2].chunks(13) {
+            checkpoint = applier.step(chunk, &mut fresh).unwrap();
+        }
+        assert!(!checkpoint.done && checkpoint.flags.is_some());
+        fresh.truncate(checkpoint.new_pos as usize);
+        let mut applier = dec::Applier::resume(&older, checkpoint);
+        for chunk in patch[checkpoint.patch_offset as usize..].chunks(13) {
+            applier.step(chunk, &mut fresh).unwrap();
+        }
+        assert!(applier.finish().unwrap().done);
+        assert_eq!(fresh, newer);
+
+        // truncated
+        let mut applier = dec::Applier::new(&older);
+        applier
+            .step(&patch[..patch.len() - 3], &mut Vec::new())
+            .unwrap();
+        let e = applier.finish().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut applier = dec::Applier::new(&older[1..]);
+        let e = applier.step(&patch, &mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mut targets = Vec::new();
+        let params = DiffParams::default().with_target_copies(true);
+        super::simple_diff_with_params(&older[..], &newer[..], &mut targets, &params).unwrap();
+        let mut applier = dec::Applier::new(&older);
+        let e = applier.step(&targets, &mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn dec_applier_write_error() {
+        use super::dec;
+
+        /// Fails the `fail_at`-th write, once
+        struct Flaky {
+            buf: Vec<u8>,
+            writes: usize,
+            fail_at: usize,
+        }
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.writes += 1;
+                if self.writes == self.fail_at {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                self.buf.write(buf)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let older = fixture(4096);
+        // several controls that both add and copy
+        let newer = [
+            &older[..1000],
+            b"something new",
+            &older[1000..3000],
+            b"and more",
+            &older[3000..],
+        ]
+        .concat();
+        let mut patch = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut patch).unwrap();
+
+        let mut counted = Flaky {
+            buf: Vec::new(),
+            writes: 0,
+            fail_at: 0,
+        };
+        dec::Applier::new(&older)
+            .step(&patch, &mut counted)
+            .unwrap();
+
+        // every control writes its add then its copy, so this fails between
+        // the two for some of them, and before the add for others
+        assert!(counted.writes > 2);
+        for fail_at in 1..=counted.writes {
+            let mut out = Flaky {
+                buf: Vec::new(),
+                writes: 0,
+                fail_at,
+            };
+            let mut applier = dec::Applier::new(&older);
+            applier.step(&patch, &mut out).unwrap_err();
+            let checkpoint = applier.progress();
+            assert!(checkpoint.new_pos <= out.buf.len() as u64);
+
+            // retried in place
+            let mut retried = Flaky { ..out };
+            retried.buf.truncate(checkpoint.new_pos as usize);
+            applier.step(&[], &mut retried).unwrap();
+            assert!(applier.finish().unwrap().done);
+            assert_eq!(retried.buf, newer, "retry after write {}", fail_at);
+
+            // resumed from the checkpoint
+            let mut fresh = newer[..checkpoint.new_pos as usize].to_vec();
+            let mut applier = dec::Applier::resume(&older, checkpoint);
+            applier
+                .step(&patch[checkpoint.patch_offset as usize..], &mut fresh)
+                .unwrap();
+            assert!(applier.finish().unwrap().done);
+            assert_eq!(fresh, newer, "resume after write {}", fail_at);
+        }
+    }
+
+    #[test]
+    fn dec_control_iter() {
+        use super::{dec, DiffParams};
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[100..105].copy_from_slice(b"apply");
+        newer[2000..2100].reverse();
+        newer.extend_from_slice(&b"tail".repeat(20));
+
+        // the controls are enough to rebuild "newer"
+        let mut patch = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut patch).unwrap();
+        let (mut fresh, mut old_pos) = (Vec::new(), 0_i64);
+        for c in dec::ControlIter::new(&older, &patch) {
+            let c = c.unwrap();
+            let old_add = &older[old_pos as usize..][..c.add.len()];
+            fresh.extend(c.add.iter().zip(old_add).map(|(d, o)| d.wrapping_add(*o)));
+            fresh.extend_from_slice(c.copy);
+            old_pos += c.add.len() as i64 + c.seek;
+        }
+        assert_eq!(fresh, newer);
+
+        // the same controls as the reader's, target copies included
+        let mut targets = Vec::new();
+        let params = DiffParams::default().with_target_copies(true);
+        super::simple_diff_with_params(&older[..], &newer[..], &mut targets, &params).unwrap();
+        let mut r = dec::Reader::new(&targets[..]).unwrap();
+        let mut controls = dec::ControlIter::new(&older, &targets);
+        while let Some(expected) = r.next_control().unwrap() {
+            let c = controls.next().unwrap().unwrap();
+            assert_eq!(
+                (c.add, c.copy, c.seek, c.target),
+                (expected.add, expected.copy, expected.seek, expected.target)
+            );
+        }
+        assert!(controls.next().is_none());
+        assert_eq!(controls.flags(), Some(r.flags()));
+
+        // errors come once, then nothing
+        let errors = |patch: &[u8]| {
+            let mut controls = dec::ControlIter::new(&older, patch);
+            let e = controls.find_map(Result::err).unwrap();
+            assert!(controls.next().is_none());
+            e.kind()
+        };
+        assert_eq!(
+            errors(&patch[..patch.len() - 3]),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(errors(&patch[4..]), io::ErrorKind::InvalidData);
+        let mut seek_past_end = patch[..12].to_vec();
+        seek_past_end.extend_from_slice(&[0, 0, 0x80, 0x80, 0x01]);
+        assert_eq!(errors(&seek_past_end), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn dec_apply_streaming() {
+        use super::{dec, DiffParams};
+        use std::io::BufWriter;
+
+        /// Records the size of each write it gets
+        struct Writes<'a> {
+            out: &'a mut Vec<u8>,
+            sizes: Vec<usize>,
+        }
+
+        impl Write for Writes<'_> {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.sizes.push(buf.len());
+                self.out.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let older: Vec<u8> = (0..200_000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        // insertions shift the alignment, each one needs a new control
+        let mut newer = Vec::new();
+        for piece in older.chunks(10_000) {
+            newer.extend_from_slice(piece);
+            newer.extend_from_slice(b"inserted");
+        }
+
+        for params in [
+            DiffParams::default(),
+            DiffParams::default().with_target_copies(true),
+        ] {
+            let mut patch = Vec::new();
+            super::simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+
+            let mut largest = 0;
+            let mut r = dec::Reader::new(&patch[..]).unwrap();
+            while let Some(c) = r.next_control().unwrap() {
+                largest = largest.max(c.add.len()).max(c.copy.len() + c.target.len());
+            }
+            assert!(largest < newer.len() / 10);
+
+            let mut fresh = Vec::new();
+            let mut writes = Writes {
+                out: &mut fresh,
+                sizes: Vec::new(),
+            };
+            {
+                let mut out = BufWriter::with_capacity(16, &mut writes);
+                dec::apply(&older, &mut &patch[..], &mut out).unwrap();
+                out.flush().unwrap();
+            }
+            // the output goes out control by control, never all at once
+            let sizes = writes.sizes;
+            assert!(sizes.len() > 20);
+            assert!(sizes.iter().all(|size| *size <= largest));
+            assert_eq!(fresh, newer);
+        }
+    }
+
+    #[test]
+    fn diff_streaming() {
+        use super::{DiffParams, Match};
+
+        let older = fixture(16384);
+        let mut newer = older.clone();
+        newer[5000..5006].copy_from_slice(b"stream");
+        newer.extend_from_slice(b"and some more");
+
+        let params = DiffParams::new(1, Some(3000)).unwrap();
+        let mut expected = Vec::new();
+        super::diff(&older[..], &newer[..], &params, |m| {
+            expected.push(m);
+            Ok::<_, io::Error>(())
+        })
+        .unwrap();
+
+        let mut matches: Vec<Match> = Vec::new();
+        let stats = super::diff_streaming(&older[..], Cursor::new(&newer), &params, |m| {
+            matches.push(m);
+            Ok::<_, io::Error>(())
+        })
+        .unwrap();
+        assert!(!stats.is_identity);
+        let key = |m: &Match| (m.add_old_start, m.add_new_start, m.add_length, m.copy_end);
+        assert!(matches.iter().map(key).eq(expected.iter().map(key)));
+
+        let mut patch = Vec::new();
+        let mut w = super::enc::Writer::new(&mut patch).unwrap();
+        let mut translator = super::Translator::new(&older[..], &newer[..], |c| w.write(c));
+        for m in matches {
+            translator.translate(m).unwrap();
+        }
+        translator.close().unwrap();
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    #[test]
+    fn progress() {
+        use super::{DiffParams, ProgressEvent};
+        use std::sync::{Arc, Mutex};
+
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        newer[30000..30008].copy_from_slice(b"progress");
+
+        for chunk_size in [None, Some(10000)] {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let recorded = events.clone();
+            let params = DiffParams::new(1, chunk_size)
+                .unwrap()
+                .with_progress(Some(Arc::new(move |e| recorded.lock().unwrap().push(e))));
+            super::diff(&older[..], &newer[..], &params, |_| Ok::<_, io::Error>(())).unwrap();
+
+            let events = events.lock().unwrap();
+            assert_eq!(events[0], ProgressEvent::SuffixArrayBuilt);
+            let scanned: Vec<usize> = events[1..]
+                .iter()
+                .map(|e| match *e {
+                    ProgressEvent::Scanned { scanned, total } => {
+                        assert_eq!(total, newer.len());
+                        scanned
+                    }
+                    e => panic!("unexpected {:?}", e),
+                })
+                .collect();
+            assert!(scanned.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(scanned.last(), Some(&newer.len()));
+            if chunk_size.is_some() {
+                assert_eq!(scanned.len(), 7);
+            }
+        }
+    }
+
+    #[test]
+    fn cancel() {
+        use super::{DiffError, DiffParams, ProgressEvent};
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc,
+        };
+        use std::time::{Duration, Instant};
+
+        let noise = |seed: u32, len: u32| -> Vec<u8> {
+            (0..len)
+                .map(|i| (i.wrapping_add(seed).wrapping_mul(2_654_435_761) >> 24) as u8)
+                .collect()
+        };
+        let older = noise(0, 1 << 20);
+        let newer = noise(12345, 8 << 20);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (sorted_tx, sorted_rx) = mpsc::channel();
+        let params = DiffParams::default()
+            .with_cancel(Some(cancel.clone()))
+            .with_progress(Some(Arc::new(move |e| {
+                if e == ProgressEvent::SuffixArrayBuilt {
+                    let _ = sorted_tx.send(());
+                }
+            })));
+
+        let handle = std::thread::spawn(move || {
+            super::diff(&older[..], &newer[..], &params, |_| Ok::<_, DiffError>(()))
+        });
+        sorted_rx.recv().unwrap();
+        cancel.store(true, Ordering::Relaxed);
+        let cancelled_at = Instant::now();
+
+        assert_eq!(handle.join().unwrap().unwrap_err(), DiffError::Cancelled);
+        assert!(cancelled_at.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn match_threshold() {
+        use super::{DiffParams, MAX_MATCH_THRESHOLD};
+
+        assert!(DiffParams::default()
+            .with_match_threshold(MAX_MATCH_THRESHOLD + 1)
+            .is_err());
+
+        let older: Vec<u8> = (0..8192_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        // short moves, which only a low threshold picks up
+        let mut newer = Vec::new();
+        for (i, chunk) in older.chunks(64).enumerate() {
+            let shift = (i % 3) * 12;
+            newer.extend_from_slice(&chunk[shift.min(chunk.len())..]);
+            newer.extend_from_slice(&chunk[..shift.min(chunk.len())]);
         }
+
+        let patch_len = |threshold| {
+            let params = DiffParams::default()
+                .with_match_threshold(threshold)
+                .unwrap();
+            super::assert_cycle_with_params(&older[..], &newer[..], &params);
+            let mut patch = Vec::new();
+            super::simple_diff_with_params(&older[..], &newer[..], &mut patch, &params).unwrap();
+            patch
+        };
+        let (low, high) = (patch_len(2), patch_len(MAX_MATCH_THRESHOLD));
+        assert_eq!(apply_patch(&older[..], &low[..]), newer);
+        assert_ne!(low, high);
     }
-}
 
-fn diff_squashfs_data<F>(old_path: &Path, new_path: &Path, mut on_match: F) -> Result<(), io::Error>
-where
-    F: FnMut(Match) -> Result<(), io::Error>,
-{
-    let old_map = Fragments::new(old_path)?
-        .into_iter()
-        .map(|(hash, pos, length)| (hash, (pos, length)))
-        .collect::<HashMap<Hash, (u64, u32)>>();
-
-    for (new_hash, new_pos, length) in Fragments::new(new_path).unwrap() {
-        let m = match old_map.get(&new_hash) {
-            Some((old_pos, old_length)) => {
-                assert_eq!(length, *old_length);
-                Match {
-                    add_old_start: *old_pos as usize,
-                    add_new_start: new_pos as usize,
-                    add_length: length as usize,
-                    copy_end: (new_pos + length as u64) as usize,
-                }
+    #[test]
+    fn diff_stats() {
+        use super::{DiffParams, Match};
+
+        let older = fixture(65536);
+        let mut newer = older[20000..].to_vec();
+        newer.extend_from_slice(b"some literal bytes");
+        newer.extend_from_slice(&older[..20000]);
+
+        for chunk_size in [None, Some(4096)] {
+            let params = DiffParams::new(1, chunk_size).unwrap();
+            let mut matches: Vec<Match> = Vec::new();
+            let stats = super::diff(&older[..], &newer[..], &params, |m| {
+                matches.push(m);
+                Ok::<_, io::Error>(())
+            })
+            .unwrap();
+
+            assert_eq!(stats.matches, matches.len());
+            assert_eq!(stats.add_bytes + stats.copy_bytes, newer.len() as u64);
+            let mut old_pos = 0_usize;
+            let mut seek_distance = 0;
+            for m in &matches {
+                seek_distance += m.add_old_start.abs_diff(old_pos) as u64;
+                old_pos = m.add_old_start + m.add_length;
+            }
+            assert_eq!(stats.seek_distance, seek_distance);
+
+            let mut patch = Vec::new();
+            let encoded =
+                super::simple_diff_with_params(&older[..], &newer[..], &mut patch, &params)
+                    .unwrap();
+            assert_eq!(encoded.copy_bytes, stats.copy_bytes);
+
+            assert!(stats.timings.sort > std::time::Duration::ZERO);
+            assert!(stats.timings.scan > std::time::Duration::ZERO);
+        }
+
+        // nothing is sorted for identical files, or with a reused index
+        let stats = super::diff(&older[..], &older[..], &DiffParams::default(), |_| {
+            Ok::<_, io::Error>(())
+        })
+        .unwrap();
+        assert_eq!(stats.timings.sort, std::time::Duration::ZERO);
+        let context = super::DiffContext::new(older.clone(), &DiffParams::default());
+        let stats = context
+            .diff_against(&newer, &DiffParams::default(), |_| Ok::<_, io::Error>(()))
+            .unwrap();
+        assert_eq!(stats.timings.sort, std::time::Duration::ZERO);
+        assert!(stats.timings.scan > std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn simple_diff_trivial() {
+        // encoding takes the same shortcut as diff, and makes the same patch
+        let buf = b"trivial".repeat(10000);
+        for (older, newer) in [
+            (&buf[..], &buf[..]),
+            (&[][..], &buf[..]),
+            (&buf[..], &[][..]),
+        ] {
+            let mut patch = Vec::new();
+            let params = super::DiffParams::default();
+            let stats = super::simple_diff_with_params(older, newer, &mut patch, &params).unwrap();
+            assert_eq!(stats.timings.sort, std::time::Duration::ZERO);
+            let expected = encode(older, newer, |on_match| {
+                super::diff(older, newer, &params, on_match).unwrap();
+            });
+            assert_eq!(patch, expected);
+            assert_eq!(apply_patch(older, &patch[..]), newer);
+
+            // and so does estimating it
+            let estimate = super::estimate_patch_size(older, newer, &params).unwrap();
+            assert_eq!(estimate, patch.len());
+        }
+    }
+
+    #[test]
+    fn source_checksum() {
+        use super::{dec, enc, DiffParams};
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[10..14].copy_from_slice(b"base");
+        let mut other = older.clone();
+        other[4000] ^= 1;
+
+        let params = DiffParams::default().with_source_checksum(true);
+        let mut patch = Vec::new();
+        super::simple_diff_with_params(&older, &newer, &mut patch, &params).unwrap();
+        assert_eq!(&patch[8..12], &enc::FLAG_SOURCE_CHECKSUM.to_le_bytes());
+        assert_eq!(&patch[12..44], &hmac_sha256::Hash::hash(&older));
+        assert_eq!(apply_patch(&older, &patch), newer);
+
+        let mut fresh = Vec::new();
+        let e = dec::apply(&other, &mut &patch[..], &mut fresh).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let wrong = e.get_ref().unwrap().downcast_ref::<dec::WrongBaseImage>();
+        assert_eq!(
+            wrong,
+            Some(&dec::WrongBaseImage {
+                expected: hmac_sha256::Hash::hash(&older),
+                actual: hmac_sha256::Hash::hash(&other),
+            })
+        );
+        assert!(fresh.is_empty());
+
+        match bipatch::Reader::new(&patch[..], Cursor::new(&other[..])) {
+            Err(bipatch::DecodeError::WrongBaseImage { .. }) => {}
+            Err(e) => panic!("unexpected {}", e),
+            Ok(_) => panic!("patch applied to the wrong base"),
+        }
+
+        // the default leaves it out, for readers that predate it
+        let mut unchecked = Vec::new();
+        super::simple_diff(&older, &newer, &mut unchecked).unwrap();
+        assert_eq!(&unchecked[8..12], &0_u32.to_le_bytes());
+        assert_eq!(unchecked.len() + 32, patch.len());
+        assert_eq!(&unchecked[12..], &patch[44..]);
+    }
+
+    #[test]
+    fn dec_header_versions() {
+        use super::{dec, enc};
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[10..16].copy_from_slice(b"header");
+        let mut patch = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut patch).unwrap();
+
+        let apply = |patch: &[u8]| {
+            let mut fresh = Vec::new();
+            dec::apply(&older[..], &mut &patch[..], &mut fresh).map(|_| fresh)
+        };
+
+        // legacy patches have no flags after the version
+        let mut legacy = patch[..4].to_vec();
+        legacy.extend_from_slice(&dec::LEGACY_VERSION.to_le_bytes());
+        legacy.extend_from_slice(&patch[12..]);
+        assert_eq!(apply(&legacy[..]).unwrap(), newer);
+
+        let mut future = patch.clone();
+        future[4..8].copy_from_slice(&(enc::VERSION + 1).to_le_bytes());
+        let e = apply(&future[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(e.to_string().contains("wrong version"));
+
+        let mut foreign = patch.clone();
+        foreign[..4].copy_from_slice(b"BSDI");
+        let e = apply(&foreign[..]).unwrap_err();
+        assert!(e.to_string().contains("wrong magic"));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_level() {
+        use super::DiffParams;
+
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        for i in (0..newer.len()).step_by(100) {
+            newer[i] = newer[i].wrapping_add(1);
+        }
+
+        let mut plain = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut plain).unwrap();
+        let mut compressed = Vec::new();
+        let params = DiffParams::default().with_zstd_level(Some(19));
+        super::simple_diff_with_params(&older[..], &newer[..], &mut compressed, &params).unwrap();
+        assert!(compressed.len() * 10 < plain.len());
+
+        let mut fresh = Vec::new();
+        super::dec::apply(&older[..], &mut &compressed[..], &mut fresh).unwrap();
+        assert_eq!(fresh, newer);
+
+        let mut decompressed = Vec::new();
+        bipatch::decompress(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, plain);
+    }
+
+    #[test]
+    fn xz_compression() {
+        use super::{enc, DiffParams, PatchCompression};
+
+        let older = fixture(65536);
+        let mut newer = older.clone();
+        for i in (0..newer.len()).step_by(100) {
+            newer[i] = newer[i].wrapping_add(1);
+        }
+
+        let mut plain = Vec::new();
+        super::simple_diff(&older[..], &newer[..], &mut plain).unwrap();
+        let mut compressed = Vec::new();
+        let params = DiffParams::default().with_compression(PatchCompression::Xz(9));
+        if cfg!(not(feature = "xz")) {
+            // caught before diffing
+            let params = params.with_progress(Some(std::sync::Arc::new(|_| {
+                panic!("diffed without a compressor")
+            })));
+            let res =
+                super::simple_diff_with_params(&older[..], &newer[..], &mut compressed, &params);
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::Unsupported);
+            assert!(compressed.is_empty());
+            let built = DiffParams::builder()
+                .compression(PatchCompression::Xz(9))
+                .build();
+            assert!(built.is_err());
+            return;
+        }
+        let res = super::simple_diff_with_params(&older[..], &newer[..], &mut compressed, &params);
+        res.unwrap();
+        assert!(compressed.len() * 10 < plain.len());
+        // the flags of the header
+        assert_eq!(&compressed[8..12], &enc::FLAG_XZ.to_le_bytes());
+
+        let mut fresh = Vec::new();
+        super::dec::apply(&older[..], &mut &compressed[..], &mut fresh).unwrap();
+        assert_eq!(fresh, newer);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        use super::{ControlOwned, Match};
+
+        let older = fixture(4096);
+        let mut newer = older.clone();
+        newer[100..105].copy_from_slice(b"serde");
+
+        let mut matches = Vec::new();
+        super::diff(&older[..], &newer[..], &Default::default(), |m| {
+            matches.push(m);
+            Ok::<_, io::Error>(())
+        })
+        .unwrap();
+        let json = serde_json::to_string(&matches).unwrap();
+        let parsed: Vec<Match> = serde_json::from_str(&json).unwrap();
+        let key = |m: &Match| (m.add_old_start, m.add_new_start, m.add_length, m.copy_end);
+        assert!(parsed.iter().map(key).eq(matches.iter().map(key)));
+
+        let mut controls = Vec::new();
+        let mut translator = super::Translator::new(&older[..], &newer[..], |c| {
+            controls.push(c.to_owned());
+            Ok::<_, io::Error>(())
+        });
+        for m in parsed {
+            translator.translate(m).unwrap();
+        }
+        translator.close().unwrap();
+        let json = serde_json::to_string(&controls).unwrap();
+        let parsed: Vec<ControlOwned> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, controls);
+
+        let mut patch = Vec::new();
+        let mut w = super::enc::Writer::new(&mut patch).unwrap();
+        for c in &parsed {
+            w.write(&c.as_control()).unwrap();
+        }
+        assert_eq!(apply_patch(&older[..], &patch[..]), newer);
+    }
+
+    /// A squashfs superblock using `compression`, followed by `options` for
+    /// it, if any, in an uncompressed metadata block
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+    fn squashfs_image(compression: u16, options: &[u8]) -> Vec<u8> {
+        let mut image = vec![0u8; 96];
+        image[0..4].copy_from_slice(b"hsqs");
+        image[20..22].copy_from_slice(&compression.to_le_bytes());
+        image[28..30].copy_from_slice(&4u16.to_le_bytes());
+        if !options.is_empty() {
+            image[24..26].copy_from_slice(&0x0400u16.to_le_bytes());
+            image.extend_from_slice(&(0x8000 | options.len() as u16).to_le_bytes());
+            image.extend_from_slice(options);
+        }
+        image.resize(4096, 0);
+        image
+    }
+
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+    #[test]
+    fn squashfs_header_len() {
+        use std::path::Path;
+
+        let path = Path::new("image.squashfs");
+        let header_len = |image: &[u8]| super::squashfs::squashfs_header_len(path, image).unwrap();
+
+        // zstd with the default options
+        assert_eq!(header_len(&squashfs_image(6, &[])), 96);
+        // gzip: level, window size and strategies
+        let gzip_options = [9, 0, 0, 0, 15, 0, 0, 0];
+        assert_eq!(header_len(&squashfs_image(1, &gzip_options)), 106);
+        // xz: dictionary size and filters
+        let xz_options = [0, 0, 0x10, 0, 0, 0, 0, 0];
+        assert_eq!(header_len(&squashfs_image(4, &xz_options)), 106);
+        // lz4: version and flags
+        let lz4_options = [1, 0, 0, 0, 1, 0, 0, 0];
+        assert_eq!(header_len(&squashfs_image(5, &lz4_options)), 106);
+
+        let unknown = squashfs_image(7, &[]);
+        assert!(matches!(
+            super::squashfs::squashfs_header_len(path, &unknown),
+            Err(super::SquashfsError::UnsupportedCompressor { id: 7, .. })
+        ));
+        for id in 1..=6 {
+            let compressor = super::SquashfsCompressor::from_id(id).unwrap();
+            assert_eq!(compressor.id(), id);
+        }
+        assert_eq!(super::SquashfsCompressor::from_id(0), None);
+
+        let mut truncated = squashfs_image(1, &gzip_options);
+        truncated.truncate(100);
+        assert!(matches!(
+            super::squashfs::squashfs_header_len(path, &truncated),
+            Err(super::SquashfsError::Truncated(_))
+        ));
+    }
+
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+    #[test]
+    fn squashfs_offsets() {
+        use super::{squashfs::file_range, SquashfsError};
+        use std::path::Path;
+
+        let path = Path::new("image.squashfs");
+        assert_eq!(file_range(path, 4096, 512, 8192).unwrap(), 4096..4608);
+        assert_eq!(file_range(path, 8000, 192, 8192).unwrap(), 8000..8192);
+
+        let out_of_range = |offset: u64, len: u32, image_len: usize| match file_range(
+            path, offset, len, image_len,
+        ) {
+            Err(SquashfsError::OffsetOutOfRange { offset: o, .. }) => assert_eq!(o, offset),
+            res => panic!("unexpected {:?}", res),
+        };
+        out_of_range(8000, 193, 8192);
+        out_of_range(u64::MAX, 1, usize::MAX);
+        #[cfg(target_pointer_width = "32")]
+        out_of_range(1 << 32, 0, usize::MAX);
+    }
+
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+    #[test]
+    fn squashfs_contiguous() {
+        use super::{squashfs::check_contiguous, SquashfsError};
+        use std::path::Path;
+
+        // gaps are stored as-is, overlaps can't be diffed
+        let path = Path::new("image.squashfs");
+        check_contiguous(path, [(96, 100), (196, 0), (196, 50), (4096, 10)]).unwrap();
+        for (blocks, at) in [
+            ([(96, 100), (150, 100)], 150),
+            ([(96, 100), (96, 50)], 96),
+            ([(u64::MAX, 1), (0, 1)], 0),
+        ] {
+            match check_contiguous(path, blocks) {
+                Err(SquashfsError::Overlap { offset, .. }) => assert_eq!(offset, at),
+                res => panic!("unexpected {:?}", res),
+            }
+        }
+    }
+
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+    #[test]
+    fn squashfs_duplicate_fragments() {
+        use super::{
+            enc,
+            squashfs::{DataRegion, SquashfsFragmentMap},
+            Match, Translator,
+        };
+        use std::path::Path;
+
+        let block = |seed: u32| -> Vec<u8> {
+            (0..4096_u32)
+                .map(|i| (i.wrapping_mul(seed) % 251) as u8)
+                .collect()
+        };
+        // a 96-byte header, then blocks laid out back to back
+        let image = |blocks: &[&[u8]]| {
+            let mut image = vec![0x5A; 96];
+            let mut fragments = Vec::new();
+            for b in blocks {
+                let hash = hmac_sha256::Hash::hash(b);
+                fragments.push((hash, image.len() as u64, b.len() as u32));
+                image.extend_from_slice(b);
+            }
+            (image, fragments)
+        };
+        let (a, b, c) = (block(3), block(5), block(7));
+        let (older, old_fragments) = image(&[&a]);
+        // `a` is in the old image, `b` and `c` aren't but repeat
+        let (newer, new_fragments) = image(&[&a, &b, &b, &c, &a, &c, &c]);
+
+        let (old_path, new_path) = (Path::new("old.squashfs"), Path::new("new.squashfs"));
+        let map = SquashfsFragmentMap::from_blocks(old_path, older.len(), old_fragments);
+        let mut regions = Vec::new();
+        map.diff_blocks(new_path, new_fragments, newer.len(), |region| {
+            regions.push(region);
+            Ok(())
+        })
+        .unwrap();
+        let kinds: String = regions
+            .iter()
+            .map(|region| match region {
+                DataRegion::Match(m) if m.add_length > 0 => 'o',
+                DataRegion::Match(_) => 'l',
+                DataRegion::Repeat(_) => 'r',
+            })
+            .collect();
+        assert_eq!(kinds, "olrlorr");
+
+        // the map is reused for other new images
+        let (other, other_fragments) = image(&[&c, &a]);
+        let mut other_regions = Vec::new();
+        map.diff_blocks(new_path, other_fragments, other.len(), |region| {
+            other_regions.push(region);
+            Ok(())
+        })
+        .unwrap();
+        match &other_regions[..] {
+            [DataRegion::Match(c), DataRegion::Match(a)] => {
+                assert_eq!((c.add_length, a.add_length, a.add_old_start), (0, 4096, 96));
+            }
+            regions => panic!("unexpected {:?}", regions),
+        }
+
+        // and only with the contents it was listed from
+        match super::diff_squashfs_with_map(
+            &map,
+            &older[..100],
+            new_path,
+            &newer,
+            &mut Vec::new(),
+            &Default::default(),
+        ) {
+            Err(super::SquashfsError::Io(e)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput)
             }
-            None => Match {
+            res => panic!("unexpected {:?}", res),
+        }
+
+        let mut patch = Vec::new();
+        let options = enc::WriterOptions {
+            target_copies: true,
+            ..Default::default()
+        };
+        let mut w = enc::Writer::with_options(&mut patch, &options).unwrap();
+        let mut translator = Translator::new(&older, &newer, |c| w.write(c));
+        translator
+            .translate(Match {
                 add_old_start: 0,
-                add_new_start: new_pos as usize,
-                add_length: 0,
-                copy_end: (new_pos + length as u64) as usize,
+                add_new_start: 0,
+                add_length: 96,
+                copy_end: 96,
+            })
+            .unwrap();
+        for region in regions {
+            match region {
+                DataRegion::Match(m) => translator.translate(m).unwrap(),
+                DataRegion::Repeat(src) => translator.copy_target(src).unwrap(),
+            }
+        }
+        translator.close().unwrap();
+        w.finish().unwrap();
+
+        // only `b` and `c` are stored, and the adds of `a`
+        assert!(patch.len() < 4 * 4096 + 1024, "{} bytes", patch.len());
+        assert_eq!(apply_patch(&older, &patch), newer);
+    }
+
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+    #[test]
+    fn squashfs_in_memory() {
+        use super::{squashfs::DataRegion, SquashfsError, SquashfsRegion};
+
+        let block = |seed: u32| -> Vec<u8> {
+            (0..4096_u32)
+                .map(|i| (i.wrapping_mul(seed) % 251) as u8)
+                .collect()
+        };
+        // a superblock, blocks laid out back to back, then the tables
+        let image = |blocks: &[&[u8]], tables: &[u8]| {
+            let mut image = squashfs_image(6, &[]);
+            image.truncate(96);
+            let mut fragments = Vec::new();
+            for b in blocks {
+                let hash = hmac_sha256::Hash::hash(b);
+                fragments.push((hash, image.len() as u64, b.len() as u32));
+                image.extend_from_slice(b);
+            }
+            let inode_table_start = image.len() as u64;
+            image[64..72].copy_from_slice(&inode_table_start.to_le_bytes());
+            image.extend_from_slice(tables);
+            (image, fragments)
+        };
+        let (a, b, c) = (block(3), block(5), block(7));
+        let (older, old_fragments) = image(&[&a, &b], b"old tables");
+        let (newer, new_fragments) = image(&[&c, &b, &c, &a], b"new tables, longer");
+
+        let mut patch = Vec::new();
+        super::diff_squashfs_in_memory(
+            &older,
+            old_fragments.clone(),
+            &newer,
+            new_fragments.clone(),
+            &mut patch,
+            &Default::default(),
+        )
+        .unwrap();
+        // only `c` is stored, once, and the adds of `b` and `a`
+        assert!(patch.len() < 3 * 4096 + 1024, "{} bytes", patch.len());
+        assert_eq!(apply_patch(&older, &patch), newer);
+
+        // the same patch, with where each match comes from
+        let mut regions = Vec::new();
+        let mut tagged = Vec::new();
+        super::diff_squashfs_in_memory_with_regions(
+            &older,
+            old_fragments.clone(),
+            &newer,
+            new_fragments.clone(),
+            &mut tagged,
+            &Default::default(),
+            &mut |region, m| {
+                let new_range = match m {
+                    DataRegion::Match(m) => m.add_new_start..m.copy_end,
+                    DataRegion::Repeat(src) => src.clone(),
+                };
+                regions.push((region, matches!(m, DataRegion::Repeat(_)), new_range));
             },
+        )
+        .unwrap();
+        assert_eq!(tagged, patch);
+        let kinds: Vec<_> = regions.iter().map(|(region, _, _)| *region).collect();
+        assert!(kinds.windows(2).all(|w| w[0] <= w[1]), "{:?}", kinds);
+        let data: Vec<_> = regions
+            .iter()
+            .filter(|(region, _, _)| *region == SquashfsRegion::Data)
+            .map(|(_, repeat, _)| *repeat)
+            .collect();
+        assert_eq!(data, [false, false, true, false]);
+        let ends = |kind| {
+            regions
+                .iter()
+                .filter(|(region, repeat, _)| *region == kind && !repeat)
+                .map(|(_, _, range)| range.end)
+                .max()
         };
-        on_match(m)?
+        assert_eq!(ends(SquashfsRegion::Header), Some(96));
+        assert_eq!(ends(SquashfsRegion::Footer), Some(newer.len()));
+
+        // the superblock has to say where the inode table starts
+        let mut no_tables = newer.clone();
+        no_tables[64..72].fill(0);
+        let res = super::diff_squashfs_in_memory(
+            &older,
+            old_fragments,
+            &no_tables,
+            new_fragments,
+            &mut Vec::new(),
+            &Default::default(),
+        );
+        match res {
+            Err(SquashfsError::InodeTable(path)) => assert_eq!(path.to_str(), Some("<new image>")),
+            res => panic!("unexpected {:?}", res),
+        }
     }
-    Ok(())
-}
 
-#[cfg(feature = "enc")]
-pub fn diff_squashfs(
-    old_path: &Path,
-    old: &[u8],
-    new_path: &Path,
-    new: &[u8],
-    out: &mut dyn Write,
-    diff_params: &DiffParams,
-) -> Result<(), io::Error> {
-    let mut w = enc::Writer::new(out)?;
-
-    let mut translator = Translator::new(old, new, |control| w.write(control));
-    // squashfs header with zstd takes 96 bytes
-    diff(&old[0..96], &new[0..96], diff_params, |m| {
-        translator.translate(m)
-    })?;
-
-    diff_squashfs_data(old_path, new_path, |m| {
-        // println!("{:?}", m);
-        translator.translate(m)
-    })?;
-
-    let footer_offset_old = get_inode_table_idx(old_path).unwrap();
-    let footer_offset_new = get_inode_table_idx(new_path).unwrap();
-
-    println!("footer_offset_old {}", footer_offset_old);
-    println!("footer_offset_new {}", footer_offset_new);
-
-    diff(
-        &old[footer_offset_old..],
-        &new[footer_offset_new..],
-        diff_params,
-        |m| {
-            let m = Match {
-                add_old_start: m.add_old_start + footer_offset_old,
-                add_new_start: m.add_new_start + footer_offset_new,
-                copy_end: m.copy_end + footer_offset_new,
-                ..m
-            };
-            //        println!("{:?}", m);
-            translator.translate(m)
-        },
-    )?;
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+    #[test]
+    fn squashfs_in_memory_gaps() {
+        use super::SquashfsError;
 
-    translator.close()?;
+        // blocks that don't tile the data region, given out of order
+        let image = |fill: u8| {
+            let mut image = squashfs_image(6, &[]);
+            image[96..2000].fill(fill);
+            image[150..160].copy_from_slice(b"0123456789");
+            image[64..72].copy_from_slice(&2000u64.to_le_bytes());
+            image
+        };
+        let (older, newer) = (image(1), image(2));
+        let hash = hmac_sha256::Hash::hash(b"0123456789");
+        let diff = |new_blocks: Vec<_>| {
+            let mut patch = Vec::new();
+            super::diff_squashfs_in_memory(
+                &older,
+                vec![(hash, 150, 10)],
+                &newer,
+                new_blocks,
+                &mut patch,
+                &Default::default(),
+            )
+            .map(|()| patch)
+        };
+        let other = [3; 32];
+        let patch = diff(vec![(other, 1000, 100), (hash, 150, 10)]).unwrap();
+        assert_eq!(apply_patch(&older, &patch), newer);
 
-    Ok(())
-}
+        // blocks can't overlap each other, the header or the inode tables
+        for (blocks, at) in [
+            (vec![(hash, 150, 10), (other, 155, 10)], 155),
+            (vec![(other, 50, 10)], 50),
+            (vec![(other, 1990, 20)], 2000),
+        ] {
+            match diff(blocks) {
+                Err(SquashfsError::Overlap { offset, .. }) => assert_eq!(offset, at),
+                res => panic!("unexpected {:?}", res.map(|_| ())),
+            }
+        }
+    }
 
-pub fn assert_cycle(older: &[u8], newer: &[u8]) {
-    let mut older_pos = 0_usize;
-    let mut newer_pos = 0_usize;
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
+    #[test]
+    fn squashfs_length_mismatch() {
+        use super::squashfs::{DataRegion, SquashfsFragmentMap};
+        use std::path::Path;
 
-    let mut translator = Translator::new(older, newer, |control| -> Result<(), std::io::Error> {
-        for &ab in control.add {
-            let fb = ab.wrapping_add(older[older_pos]);
-            older_pos += 1;
+        // the shim reports the same hash for blocks of different lengths
+        let hash = [7; 32];
+        let map = SquashfsFragmentMap::from_blocks(
+            Path::new("old.squashfs"),
+            96 + 4096,
+            [(hash, 96, 4096)],
+        );
+        let mut regions = Vec::new();
+        let new_blocks = [(hash, 96, 1024), (hash, 96 + 1024, 2048)];
+        map.diff_blocks(
+            Path::new("new.squashfs"),
+            new_blocks,
+            96 + 1024 + 2048,
+            |region| {
+                regions.push(region);
+                Ok(())
+            },
+        )
+        .unwrap();
+        match &regions[..] {
+            [DataRegion::Match(a), DataRegion::Match(b)] => {
+                assert_eq!(
+                    (a.add_length, a.add_new_start, a.copy_end),
+                    (0, 96, 96 + 1024)
+                );
+                assert_eq!(
+                    (b.add_length, b.add_new_start, b.copy_end),
+                    (0, 96 + 1024, 96 + 1024 + 2048)
+                );
+            }
+            regions => panic!("unexpected {:?}", regions),
+        }
+    }
+
+    /// A squashfs image with gzip metadata, whose `data_block` is the second
+    /// block of its second file. Its blocks and the list
+    /// [list_blocks](super::squashfs::layout::list_blocks) should find are
+    /// returned.
+    #[cfg(feature = "squashfs-rust")]
+    fn squashfs_layout_image(data_block: &[u8; 60]) -> (Vec<u8>, Vec<(u64, u32)>) {
+        use std::io::Write;
 
-            let nb = newer[newer_pos];
-            newer_pos += 1;
+        let le16 = |v: &mut Vec<u8>, x: u16| v.extend_from_slice(&x.to_le_bytes());
+        let le32 = |v: &mut Vec<u8>, x: u32| v.extend_from_slice(&x.to_le_bytes());
+        let le64 = |v: &mut Vec<u8>, x: u64| v.extend_from_slice(&x.to_le_bytes());
+        let inode_header = |v: &mut Vec<u8>, kind: u16, number: u32| {
+            le16(v, kind);
+            v.extend_from_slice(&[0; 10]);
+            le32(v, number);
+        };
+        const UNCOMPRESSED: u32 = 1 << 24;
 
-            assert_eq!(fb, nb);
+        // blocks: 100 and 200 bytes for the first file, 50, a sparse one and
+        // 60 for the second, then a 30-byte fragment
+        let mut data = Vec::new();
+        for (len, seed) in [(100, 1_u8), (200, 2), (50, 3)] {
+            data.extend((0..len).map(|i: u32| (i as u8).wrapping_mul(seed)));
         }
+        data.extend_from_slice(data_block);
+        data.extend_from_slice(&[0xF0; 30]);
 
-        for &cb in control.copy {
-            let nb = newer[newer_pos];
-            newer_pos += 1;
+        let mut inodes = Vec::new();
+        inode_header(&mut inodes, 1, 1); // directory
+        inodes.extend_from_slice(&[0; 16]);
+        inode_header(&mut inodes, 2, 2); // file ending with fragment 0
+        for x in [96, 0, 0, 2 * 4096 + 10, 100, 200 | UNCOMPRESSED] {
+            le32(&mut inodes, x);
+        }
+        inode_header(&mut inodes, 9, 3); // extended file, with a sparse block
+        le64(&mut inodes, 96 + 300);
+        le64(&mut inodes, 3 * 4096);
+        le64(&mut inodes, 4096);
+        for x in [1, u32::MAX, 0, u32::MAX, 50, 0, 60] {
+            le32(&mut inodes, x);
+        }
+        inode_header(&mut inodes, 3, 4); // symlink
+        le32(&mut inodes, 1);
+        le32(&mut inodes, 5);
+        inodes.extend_from_slice(b"file1");
+        inode_header(&mut inodes, 8, 5); // extended directory, with an index
+        for x in [2, 0, 0, 1] {
+            le32(&mut inodes, x);
+        }
+        le16(&mut inodes, 1);
+        le16(&mut inodes, 0);
+        le32(&mut inodes, u32::MAX);
+        for x in [0, 0, 2] {
+            le32(&mut inodes, x);
+        }
+        inodes.extend_from_slice(b"abc");
+        inode_header(&mut inodes, 2, 6); // file sharing a block with the first
+        for x in [96, u32::MAX, 0, 4096, 100] {
+            le32(&mut inodes, x);
+        }
+
+        let mut e = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        e.write_all(&inodes).unwrap();
+        let inodes = e.finish().unwrap();
 
-            assert_eq!(cb, nb);
+        let inode_table_start = 96 + data.len() as u64;
+        let directory_table_start = inode_table_start + 2 + inodes.len() as u64;
+        let fragment_table_start = directory_table_start + 2 + 16;
+
+        let mut image = Vec::new();
+        le32(&mut image, 0x7371_7368);
+        le32(&mut image, 6);
+        le32(&mut image, 0);
+        le32(&mut image, 4096);
+        le32(&mut image, 1);
+        for x in [1, 12, 0, 1, 4, 0] {
+            le16(&mut image, x);
+        }
+        for x in [
+            0,
+            fragment_table_start + 8,
+            u64::MAX,
+            u64::MAX,
+            inode_table_start,
+            directory_table_start,
+            fragment_table_start,
+            u64::MAX,
+        ] {
+            le64(&mut image, x);
         }
+        image.extend_from_slice(&data);
+        le16(&mut image, inodes.len() as u16);
+        image.extend_from_slice(&inodes);
+        le16(&mut image, 0x8000 | 16);
+        le64(&mut image, 96 + 410);
+        le32(&mut image, 30 | UNCOMPRESSED);
+        le32(&mut image, 0);
+        le64(&mut image, directory_table_start);
 
-        older_pos = (older_pos as i64 + control.seek) as usize;
+        let blocks = vec![(96, 100), (196, 200), (396, 50), (446, 60), (506, 30)];
+        (image, blocks)
+    }
 
-        Ok(())
-    });
+    #[cfg(feature = "squashfs-rust")]
+    #[test]
+    fn squashfs_layout() {
+        use super::squashfs::layout::list_blocks;
+        use std::io::Cursor;
 
-    diff(older, newer, &Default::default(), |m| {
-        translator.translate(m)
-    })
-    .unwrap();
+        let (image, expected) = squashfs_layout_image(&[0x33; 60]);
+        let blocks = list_blocks(&mut Cursor::new(&image)).unwrap();
+        let expected: Vec<_> = expected
+            .into_iter()
+            .map(|(offset, size)| {
+                let range = offset as usize..offset as usize + size as usize;
+                (hmac_sha256::Hash::hash(&image[range]), offset, size)
+            })
+            .collect();
+        assert_eq!(blocks, expected);
 
-    translator.close().unwrap();
+        // truncated images are rejected rather than read out of bounds
+        let truncated = &image[..image.len() - 4];
+        assert!(list_blocks(&mut Cursor::new(truncated)).is_err());
+        assert!(list_blocks(&mut Cursor::new(&image[..600])).is_err());
+    }
 
-    assert_eq!(
-        newer_pos,
-        newer.len(),
-        "fresh should have same length as newer"
-    );
-}
+    #[cfg(feature = "squashfs-rust")]
+    #[test]
+    fn squashfs_rust_diff() {
+        let dir = std::env::temp_dir().join(format!("bidiff-squashfs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (old_path, new_path) = (dir.join("old.squashfs"), dir.join("new.squashfs"));
+        let (older, _) = squashfs_layout_image(&[0x33; 60]);
+        let (newer, _) = squashfs_layout_image(&[0x44; 60]);
+        std::fs::write(&old_path, &older).unwrap();
+        std::fs::write(&new_path, &newer).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::instructions::apply_instructions;
-    use proptest::prelude::*;
+        assert_eq!(
+            super::squashfs_compressor(&old_path).unwrap(),
+            super::SquashfsCompressor::Gzip
+        );
+        let mut patch = Vec::new();
+        let res = super::diff_squashfs(
+            &old_path,
+            &older,
+            &new_path,
+            &newer,
+            &mut patch,
+            &Default::default(),
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        res.unwrap();
+        assert_eq!(apply_patch(&older, &patch), newer);
+    }
 
+    #[cfg(any(feature = "squashfs", feature = "squashfs-rust"))]
     #[test]
-    fn short_patch() {
-        let older = [
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            1, 2, 0,
-        ];
-        let instructions = [
-            12, 16, 5, 40, 132, 1, 47, 43, 20, 86, 150, 0, 150, 0, 150, 0, 115, 31, 0, 0, 0, 0, 0,
-            0, 0, 1, 38, 188, 128, 0, 150, 0,
-        ];
-        let newer = apply_instructions(&older[..], &instructions[..]);
+    fn squashfs_errors() {
+        use super::SquashfsError;
+        use std::path::Path;
 
-        super::assert_cycle(&older[..], &newer[..]);
+        let image = squashfs_image(6, &[]);
+        let diff = |old_path: &Path, old: &[u8]| {
+            super::diff_squashfs(
+                old_path,
+                old,
+                Path::new("new.squashfs"),
+                &image[..],
+                &mut Vec::new(),
+                &Default::default(),
+            )
+        };
+
+        // libsquashfs takes C strings
+        let nul = Path::new("old\0.squashfs");
+        #[cfg(not(feature = "squashfs-rust"))]
+        match diff(nul, &image[..]) {
+            Err(SquashfsError::InvalidPath(path)) => assert_eq!(path, nul),
+            res => panic!("unexpected {:?}", res),
+        }
+        #[cfg(feature = "squashfs-rust")]
+        match diff(nul, &image[..]) {
+            Err(SquashfsError::Layout { path, .. }) => assert_eq!(path, nul),
+            res => panic!("unexpected {:?}", res),
+        }
+
+        let old = Path::new("old.squashfs");
+        match diff(old, &image[..10]) {
+            Err(SquashfsError::Truncated(path)) => assert_eq!(path, old),
+            res => panic!("unexpected {:?}", res),
+        }
+        match diff(old, &[0u8; 4096]) {
+            Err(SquashfsError::BadSuperblock(path)) => assert_eq!(path, old),
+            res => panic!("unexpected {:?}", res),
+        }
+
+        let missing = Path::new("/nonexistent/old.squashfs");
+        match super::squashfs_compressor(missing) {
+            Err(SquashfsError::BadSuperblock(path)) => assert_eq!(path, missing),
+            res => panic!("unexpected {:?}", res),
+        }
     }
 
     proptest! {
@@ -684,5 +7411,173 @@ mod tests {
             println!("{} => {}", older.len(), newer.len());
             super::assert_cycle(&older[..], &newer[..]);
         }
+
+        #[test]
+        fn cycle_empty_older(newer: Vec<u8>) {
+            super::assert_cycle(&[], &newer[..]);
+        }
+
+        #[test]
+        fn diff_empty_older(
+            newer: Vec<u8>,
+            partitions in 1..8_usize,
+            chunk_size in proptest::option::of(1..256_usize),
+        ) {
+            // all of "newer" is taken as-is, without sorting anything
+            let params = super::DiffParams::new(partitions, chunk_size).unwrap();
+            let mut matches = Vec::new();
+            let patch = encode(&[], &newer[..], |on_match| {
+                super::diff(&[], &newer[..], &params, |m| {
+                    matches.push((m.add_old_start, m.add_new_start, m.add_length, m.copy_end));
+                    on_match(m)
+                })
+                .unwrap();
+            });
+            prop_assert_eq!(apply_patch(&[], &patch[..]), newer.clone());
+            let all: Vec<_> = super::matches(&[], &newer[..], &params)
+                .map(|m| (m.add_old_start, m.add_new_start, m.add_length, m.copy_end))
+                .collect();
+            prop_assert_eq!(&all, &matches);
+            if newer.is_empty() {
+                prop_assert!(matches.is_empty());
+            } else {
+                prop_assert_eq!(matches, vec![(0, 0, 0, newer.len())]);
+            }
+        }
+
+        #[test]
+        fn cycle_empty_newer(older: Vec<u8>) {
+            super::assert_cycle(&older[..], &[]);
+            let stats = super::diff(&older[..], &[], &Default::default(), |_| {
+                Err(super::DiffError::Cancelled)
+            })
+            .unwrap();
+            prop_assert_eq!(stats.matches, 0);
+        }
+
+        #[test]
+        fn cycle_identical(buf in proptest::collection::vec(any::<u8>(), 1..2048)) {
+            super::assert_cycle(&buf[..], &buf[..]);
+            let params = super::DiffParams::new(1, Some(64)).unwrap();
+            let mut matches = Vec::new();
+            let stats = super::diff(&buf[..], &buf[..], &params, |m| {
+                matches.push((m.add_old_start, m.add_new_start, m.add_length, m.copy_end));
+                Ok::<_, super::DiffError>(())
+            })
+            .unwrap();
+            prop_assert_eq!(matches, vec![(0, 0, buf.len(), buf.len())]);
+            prop_assert!(stats.is_identity);
+            prop_assert!(!stats.sort_skipped);
+        }
+
+        #[test]
+        fn copies_tile_newer(
+            older in proptest::collection::vec(0..4_u8, 0..1024),
+            newer in proptest::collection::vec(0..4_u8, 0..1024),
+            chunk_size in proptest::option::of(1..256_usize),
+            target_copies: bool,
+        ) {
+            // few distinct bytes make for many overlapping candidate matches,
+            // which the Translator checks tile "newer" in debug builds
+            let params = super::DiffParams::new(1, chunk_size).unwrap();
+            let mut controls = Vec::new();
+            let mut translator = super::Translator::new(&older, &newer, |c| {
+                controls.push(c.to_owned());
+                Ok::<_, io::Error>(())
+            });
+            if target_copies {
+                translator = translator.with_target_copies();
+            }
+            let res = super::diff(&older, &newer, &params, |m| translator.translate(m));
+            translator.close_after(res).unwrap();
+
+            let mut new_pos = 0;
+            for c in &controls {
+                new_pos += c.add.len() + c.copy.len();
+                // target copies only read from what's reconstructed already
+                prop_assert!(c.target.is_empty() || c.target.start < new_pos);
+                new_pos += c.target.len();
+            }
+            prop_assert_eq!(new_pos, newer.len());
+        }
+
+        #[test]
+        fn instructions_roundtrip(older: [u8; 32], swaps: Vec<u8>, tail: Vec<u8>) {
+            let mut newer = older.to_vec();
+            for s in swaps {
+                let a = s as usize % (newer.len() - 1);
+                newer.swap(a, a + 1);
+            }
+            newer.extend_from_slice(&tail[..]);
+
+            let instructions = make_instructions(&older[..], &newer[..]);
+            prop_assert_eq!(apply_instructions(&older[..], &instructions[..]), newer);
+        }
+
+        #[test]
+        fn dec_garbage_controls(controls: Vec<(u8, i16)>) {
+            let older = fixture(256);
+            let controls: Vec<_> = controls
+                .into_iter()
+                .map(|(add_len, seek)| (add_len as usize, seek as i64))
+                .collect();
+            // never panics, and only fails on out of bounds accesses
+            if let Err(e) = dec_apply_controls(&older, &controls) {
+                prop_assert!(matches!(malformed(&e), Some(super::dec::MalformedPatch::OutOfBounds(_))));
+            }
+        }
+
+        #[test]
+        fn dec_garbage_patch(flags in 0..8_u32, body: Vec<u8>, headerless: Vec<u8>) {
+            use super::{dec, enc};
+
+            /// Fails past 1 MiB, since target copies can make a huge output
+            /// out of a few bytes
+            struct Bounded(usize);
+            impl Write for Bounded {
+                fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                    self.0 += buf.len();
+                    if self.0 > 1 << 20 {
+                        return Err(io::ErrorKind::WriteZero.into());
+                    }
+                    Ok(buf.len())
+                }
+                fn flush(&mut self) -> io::Result<()> {
+                    Ok(())
+                }
+            }
+
+            let older = fixture(256);
+            // random bytes aren't a patch
+            prop_assume!(!headerless.starts_with(&enc::MAGIC.to_le_bytes()));
+            prop_assert!(dec::apply(&older, &mut &headerless[..], &mut Bounded(0)).is_err());
+
+            // random controls, with any combination of manifest, raw delta
+            // and target copies: never panics
+            let flags = [enc::FLAG_MANIFEST, enc::FLAG_RAW_DELTA, enc::FLAG_TARGET_COPY]
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| flags & (1 << i) != 0)
+                .fold(0, |acc, (_, flag)| acc | flag);
+            let mut patch = Vec::new();
+            patch.extend_from_slice(&enc::MAGIC.to_le_bytes());
+            patch.extend_from_slice(&enc::VERSION.to_le_bytes());
+            patch.extend_from_slice(&flags.to_le_bytes());
+            patch.extend_from_slice(&body);
+            let _ = dec::apply(&older, &mut &patch[..], &mut Bounded(0));
+        }
+
+        #[test]
+        fn garbage_controls(controls: Vec<(u8, i16)>) {
+            let older = fixture(256);
+            let controls: Vec<_> = controls
+                .into_iter()
+                .map(|(add_len, seek)| (add_len as usize, seek as i64))
+                .collect();
+            // never panics, and only fails on out of bounds accesses
+            if let Err(e) = apply_controls(&older, &controls) {
+                prop_assert!(out_of_bounds_offset(&e).is_some());
+            }
+        }
     }
 }
@@ -0,0 +1,98 @@
+//! A compact binary format for lists of [Match]es, see [write_match_list].
+//!
+//! This only keeps the matches a diff found, not the bytes, so that they can
+//! be stored, and translated or encoded differently later on.
+
+use super::Match;
+use integer_encoding::{VarIntReader, VarIntWriter};
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+};
+
+/// Write `matches` to `out`
+///
+/// The number of matches comes first, then each match as four varints,
+/// relative to the previous match (or to zeroes, for the first one): how far
+/// its add region starts from the end of the previous add region in "older"
+/// and from the end of the previous match in "newer" (both zigzag-encoded),
+/// the length of its add region, and that of its copy region (both
+/// LEB128-encoded). For matches of a diff, the second one is always zero, and
+/// the first is the seek of the control.
+///
+/// Fails with [io::ErrorKind::InvalidInput] when a match ends before its add
+/// region does.
+pub fn write_match_list(matches: &[Match], mut out: &mut dyn Write) -> io::Result<()> {
+    out.write_varint(matches.len())?;
+
+    let (mut old_pos, mut new_pos) = (0_i64, 0_i64);
+    for m in matches {
+        let copy_length = m.copy_end.checked_sub(m.copy_start()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("match ends at {}, before {}", m.copy_end, m.copy_start()),
+            )
+        })?;
+        let old_start = m.add_old_start as i64;
+        let new_start = m.add_new_start as i64;
+        out.write_varint(old_start - old_pos)?;
+        out.write_varint(new_start - new_pos)?;
+        out.write_varint(m.add_length)?;
+        out.write_varint(copy_length)?;
+        old_pos = old_start + m.add_length as i64;
+        new_pos = m.copy_end as i64;
+    }
+    Ok(())
+}
+
+/// Read matches written by [write_match_list]
+///
+/// Fails with [io::ErrorKind::InvalidData] when a match would start before
+/// the beginning of a file or past the end of the address space, and
+/// [io::ErrorKind::UnexpectedEof] when `input` is truncated.
+pub fn read_match_list(mut input: &mut dyn Read) -> io::Result<Vec<Match>> {
+    let len: usize = input.read_varint()?;
+
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "match list points out of bounds",
+        )
+    };
+    let offset = |pos: i64, delta: i64| {
+        pos.checked_add(delta)
+            .and_then(|pos| usize::try_from(pos).ok())
+            .ok_or_else(invalid)
+    };
+    let end = |start: usize, len: usize| {
+        start
+            .checked_add(len)
+            .filter(|end| i64::try_from(*end).is_ok())
+            .ok_or_else(invalid)
+    };
+
+    // the length comes from the input, so it's not trusted for how much to
+    // allocate
+    let mut matches = Vec::with_capacity(len.min(4096));
+    let (mut old_pos, mut new_pos) = (0_i64, 0_i64);
+    for _ in 0..len {
+        let old_delta: i64 = input.read_varint()?;
+        let new_delta: i64 = input.read_varint()?;
+        let add_length: usize = input.read_varint()?;
+        let copy_length: usize = input.read_varint()?;
+
+        let add_old_start = offset(old_pos, old_delta)?;
+        let add_new_start = offset(new_pos, new_delta)?;
+        let copy_end = end(end(add_new_start, add_length)?, copy_length)?;
+        old_pos = end(add_old_start, add_length)? as i64;
+        new_pos = copy_end as i64;
+
+        matches.push(Match {
+            add_old_start,
+            add_new_start,
+            add_length,
+            copy_end,
+        });
+    }
+    Ok(matches)
+}
@@ -0,0 +1,63 @@
+//! Diffing files without reading them into memory first, see
+//! [simple_diff_files].
+
+use super::{simple_diff_with_params, DiffParams, DiffStats};
+use memmap2::Mmap;
+use std::{
+    fs::File,
+    io::{self, Write},
+    ops::Deref,
+    path::Path,
+};
+
+/// A memory-mapped file. Zero-length files can't be mapped on every
+/// platform, so they're not mapped at all.
+enum Mapped {
+    Map(Mmap),
+    Empty,
+}
+
+impl Mapped {
+    fn open(path: &Path) -> Result<Self, io::Error> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() == 0 {
+            return Ok(Mapped::Empty);
+        }
+        // Safety: upheld by the caller of `simple_diff_files`
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Mapped::Map(map))
+    }
+}
+
+impl Deref for Mapped {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Mapped::Map(map) => map,
+            Mapped::Empty => &[],
+        }
+    }
+}
+
+/// Diff the files at `old_path` and `new_path`, writing the patch to `out`
+///
+/// Both files are memory-mapped rather than read, which keeps them out of
+/// the resident memory of the process for the most part.
+///
+/// # Safety
+///
+/// Neither file may be modified or truncated, by this process or any other,
+/// until this returns. Nothing prevents it, unlike with
+/// [diff_owned](crate::diff_owned), and the mapped memory changing under
+/// the diff is undefined behavior.
+pub unsafe fn simple_diff_files(
+    old_path: &Path,
+    new_path: &Path,
+    out: &mut dyn Write,
+    params: &DiffParams,
+) -> Result<DiffStats, io::Error> {
+    let old = Mapped::open(old_path)?;
+    let new = Mapped::open(new_path)?;
+    simple_diff_with_params(&old, &new, out, params)
+}
@@ -0,0 +1,101 @@
+//! Bundles of a forward and a reverse patch, for rolling back an update, see
+//! [simple_diff_reversible].
+//!
+//! A bundle starts with the usual header, with [FLAG_REVERSIBLE] set, then
+//! an index of the sizes of the forward and the reverse patch as
+//! little-endian u64s, followed by both patches. Each one is a complete
+//! patch, as written by [simple_diff_with_params], so it can be extracted
+//! with [patch] and applied on its own.
+
+use super::{
+    dec,
+    enc::{self, FLAG_REVERSIBLE},
+    simple_diff_with_params, DiffParams, DiffStats,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    convert::TryFrom,
+    io::{self, ErrorKind, Write},
+};
+
+/// Which way a patch of a bundle goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Reconstructs "newer" from "older"
+    Forward,
+    /// Reconstructs "older" from "newer"
+    Reverse,
+}
+
+/// What [simple_diff_reversible] did, for each direction
+#[derive(Debug, Clone)]
+pub struct ReversibleStats {
+    pub forward: DiffStats,
+    pub reverse: DiffStats,
+}
+
+/// Diff `older` against `newer` and the other way around, writing both
+/// patches to `out` as a single bundle.
+///
+/// This costs two diffs, but saves storing and keeping track of two patch
+/// files. `diff_params` apply to both directions.
+pub fn simple_diff_reversible(
+    older: &[u8],
+    newer: &[u8],
+    mut out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> io::Result<ReversibleStats> {
+    let mut forward_patch = Vec::new();
+    let forward = simple_diff_with_params(older, newer, &mut forward_patch, diff_params)?;
+    let mut reverse_patch = Vec::new();
+    let reverse = simple_diff_with_params(newer, older, &mut reverse_patch, diff_params)?;
+
+    enc::write_header(&mut out, FLAG_REVERSIBLE)?;
+    out.write_u64::<LittleEndian>(forward_patch.len() as u64)?;
+    out.write_u64::<LittleEndian>(reverse_patch.len() as u64)?;
+    out.write_all(&forward_patch)?;
+    out.write_all(&reverse_patch)?;
+
+    Ok(ReversibleStats { forward, reverse })
+}
+
+/// The patch of `bundle` that goes in `direction`, as written by
+/// [simple_diff_reversible]
+pub fn patch(bundle: &[u8], direction: Direction) -> io::Result<&[u8]> {
+    let mut r = bundle;
+    let flags = dec::read_header(&mut r)?;
+    if flags != FLAG_REVERSIBLE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("not a reversible bundle: flags are `{:X}`", flags),
+        ));
+    }
+    let forward_len = r.read_u64::<LittleEndian>()?;
+    let reverse_len = r.read_u64::<LittleEndian>()?;
+
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "bundle index out of bounds");
+    let forward_len = usize::try_from(forward_len).map_err(|_| invalid())?;
+    let reverse_len = usize::try_from(reverse_len).map_err(|_| invalid())?;
+    let patches = r;
+    let end = forward_len.checked_add(reverse_len).ok_or_else(invalid)?;
+    if end != patches.len() {
+        return Err(invalid());
+    }
+
+    Ok(match direction {
+        Direction::Forward => &patches[..forward_len],
+        Direction::Reverse => &patches[forward_len..],
+    })
+}
+
+/// Apply the patch of `bundle` that goes in `direction` to `source` ("older"
+/// for [Direction::Forward], "newer" for [Direction::Reverse]), writing the
+/// other file to `out`, and return its length, see [dec::apply]
+pub fn apply(
+    source: &[u8],
+    bundle: &[u8],
+    direction: Direction,
+    out: &mut dyn Write,
+) -> io::Result<u64> {
+    dec::apply(source, &mut patch(bundle, direction)?, out)
+}
@@ -0,0 +1,755 @@
+//! Diffing squashfs images block by block, see [diff_squashfs].
+//!
+//! With the `squashfs` feature, blocks are listed by libsquashfs, through
+//! the C shim in `shim.c`. With the `squashfs-rust` feature, they're listed
+//! by reading the layout of images in Rust instead, see `layout.rs`, which
+//! needs no C library (besides those of the `xz` and `zstd` features, for
+//! images whose metadata uses these compressors). Without either, the crate
+//! has no C dependencies and builds for targets like
+//! `wasm32-unknown-unknown`.
+
+use super::{diff, diff_range, enc, DiffParams, Hash, Match, Translator};
+use log::{debug, warn};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    error::Error,
+    ffi::c_int,
+    fmt,
+    io::{self, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+#[cfg(not(feature = "squashfs-rust"))]
+mod ffi;
+#[cfg(not(feature = "squashfs-rust"))]
+use ffi as backend;
+#[cfg(feature = "squashfs-rust")]
+pub(crate) mod layout;
+#[cfg(feature = "squashfs-rust")]
+use layout as backend;
+
+/// Offset in a squashfs image, which is 64-bit whatever the target
+pub type FileOffset = u64;
+
+/// Compressor of a squashfs image, as stored in its superblock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SquashfsCompressor {
+    Gzip,
+    Lzma,
+    Lzo,
+    Xz,
+    Lz4,
+    Zstd,
+}
+
+impl SquashfsCompressor {
+    /// The compressor with this ID, or `None` for IDs squashfs doesn't define
+    pub fn from_id(id: u16) -> Option<Self> {
+        match id {
+            1 => Some(Self::Gzip),
+            2 => Some(Self::Lzma),
+            3 => Some(Self::Lzo),
+            4 => Some(Self::Xz),
+            5 => Some(Self::Lz4),
+            6 => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// The ID of this compressor in squashfs superblocks
+    pub fn id(self) -> u16 {
+        match self {
+            Self::Gzip => 1,
+            Self::Lzma => 2,
+            Self::Lzo => 3,
+            Self::Xz => 4,
+            Self::Lz4 => 5,
+            Self::Zstd => 6,
+        }
+    }
+}
+
+/// Errors raised by [diff_squashfs]
+#[derive(Debug)]
+pub enum SquashfsError {
+    /// The path can't be passed to libsquashfs, because it contains a NUL
+    /// byte (or, outside of Unix, isn't valid UTF-8)
+    InvalidPath(PathBuf),
+    /// libsquashfs couldn't list the blocks of this image, `code` is what
+    /// the shim returned
+    Blocks { path: PathBuf, code: c_int },
+    /// libsquashfs couldn't read where the inode table of this image starts
+    InodeTable(PathBuf),
+    /// The contents of this image are shorter than its superblock, or than
+    /// where its inode table starts
+    Truncated(PathBuf),
+    /// The contents of this image don't start with a squashfs superblock
+    BadSuperblock(PathBuf),
+    /// libsquashfs gave this offset in the image, which is past its end or
+    /// doesn't fit in a `usize`
+    OffsetOutOfRange { path: PathBuf, offset: FileOffset },
+    /// The superblock of this image names a compressor squashfs doesn't
+    /// define, so the rest of it can't be parsed
+    UnsupportedCompressor { path: PathBuf, id: u16 },
+    /// The blocks of this image couldn't be listed without libsquashfs,
+    /// because its layout is malformed or its metadata uses a compressor
+    /// that wasn't compiled in, see the `squashfs-rust` feature
+    Layout { path: PathBuf, error: io::Error },
+    /// A block of this image at this offset, or its inode table when the
+    /// offset is where that starts, overlaps what comes before it
+    Overlap { path: PathBuf, offset: FileOffset },
+    /// Diffing or writing the patch failed
+    Io(io::Error),
+}
+
+impl fmt::Display for SquashfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SquashfsError::InvalidPath(path) => {
+                write!(f, "path {:?} can't be passed to libsquashfs", path)
+            }
+            SquashfsError::Blocks { path, code } => {
+                write!(f, "could not list the blocks of {:?} (code {})", path, code)
+            }
+            SquashfsError::InodeTable(path) => {
+                write!(f, "could not find the inode table of {:?}", path)
+            }
+            SquashfsError::Truncated(path) => write!(f, "squashfs image {:?} is truncated", path),
+            SquashfsError::BadSuperblock(path) => {
+                write!(f, "{:?} doesn't start with a squashfs superblock", path)
+            }
+            SquashfsError::OffsetOutOfRange { path, offset } => {
+                write!(f, "offset {} is out of range of {:?}", offset, path)
+            }
+            SquashfsError::UnsupportedCompressor { path, id } => {
+                write!(f, "{:?} uses unknown compressor {}", path, id)
+            }
+            SquashfsError::Layout { path, .. } => {
+                write!(f, "could not read the layout of {:?}", path)
+            }
+            SquashfsError::Overlap { path, offset } => {
+                write!(
+                    f,
+                    "offset {} of {:?} overlaps what comes before it",
+                    offset, path
+                )
+            }
+            SquashfsError::Io(_) => write!(f, "I/O error"),
+        }
+    }
+}
+
+impl Error for SquashfsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SquashfsError::Io(e) | SquashfsError::Layout { error: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SquashfsError {
+    fn from(e: io::Error) -> Self {
+        SquashfsError::Io(e)
+    }
+}
+
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+const SQUASHFS_SUPERBLOCK_LEN: usize = 96;
+const SQUASHFS_FLAG_COMPRESSOR_OPTIONS: u16 = 0x0400;
+
+/// Length of the header of a squashfs image: its superblock, followed by the
+/// options of its compressor when they're not the defaults, which is a
+/// metadata block of its own
+pub(crate) fn squashfs_header_len(path: &Path, image: &[u8]) -> Result<usize, SquashfsError> {
+    let superblock = image
+        .get(..SQUASHFS_SUPERBLOCK_LEN)
+        .ok_or_else(|| SquashfsError::Truncated(path.to_path_buf()))?;
+    let magic = u32::from_le_bytes([superblock[0], superblock[1], superblock[2], superblock[3]]);
+    if magic != SQUASHFS_MAGIC {
+        return Err(SquashfsError::BadSuperblock(path.to_path_buf()));
+    }
+    let compressor = u16::from_le_bytes([superblock[20], superblock[21]]);
+    if SquashfsCompressor::from_id(compressor).is_none() {
+        return Err(SquashfsError::UnsupportedCompressor {
+            path: path.to_path_buf(),
+            id: compressor,
+        });
+    }
+    let flags = u16::from_le_bytes([superblock[24], superblock[25]]);
+    if flags & SQUASHFS_FLAG_COMPRESSOR_OPTIONS == 0 {
+        return Ok(SQUASHFS_SUPERBLOCK_LEN);
+    }
+
+    // metadata blocks start with their on-disk size, the top bit marks them
+    // as uncompressed
+    let size_header = image
+        .get(SQUASHFS_SUPERBLOCK_LEN..SQUASHFS_SUPERBLOCK_LEN + 2)
+        .ok_or_else(|| SquashfsError::Truncated(path.to_path_buf()))?;
+    let len = SQUASHFS_SUPERBLOCK_LEN
+        + 2
+        + (u16::from_le_bytes([size_header[0], size_header[1]]) & 0x7fff) as usize;
+    if len > image.len() {
+        return Err(SquashfsError::Truncated(path.to_path_buf()));
+    }
+    Ok(len)
+}
+
+/// Compressor of the squashfs image at `path`, read from its superblock
+///
+/// Fails with [SquashfsError::BadSuperblock] when the image can't be read,
+/// and [SquashfsError::UnsupportedCompressor] when its compressor isn't one
+/// of [SquashfsCompressor].
+pub fn squashfs_compressor(path: &Path) -> Result<SquashfsCompressor, SquashfsError> {
+    let id = backend::compressor_id(path)?;
+    SquashfsCompressor::from_id(id).ok_or_else(|| SquashfsError::UnsupportedCompressor {
+        path: path.to_path_buf(),
+        id,
+    })
+}
+
+/// The `len` bytes at `offset` of the image at `path`, which is `image_len`
+/// bytes long
+pub(crate) fn file_range(
+    path: &Path,
+    offset: FileOffset,
+    len: u32,
+    image_len: usize,
+) -> Result<Range<usize>, SquashfsError> {
+    usize::try_from(offset)
+        .ok()
+        .and_then(|start| Some(start..start.checked_add(len as usize)?))
+        .filter(|range| range.end <= image_len)
+        .ok_or_else(|| SquashfsError::OffsetOutOfRange {
+            path: path.to_path_buf(),
+            offset,
+        })
+}
+
+/// Check `blocks` of the image at `path`, given as offset and size and
+/// sorted by offset, that both backends list whatever the image says: what's
+/// between blocks that don't end where the next one starts is stored as-is,
+/// with a warning, but a block that starts before the previous one ends
+/// can't be diffed, and fails with [SquashfsError::Overlap].
+pub(crate) fn check_contiguous(
+    path: &Path,
+    blocks: impl IntoIterator<Item = (FileOffset, u32)>,
+) -> Result<(), SquashfsError> {
+    let mut prev: Option<(FileOffset, u32)> = None;
+    for (offset, size) in blocks {
+        if let Some((prev_offset, prev_size)) = prev {
+            match prev_offset.checked_add(prev_size as u64) {
+                Some(end) if end == offset => {}
+                Some(end) if end < offset => warn!(
+                    "blocks at {} ({} bytes) and {} of {:?} aren't contiguous, storing the {} bytes between them as-is",
+                    prev_offset,
+                    prev_size,
+                    offset,
+                    path,
+                    offset - end
+                ),
+                _ => {
+                    return Err(SquashfsError::Overlap {
+                        path: path.to_path_buf(),
+                        offset,
+                    })
+                }
+            }
+        }
+        prev = Some((offset, size));
+    }
+    Ok(())
+}
+
+/// Where a block of the new image comes from, see
+/// [SquashfsFragmentMap::diff_new], or any other part of it, see
+/// [diff_squashfs_with_regions]
+#[derive(Debug)]
+pub enum DataRegion {
+    /// A match against the old image, or literal bytes
+    Match(Match),
+    /// A repeat of the block of the new image at this range, which appeared
+    /// earlier
+    Repeat(Range<usize>),
+}
+
+/// Which part of the images [diff_squashfs] diffs a match comes from, in
+/// the order they come in images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SquashfsRegion {
+    /// The superblock and compressor options, diffed together
+    Header,
+    /// Data and fragment blocks, paired by hash
+    Data,
+    /// Everything from the inode tables on, diffed together
+    Footer,
+}
+
+/// The blocks of an old image, listed once and looked up by hash to diff
+/// any number of new images against it, see [diff_squashfs_with_map]
+pub struct SquashfsFragmentMap {
+    path: PathBuf,
+    len: usize,
+    /// Blocks by hash, split by the first byte of their hash into
+    /// [FRAGMENT_MAP_SHARDS] maps, which are built in parallel with the
+    /// `parallel` feature
+    blocks: Vec<HashMap<Hash, (FileOffset, u32)>>,
+}
+
+/// Number of maps the blocks of a [SquashfsFragmentMap] are split into
+const FRAGMENT_MAP_SHARDS: usize = 64;
+
+impl SquashfsFragmentMap {
+    /// List the blocks of the image at `path`, whose contents are `len`
+    /// bytes long
+    pub fn new(path: &Path, len: usize) -> Result<Self, SquashfsError> {
+        Ok(Self::from_blocks(path, len, backend::blocks(path)?))
+    }
+
+    /// Map the blocks of an image listed elsewhere, given as hash, offset
+    /// and on-disk size like [diff_squashfs_in_memory] takes them. When
+    /// blocks share a hash, the last one is kept.
+    pub fn from_blocks<I>(path: &Path, len: usize, blocks: I) -> Self
+    where
+        I: IntoIterator<Item = (Hash, FileOffset, u32)>,
+    {
+        let mut shards = vec![Vec::new(); FRAGMENT_MAP_SHARDS];
+        for (hash, pos, length) in blocks {
+            shards[shard(&hash)].push((hash, (pos, length)));
+        }
+        Self {
+            path: path.to_path_buf(),
+            len,
+            blocks: in_parallel(shards)
+                .map(|shard| shard.into_iter().collect())
+                .collect(),
+        }
+    }
+
+    fn get(&self, hash: &Hash) -> Option<&(FileOffset, u32)> {
+        self.blocks[shard(hash)].get(hash)
+    }
+
+    /// Path of the old image
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Pair the blocks of the new image at `new_path`, whose contents are
+    /// `new_len` bytes long, with identical blocks of the old one, passing
+    /// each of them to `on_region` in order.
+    ///
+    /// Blocks that aren't in the old image but repeat an earlier block of
+    /// the new image are copied from that first occurence, instead of being
+    /// stored again. Everything else is stored as-is, including blocks whose
+    /// hash is that of a block with another length, with a warning.
+    pub fn diff_new<F>(
+        &self,
+        new_path: &Path,
+        new_len: usize,
+        on_region: F,
+    ) -> Result<(), SquashfsError>
+    where
+        F: FnMut(DataRegion) -> Result<(), io::Error>,
+    {
+        self.diff_blocks(new_path, backend::blocks(new_path)?, new_len, on_region)
+    }
+
+    pub(crate) fn diff_blocks<I, F>(
+        &self,
+        new_path: &Path,
+        new_blocks: I,
+        new_len: usize,
+        mut on_region: F,
+    ) -> Result<(), SquashfsError>
+    where
+        I: IntoIterator<Item = (Hash, FileOffset, u32)>,
+        F: FnMut(DataRegion) -> Result<(), io::Error>,
+    {
+        // blocks with the same hash should have the same length, but a hash
+        // collision or a shim bug shouldn't abort the diff
+        let same_length = |new_pos: FileOffset, length: u32, path: &Path, other_length: u32| {
+            if other_length != length {
+                warn!(
+                    "block at {} of {} ({} bytes) has the hash of a {}-byte block of {}, storing it as-is",
+                    new_pos,
+                    new_path.display(),
+                    length,
+                    other_length,
+                    path.display()
+                );
+            }
+            other_length == length
+        };
+
+        // looking blocks up in the old image doesn't depend on the other
+        // blocks, so it's done for all of them first, in parallel with the
+        // `parallel` feature. Repeats depend on the blocks before them, and
+        // are found in order afterwards.
+        let lookups = in_parallel(new_blocks.into_iter().collect())
+            .map(|(new_hash, new_pos, length)| {
+                let new_range = file_range(new_path, new_pos, length, new_len)?;
+                let old_range = match self.get(&new_hash) {
+                    Some((old_pos, old_length))
+                        if same_length(new_pos, length, &self.path, *old_length) =>
+                    {
+                        Some(file_range(&self.path, *old_pos, *old_length, self.len)?)
+                    }
+                    _ => None,
+                };
+                Ok((new_hash, new_pos, length, new_range, old_range))
+            })
+            .collect::<Result<Vec<_>, SquashfsError>>()?;
+
+        let mut new_map = HashMap::<Hash, (Range<usize>, u32)>::new();
+        for (new_hash, new_pos, length, new_range, old_range) in lookups {
+            let first = new_map.get(&new_hash).filter(|(_, first_length)| {
+                old_range.is_none() && same_length(new_pos, length, new_path, *first_length)
+            });
+            let region = match (old_range, first) {
+                (Some(old_range), _) => DataRegion::Match(Match {
+                    add_old_start: old_range.start,
+                    add_new_start: new_range.start,
+                    add_length: new_range.len(),
+                    copy_end: new_range.end,
+                }),
+                (None, Some((first_range, _))) => DataRegion::Repeat(first_range.clone()),
+                (None, None) => {
+                    new_map
+                        .entry(new_hash)
+                        .or_insert((new_range.clone(), length));
+                    DataRegion::Match(Match {
+                        add_old_start: 0,
+                        add_new_start: new_range.start,
+                        add_length: 0,
+                        copy_end: new_range.end,
+                    })
+                }
+            };
+            on_region(region)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which of the [FRAGMENT_MAP_SHARDS] maps of a [SquashfsFragmentMap] a
+/// block with this hash goes in
+fn shard(hash: &Hash) -> usize {
+    hash[0] as usize % FRAGMENT_MAP_SHARDS
+}
+
+/// `items`, processed in parallel with the `parallel` feature, and in order
+/// without it
+#[cfg(feature = "parallel")]
+fn in_parallel<T: Send>(items: Vec<T>) -> rayon::vec::IntoIter<T> {
+    items.into_par_iter()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn in_parallel<T>(items: Vec<T>) -> std::vec::IntoIter<T> {
+    items.into_iter()
+}
+
+pub fn diff_squashfs(
+    old_path: &Path,
+    old: &[u8],
+    new_path: &Path,
+    new: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<(), SquashfsError> {
+    // reading the superblocks is cheaper than listing blocks, and catches
+    // most bad inputs
+    squashfs_header_len(old_path, old)?;
+    squashfs_header_len(new_path, new)?;
+    let map = SquashfsFragmentMap::new(old_path, old.len())?;
+    diff_squashfs_with_map(&map, old, new_path, new, out, diff_params)
+}
+
+/// Called with each match [diff_squashfs_with_regions] and
+/// [diff_squashfs_in_memory_with_regions] write, in order, and the region
+/// of the images it comes from
+pub type OnSquashfsRegion<'a> = dyn FnMut(SquashfsRegion, &DataRegion) + 'a;
+
+/// [diff_squashfs] against an old image whose blocks were already listed in
+/// `map`, from `old`
+pub fn diff_squashfs_with_map(
+    map: &SquashfsFragmentMap,
+    old: &[u8],
+    new_path: &Path,
+    new: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<(), SquashfsError> {
+    diff_squashfs_with_regions(map, old, new_path, new, out, diff_params, &mut |_, _| {})
+}
+
+/// [diff_squashfs_with_map], passing each match to `on_region` with the
+/// region of the images it comes from, to see where the bytes of the patch
+/// go: the [DataRegion::Match]es of the header and footer cover their whole
+/// region, and the data blocks come one by one
+pub fn diff_squashfs_with_regions(
+    map: &SquashfsFragmentMap,
+    old: &[u8],
+    new_path: &Path,
+    new: &[u8],
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+    on_region: &mut OnSquashfsRegion,
+) -> Result<(), SquashfsError> {
+    let old_path = map.path();
+    if old.len() != map.len {
+        return Err(SquashfsError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the blocks of {:?} were listed from {} bytes, not {}",
+                old_path,
+                map.len,
+                old.len()
+            ),
+        )));
+    }
+    // reading the superblocks is cheaper than listing blocks, and catches
+    // most bad inputs
+    squashfs_header_len(old_path, old)?;
+    squashfs_header_len(new_path, new)?;
+    let inode_tables = (
+        backend::inode_table_start(old_path)?,
+        backend::inode_table_start(new_path)?,
+    );
+    let new_blocks = backend::blocks(new_path)?;
+    diff_images(
+        map,
+        old,
+        new_path,
+        new,
+        new_blocks,
+        inode_tables,
+        out,
+        diff_params,
+        on_region,
+    )
+}
+
+/// Name of the old image in the errors of [diff_squashfs_in_memory]
+const IN_MEMORY_OLD: &str = "<old image>";
+/// Name of the new image in the errors of [diff_squashfs_in_memory]
+const IN_MEMORY_NEW: &str = "<new image>";
+
+/// [diff_squashfs] between images that are only in memory, given their
+/// blocks instead of paths to list them from
+///
+/// `old_blocks` and `new_blocks` are the hash, offset and on-disk size of
+/// the data blocks and fragment blocks of each image, as libsquashfs or the
+/// `squashfs-rust` feature list them, and where the inode tables start is
+/// read from the superblocks. Blocks can be given in any order, and what's
+/// between them in the new image is stored as-is, but they can't overlap
+/// each other, the header or the inode tables ([SquashfsError::Overlap]).
+/// Errors name the images `<old image>` and `<new image>`.
+pub fn diff_squashfs_in_memory(
+    old: &[u8],
+    old_blocks: Vec<(Hash, FileOffset, u32)>,
+    new: &[u8],
+    new_blocks: Vec<(Hash, FileOffset, u32)>,
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+) -> Result<(), SquashfsError> {
+    diff_squashfs_in_memory_with_regions(
+        old,
+        old_blocks,
+        new,
+        new_blocks,
+        out,
+        diff_params,
+        &mut |_, _| {},
+    )
+}
+
+/// [diff_squashfs_in_memory], passing each match to `on_region` like
+/// [diff_squashfs_with_regions] does
+pub fn diff_squashfs_in_memory_with_regions(
+    old: &[u8],
+    old_blocks: Vec<(Hash, FileOffset, u32)>,
+    new: &[u8],
+    new_blocks: Vec<(Hash, FileOffset, u32)>,
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+    on_region: &mut OnSquashfsRegion,
+) -> Result<(), SquashfsError> {
+    let (old_path, new_path) = (Path::new(IN_MEMORY_OLD), Path::new(IN_MEMORY_NEW));
+    let map = SquashfsFragmentMap::from_blocks(old_path, old.len(), old_blocks);
+    let inode_tables = (
+        superblock_inode_table_start(old_path, old)?,
+        superblock_inode_table_start(new_path, new)?,
+    );
+    diff_images(
+        &map,
+        old,
+        new_path,
+        new,
+        new_blocks,
+        inode_tables,
+        out,
+        diff_params,
+        on_region,
+    )
+}
+
+/// Where the inode table of `image` starts, read from its superblock
+fn superblock_inode_table_start(path: &Path, image: &[u8]) -> Result<usize, SquashfsError> {
+    squashfs_header_len(path, image)?;
+    let mut start = [0; 8];
+    start.copy_from_slice(&image[64..72]);
+    match u64::from_le_bytes(start) {
+        0 => Err(SquashfsError::InodeTable(path.to_path_buf())),
+        start => Ok(file_range(path, start, 0, usize::MAX)?.start),
+    }
+}
+
+/// Write the patch of `new` against the image `map` was listed from, `old`:
+/// their headers are diffed, then the blocks of `new` are paired with those
+/// of `old`, then everything from their inode tables on is diffed. Each
+/// match is passed to `on_region` before it's written.
+#[allow(clippy::too_many_arguments)]
+fn diff_images<I>(
+    map: &SquashfsFragmentMap,
+    old: &[u8],
+    new_path: &Path,
+    new: &[u8],
+    new_blocks: I,
+    (footer_offset_old, footer_offset_new): (usize, usize),
+    out: &mut dyn Write,
+    diff_params: &DiffParams,
+    on_region: &mut OnSquashfsRegion,
+) -> Result<(), SquashfsError>
+where
+    I: IntoIterator<Item = (Hash, FileOffset, u32)>,
+{
+    let old_path = map.path();
+    let old_header_len = squashfs_header_len(old_path, old)?;
+    let new_header_len = squashfs_header_len(new_path, new)?;
+    if footer_offset_old > old.len() {
+        return Err(SquashfsError::Truncated(old_path.to_path_buf()));
+    }
+    if footer_offset_new > new.len() {
+        return Err(SquashfsError::Truncated(new_path.to_path_buf()));
+    }
+    debug!(
+        "inode tables start at {} in old, {} in new",
+        footer_offset_old, footer_offset_new
+    );
+
+    // blocks are patched in the order they come in `new`, and whatever is
+    // between them is stored as-is
+    let mut new_blocks: Vec<_> = new_blocks.into_iter().collect();
+    new_blocks.sort_by_key(|(_, offset, _)| *offset);
+    let gaps = data_gaps(
+        new_path,
+        &new_blocks,
+        new_header_len..footer_offset_new,
+        new.len(),
+    )?;
+
+    let mut regions = Vec::new();
+    map.diff_blocks(new_path, new_blocks, new.len(), |region| {
+        regions.push(region);
+        Ok(())
+    })?;
+
+    // repeated blocks are copied from the reconstructed image, which the
+    // patch header has to announce
+    let options = enc::WriterOptions {
+        target_copies: regions
+            .iter()
+            .any(|region| matches!(region, DataRegion::Repeat(_))),
+        source_checksum: diff_params
+            .source_checksum
+            .then(|| hmac_sha256::Hash::hash(old)),
+        ..Default::default()
+    };
+    let mut w = enc::Writer::with_options(out, &options)?;
+
+    let mut translator = Translator::new(old, new, |control| w.write(control));
+    let mut translate = |kind: SquashfsRegion, region: DataRegion| {
+        on_region(kind, &region);
+        match region {
+            DataRegion::Match(m) => translator.translate(m),
+            DataRegion::Repeat(src) => translator.copy_target(src),
+        }
+    };
+    let res = (|| {
+        diff(
+            &old[..old_header_len],
+            &new[..new_header_len],
+            diff_params,
+            |m| translate(SquashfsRegion::Header, DataRegion::Match(m)),
+        )?;
+
+        let literal = |gap: Range<usize>| {
+            DataRegion::Match(Match {
+                add_old_start: 0,
+                add_new_start: gap.start,
+                add_length: 0,
+                copy_end: gap.end,
+            })
+        };
+        let mut gaps = gaps.into_iter();
+        for region in regions {
+            if let Some(gap) = gaps.next().filter(|gap| !gap.is_empty()) {
+                translate(SquashfsRegion::Data, literal(gap))?;
+            }
+            translate(SquashfsRegion::Data, region)?;
+        }
+        if let Some(gap) = gaps.next().filter(|gap| !gap.is_empty()) {
+            translate(SquashfsRegion::Data, literal(gap))?;
+        }
+
+        // the footer offsets of both images can be very far apart, the
+        // resulting seek is encoded as a signed varint so any distance
+        // round-trips.
+        diff_range(
+            old,
+            footer_offset_old..old.len(),
+            new,
+            footer_offset_new..new.len(),
+            diff_params,
+            |m| translate(SquashfsRegion::Footer, DataRegion::Match(m)),
+        )?;
+        Ok(())
+    })();
+    translator.close_after(res)
+}
+
+/// What's before each of `blocks` of the image at `path`, sorted by offset,
+/// and after the last one, within its `data` range: from the end of its
+/// header to the start of its inode table
+fn data_gaps(
+    path: &Path,
+    blocks: &[(Hash, FileOffset, u32)],
+    data: Range<usize>,
+    image_len: usize,
+) -> Result<Vec<Range<usize>>, SquashfsError> {
+    let overlap = |offset| SquashfsError::Overlap {
+        path: path.to_path_buf(),
+        offset,
+    };
+    let mut pos = data.start;
+    let mut gaps = Vec::with_capacity(blocks.len() + 1);
+    for (_, offset, size) in blocks {
+        let range = file_range(path, *offset, *size, image_len)?;
+        if range.start < pos {
+            return Err(overlap(*offset));
+        }
+        gaps.push(pos..range.start);
+        pos = range.end;
+    }
+    if data.end < pos {
+        return Err(overlap(data.end as FileOffset));
+    }
+    gaps.push(pos..data.end);
+    Ok(gaps)
+}
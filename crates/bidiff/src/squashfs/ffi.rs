@@ -0,0 +1,130 @@
+//! Listing blocks with libsquashfs, through the C shim in `shim.c`, for the
+//! `squashfs` feature.
+
+use super::{check_contiguous, file_range, FileOffset, SquashfsError};
+use crate::Hash;
+use std::{
+    ffi::{c_char, c_int, CString},
+    path::Path,
+};
+
+#[repr(C)]
+struct Block {
+    offset: FileOffset,
+    size: u32,
+    hash: Hash, //sha256
+}
+
+extern "C" {
+    fn shim_get_blocks(
+        path: *const c_char,
+        blocks: *mut *mut Block,
+        blocks_len: *mut usize,
+    ) -> c_int;
+    fn shim_free_blocks(blocks: *mut Block);
+    fn shim_get_inode_table_idx(path: *const c_char) -> FileOffset;
+    fn shim_get_compressor(path: *const c_char) -> u16;
+}
+
+fn c_path(path: &Path) -> Result<CString, SquashfsError> {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        Some(path.as_os_str().as_bytes())
+    };
+    #[cfg(not(unix))]
+    let bytes = path.to_str().map(str::as_bytes);
+
+    bytes
+        .and_then(|bytes| CString::new(bytes).ok())
+        .ok_or_else(|| SquashfsError::InvalidPath(path.to_path_buf()))
+}
+
+/// ID of the compressor of the image at `path`, as stored in its superblock
+pub(super) fn compressor_id(path: &Path) -> Result<u16, SquashfsError> {
+    let c_path = c_path(path)?;
+    let id = unsafe { shim_get_compressor(c_path.as_ptr() as *const c_char) };
+    if id == 0 {
+        return Err(SquashfsError::BadSuperblock(path.to_path_buf()));
+    }
+    Ok(id)
+}
+
+/// Where the inode table of the image at `path` starts
+pub(super) fn inode_table_start(path: &Path) -> Result<usize, SquashfsError> {
+    let c_path = c_path(path)?;
+    let ret = unsafe { shim_get_inode_table_idx(c_path.as_ptr() as *const c_char) };
+    if ret == 0 {
+        return Err(SquashfsError::InodeTable(path.to_path_buf()));
+    }
+    Ok(file_range(path, ret, 0, usize::MAX)?.start)
+}
+
+/// The data blocks and fragment blocks of the image at `path`, in order
+pub(super) fn blocks(path: &Path) -> Result<Fragments, SquashfsError> {
+    Fragments::new(path)
+}
+
+/// Blocks listed by `shim_get_blocks`, which stay owned by the C side: they
+/// were allocated by glib, so they're freed by `shim_free_blocks` rather than
+/// by Rust's allocator.
+pub(super) struct Fragments {
+    blocks: *mut Block,
+    len: usize,
+    pos: usize,
+}
+
+impl Fragments {
+    fn new(path: &Path) -> Result<Self, SquashfsError> {
+        let c_path = c_path(path)?;
+        let mut blocks = std::ptr::null_mut();
+        let mut blocks_len = 0usize;
+        let ret = unsafe {
+            shim_get_blocks(
+                c_path.as_ptr() as *const c_char,
+                &mut blocks as *mut *mut Block,
+                &mut blocks_len as *mut usize,
+            )
+        };
+        if ret != 0 {
+            return Err(SquashfsError::Blocks {
+                path: path.to_path_buf(),
+                code: ret,
+            });
+        }
+        let fragments = Self {
+            blocks,
+            len: blocks_len,
+            pos: 0,
+        };
+        check_contiguous(path, fragments.blocks().iter().map(|b| (b.offset, b.size)))?;
+        Ok(fragments)
+    }
+
+    fn blocks(&self) -> &[Block] {
+        if self.blocks.is_null() {
+            return &[];
+        }
+        // SAFETY: `shim_get_blocks` returned `len` initialized blocks, which
+        // live until `shim_free_blocks` is called in `drop`
+        unsafe { std::slice::from_raw_parts(self.blocks, self.len) }
+    }
+}
+
+impl Drop for Fragments {
+    fn drop(&mut self) {
+        if !self.blocks.is_null() {
+            unsafe { shim_free_blocks(self.blocks) };
+        }
+    }
+}
+
+impl Iterator for Fragments {
+    type Item = (Hash, FileOffset, u32); //Hash & offset & size
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = self.blocks().get(self.pos)?;
+        let item = (block.hash, block.offset, block.size);
+        self.pos += 1;
+        Some(item)
+    }
+}
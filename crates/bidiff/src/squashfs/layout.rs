@@ -0,0 +1,360 @@
+//! Listing blocks by reading the layout of squashfs images in Rust, for the
+//! `squashfs-rust` feature.
+//!
+//! This lists the same blocks as the C shim: the data blocks of every file
+//! inode, and the fragment blocks. Data blocks and fragments are hashed as
+//! they're stored, so only metadata (the inode table and the fragment table)
+//! is decompressed. Gzip metadata is always supported, xz and zstd metadata
+//! need the `xz` and `zstd` features, and other compressors aren't.
+
+use super::{
+    check_contiguous, FileOffset, SquashfsCompressor, SquashfsError, SQUASHFS_MAGIC,
+    SQUASHFS_SUPERBLOCK_LEN,
+};
+use crate::Hash;
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// Most bytes of a metadata block, once decompressed
+const METADATA_LEN: usize = 8192;
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+/// Data block and fragment sizes have this bit set when they're stored
+/// uncompressed, and are 0 for sparse blocks
+const BLOCK_UNCOMPRESSED: u32 = 1 << 24;
+const FRAGMENT_ENTRY_LEN: usize = 16;
+/// Fragment index of files that don't end with a fragment
+const NO_FRAGMENT: u32 = u32::MAX;
+/// Start of tables an image doesn't have
+const NO_TABLE: u64 = u64::MAX;
+
+/// The parts of a superblock needed to find blocks
+struct Superblock {
+    inode_count: u32,
+    block_size: u32,
+    fragment_count: u32,
+    compressor: u16,
+    inode_table_start: u64,
+    directory_table_start: u64,
+    fragment_table_start: u64,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_superblock<R: Read>(r: &mut R) -> io::Result<Superblock> {
+    let mut raw = [0u8; SQUASHFS_SUPERBLOCK_LEN];
+    r.read_exact(&mut raw)?;
+    let mut r = &raw[..];
+
+    if r.read_u32::<LittleEndian>()? != SQUASHFS_MAGIC {
+        return Err(invalid("wrong magic in superblock"));
+    }
+    let inode_count = r.read_u32::<LittleEndian>()?;
+    let _mtime = r.read_u32::<LittleEndian>()?;
+    let block_size = r.read_u32::<LittleEndian>()?;
+    let fragment_count = r.read_u32::<LittleEndian>()?;
+    let compressor = r.read_u16::<LittleEndian>()?;
+    let _block_log = r.read_u16::<LittleEndian>()?;
+    let _flags = r.read_u16::<LittleEndian>()?;
+    let _id_count = r.read_u16::<LittleEndian>()?;
+    let version = (r.read_u16::<LittleEndian>()?, r.read_u16::<LittleEndian>()?);
+    if version != (4, 0) {
+        return Err(invalid("only squashfs 4.0 images are supported"));
+    }
+    if !block_size.is_power_of_two() || !(4096..=1024 * 1024).contains(&block_size) {
+        return Err(invalid("invalid block size in superblock"));
+    }
+    let _root_inode = r.read_u64::<LittleEndian>()?;
+    let _bytes_used = r.read_u64::<LittleEndian>()?;
+    let _id_table_start = r.read_u64::<LittleEndian>()?;
+    let _xattr_id_table_start = r.read_u64::<LittleEndian>()?;
+    let inode_table_start = r.read_u64::<LittleEndian>()?;
+    let directory_table_start = r.read_u64::<LittleEndian>()?;
+    let fragment_table_start = r.read_u64::<LittleEndian>()?;
+
+    Ok(Superblock {
+        inode_count,
+        block_size,
+        fragment_count,
+        compressor,
+        inode_table_start,
+        directory_table_start,
+        fragment_table_start,
+    })
+}
+
+fn open_superblock(path: &Path) -> io::Result<Superblock> {
+    read_superblock(&mut File::open(path)?)
+}
+
+/// ID of the compressor of the image at `path`, as stored in its superblock
+pub(super) fn compressor_id(path: &Path) -> Result<u16, SquashfsError> {
+    open_superblock(path)
+        .map(|superblock| superblock.compressor)
+        .map_err(|_| SquashfsError::BadSuperblock(path.to_path_buf()))
+}
+
+/// Where the inode table of the image at `path` starts
+pub(super) fn inode_table_start(path: &Path) -> Result<usize, SquashfsError> {
+    open_superblock(path)
+        .ok()
+        .and_then(|superblock| usize::try_from(superblock.inode_table_start).ok())
+        .filter(|start| *start > 0)
+        .ok_or_else(|| SquashfsError::InodeTable(path.to_path_buf()))
+}
+
+/// The data blocks and fragment blocks of the image at `path`, in order
+pub(super) fn blocks(
+    path: &Path,
+) -> Result<std::vec::IntoIter<(Hash, FileOffset, u32)>, SquashfsError> {
+    let blocks = File::open(path)
+        .and_then(|file| list_blocks(&mut BufReader::new(file)))
+        .map_err(|error| SquashfsError::Layout {
+            path: path.to_path_buf(),
+            error,
+        })?;
+    check_contiguous(
+        path,
+        blocks.iter().map(|(_, offset, size)| (*offset, *size)),
+    )?;
+    Ok(blocks.into_iter())
+}
+
+/// The hash, offset and on-disk size of the data blocks and fragment blocks
+/// of the image `r` reads, sorted by offset, without duplicates
+pub(crate) fn list_blocks<R: Read + Seek>(r: &mut R) -> io::Result<Vec<(Hash, FileOffset, u32)>> {
+    r.seek(SeekFrom::Start(0))?;
+    let superblock = read_superblock(r)?;
+
+    let mut blocks = Vec::new();
+    let inodes = read_metadata(
+        r,
+        superblock.compressor,
+        superblock.inode_table_start,
+        superblock.directory_table_start,
+    )?;
+    let mut inodes = &inodes[..];
+    for _ in 0..superblock.inode_count {
+        read_inode(&mut inodes, superblock.block_size, &mut blocks)?;
+    }
+    read_fragments(r, &superblock, &mut blocks)?;
+
+    // files with the same contents share blocks
+    blocks.sort_unstable();
+    blocks.dedup();
+
+    let mut data = Vec::new();
+    blocks
+        .into_iter()
+        .map(|(offset, size)| {
+            r.seek(SeekFrom::Start(offset))?;
+            data.resize(size as usize, 0);
+            r.read_exact(&mut data)?;
+            Ok((hmac_sha256::Hash::hash(&data), offset, size))
+        })
+        .collect()
+}
+
+/// Read the metadata blocks from `start` to `end`, decompressed and in a
+/// single buffer
+fn read_metadata<R: Read + Seek>(
+    r: &mut R,
+    compressor: u16,
+    start: u64,
+    end: u64,
+) -> io::Result<Vec<u8>> {
+    let mut metadata = Vec::new();
+    let mut pos = start;
+    r.seek(SeekFrom::Start(pos))?;
+    while pos < end {
+        pos += read_metadata_block(r, compressor, &mut metadata)?;
+    }
+    Ok(metadata)
+}
+
+/// Read the metadata block at the current position, appending it to `out`,
+/// and return its on-disk length
+fn read_metadata_block<R: Read>(r: &mut R, compressor: u16, out: &mut Vec<u8>) -> io::Result<u64> {
+    let header = r.read_u16::<LittleEndian>()?;
+    let len = (header & !METADATA_UNCOMPRESSED) as usize;
+    let mut raw = vec![0u8; len];
+    r.read_exact(&mut raw)?;
+
+    if header & METADATA_UNCOMPRESSED != 0 {
+        if len > METADATA_LEN {
+            return Err(invalid("metadata block too large"));
+        }
+        out.extend_from_slice(&raw);
+    } else {
+        let before = out.len();
+        decompress(compressor, &raw)?
+            .take(METADATA_LEN as u64 + 1)
+            .read_to_end(out)?;
+        if out.len() - before > METADATA_LEN {
+            return Err(invalid("metadata block too large"));
+        }
+    }
+    Ok(2 + len as u64)
+}
+
+fn decompress<'a>(compressor: u16, raw: &'a [u8]) -> io::Result<Box<dyn Read + 'a>> {
+    match SquashfsCompressor::from_id(compressor) {
+        Some(SquashfsCompressor::Gzip) => Ok(Box::new(flate2::read::ZlibDecoder::new(raw))),
+        #[cfg(feature = "xz")]
+        Some(SquashfsCompressor::Xz) => Ok(Box::new(xz2::read::XzDecoder::new(raw))),
+        #[cfg(feature = "zstd")]
+        Some(SquashfsCompressor::Zstd) => Ok(Box::new(zstd::stream::read::Decoder::new(raw)?)),
+        compressor => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "can't decompress {:?} metadata without libsquashfs",
+                compressor
+            ),
+        )),
+    }
+}
+
+fn skip(r: &mut &[u8], len: usize) -> io::Result<()> {
+    if len > r.len() {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    *r = &r[len..];
+    Ok(())
+}
+
+/// Read the inode at the start of `r`, adding the data blocks of files to
+/// `blocks`
+fn read_inode(
+    r: &mut &[u8],
+    block_size: u32,
+    blocks: &mut Vec<(FileOffset, u32)>,
+) -> io::Result<()> {
+    let kind = r.read_u16::<LittleEndian>()?;
+    // permissions, uid, gid, mtime and inode number
+    skip(r, 14)?;
+    match kind {
+        // directory
+        1 => skip(r, 16),
+        // file
+        2 => {
+            let start = r.read_u32::<LittleEndian>()? as u64;
+            let fragment = r.read_u32::<LittleEndian>()?;
+            let _fragment_offset = r.read_u32::<LittleEndian>()?;
+            let size = r.read_u32::<LittleEndian>()? as u64;
+            read_file_blocks(r, start, size, fragment, block_size, blocks)
+        }
+        // symlink, and extended symlink with its xattr index
+        3 | 10 => {
+            let _link_count = r.read_u32::<LittleEndian>()?;
+            let target_len = r.read_u32::<LittleEndian>()? as usize;
+            skip(r, target_len)?;
+            skip(r, if kind == 10 { 4 } else { 0 })
+        }
+        // block and character devices
+        4 | 5 => skip(r, 8),
+        // fifo and socket
+        6 | 7 => skip(r, 4),
+        // extended directory, followed by its index
+        8 => {
+            skip(r, 16)?;
+            let index_count = r.read_u16::<LittleEndian>()?;
+            skip(r, 6)?;
+            for _ in 0..index_count {
+                skip(r, 8)?;
+                let name_len = r.read_u32::<LittleEndian>()? as usize;
+                skip(r, name_len + 1)?;
+            }
+            Ok(())
+        }
+        // extended file
+        9 => {
+            let start = r.read_u64::<LittleEndian>()?;
+            let size = r.read_u64::<LittleEndian>()?;
+            // sparse byte count and link count
+            skip(r, 12)?;
+            let fragment = r.read_u32::<LittleEndian>()?;
+            // fragment offset and xattr index
+            skip(r, 8)?;
+            read_file_blocks(r, start, size, fragment, block_size, blocks)
+        }
+        // extended block and character devices
+        11 | 12 => skip(r, 12),
+        // extended fifo and socket
+        13 | 14 => skip(r, 8),
+        _ => Err(invalid("unknown inode type")),
+    }
+}
+
+/// Read the block sizes of a file, whose blocks start at `start`
+fn read_file_blocks(
+    r: &mut &[u8],
+    start: u64,
+    size: u64,
+    fragment: u32,
+    block_size: u32,
+    blocks: &mut Vec<(FileOffset, u32)>,
+) -> io::Result<()> {
+    let block_size = block_size as u64;
+    // the end of the file is in a fragment, unless there's none
+    let count = if fragment == NO_FRAGMENT {
+        size.div_ceil(block_size)
+    } else {
+        size / block_size
+    };
+
+    let mut location = start;
+    for _ in 0..count {
+        let on_disk = r.read_u32::<LittleEndian>()? & !BLOCK_UNCOMPRESSED;
+        // sparse blocks aren't stored
+        if on_disk == 0 {
+            continue;
+        }
+        blocks.push((location, on_disk));
+        location = location
+            .checked_add(on_disk as u64)
+            .ok_or_else(|| invalid("file blocks past the end of the image"))?;
+    }
+    Ok(())
+}
+
+/// Read the fragment table, adding fragment blocks to `blocks`
+fn read_fragments<R: Read + Seek>(
+    r: &mut R,
+    superblock: &Superblock,
+    blocks: &mut Vec<(FileOffset, u32)>,
+) -> io::Result<()> {
+    let count = superblock.fragment_count as usize;
+    if count == 0 || superblock.fragment_table_start == NO_TABLE {
+        return Ok(());
+    }
+
+    // the table is an index of the metadata blocks holding its entries
+    let metadata_blocks = (count * FRAGMENT_ENTRY_LEN).div_ceil(METADATA_LEN);
+    r.seek(SeekFrom::Start(superblock.fragment_table_start))?;
+    let index = (0..metadata_blocks)
+        .map(|_| r.read_u64::<LittleEndian>())
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut entries = Vec::with_capacity(count * FRAGMENT_ENTRY_LEN);
+    for start in index {
+        r.seek(SeekFrom::Start(start))?;
+        read_metadata_block(r, superblock.compressor, &mut entries)?;
+    }
+
+    let mut entries = &entries[..];
+    for _ in 0..count {
+        let start = entries.read_u64::<LittleEndian>()?;
+        let on_disk = entries.read_u32::<LittleEndian>()? & !BLOCK_UNCOMPRESSED;
+        let _unused = entries.read_u32::<LittleEndian>()?;
+        if on_disk == 0 {
+            return Err(invalid("sparse fragment"));
+        }
+        blocks.push((start, on_disk));
+    }
+    Ok(())
+}
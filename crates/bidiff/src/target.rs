@@ -0,0 +1,78 @@
+//! Finding repeats of "newer" bytes earlier in "newer" itself, see
+//! [Translator::with_target_copies](crate::Translator::with_target_copies).
+
+use std::ops::Range;
+
+/// Number of bytes hashed to look up earlier occurences
+const WINDOW: usize = 8;
+/// Shortest repeat worth a copy: each one costs a control of its own, which
+/// is around 10 bytes
+const MIN_LENGTH: usize = 32;
+const HASH_BITS: u32 = 16;
+const NONE: usize = usize::MAX;
+
+/// Hash table of the last position of each [WINDOW]-byte sequence of "newer",
+/// filled in as the literal regions are split
+pub(crate) struct TargetIndex {
+    head: Vec<usize>,
+    /// Positions before this one are in `head`
+    indexed: usize,
+}
+
+impl TargetIndex {
+    pub(crate) fn new() -> Self {
+        Self {
+            head: vec![NONE; 1 << HASH_BITS],
+            indexed: 0,
+        }
+    }
+
+    /// Split the literal region of `nbuf` into `(literal, target)` pieces:
+    /// literal bytes followed by a copy of earlier bytes of `nbuf`, which may
+    /// be empty. The copied range may overlap the bytes it produces, as long
+    /// as it starts before them.
+    ///
+    /// Literal regions must be passed in order, and everything before them
+    /// is considered already reconstructed.
+    pub(crate) fn split(
+        &mut self,
+        nbuf: &[u8],
+        literal: Range<usize>,
+        pieces: &mut Vec<(Range<usize>, Range<usize>)>,
+    ) {
+        let mut lit_start = literal.start;
+        let mut i = literal.start;
+        while i + MIN_LENGTH <= literal.end {
+            self.index_up_to(nbuf, i);
+            let src = self.head[hash(&nbuf[i..i + WINDOW])];
+            if src != NONE {
+                let len = (0..literal.end - i)
+                    .take_while(|&k| nbuf[src + k] == nbuf[i + k])
+                    .count();
+                if len >= MIN_LENGTH {
+                    pieces.push((lit_start..i, src..src + len));
+                    i += len;
+                    lit_start = i;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        pieces.push((lit_start..literal.end, 0..0));
+    }
+
+    fn index_up_to(&mut self, nbuf: &[u8], pos: usize) {
+        for p in self.indexed..pos {
+            if p + WINDOW <= nbuf.len() {
+                self.head[hash(&nbuf[p..p + WINDOW])] = p;
+            }
+        }
+        self.indexed = self.indexed.max(pos);
+    }
+}
+
+fn hash(window: &[u8]) -> usize {
+    let mut bytes = [0u8; WINDOW];
+    bytes.copy_from_slice(window);
+    (u64::from_le_bytes(bytes).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (64 - HASH_BITS)) as usize
+}
@@ -0,0 +1,97 @@
+//! Metrics about diffs, emitted through the [metrics](https://docs.rs/metrics)
+//! crate when the `metrics` feature is enabled, for monitoring services that
+//! run many diffs. Without the feature, nothing is recorded and this costs
+//! nothing.
+//!
+//! Each call to [Differ::prepare](crate::Differ::prepare) records the size
+//! of "older" and the time spent sorting it, each call to
+//! [Differ::scan](crate::Differ::scan) the size of "newer" and the time spent
+//! scanning it, and the `simple_diff` family the size of the patch.
+
+use std::time::Duration;
+
+/// Counter of "older" bytes prepared for diffing
+pub const OLDER_BYTES: &str = "bidiff_older_bytes";
+/// Counter of "newer" bytes scanned
+pub const NEWER_BYTES: &str = "bidiff_newer_bytes";
+/// Counter of encoded patch bytes
+pub const PATCH_BYTES: &str = "bidiff_patch_bytes";
+/// Histogram of the time spent sorting, in seconds
+pub const SORT_SECONDS: &str = "bidiff_sort_seconds";
+/// Histogram of the time spent scanning, in seconds
+pub const SCAN_SECONDS: &str = "bidiff_scan_seconds";
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use super::*;
+    #[cfg(feature = "enc")]
+    use std::io::{self, Write};
+
+    pub(crate) fn record_sort(older_len: usize, elapsed: Duration) {
+        metrics::counter!(OLDER_BYTES).increment(older_len as u64);
+        metrics::histogram!(SORT_SECONDS).record(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn record_scan(newer_len: usize, elapsed: Duration) {
+        metrics::counter!(NEWER_BYTES).increment(newer_len as u64);
+        metrics::histogram!(SCAN_SECONDS).record(elapsed.as_secs_f64());
+    }
+
+    /// Record the number of bytes `f` writes to `out`, in [PATCH_BYTES]
+    #[cfg(feature = "enc")]
+    pub(crate) fn count_patch<F, T>(out: &mut dyn Write, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<T>,
+    {
+        let mut counting = CountingWriter {
+            inner: out,
+            count: 0,
+        };
+        let res = f(&mut counting);
+        metrics::counter!(PATCH_BYTES).increment(counting.count);
+        res
+    }
+
+    #[cfg(feature = "enc")]
+    struct CountingWriter<'a> {
+        inner: &'a mut dyn Write,
+        count: u64,
+    }
+
+    #[cfg(feature = "enc")]
+    impl Write for CountingWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = self.inner.write(buf)?;
+            self.count += n as u64;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    use super::*;
+    #[cfg(feature = "enc")]
+    use std::io::{self, Write};
+
+    #[inline(always)]
+    pub(crate) fn record_sort(_older_len: usize, _elapsed: Duration) {}
+
+    #[inline(always)]
+    pub(crate) fn record_scan(_newer_len: usize, _elapsed: Duration) {}
+
+    #[cfg(feature = "enc")]
+    #[inline(always)]
+    pub(crate) fn count_patch<F, T>(out: &mut dyn Write, f: F) -> io::Result<T>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<T>,
+    {
+        f(out)
+    }
+}
+
+pub(crate) use imp::*;
@@ -2,19 +2,192 @@ use byteorder::{LittleEndian, ReadBytesExt};
 use integer_encoding::VarIntReader;
 use std::{
     cmp::min,
+    convert::TryFrom,
     error::Error as StdError,
     fmt,
     io::{self, ErrorKind, Read, Seek, SeekFrom},
 };
 
 pub const MAGIC: u32 = 0xB1DF;
-pub const VERSION: u32 = 0x1000;
+pub const VERSION: u32 = 0x1001;
+/// Version of patches written before header flags were introduced
+pub const LEGACY_VERSION: u32 = 0x1000;
+
+/// The patch is made of independently-applicable segments
+pub const FLAG_SEGMENTED: u32 = 1 << 0;
+/// The controls are followed by a manifest of the "older" regions they use
+pub const FLAG_MANIFEST: u32 = 1 << 1;
+/// Add regions store the "newer" bytes as-is rather than a difference
+pub const FLAG_RAW_DELTA: u32 = 1 << 2;
+/// The rest of the patch is a zstd-compressed patch, see [decompress]
+pub const FLAG_ZSTD: u32 = 1 << 3;
+/// The rest of the patch is an xz-compressed patch, see [decompress]
+pub const FLAG_XZ: u32 = 1 << 4;
+/// The header is followed by a signature, and then by the signed patch, see
+/// [signed_bytes]
+pub const FLAG_SIGNED: u32 = 1 << 5;
+/// The literal bytes of each control are followed by a copy of bytes of the
+/// "newer" file that were already reconstructed
+pub const FLAG_TARGET_COPY: u32 = 1 << 6;
+/// The controls are followed by a SHA-256 of the "newer" file, after the
+/// manifest if there's one
+pub const FLAG_CHECKSUM: u32 = 1 << 7;
+/// The header is followed by a SHA-256 of the "older" file, which
+/// [Reader::new] checks
+pub const FLAG_SOURCE_CHECKSUM: u32 = 1 << 9;
+
+/// Marks the end of the controls, when they're followed by a trailer
+pub const END_OF_CONTROLS: u64 = u64::MAX;
+
+const KNOWN_FLAGS: u32 = FLAG_SEGMENTED
+    | FLAG_MANIFEST
+    | FLAG_RAW_DELTA
+    | FLAG_TARGET_COPY
+    | FLAG_CHECKSUM
+    | FLAG_SOURCE_CHECKSUM;
+/// Flags of patches whose controls are followed by a trailer
+const TRAILER_FLAGS: u32 = FLAG_MANIFEST | FLAG_CHECKSUM;
+const COMPRESSION_FLAGS: u32 = FLAG_ZSTD | FLAG_XZ;
+
+/// Read the magic, version and flags at the start of a patch
+fn read_header<R: Read>(patch: &mut R) -> Result<(u32, u32), DecodeError> {
+    let magic = patch.read_u32::<LittleEndian>()?;
+    if magic != MAGIC {
+        return Err(DecodeError::WrongMagic(magic));
+    }
+
+    let version = patch.read_u32::<LittleEndian>()?;
+    let flags = match version {
+        VERSION => patch.read_u32::<LittleEndian>()?,
+        LEGACY_VERSION => 0,
+        _ => return Err(DecodeError::WrongVersion(version)),
+    };
+    Ok((version, flags))
+}
+
+/// Split a patch into its signature, if any, and the bytes it signs
+fn split_signed(patch: &[u8]) -> Result<(Option<&[u8]>, &[u8]), DecodeError> {
+    let mut r = patch;
+    let (_, flags) = read_header(&mut r)?;
+    if flags & FLAG_SIGNED == 0 {
+        return Ok((None, patch));
+    }
+
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    if r.len() < len {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok((Some(&r[..len]), &r[len..]))
+}
+
+/// The bytes of `patch` a signature is computed over: all of it for unsigned
+/// patches, and everything after the signature for signed ones.
+///
+/// Signing a patch doesn't change its signed bytes, so a signature can be
+/// computed over an unsigned patch and then added with `bidiff`'s
+/// `WriterOptions::signature`.
+pub fn signed_bytes(patch: &[u8]) -> Result<&[u8], DecodeError> {
+    split_signed(patch).map(|(_, signed)| signed)
+}
+
+/// The signature stored in the header of `patch`, if it's signed. Checking it
+/// against [signed_bytes] is up to the caller.
+pub fn signature(patch: &[u8]) -> Result<Option<&[u8]>, DecodeError> {
+    split_signed(patch).map(|(signature, _)| signature)
+}
+
+/// Sizes recorded in the header of a compressed patch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressedSizes {
+    /// Size of the uncompressed patch
+    pub raw: u64,
+    /// Size of the compressed patch, excluding its header
+    pub compressed: u64,
+}
+
+/// Read the header of a patch written by `bidiff::auto_diff`, returning the
+/// sizes it records, or `None` if the patch isn't compressed.
+pub fn compressed_sizes<R: Read>(mut patch: R) -> Result<Option<CompressedSizes>, DecodeError> {
+    let (_, flags) = read_header(&mut patch)?;
+    if flags & COMPRESSION_FLAGS == 0 {
+        return Ok(None);
+    }
+    Ok(Some(read_compressed_sizes(&mut patch)?))
+}
+
+fn read_compressed_sizes<R: Read>(patch: &mut R) -> Result<CompressedSizes, DecodeError> {
+    Ok(CompressedSizes {
+        raw: patch.read_u64::<LittleEndian>()?,
+        compressed: patch.read_u64::<LittleEndian>()?,
+    })
+}
+
+/// Undo the compression of a patch written by `bidiff::auto_diff`, returning
+/// a patch that can be passed to [Reader::new].
+///
+/// Uncompressed patches are returned as-is, compressed ones need the `zstd` or
+/// `xz` feature, as appropriate.
+pub fn decompress<'a, R>(mut patch: R) -> Result<Box<dyn Read + 'a>, DecodeError>
+where
+    R: Read + 'a,
+{
+    let (version, flags) = read_header(&mut patch)?;
+    let compression = flags & COMPRESSION_FLAGS;
+    if compression != 0 {
+        let sizes = read_compressed_sizes(&mut patch)?;
+        return decoder(compression, patch.take(sizes.compressed));
+    }
+
+    // put the header back, `Reader::new` wants it
+    let mut header = Vec::with_capacity(12);
+    header.extend_from_slice(&MAGIC.to_le_bytes());
+    header.extend_from_slice(&version.to_le_bytes());
+    if version != LEGACY_VERSION {
+        header.extend_from_slice(&flags.to_le_bytes());
+    }
+    Ok(Box::new(io::Cursor::new(header).chain(patch)))
+}
+
+#[cfg_attr(not(any(feature = "zstd", feature = "xz")), allow(unused_variables))]
+fn decoder<'a, R>(compression: u32, patch: R) -> Result<Box<dyn Read + 'a>, DecodeError>
+where
+    R: Read + 'a,
+{
+    match compression {
+        #[cfg(feature = "zstd")]
+        FLAG_ZSTD => Ok(Box::new(zstd::stream::read::Decoder::new(patch)?)),
+        #[cfg(feature = "xz")]
+        FLAG_XZ => Ok(Box::new(xz2::read::XzDecoder::new(patch))),
+        _ => Err(DecodeError::UnsupportedCompression(compression)),
+    }
+}
 
 #[derive(Debug)]
 pub enum DecodeError {
     IO(io::Error),
     WrongMagic(u32),
     WrongVersion(u32),
+    UnknownFlags(u32),
+    /// The patch is compressed with a compressor that wasn't compiled in, or
+    /// was passed to [Reader::new] without going through [decompress] first
+    UnsupportedCompression(u32),
+    /// The patch adds from "older" up to this offset, or seeks to it, but
+    /// it's outside of "older". Applying the patch fails with an
+    /// [io::Error] of kind [ErrorKind::InvalidData] wrapping this.
+    OutOfBounds(i64),
+    /// The reconstructed "newer" file doesn't match the checksum of the
+    /// patch, see [FLAG_CHECKSUM]. Reading it fails with an [io::Error] of
+    /// kind [ErrorKind::InvalidData] wrapping this, once all of it was read.
+    ChecksumMismatch {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+    /// The "older" file isn't the one the patch was made from, see
+    /// [FLAG_SOURCE_CHECKSUM]
+    WrongBaseImage {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
 }
 
 impl fmt::Display for DecodeError {
@@ -27,6 +200,39 @@ impl fmt::Display for DecodeError {
             DecodeError::WrongVersion(e) => {
                 write!(f, "wrong version: expected `{:X}`, got `{:X}`", VERSION, e)
             }
+            DecodeError::UnknownFlags(e) => write!(f, "unknown header flags: `{:X}`", e),
+            DecodeError::UnsupportedCompression(e) => {
+                write!(f, "unsupported patch compression: `{:X}`", e)
+            }
+            DecodeError::OutOfBounds(offset) => {
+                write!(
+                    f,
+                    "patch reads out of bounds of the old file, at {}",
+                    offset
+                )
+            }
+            DecodeError::ChecksumMismatch { expected, actual } => {
+                write!(f, "checksum mismatch: expected ")?;
+                for b in expected {
+                    write!(f, "{:02x}", b)?;
+                }
+                write!(f, ", got ")?;
+                for b in actual {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
+            DecodeError::WrongBaseImage { expected, actual } => {
+                write!(f, "patch was made from another old file: expected ")?;
+                for b in expected {
+                    write!(f, "{:02x}", b)?;
+                }
+                write!(f, ", got ")?;
+                for b in actual {
+                    write!(f, "{:02x}", b)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -37,6 +243,11 @@ impl StdError for DecodeError {
             DecodeError::IO(e) => Some(e),
             DecodeError::WrongMagic { .. } => None,
             DecodeError::WrongVersion { .. } => None,
+            DecodeError::UnknownFlags { .. } => None,
+            DecodeError::UnsupportedCompression { .. } => None,
+            DecodeError::OutOfBounds { .. } => None,
+            DecodeError::ChecksumMismatch { .. } => None,
+            DecodeError::WrongBaseImage { .. } => None,
         }
     }
 }
@@ -47,15 +258,43 @@ impl From<io::Error> for DecodeError {
     }
 }
 
+/// SHA-256 of all of `r`, from its start
+fn hash_all<R: Read + Seek>(r: &mut R) -> io::Result<[u8; 32]> {
+    r.seek(SeekFrom::Start(0))?;
+    let mut hasher = hmac_sha256::Hash::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match r.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finalize()),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Applies a patch to the "older" file, reading as the "newer" file
+///
+/// Patches with [FLAG_TARGET_COPY] keep everything read so far in memory.
 pub struct Reader<R, RS>
 where
     R: Read,
     RS: Read + Seek,
 {
-    patch: R,
+    patch: io::Take<R>,
     old: RS,
+    old_pos: u64,
+    old_len: u64,
     state: ReaderState,
     buf: Vec<u8>,
+    flags: u32,
+    // lengths of the segments that haven't been started yet, in reverse order
+    segments: Vec<u64>,
+    manifest: Option<Vec<(u64, u64)>>,
+    // everything read so far, for patches with target copies
+    history: Option<Vec<u8>>,
+    // hash of everything read so far, for patches with a checksum
+    hasher: Option<hmac_sha256::Hash>,
 }
 
 #[derive(Debug)]
@@ -63,6 +302,7 @@ enum ReaderState {
     Initial,
     Add(usize),
     Copy(usize),
+    Target(usize, usize),
     Final,
 }
 
@@ -71,24 +311,225 @@ where
     R: Read,
     RS: Read + Seek,
 {
-    pub fn new(mut patch: R, old: RS) -> Result<Self, DecodeError> {
-        let magic = patch.read_u32::<LittleEndian>()?;
-        if magic != MAGIC {
-            return Err(DecodeError::WrongMagic(magic));
+    /// Read the header of `patch`, and check that `old` is the file it was
+    /// made from, for patches with [FLAG_SOURCE_CHECKSUM]
+    pub fn new(patch: R, old: RS) -> Result<Self, DecodeError> {
+        Self::open(patch, old, true)
+    }
+
+    fn open(mut patch: R, mut old: RS, check_source: bool) -> Result<Self, DecodeError> {
+        let (_, mut flags) = read_header(&mut patch)?;
+        if flags & FLAG_SIGNED != 0 {
+            let len = patch.read_u32::<LittleEndian>()?;
+            io::copy(&mut (&mut patch).take(len as u64), &mut io::sink())?;
+            flags = read_header(&mut patch)?.1;
+        }
+        if flags & COMPRESSION_FLAGS != 0 {
+            return Err(DecodeError::UnsupportedCompression(
+                flags & COMPRESSION_FLAGS,
+            ));
+        }
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(DecodeError::UnknownFlags(flags));
+        }
+        let mut source_checksum = None;
+        if flags & FLAG_SOURCE_CHECKSUM != 0 {
+            let mut checksum = [0u8; 32];
+            patch.read_exact(&mut checksum)?;
+            source_checksum = Some(checksum);
         }
 
-        let version = patch.read_u32::<LittleEndian>()?;
-        if version != VERSION {
-            return Err(DecodeError::WrongMagic(version));
+        let mut segments = Vec::new();
+        let mut limit = u64::MAX;
+        if flags & FLAG_SEGMENTED != 0 {
+            let num_segments: usize = patch.read_varint()?;
+            for _ in 0..num_segments {
+                segments.push(patch.read_varint()?);
+            }
+            segments.reverse();
+            limit = 0;
         }
 
+        let old_pos = old.stream_position()?;
+        let old_len = old.seek(SeekFrom::End(0))?;
+        if let Some(expected) = source_checksum.filter(|_| check_source) {
+            let actual = hash_all(&mut old)?;
+            if actual != expected {
+                return Err(DecodeError::WrongBaseImage { expected, actual });
+            }
+        }
+        old.seek(SeekFrom::Start(old_pos))?;
+
         Ok(Self {
-            patch,
+            patch: patch.take(limit),
             old,
+            old_pos,
+            old_len,
             state: ReaderState::Initial,
             buf: vec![0u8; 4096],
+            flags,
+            segments,
+            manifest: None,
+            history: (flags & FLAG_TARGET_COPY != 0).then(Vec::new),
+            hasher: (flags & FLAG_CHECKSUM != 0).then(hmac_sha256::Hash::new),
         })
     }
+
+    /// The distinct `(offset, length)` regions of the "older" file used by
+    /// the patch, sorted by offset.
+    ///
+    /// Only available once the whole patch has been read, and if it was
+    /// written with a manifest.
+    pub fn manifest(&self) -> Option<&[(u64, u64)]> {
+        self.manifest.as_deref()
+    }
+
+    /// Walk the controls without applying them, collecting the regions of
+    /// the "older" file they read from
+    fn walk_old_regions(mut self) -> io::Result<Vec<(u64, u64)>> {
+        let mut regions = Vec::new();
+        let mut old_pos: i64 = 0;
+        loop {
+            if self.patch.limit() == 0 {
+                match self.segments.pop() {
+                    Some(segment_len) => {
+                        self.patch.set_limit(segment_len);
+                        old_pos = 0;
+                        continue;
+                    }
+                    None if self.flags & FLAG_SEGMENTED != 0 => break,
+                    None => {}
+                }
+            }
+
+            let add_len = match self.patch.read_varint::<u64>() {
+                Ok(END_OF_CONTROLS) if self.flags & TRAILER_FLAGS != 0 => break,
+                Ok(add_len) => add_len,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            skip(&mut self.patch, add_len)?;
+            let add_end = i64::try_from(add_len)
+                .ok()
+                .and_then(|len| old_pos.checked_add(len))
+                .ok_or_else(|| out_of_bounds(i64::MAX))?;
+            // raw add regions don't read from the old file
+            if add_len > 0 && self.flags & FLAG_RAW_DELTA == 0 {
+                if old_pos < 0 {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "seek before the start of the old file",
+                    ));
+                }
+                regions.push((old_pos as u64, add_end as u64));
+            }
+            old_pos = add_end;
+
+            let copy_len: u64 = self.patch.read_varint()?;
+            skip(&mut self.patch, copy_len)?;
+
+            if self.flags & FLAG_TARGET_COPY != 0 {
+                let target_len: u64 = self.patch.read_varint()?;
+                if target_len > 0 {
+                    let _target_start: u64 = self.patch.read_varint()?;
+                }
+            }
+
+            let seek: i64 = self.patch.read_varint()?;
+            old_pos = old_pos
+                .checked_add(seek)
+                .ok_or_else(|| out_of_bounds(old_pos.saturating_add(seek)))?;
+        }
+
+        regions.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(regions.len());
+        for (start, end) in regions {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Read what follows the literal bytes of a control: the target copy, if
+    /// any, and the seek
+    fn end_copy(&mut self) -> io::Result<ReaderState> {
+        if self.flags & FLAG_TARGET_COPY != 0 {
+            let target_len: usize = self.patch.read_varint()?;
+            if target_len > 0 {
+                let target_start: usize = self.patch.read_varint()?;
+                return Ok(ReaderState::Target(target_start, target_len));
+            }
+        }
+        let seek: i64 = self.patch.read_varint()?;
+        self.seek_old(seek)?;
+        Ok(ReaderState::Initial)
+    }
+
+    /// Seek "older" by `delta`, as long as that stays within its bounds
+    fn seek_old(&mut self, delta: i64) -> io::Result<()> {
+        let pos = (self.old_pos as i64).saturating_add(delta);
+        if pos < 0 || pos as u64 > self.old_len {
+            return Err(out_of_bounds(pos));
+        }
+        self.old.seek(SeekFrom::Current(delta))?;
+        self.old_pos = pos as u64;
+        Ok(())
+    }
+
+    /// Compare what was read so far against the checksum that follows the
+    /// manifest, if the patch has one
+    fn check_checksum(&mut self) -> io::Result<()> {
+        let hasher = match self.hasher.take() {
+            Some(hasher) => hasher,
+            None => return Ok(()),
+        };
+        let mut expected = [0u8; 32];
+        self.patch.read_exact(&mut expected)?;
+        let actual = hasher.finalize();
+        if actual != expected {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                DecodeError::ChecksumMismatch { expected, actual },
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_manifest(&mut self) -> io::Result<Vec<(u64, u64)>> {
+        let len: usize = self.patch.read_varint()?;
+        let mut manifest = Vec::with_capacity(min(len, 4096));
+        for _ in 0..len {
+            let offset = self.patch.read_varint()?;
+            let length = self.patch.read_varint()?;
+            manifest.push((offset, length));
+        }
+        Ok(manifest)
+    }
+}
+
+/// The `(start, end)` regions of the "older" file that applying `patch` reads
+/// from, sorted and merged so that they're disjoint.
+///
+/// This only walks the controls, so it's cheap compared to applying the
+/// patch, and doesn't need the "older" file. Storage layers can use it to
+/// find out which patches depend on the same parts of a file.
+pub fn old_regions<R: Read>(patch: R) -> Result<Vec<(u64, u64)>, DecodeError> {
+    let r = Reader::open(patch, io::Cursor::new(&[][..]), false)?;
+    Ok(r.walk_old_regions()?)
+}
+
+fn out_of_bounds(offset: i64) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, DecodeError::OutOfBounds(offset))
+}
+
+fn skip<R: Read>(r: &mut R, len: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut r.take(len), &mut io::sink())?;
+    if skipped < len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
 }
 
 impl<R, RS> Read for Reader<R, RS>
@@ -101,13 +542,34 @@ where
 
         while !buf.is_empty() {
             let processed = match self.state {
-                ReaderState::Initial => match self.patch.read_varint() {
+                ReaderState::Initial if self.patch.limit() == 0 && !self.segments.is_empty() => {
+                    // each segment starts over from the beginning of the old file
+                    let segment_len = self.segments.pop().unwrap_or_default();
+                    self.patch.set_limit(segment_len);
+                    self.old.seek(SeekFrom::Start(0))?;
+                    self.old_pos = 0;
+                    0
+                }
+                ReaderState::Initial => match self.patch.read_varint::<u64>() {
+                    Ok(END_OF_CONTROLS) if self.flags & TRAILER_FLAGS != 0 => {
+                        if self.flags & FLAG_MANIFEST != 0 {
+                            self.manifest = Some(self.read_manifest()?);
+                        }
+                        self.state = ReaderState::Final;
+                        self.check_checksum()?;
+                        0
+                    }
                     Ok(add_len) => {
-                        self.state = ReaderState::Add(add_len);
+                        if add_len > self.old_len.saturating_sub(self.old_pos) {
+                            let end = self.old_pos.saturating_add(add_len);
+                            return Err(out_of_bounds(end.min(i64::MAX as u64) as i64));
+                        }
+                        self.state = ReaderState::Add(add_len as usize);
                         0
                     }
                     Err(e) => match e.kind() {
-                        ErrorKind::UnexpectedEof => {
+                        // the checksum can't be missing
+                        ErrorKind::UnexpectedEof if self.hasher.is_none() => {
                             self.state = ReaderState::Final;
                             0
                         }
@@ -120,14 +582,20 @@ where
                     let n = min(min(add_len, buf.len()), self.buf.len());
 
                     let out = &mut buf[..n];
-                    self.old.read_exact(out)?;
+                    if self.flags & FLAG_RAW_DELTA != 0 {
+                        self.patch.read_exact(out)?;
+                        self.old.seek(SeekFrom::Current(n as i64))?;
+                    } else {
+                        self.old.read_exact(out)?;
 
-                    let dif = &mut self.buf[..n];
-                    self.patch.read_exact(dif)?;
+                        let dif = &mut self.buf[..n];
+                        self.patch.read_exact(dif)?;
 
-                    for i in 0..n {
-                        out[i] = out[i].wrapping_add(dif[i]);
+                        for i in 0..n {
+                            out[i] = out[i].wrapping_add(dif[i]);
+                        }
                     }
+                    self.old_pos += n as u64;
 
                     if add_len == n {
                         let copy_len: usize = self.patch.read_varint()?;
@@ -145,11 +613,32 @@ where
                     self.patch.read_exact(out)?;
 
                     if copy_len == n {
+                        self.state = self.end_copy()?;
+                    } else {
+                        self.state = ReaderState::Copy(copy_len - n);
+                    }
+
+                    n
+                }
+                ReaderState::Target(start, len) => {
+                    let history = self.history.as_deref().unwrap_or_default();
+                    if start >= history.len() {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            "target copy from past the reconstructed output",
+                        ));
+                    }
+                    // copies can overlap what they produce, so stop at
+                    // what's been reconstructed so far
+                    let n = min(min(len, buf.len()), history.len() - start);
+                    buf[..n].copy_from_slice(&history[start..start + n]);
+
+                    if len == n {
                         let seek: i64 = self.patch.read_varint()?;
-                        self.old.seek(SeekFrom::Current(seek))?;
+                        self.seek_old(seek)?;
                         self.state = ReaderState::Initial;
                     } else {
-                        self.state = ReaderState::Copy(copy_len - n);
+                        self.state = ReaderState::Target(start + n, len - n);
                     }
 
                     n
@@ -158,6 +647,12 @@ where
                     break;
                 }
             };
+            if let Some(history) = self.history.as_mut() {
+                history.extend_from_slice(&buf[..processed]);
+            }
+            if let Some(hasher) = self.hasher.as_mut() {
+                hasher.update(&buf[..processed]);
+            }
             read += processed;
             buf = &mut buf[processed..];
         }
@@ -0,0 +1,37 @@
+//! Arbitrary bytes as a patch: `dec::apply` may fail on them, but never
+//! panic.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::{self, Write};
+
+/// The "older" file of the `short_patch` test, which the seed patch was made
+/// from
+const OLDER: [u8; 32] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 0,
+];
+
+/// Most bytes a patch may write: short patches can describe huge outputs,
+/// with target copies, and those aren't bugs
+const MAX_OUTPUT: usize = 1024 * 1024;
+
+/// Discards what's written to it, failing past [MAX_OUTPUT] bytes
+struct Bounded(usize);
+
+impl Write for Bounded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        if self.0 > MAX_OUTPUT {
+            return Err(io::Error::other("output too long"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fuzz_target!(|patch: &[u8]| {
+    let _ = bidiff::dec::apply(&OLDER, &mut &patch[..], &mut Bounded(0));
+});
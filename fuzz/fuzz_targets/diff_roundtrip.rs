@@ -0,0 +1,29 @@
+//! Two arbitrary buffers, diffed then patched back: the patch must rebuild
+//! "newer" exactly.
+//!
+//! Inputs start with the length of "older" as a little-endian u16, then the
+//! scan chunk size (0 for none), followed by "older" and "newer".
+#![no_main]
+
+use bidiff::DiffParams;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let (header, data) = match data {
+        [lo, hi, chunk_size, data @ ..] => ((u16::from_le_bytes([*lo, *hi]), *chunk_size), data),
+        _ => return,
+    };
+    let (older_len, chunk_size) = (header.0 as usize, header.1 as usize);
+    let (older, newer) = data.split_at(older_len.min(data.len()));
+
+    let chunk_size = Some(chunk_size).filter(|size| *size > 0);
+    let params = DiffParams::new(1, chunk_size)
+        .unwrap()
+        .with_coverage_check(true);
+    let mut patch = Vec::new();
+    bidiff::simple_diff_with_params(older, newer, &mut patch, &params).unwrap();
+
+    let mut fresh = Vec::new();
+    bidiff::dec::apply(older, &mut &patch[..], &mut fresh).unwrap();
+    assert_eq!(fresh, newer);
+});